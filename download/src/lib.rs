@@ -41,12 +41,28 @@ fn download_with_backend(
     resume_from: u64,
     callback: &dyn Fn(Event<'_>) -> Result<()>,
 ) -> Result<()> {
+    // Local files are read the same way whatever the backend
+    if url.scheme() == "file" {
+        return file::download(url, resume_from, callback);
+    }
     match backend {
         Backend::Curl => curl::download(url, resume_from, callback),
         Backend::Reqwest(tls) => reqwest_be::download(url, resume_from, callback, tls),
     }
 }
 
+/// The size of what `url` would download, if the server says what it is,
+/// found without downloading it
+pub fn content_length_with_backend(backend: Backend, url: &Url) -> Result<Option<u64>> {
+    if url.scheme() == "file" {
+        return file::content_length(url);
+    }
+    match backend {
+        Backend::Curl => curl::content_length(url),
+        Backend::Reqwest(tls) => reqwest_be::content_length(url, tls),
+    }
+}
+
 pub fn download_to_path_with_backend(
     backend: Backend,
     url: &Url,
@@ -137,6 +153,65 @@ pub fn download_to_path_with_backend(
     })
 }
 
+/// Read `file:` URLs straight from the disk, e.g. for a dist server which is
+/// a local directory
+pub mod file {
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
+
+    use anyhow::{anyhow, Context, Result};
+    use url::Url;
+
+    use super::Event;
+    use crate::errors::*;
+
+    pub fn download(
+        url: &Url,
+        resume_from: u64,
+        callback: &dyn Fn(Event<'_>) -> Result<()>,
+    ) -> Result<()> {
+        let src = url
+            .to_file_path()
+            .map_err(|_| DownloadError::Message(format!("bogus file url: '{}'", url)))?;
+        if !src.is_file() {
+            // Because some of rustup's logic depends on checking
+            // the error when a downloaded file doesn't exist, make
+            // the file case return the same error value as the
+            // network case.
+            return Err(anyhow!(DownloadError::FileNotFound));
+        }
+
+        let mut f = fs::File::open(src).context("unable to open downloaded file")?;
+        let len = f.metadata()?.len();
+        if resume_from > len {
+            // What a server answers to a range past the end of the file
+            return Err(anyhow!(DownloadError::HttpStatus(416)));
+        }
+        // Like the Content-Length of a server, plus what was resumed
+        callback(Event::DownloadContentLengthReceived(len))?;
+        f.seek(SeekFrom::Start(resume_from))?;
+
+        let mut buffer = vec![0u8; 0x10000];
+        loop {
+            let bytes_read = f.read(&mut buffer)?;
+            if bytes_read == 0 {
+                return Ok(());
+            }
+            callback(Event::DownloadDataReceived(&buffer[0..bytes_read]))?;
+        }
+    }
+
+    pub fn content_length(url: &Url) -> Result<Option<u64>> {
+        let src = url
+            .to_file_path()
+            .map_err(|_| DownloadError::Message(format!("bogus file url: '{}'", url)))?;
+        match fs::metadata(src) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(metadata.len())),
+            _ => Err(anyhow!(DownloadError::FileNotFound)),
+        }
+    }
+}
+
 /// Download via libcurl; encrypt with the native (or OpenSSl) TLS
 /// stack via libcurl
 #[cfg(feature = "curl-backend")]
@@ -250,6 +325,30 @@ pub mod curl {
             Ok(())
         })
     }
+
+    pub fn content_length(url: &Url) -> Result<Option<u64>> {
+        // A HEAD request leaves the handle unfit for downloads, so it gets
+        // one of its own
+        let mut handle = Easy::new();
+        handle.url(url.as_ref())?;
+        handle.follow_location(true)?;
+        handle.useragent(super::USER_AGENT)?;
+        handle.nobody(true)?;
+        handle.connect_timeout(Duration::new(30, 0))?;
+        handle.perform().context("error during request")?;
+
+        let code = handle.response_code()?;
+        match code {
+            0 | 200..=299 => {}
+            _ => {
+                return Err(DownloadError::HttpStatus(code).into());
+            }
+        };
+
+        // Which is negative when the server did not say
+        let len = handle.content_length_download()?;
+        Ok(if len >= 0.0 { Some(len as u64) } else { None })
+    }
 }
 
 #[cfg(feature = "reqwest-backend")]
@@ -273,11 +372,6 @@ pub mod reqwest_be {
         callback: &dyn Fn(Event<'_>) -> Result<()>,
         tls: TlsBackend,
     ) -> Result<()> {
-        // Short-circuit reqwest for the "file:" URL scheme
-        if download_from_file_url(url, resume_from, callback)? {
-            return Ok(());
-        }
-
         let mut res = request(url, resume_from, tls).context("failed to make network request")?;
 
         if !res.status().is_success() {
@@ -305,6 +399,24 @@ pub mod reqwest_be {
         }
     }
 
+    pub fn content_length(url: &Url, tls: TlsBackend) -> Result<Option<u64>> {
+        let res = client(tls)?
+            .head(url.as_str())
+            .send()
+            .context("failed to make network request")?;
+
+        if !res.status().is_success() {
+            let code: u16 = res.status().into();
+            return Err(anyhow!(DownloadError::HttpStatus(u32::from(code))));
+        }
+
+        Ok(res
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok()))
+    }
+
     fn client_generic() -> ClientBuilder {
         Client::builder()
             .gzip(false)
@@ -356,7 +468,17 @@ pub mod reqwest_be {
         resume_from: u64,
         backend: TlsBackend,
     ) -> Result<Response, DownloadError> {
-        let client: &Client = match backend {
+        let mut req = client(backend)?.get(url.as_str());
+
+        if resume_from != 0 {
+            req = req.header(header::RANGE, format!("bytes={}-", resume_from));
+        }
+
+        Ok(req.send()?)
+    }
+
+    fn client(backend: TlsBackend) -> Result<&'static Client, DownloadError> {
+        let client: &'static Client = match backend {
             #[cfg(feature = "reqwest-rustls-tls")]
             TlsBackend::Rustls => &CLIENT_RUSTLS_TLS,
             #[cfg(not(feature = "reqwest-rustls-tls"))]
@@ -370,51 +492,7 @@ pub mod reqwest_be {
                 return Err(DownloadError::BackendUnavailable("reqwest default TLS"));
             }
         };
-        let mut req = client.get(url.as_str());
-
-        if resume_from != 0 {
-            req = req.header(header::RANGE, format!("bytes={}-", resume_from));
-        }
-
-        Ok(req.send()?)
-    }
-
-    fn download_from_file_url(
-        url: &Url,
-        resume_from: u64,
-        callback: &dyn Fn(Event<'_>) -> Result<()>,
-    ) -> Result<bool> {
-        use std::fs;
-
-        // The file scheme is mostly for use by tests to mock the dist server
-        if url.scheme() == "file" {
-            let src = url
-                .to_file_path()
-                .map_err(|_| DownloadError::Message(format!("bogus file url: '{}'", url)))?;
-            if !src.is_file() {
-                // Because some of rustup's logic depends on checking
-                // the error when a downloaded file doesn't exist, make
-                // the file case return the same error value as the
-                // network case.
-                return Err(anyhow!(DownloadError::FileNotFound));
-            }
-
-            let mut f = fs::File::open(src).context("unable to open downloaded file")?;
-            io::Seek::seek(&mut f, io::SeekFrom::Start(resume_from))?;
-
-            let mut buffer = vec![0u8; 0x10000];
-            loop {
-                let bytes_read = io::Read::read(&mut f, &mut buffer)?;
-                if bytes_read == 0 {
-                    break;
-                }
-                callback(Event::DownloadDataReceived(&buffer[0..bytes_read]))?;
-            }
-
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+        Ok(client)
     }
 }
 
@@ -434,6 +512,10 @@ pub mod curl {
     ) -> Result<()> {
         Err(anyhow!(DownloadError::BackendUnavailable("curl")))
     }
+
+    pub fn content_length(_url: &Url) -> Result<Option<u64>> {
+        Err(anyhow!(DownloadError::BackendUnavailable("curl")))
+    }
 }
 
 #[cfg(not(feature = "reqwest-backend"))]
@@ -454,4 +536,8 @@ pub mod reqwest_be {
     ) -> Result<()> {
         Err(anyhow!(DownloadError::BackendUnavailable("reqwest")))
     }
+
+    pub fn content_length(_url: &Url, _tls: TlsBackend) -> Result<Option<u64>> {
+        Err(anyhow!(DownloadError::BackendUnavailable("reqwest")))
+    }
 }
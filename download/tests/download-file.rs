@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+use std::fs;
+
+use url::Url;
+
+use download::*;
+
+/// Every backend, as `file:` URLs are read the same way by all of them
+const BACKENDS: [Backend; 3] = [
+    Backend::Curl,
+    Backend::Reqwest(TlsBackend::Rustls),
+    Backend::Reqwest(TlsBackend::Default),
+];
+
+fn tmp_dir() -> tempfile::TempDir {
+    tempfile::Builder::new()
+        .prefix("rustup-download-test-")
+        .tempdir()
+        .expect("creating tempdir for test")
+}
+
+#[test]
+fn file_url_is_resumed_with_the_same_events_in_every_backend() {
+    for backend in BACKENDS {
+        let tmpdir = tmp_dir();
+        let from_path = tmpdir.path().join("download-source");
+        fs::write(&from_path, "xxx45").unwrap();
+        let target_path = tmpdir.path().join("downloaded");
+        fs::write(&target_path, "123").unwrap();
+
+        let events = RefCell::new(Vec::new());
+        download_to_path_with_backend(
+            backend,
+            &Url::from_file_path(&from_path).unwrap(),
+            &target_path,
+            true,
+            Some(&|event| {
+                events.borrow_mut().push(match event {
+                    Event::ResumingPartialDownload => "resuming".to_owned(),
+                    Event::DownloadContentLengthReceived(len) => format!("length {}", len),
+                    Event::DownloadDataReceived(data) => String::from_utf8(data.to_vec()).unwrap(),
+                });
+                Ok(())
+            }),
+        )
+        .expect("Test download failed");
+
+        assert_eq!(fs::read_to_string(&target_path).unwrap(), "12345");
+        assert_eq!(
+            events.into_inner(),
+            vec!["resuming", "123", "length 5", "45"],
+            "{:?}",
+            backend
+        );
+    }
+}
+
+#[test]
+fn missing_file_url_is_not_found_in_every_backend() {
+    for backend in BACKENDS {
+        let tmpdir = tmp_dir();
+        let from_url = Url::from_file_path(tmpdir.path().join("missing")).unwrap();
+        let target_path = tmpdir.path().join("downloaded");
+
+        let err = download_to_path_with_backend(backend, &from_url, &target_path, false, None)
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<DownloadError>(),
+                Some(DownloadError::FileNotFound)
+            ),
+            "{:?}: {}",
+            backend,
+            err
+        );
+        assert!(!target_path.exists());
+    }
+}
+
+#[test]
+fn file_url_content_length_in_every_backend() {
+    for backend in BACKENDS {
+        let tmpdir = tmp_dir();
+        let from_path = tmpdir.path().join("download-source");
+        fs::write(&from_path, "12345").unwrap();
+
+        let len = content_length_with_backend(backend, &Url::from_file_path(&from_path).unwrap())
+            .expect("Test content length failed");
+        assert_eq!(len, Some(5), "{:?}", backend);
+
+        let missing = Url::from_file_path(tmpdir.path().join("missing")).unwrap();
+        let err = content_length_with_backend(backend, &missing).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<DownloadError>(),
+                Some(DownloadError::FileNotFound)
+            ),
+            "{:?}: {}",
+            backend,
+            err
+        );
+    }
+}
@@ -80,3 +80,13 @@ fn callback_gets_all_data_as_if_the_download_happened_all_at_once() {
     assert_eq!(observed_bytes, vec![b'1', b'2', b'3', b'4', b'5']);
     assert_eq!(std::fs::read_to_string(&target_path).unwrap(), "12345");
 }
+
+#[test]
+fn content_length_is_found_without_downloading() {
+    let addr = serve_file(b"12345".to_vec());
+    let from_url = format!("http://{}", addr).parse().unwrap();
+
+    let len = content_length_with_backend(Backend::Reqwest(TlsBackend::Default), &from_url)
+        .expect("Test content length failed");
+    assert_eq!(len, Some(5));
+}
@@ -4,6 +4,7 @@ pub mod log;
 pub mod common;
 mod download_tracker;
 pub mod errors;
+mod format;
 mod help;
 mod job;
 mod markdown;
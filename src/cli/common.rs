@@ -11,13 +11,15 @@ use git_testament::{git_testament, render_testament};
 use lazy_static::lazy_static;
 use term2::Terminal;
 
+use super::format::{self, OutputFormat, Value};
 use super::self_update;
 use super::term2;
 use crate::dist::notifications as dist_notifications;
 use crate::process;
-use crate::toolchain::DistributableToolchain;
+use crate::toolchain::{ComponentStatus, DistributableToolchain};
 use crate::utils::notifications as util_notifications;
 use crate::utils::notify::NotificationLevel;
+use crate::utils::retry_policy::RetryPolicy;
 use crate::utils::utils;
 use crate::{Cfg, Notification, Toolchain, UpdateStatus};
 
@@ -274,7 +276,7 @@ pub(crate) fn update_all_channels(
     };
 
     if do_self_update {
-        self_update(show_channel_updates)
+        self_update(&cfg.retry_policy, show_channel_updates)
     } else {
         show_channel_updates()
     }
@@ -331,7 +333,10 @@ pub(crate) fn self_update_permitted(explicit: bool) -> Result<SelfUpdatePermissi
     }
 }
 
-pub(crate) fn self_update<F>(before_restart: F) -> Result<utils::ExitCode>
+pub(crate) fn self_update<F>(
+    retry_policy: &RetryPolicy,
+    before_restart: F,
+) -> Result<utils::ExitCode>
 where
     F: FnOnce() -> Result<utils::ExitCode>,
 {
@@ -344,7 +349,7 @@ where
         SelfUpdatePermission::Permit => {}
     }
 
-    let setup_path = self_update::prepare_update()?;
+    let setup_path = self_update::prepare_update(retry_policy)?;
 
     before_restart()?;
 
@@ -358,10 +363,16 @@ where
     Ok(utils::ExitCode(0))
 }
 
-pub(crate) fn list_targets(toolchain: &Toolchain<'_>) -> Result<utils::ExitCode> {
-    let mut t = term2::stdout();
+pub(crate) fn list_targets(
+    toolchain: &Toolchain<'_>,
+    format: OutputFormat,
+) -> Result<utils::ExitCode> {
     let distributable = DistributableToolchain::new_for_components(toolchain)?;
     let components = distributable.list_components()?;
+    if format != OutputFormat::Human {
+        return print_targets(components, false, format);
+    }
+    let mut t = term2::stdout();
     for component in components {
         if component.component.short_name_in_manifest() == "rust-std" {
             let target = component
@@ -382,10 +393,16 @@ pub(crate) fn list_targets(toolchain: &Toolchain<'_>) -> Result<utils::ExitCode>
     Ok(utils::ExitCode(0))
 }
 
-pub(crate) fn list_installed_targets(toolchain: &Toolchain<'_>) -> Result<utils::ExitCode> {
-    let mut t = term2::stdout();
+pub(crate) fn list_installed_targets(
+    toolchain: &Toolchain<'_>,
+    format: OutputFormat,
+) -> Result<utils::ExitCode> {
     let distributable = DistributableToolchain::new_for_components(toolchain)?;
     let components = distributable.list_components()?;
+    if format != OutputFormat::Human {
+        return print_targets(components, true, format);
+    }
+    let mut t = term2::stdout();
     for component in components {
         if component.component.short_name_in_manifest() == "rust-std" {
             let target = component
@@ -401,10 +418,16 @@ pub(crate) fn list_installed_targets(toolchain: &Toolchain<'_>) -> Result<utils:
     Ok(utils::ExitCode(0))
 }
 
-pub(crate) fn list_components(toolchain: &Toolchain<'_>) -> Result<utils::ExitCode> {
-    let mut t = term2::stdout();
+pub(crate) fn list_components(
+    toolchain: &Toolchain<'_>,
+    format: OutputFormat,
+) -> Result<utils::ExitCode> {
     let distributable = DistributableToolchain::new_for_components(toolchain)?;
     let components = distributable.list_components()?;
+    if format != OutputFormat::Human {
+        return print_components(components, false, format);
+    }
+    let mut t = term2::stdout();
     for component in components {
         let name = component.name;
         if component.installed {
@@ -419,10 +442,16 @@ pub(crate) fn list_components(toolchain: &Toolchain<'_>) -> Result<utils::ExitCo
     Ok(utils::ExitCode(0))
 }
 
-pub(crate) fn list_installed_components(toolchain: &Toolchain<'_>) -> Result<utils::ExitCode> {
-    let mut t = term2::stdout();
+pub(crate) fn list_installed_components(
+    toolchain: &Toolchain<'_>,
+    format: OutputFormat,
+) -> Result<utils::ExitCode> {
     let distributable = DistributableToolchain::new_for_components(toolchain)?;
     let components = distributable.list_components()?;
+    if format != OutputFormat::Human {
+        return print_components(components, true, format);
+    }
+    let mut t = term2::stdout();
     for component in components {
         if component.installed {
             writeln!(t, "{}", component.name)?;
@@ -431,6 +460,65 @@ pub(crate) fn list_installed_components(toolchain: &Toolchain<'_>) -> Result<uti
     Ok(utils::ExitCode(0))
 }
 
+fn print_targets(
+    components: Vec<ComponentStatus>,
+    installed_only: bool,
+    format: OutputFormat,
+) -> Result<utils::ExitCode> {
+    let targets = components
+        .into_iter()
+        .filter(|c| c.component.short_name_in_manifest() == "rust-std")
+        .filter(|c| c.installed || !installed_only)
+        .map(|c| {
+            let target = c
+                .component
+                .target
+                .as_ref()
+                .expect("rust-std should have a target")
+                .to_string();
+            (target, c.installed, c.available)
+        });
+    if format == OutputFormat::Json {
+        let targets = targets
+            .map(|(name, installed, available)| {
+                Value::object(vec![
+                    ("name", name.into()),
+                    ("installed", installed.into()),
+                    ("available", available.into()),
+                ])
+            })
+            .collect();
+        format::print_json(&Value::document(vec![("targets", Value::Array(targets))]))?;
+    } else {
+        for (name, installed, available) in targets {
+            format::print_tsv(&[name, installed.to_string(), available.to_string()])?;
+        }
+    }
+    Ok(utils::ExitCode(0))
+}
+
+fn print_components(
+    components: Vec<ComponentStatus>,
+    installed_only: bool,
+    format: OutputFormat,
+) -> Result<utils::ExitCode> {
+    let components = components
+        .into_iter()
+        .filter(|c| c.installed || !installed_only);
+    if format == OutputFormat::Json {
+        let components = components.map(|c| format::component(&c)).collect();
+        format::print_json(&Value::document(vec![(
+            "components",
+            Value::Array(components),
+        )]))?;
+    } else {
+        for c in components {
+            format::print_tsv(&[c.name, c.installed.to_string(), c.available.to_string()])?;
+        }
+    }
+    Ok(utils::ExitCode(0))
+}
+
 fn print_toolchain_path(
     cfg: &Cfg,
     toolchain: &str,
@@ -464,8 +552,15 @@ fn print_toolchain_path(
     Ok(())
 }
 
-pub(crate) fn list_toolchains(cfg: &Cfg, verbose: bool) -> Result<utils::ExitCode> {
+pub(crate) fn list_toolchains(
+    cfg: &Cfg,
+    verbose: bool,
+    format: OutputFormat,
+) -> Result<utils::ExitCode> {
     let toolchains = cfg.list_toolchains()?;
+    if format != OutputFormat::Human {
+        return print_toolchains(cfg, toolchains, format);
+    }
     if toolchains.is_empty() {
         writeln!(process().stdout(), "no installed toolchains")?;
     } else {
@@ -499,9 +594,86 @@ pub(crate) fn list_toolchains(cfg: &Cfg, verbose: bool) -> Result<utils::ExitCod
     Ok(utils::ExitCode(0))
 }
 
-pub(crate) fn list_overrides(cfg: &Cfg) -> Result<utils::ExitCode> {
+fn print_toolchains(
+    cfg: &Cfg,
+    toolchains: Vec<String>,
+    format: OutputFormat,
+) -> Result<utils::ExitCode> {
+    let def_toolchain_name = cfg.get_default()?.unwrap_or_default();
+    let cwd = utils::current_dir()?;
+    let ovr = cfg.find_override(&cwd).ok().flatten();
+    let mut rows = Vec::new();
+    for name in toolchains {
+        let path = cfg.toolchains_dir.join(&name);
+        let path = fs::read_link(&path).unwrap_or(path);
+        let reason = ovr
+            .as_ref()
+            .filter(|(toolchain, _)| toolchain.name() == name)
+            .map(|(_, reason)| reason);
+        let default = def_toolchain_name == name;
+        rows.push((name, default, reason, path));
+    }
+    if format == OutputFormat::Json {
+        let toolchains = rows
+            .into_iter()
+            .map(|(name, default, reason, path)| {
+                Value::object(vec![
+                    ("name", name.into()),
+                    ("path", path.display().to_string().into()),
+                    ("default", default.into()),
+                    ("override", reason.map(|r| format::reason(Some(r))).into()),
+                ])
+            })
+            .collect();
+        format::print_json(&Value::document(vec![(
+            "toolchains",
+            Value::Array(toolchains),
+        )]))?;
+    } else {
+        for (name, default, reason, path) in rows {
+            format::print_tsv(&[
+                name,
+                default.to_string(),
+                reason
+                    .map(|r| format::reason_kind(Some(r)))
+                    .unwrap_or("")
+                    .to_owned(),
+                path.display().to_string(),
+            ])?;
+        }
+    }
+    Ok(utils::ExitCode(0))
+}
+
+pub(crate) fn list_overrides(cfg: &Cfg, format: OutputFormat) -> Result<utils::ExitCode> {
     let overrides = cfg.settings_file.with(|s| Ok(s.overrides.clone()))?;
 
+    if format != OutputFormat::Human {
+        let overrides = overrides
+            .into_iter()
+            .map(|(k, v)| (Path::new(&k).is_dir(), k, v));
+        if format == OutputFormat::Json {
+            let overrides = overrides
+                .map(|(exists, path, toolchain)| {
+                    Value::object(vec![
+                        ("path", path.into()),
+                        ("toolchain", toolchain.into()),
+                        ("exists", exists.into()),
+                    ])
+                })
+                .collect();
+            format::print_json(&Value::document(vec![(
+                "overrides",
+                Value::Array(overrides),
+            )]))?;
+        } else {
+            for (exists, path, toolchain) in overrides {
+                format::print_tsv(&[path, toolchain, exists.to_string()])?;
+            }
+        }
+        return Ok(utils::ExitCode(0));
+    }
+
     if overrides.is_empty() {
         writeln!(process().stdout(), "no overrides")?;
     } else {
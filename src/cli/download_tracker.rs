@@ -86,6 +86,12 @@ impl DownloadTracker {
                 }
                 true
             }
+            Notification::Install(In::Utils(Un::DownloadBytesReceived(len))) => {
+                if tty::stdout_isatty() {
+                    self.data_received(len);
+                }
+                true
+            }
             Notification::Install(In::Utils(Un::DownloadFinished)) => {
                 self.download_finished();
                 true
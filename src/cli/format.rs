@@ -0,0 +1,258 @@
+//! Machine-readable output for the inspection commands.
+//!
+//! `--format json` and `--format tsv` are a stable interface for tools
+//! which would otherwise have to scrape the human-readable output. The
+//! layout of every document is described in the user guide, and any
+//! incompatible change to it must bump `SCHEMA_VERSION`.
+
+use std::fmt::{self, Display, Write as _};
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+
+use crate::config::OverrideReason;
+use crate::process;
+use crate::toolchain::ComponentStatus;
+
+pub(crate) const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Human,
+    Json,
+    Tsv,
+}
+
+impl OutputFormat {
+    pub(crate) const VALUES: &'static [&'static str] = &["human", "json", "tsv"];
+
+    pub(crate) fn from_matches(m: &ArgMatches<'_>) -> Result<Self> {
+        m.value_of("format").map_or(Ok(Self::Human), str::parse)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            "tsv" => Ok(Self::Tsv),
+            _ => Err(anyhow!(
+                "unknown output format: '{}'. Valid formats are: {}",
+                s,
+                Self::VALUES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Just enough of JSON to describe the documents rustup emits.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub(crate) fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Self::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    /// A top level document, tagged with the schema version
+    pub(crate) fn document<'a>(fields: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        let mut doc = vec![("schema_version", Self::Number(SCHEMA_VERSION))];
+        doc.extend(fields);
+        Self::object(doc)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Self::Number(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Self::String(s.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Self::String(s)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(o: Option<T>) -> Self {
+        o.map_or(Self::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Self {
+        Self::Array(v.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_json_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Number(n) => write!(f, "{}", n),
+            Self::String(s) => write_json_str(f, s),
+            Self::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Self::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_json_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// Print a document as a single line of JSON on stdout
+pub(crate) fn print_json(doc: &Value) -> Result<()> {
+    writeln!(process().stdout(), "{}", doc)?;
+    Ok(())
+}
+
+/// Print one tab separated record on stdout. Tabs, newlines and
+/// backslashes in fields are escaped so every record is exactly one line.
+pub(crate) fn print_tsv<S: AsRef<str>>(fields: &[S]) -> Result<()> {
+    let line = fields
+        .iter()
+        .map(|f| {
+            f.as_ref()
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\t");
+    writeln!(process().stdout(), "{}", line)?;
+    Ok(())
+}
+
+/// The stable name of an override reason; `None` is the default toolchain
+pub(crate) fn reason_kind(reason: Option<&OverrideReason>) -> &'static str {
+    match reason {
+        None => "default",
+        Some(OverrideReason::Environment) => "environment",
+        Some(OverrideReason::CommandLine) => "command-line",
+        Some(OverrideReason::OverrideDB(_)) => "directory-override",
+        Some(OverrideReason::ToolchainFile(_)) => "toolchain-file",
+    }
+}
+
+pub(crate) fn reason(reason: Option<&OverrideReason>) -> Value {
+    let path = match reason {
+        Some(OverrideReason::OverrideDB(path)) | Some(OverrideReason::ToolchainFile(path)) => {
+            Some(path.display().to_string())
+        }
+        _ => None,
+    };
+    Value::object(vec![
+        ("kind", reason_kind(reason).into()),
+        ("path", path.into()),
+    ])
+}
+
+pub(crate) fn component(status: &ComponentStatus) -> Value {
+    Value::object(vec![
+        ("name", status.name.as_str().into()),
+        (
+            "package",
+            status.component.short_name_in_manifest().as_str().into(),
+        ),
+        (
+            "target",
+            status
+                .component
+                .target
+                .as_ref()
+                .map(|t| t.to_string())
+                .into(),
+        ),
+        ("installed", status.installed.into()),
+        ("available", status.available.into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escapes_strings() {
+        let v = Value::from("a\"b\\c\nd\te\u{1}");
+        assert_eq!(v.to_string(), r#""a\"b\\c\nd\te\u0001""#);
+    }
+
+    #[test]
+    fn json_document_has_schema_version() {
+        let v = Value::document(vec![
+            ("names", vec!["a", "b"].into()),
+            ("missing", Option::<String>::None.into()),
+            ("ok", true.into()),
+        ]);
+        assert_eq!(
+            v.to_string(),
+            r#"{"schema_version":1,"names":["a","b"],"missing":null,"ok":true}"#
+        );
+    }
+
+    #[test]
+    fn parse_output_format() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!("tsv".parse::<OutputFormat>().unwrap(), OutputFormat::Tsv);
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -32,7 +32,12 @@ pub(crate) static UPDATE_HELP: &str = r"DISCUSSION:
     updates rustup itself.
 
     If given a toolchain argument then `update` updates that
-    toolchain, the same as `rustup toolchain install`.";
+    toolchain, the same as `rustup toolchain install`.
+
+    With `--dry-run` the manifests are fetched and what would be
+    installed, uninstalled and kept is shown, including the nightly
+    chosen when the latest ones lack components, but nothing is
+    downloaded or changed. `--format json` shows it as JSON.";
 
 pub(crate) static INSTALL_HELP: &str = r"DISCUSSION:
     Installs a specific rust toolchain.
@@ -85,6 +90,39 @@ pub(crate) static TOOLCHAIN_HELP: &str = r"DISCUSSION:
     often used for developing Rust itself. For more information see
     `rustup toolchain help link`.";
 
+pub(crate) static TOOLCHAIN_LOCK_HELP: &str = r"DISCUSSION:
+    Resolves the channel of the `rust-toolchain.toml` (or
+    `rust-toolchain`) file which applies to the current directory, and
+    records the result in `rustup-toolchain.lock` next to it: the dated
+    toolchain, the SHA-256 of its manifest, and every component with
+    the hash of its archive. Commit the lockfile alongside the
+    toolchain file.
+
+    While the lockfile exists, `rustc`, `cargo` and the other proxies
+    install exactly the pinned toolchain, and fail if the dist server
+    no longer serves identical files. `rustup toolchain install
+    --locked` does the same explicitly.
+
+    Run `rustup toolchain lock` again to move to the newest release of
+    the channel, or after editing the toolchain file.";
+
+pub(crate) static TOOLCHAIN_BUNDLE_HELP: &str = r"DISCUSSION:
+    Downloads everything needed to install a toolchain into a single
+    file, for installing it on a host without network access:
+
+        $ rustup toolchain bundle nightly-2022-03-01 -c clippy,rustfmt -o tc.tar
+
+    and then, on the other host:
+
+        $ rustup toolchain install --from-bundle tc.tar
+
+    The bundle holds the signed channel manifest of the toolchain and
+    the archives of the components of the profile, plus any extra
+    components and targets. Installing from it checks the manifest
+    and every archive against their hashes and signatures, just like
+    installing from the dist server does, using the keys trusted on
+    the host where the bundle is installed.";
+
 pub(crate) static TOOLCHAIN_LINK_HELP: &str = r"DISCUSSION:
     'toolchain' is the custom name to be assigned to the new toolchain.
     Any name is permitted as long as it does not fully match an initial
@@ -104,6 +142,142 @@ pub(crate) static TOOLCHAIN_LINK_HELP: &str = r"DISCUSSION:
     If you now compile a crate in the current directory, the custom
     toolchain 'latest-stage1' will be used.";
 
+pub(crate) static MIRROR_SYNC_HELP: &str = r"DISCUSSION:
+    Downloads the current release of each channel into a directory
+    laid out like the dist server, which can be served as is:
+
+        $ rustup mirror sync --channels stable,nightly --targets x86_64-unknown-linux-gnu --dest /srv/rust-dist
+
+    and then used by pointing `RUSTUP_DIST_SERVER` at it. The mirror
+    holds the archives of every component available for the given
+    targets. Without `--targets`, only the default host is mirrored.
+
+    Every manifest and archive is checked against its hash and its
+    signature before it is added to the mirror. Syncing again only
+    downloads new releases, and with `--keep-days` also removes the
+    releases which are older than that, except for the current
+    release of each channel.";
+
+pub(crate) static DIST_BUILD_CHANNEL_HELP: &str = r"DISCUSSION:
+    Builds a channel out of the rust-installer tarballs of a build of
+    Rust, into a directory laid out like the dist server:
+
+        $ rustup dist build-channel --name stable --from ./artifacts --dest /srv/rust-dist --sign-key key.asc
+
+    The tarballs are named as on the dist server, e.g.
+    `rustc-1.53.0-x86_64-unknown-linux-gnu.tar.xz` or
+    `rust-src-nightly.tar.gz`. The channel can be installed from by
+    pointing `RUSTUP_DIST_SERVER` at the directory, once the public
+    part of the signing key is added with `rustup keys add`.
+
+    The name of the channel, such as `acme-stable`, is that of its
+    manifest, `channel-rust-<name>.toml`, and the release in the
+    names of its tarballs. Every target with a `rustc` tarball is a
+    host of the channel.";
+
+pub(crate) static KEYS_HELP: &str = r"DISCUSSION:
+    Rustup verifies the signatures of everything it downloads from the
+    dist server against a set of trusted PGP keys. The builtin Rust
+    release key is always trusted. Additional keys can be added to a
+    keyring in the rustup home directory, for instance to install from
+    a private dist server:
+
+        $ rustup keys add --dist-server https://example.com/rust key.asc
+
+    A key added with `--dist-server` is only trusted when
+    `RUSTUP_DIST_SERVER`, or one of the `dist_servers` in the settings
+    file, is that URL; otherwise it is trusted for every dist server.
+    Keys are removed by fingerprint:
+
+        $ rustup keys remove 'B695 EF92 BE5C D24E D391 24DD 1F62 3994 2A48 2D51'
+
+    Adding the new key before removing the old one lets the signing
+    key of a dist server be rotated without interruption. Use `rustup
+    show keys` to see the keys currently in use and when they expire.";
+
+pub(crate) static CACHE_HELP: &str = r"DISCUSSION:
+    Archives are downloaded into the `downloads` directory of the
+    rustup home, and are normally deleted once they are installed.
+    With a `[cache]` table in `settings.toml` they are kept, so that
+    installing the same components again does not download them again:
+
+        [cache]
+        max_size_mb = 4096
+        shared_dir = '/mnt/rust-cache'
+
+    Once the cache is larger than `max_size_mb`, the archives used
+    least recently are removed. Archives in `shared_dir`, a read-only
+    copy of another cache, are used in place before going to the
+    network. Without a `[cache]` table, `rustup cache prune` removes
+    whatever a failed install left behind.";
+
+pub(crate) static GC_HELP: &str = r"DISCUSSION:
+    Removes the installed toolchains which nothing uses, along with the
+    update hashes of toolchains which are no longer installed, files
+    left in the `tmp` directory of the rustup home by runs of rustup
+    which did not finish, and downloads which were never finished.
+
+    A toolchain is used if it is the default toolchain, a directory
+    override, or named by a `rust-toolchain` or `rust-toolchain.toml`
+    file under one of the `project_roots` of `settings.toml`:
+
+        project_roots = ['/home/user/src']
+
+    Custom toolchains are never removed. What would be removed is
+    listed before asking for confirmation; to run unattended, e.g. from
+    a scheduled job, pass `--yes`, and usually `--older-than 30d` so
+    that toolchains updated in the last 30 days are kept.";
+
+pub(crate) static DU_HELP: &str = r"DISCUSSION:
+    Shows how much disk space each installed toolchain takes up, and
+    within each one how much every component takes up, according to
+    the list of files the component installed. The `downloads` and
+    `tmp` directories are shown separately, and the total covers the
+    whole rustup home.
+
+    With `--format json` or `--format tsv` the sizes are in bytes.
+    `--json` is the same as `--format json`.";
+
+pub(crate) static HISTORY_HELP: &str = r"DISCUSSION:
+    Every rustup command which changes the rustup home is logged in
+    its `history` file: installs, updates, uninstalls, component and
+    target changes, default and override changes, and self updates.
+    Each entry has the time, the command, the toolchain it changed,
+    the date and hash of the release installed, the rustc versions
+    before and after, the components installed (+) and removed (-),
+    and the exit status of the command.
+
+    `--json` is the same as `--format json`.";
+
+pub(crate) static TOOLCHAIN_VERIFY_HELP: &str = r"DISCUSSION:
+    When a component is installed, rustup records the SHA-256 hash of
+    every file it installs. `rustup toolchain verify` compares the
+    files of each component with those hashes, and reports files
+    which are missing, which have been modified, and which were not
+    installed by the component but are in one of its directories.
+
+    With `--repair`, the damaged components are installed again from
+    the same release, using the download cache if it has them. The
+    exit status is 1 if damage was found and not repaired.
+
+    Components installed by versions of rustup which did not record
+    hashes cannot be verified until the toolchain is next updated.";
+
+pub(crate) static TOOLCHAIN_ROLLBACK_HELP: &str = r"DISCUSSION:
+    When `rustup set retained-versions` is more than zero, updating a
+    tracking toolchain such as `nightly` or `stable` keeps the version
+    it replaces, up to that many versions. Files which did not change
+    are shared between the versions, so each one takes little more
+    space than what changed in it.
+
+    `rustup toolchain rollback` puts the newest kept version back in
+    place, or the one from the date given with `--to`, without
+    downloading anything. The version rolled back from is kept in
+    turn, so you can go forward to it again the same way. The next
+    `rustup update` installs the latest release of the channel.
+
+    `rustup toolchain versions` lists the versions which are kept.";
+
 pub(crate) static OVERRIDE_HELP: &str = r"DISCUSSION:
     Overrides configure Rustup to use a specific toolchain when
     running in a specific directory.
@@ -3,10 +3,12 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, bail, Error, Result};
 use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, Shell, SubCommand};
 
+use super::format::{self, OutputFormat, Value};
 use super::help::*;
 use super::self_update;
 use super::term2;
@@ -19,12 +21,16 @@ use super::{
 use crate::cli::errors::CLIError;
 use crate::dist::dist::{PartialTargetTriple, PartialToolchainDesc, Profile, TargetTriple};
 use crate::dist::manifest::Component;
+use crate::dist::manifestation::Plan;
+use crate::dist::signatures::SignaturePolicy;
 use crate::errors::RustupError;
+use crate::gc;
 use crate::process;
 use crate::toolchain::{CustomToolchain, DistributableToolchain};
+use crate::utils::units::{Size, Unit, UnitMode};
 use crate::utils::utils;
 use crate::Notification;
-use crate::{command, Cfg, ComponentStatus, Toolchain};
+use crate::{command, Cfg, ComponentStatus, PgpPublicKey, Toolchain};
 
 fn handle_epipe(res: Result<utils::ExitCode>) -> Result<utils::ExitCode> {
     match res {
@@ -135,6 +141,25 @@ pub fn main() -> Result<utils::ExitCode> {
 
     cfg.check_metadata_version()?;
 
+    let operation = history_operation(&matches);
+    if let Some(operation) = &operation {
+        cfg.history.begin(operation);
+    }
+    let result = run_command(cfg, &matches);
+    if operation.is_some() {
+        // The exit status the process will have
+        let status = match &result {
+            Ok(utils::ExitCode(code)) => u32::try_from(*code).unwrap_or(1),
+            Err(_) => 1,
+        };
+        if let Err(e) = cfg.history.write(status) {
+            (cfg.notify_handler)(Notification::NonFatalError(&e));
+        }
+    }
+    result
+}
+
+fn run_command(cfg: &mut Cfg, matches: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     Ok(match matches.subcommand() {
         ("dump-testament", _) => common::dump_testament()?,
         ("show", Some(c)) => match c.subcommand() {
@@ -142,18 +167,23 @@ pub fn main() -> Result<utils::ExitCode> {
             ("home", Some(_)) => handle_epipe(show_rustup_home(cfg))?,
             ("profile", Some(_)) => handle_epipe(show_profile(cfg))?,
             ("keys", Some(_)) => handle_epipe(show_keys(cfg))?,
-            (_, _) => handle_epipe(show(cfg))?,
+            (_, _) => handle_epipe(show(cfg, c))?,
         },
         ("install", Some(m)) => deprecated("toolchain install", cfg, m, update)?,
         ("update", Some(m)) => update(cfg, m)?,
-        ("check", Some(_)) => check_updates(cfg)?,
+        ("check", Some(m)) => check_updates(cfg, m)?,
         ("uninstall", Some(m)) => deprecated("toolchain uninstall", cfg, m, toolchain_remove)?,
         ("default", Some(m)) => default_(cfg, m)?,
         ("toolchain", Some(c)) => match c.subcommand() {
             ("install", Some(m)) => update(cfg, m)?,
             ("list", Some(m)) => handle_epipe(toolchain_list(cfg, m))?,
             ("link", Some(m)) => toolchain_link(cfg, m)?,
+            ("lock", Some(_)) => toolchain_lock(cfg)?,
+            ("bundle", Some(m)) => toolchain_bundle(cfg, m)?,
             ("uninstall", Some(m)) => toolchain_remove(cfg, m)?,
+            ("verify", Some(m)) => toolchain_verify(cfg, m)?,
+            ("versions", Some(m)) => handle_epipe(toolchain_versions(cfg, m))?,
+            ("rollback", Some(m)) => toolchain_rollback(cfg, m)?,
             (_, _) => unreachable!(),
         },
         ("target", Some(c)) => match c.subcommand() {
@@ -169,7 +199,9 @@ pub fn main() -> Result<utils::ExitCode> {
             (_, _) => unreachable!(),
         },
         ("override", Some(c)) => match c.subcommand() {
-            ("list", Some(_)) => handle_epipe(common::list_overrides(cfg))?,
+            ("list", Some(m)) => {
+                handle_epipe(common::list_overrides(cfg, OutputFormat::from_matches(m)?))?
+            }
             ("set", Some(m)) => override_add(cfg, m)?,
             ("unset", Some(m)) => override_remove(cfg, m)?,
             (_, _) => unreachable!(),
@@ -187,8 +219,33 @@ pub fn main() -> Result<utils::ExitCode> {
             ("default-host", Some(m)) => set_default_host_triple(cfg, m)?,
             ("profile", Some(m)) => set_profile(cfg, m)?,
             ("auto-self-update", Some(m)) => set_auto_self_update(cfg, m)?,
+            ("signature-policy", Some(m)) => set_signature_policy(cfg, m)?,
+            ("retained-versions", Some(m)) => set_retained_versions(cfg, m)?,
+            (_, _) => unreachable!(),
+        },
+        ("keys", Some(c)) => match c.subcommand() {
+            ("list", Some(_)) => handle_epipe(keys_list(cfg))?,
+            ("add", Some(m)) => keys_add(cfg, m)?,
+            ("remove", Some(m)) => keys_remove(cfg, m)?,
+            (_, _) => unreachable!(),
+        },
+        ("mirror", Some(c)) => match c.subcommand() {
+            ("sync", Some(m)) => mirror_sync(cfg, m)?,
+            (_, _) => unreachable!(),
+        },
+        ("dist", Some(c)) => match c.subcommand() {
+            ("build-channel", Some(m)) => dist_build_channel(cfg, m)?,
+            (_, _) => unreachable!(),
+        },
+        ("cache", Some(c)) => match c.subcommand() {
+            ("list", Some(_)) => handle_epipe(cache_list(cfg))?,
+            ("clear", Some(_)) => cache_clear(cfg)?,
+            ("prune", Some(_)) => cache_prune(cfg)?,
             (_, _) => unreachable!(),
         },
+        ("gc", Some(m)) => collect_garbage(cfg, m)?,
+        ("du", Some(m)) => handle_epipe(disk_usage(cfg, m))?,
+        ("history", Some(m)) => handle_epipe(history(cfg, m))?,
         ("completions", Some(c)) => {
             if let Some(shell) = c.value_of("shell") {
                 (output_completion_script(
@@ -205,6 +262,54 @@ pub fn main() -> Result<utils::ExitCode> {
     })
 }
 
+/// The name the command `matches` goes by in the history, if it is one
+/// which changes anything
+fn history_operation(matches: &ArgMatches<'_>) -> Option<String> {
+    // A dry run changes nothing
+    let mut command = matches;
+    while let (_, Some(m)) = command.subcommand() {
+        command = m;
+    }
+    if command.is_present("dry-run") {
+        return None;
+    }
+    let operation = match matches.subcommand() {
+        ("install", Some(_)) => "install",
+        ("update", Some(_)) => "update",
+        ("uninstall", Some(_)) => "uninstall",
+        ("default", Some(m)) if m.is_present("toolchain") => "default",
+        ("toolchain", Some(c)) => match c.subcommand() {
+            ("install", Some(_)) => "install",
+            ("uninstall", Some(_)) => "uninstall",
+            ("link", Some(_)) => "toolchain link",
+            ("rollback", Some(_)) => "toolchain rollback",
+            ("verify", Some(m)) if m.is_present("repair") => "toolchain verify --repair",
+            _ => return None,
+        },
+        ("component", Some(c)) | ("target", Some(c)) => match c.subcommand() {
+            ("add", Some(_)) | ("remove", Some(_)) => {
+                return Some(format!(
+                    "{} {}",
+                    matches.subcommand_name()?,
+                    c.subcommand_name()?
+                ))
+            }
+            _ => return None,
+        },
+        ("override", Some(c)) => match c.subcommand() {
+            ("set", Some(_)) => "override set",
+            ("unset", Some(_)) => "override unset",
+            _ => return None,
+        },
+        ("self", Some(c)) => match c.subcommand() {
+            ("update", Some(_)) => "self update",
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(operation.to_owned())
+}
+
 pub(crate) fn cli() -> App<'static, 'static> {
     let mut app = App::new("rustup")
         .version(common::version())
@@ -248,10 +353,12 @@ pub(crate) fn cli() -> App<'static, 'static> {
                 .after_help(SHOW_HELP)
                 .setting(AppSettings::VersionlessSubcommands)
                 .setting(AppSettings::DeriveDisplayOrder)
+                .arg(format_arg())
                 .subcommand(
                     SubCommand::with_name("active-toolchain")
                         .about("Show the active toolchain")
                         .after_help(SHOW_ACTIVE_TOOLCHAIN_HELP)
+                        .arg(format_arg())
                         .arg(
                             Arg::with_name("verbose")
                                 .help("Enable verbose output with rustc information")
@@ -272,6 +379,7 @@ pub(crate) fn cli() -> App<'static, 'static> {
                 .about("Update Rust toolchains")
                 .after_help(INSTALL_HELP)
                 .setting(AppSettings::Hidden) // synonym for 'toolchain install'
+                .arg(format_arg())
                 .arg(
                     Arg::with_name("toolchain")
                         .help(TOOLCHAIN_ARG_HELP)
@@ -296,6 +404,12 @@ pub(crate) fn cli() -> App<'static, 'static> {
                         .help("Force an update, even if some components are missing")
                         .long("force")
                         .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .help("Show what would be installed and removed, without changing anything")
+                        .long("dry-run")
+                        .takes_value(false),
                 ),
         )
         .subcommand(
@@ -314,6 +428,7 @@ pub(crate) fn cli() -> App<'static, 'static> {
                 .about("Update Rust toolchains and rustup")
                 .aliases(&["upgrade"])
                 .after_help(UPDATE_HELP)
+                .arg(format_arg())
                 .arg(
                     Arg::with_name("toolchain")
                         .help(TOOLCHAIN_ARG_HELP)
@@ -336,9 +451,19 @@ pub(crate) fn cli() -> App<'static, 'static> {
                     Arg::with_name("force-non-host")
                         .help("Install toolchains that require an emulator. See https://github.com/rust-lang/rustup/wiki/Non-host-toolchains")
                         .long("force-non-host")
-                        .takes_value(false)),
+                        .takes_value(false))
+                .arg(
+                    Arg::with_name("dry-run")
+                        .help("Show what would be installed and removed, without changing anything")
+                        .long("dry-run")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check for updates to Rust toolchains and rustup")
+                .arg(format_arg()),
         )
-        .subcommand(SubCommand::with_name("check").about("Check for updates to Rust toolchains and rustup"))
         .subcommand(
             SubCommand::with_name("default")
                 .about("Set the default toolchain")
@@ -359,6 +484,7 @@ pub(crate) fn cli() -> App<'static, 'static> {
                 .subcommand(
                     SubCommand::with_name("list")
                         .about("List installed toolchains")
+                        .arg(format_arg())
                         .arg(
                             Arg::with_name("verbose")
                                 .help("Enable verbose output with toolchain information")
@@ -371,12 +497,43 @@ pub(crate) fn cli() -> App<'static, 'static> {
                     SubCommand::with_name("install")
                         .about("Install or update a given toolchain")
                         .aliases(&["update", "add"])
+                        .arg(format_arg())
                         .arg(
                             Arg::with_name("toolchain")
                                 .help(TOOLCHAIN_ARG_HELP)
-                                .required(true)
+                                .required_unless_one(&["locked", "from-bundle"])
                                 .multiple(true),
                         )
+                        .arg(
+                            Arg::with_name("locked")
+                                .help("Install the toolchain pinned by rustup-toolchain.lock")
+                                .long("locked")
+                                .takes_value(false)
+                                .conflicts_with_all(&[
+                                    "toolchain",
+                                    "profile",
+                                    "components",
+                                    "targets",
+                                    "force",
+                                    "allow-downgrade",
+                                ]),
+                        )
+                        .arg(
+                            Arg::with_name("from-bundle")
+                                .help("Install the toolchain in a bundle made by `rustup toolchain bundle`")
+                                .long("from-bundle")
+                                .takes_value(true)
+                                .value_name("FILE")
+                                .conflicts_with_all(&[
+                                    "toolchain",
+                                    "locked",
+                                    "profile",
+                                    "components",
+                                    "targets",
+                                    "force",
+                                    "allow-downgrade",
+                                ]),
+                        )
                         .arg(
                             Arg::with_name("profile")
                                 .long("profile")
@@ -422,6 +579,64 @@ pub(crate) fn cli() -> App<'static, 'static> {
                                 .help("Allow rustup to downgrade the toolchain to satisfy your component choice")
                                 .long("allow-downgrade")
                                 .takes_value(false),
+                        )
+                        .arg(
+                            Arg::with_name("dry-run")
+                                .help("Show what would be installed and removed, without changing anything")
+                                .long("dry-run")
+                                .conflicts_with_all(&["locked", "from-bundle"])
+                                .takes_value(false),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("lock")
+                        .about("Write rustup-toolchain.lock for the toolchain file in the current directory")
+                        .after_help(TOOLCHAIN_LOCK_HELP),
+                )
+                .subcommand(
+                    SubCommand::with_name("bundle")
+                        .about("Download a toolchain into a bundle for offline installation")
+                        .after_help(TOOLCHAIN_BUNDLE_HELP)
+                        .arg(
+                            Arg::with_name("toolchain")
+                                .help(TOOLCHAIN_ARG_HELP)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .help("The file to write the bundle to")
+                                .long("output")
+                                .short("o")
+                                .takes_value(true)
+                                .value_name("FILE")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("profile")
+                                .long("profile")
+                                .takes_value(true)
+                                .possible_values(Profile::names())
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::with_name("components")
+                                .help("Add specific components to the bundle")
+                                .long("component")
+                                .alias("components")
+                                .short("c")
+                                .takes_value(true)
+                                .multiple(true)
+                                .use_delimiter(true),
+                        )
+                        .arg(
+                            Arg::with_name("targets")
+                                .help("Add specific targets to the bundle")
+                                .long("target")
+                                .alias("targets")
+                                .short("t")
+                                .takes_value(true)
+                                .multiple(true)
+                                .use_delimiter(true),
                         ),
                 )
                 .subcommand(
@@ -449,6 +664,48 @@ pub(crate) fn cli() -> App<'static, 'static> {
                                 .help("Path to the directory")
                                 .required(true),
                         ),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Check the installed files of toolchains against their hashes")
+                        .after_help(TOOLCHAIN_VERIFY_HELP)
+                        .arg(
+                            Arg::with_name("toolchain")
+                                .help("Toolchain to verify [default: every installed toolchain]"),
+                        )
+                        .arg(
+                            Arg::with_name("repair")
+                                .help("Reinstall the components which are damaged")
+                                .long("repair"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("versions")
+                        .about("List the previous versions kept of a tracking toolchain")
+                        .after_help(TOOLCHAIN_ROLLBACK_HELP)
+                        .arg(format_arg())
+                        .arg(
+                            Arg::with_name("toolchain")
+                                .help(TOOLCHAIN_ARG_HELP)
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("rollback")
+                        .about("Put a previous version of a tracking toolchain back in place")
+                        .after_help(TOOLCHAIN_ROLLBACK_HELP)
+                        .arg(
+                            Arg::with_name("toolchain")
+                                .help(TOOLCHAIN_ARG_HELP)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("to")
+                                .help("Date of the version to roll back to [default: the newest kept]")
+                                .long("to")
+                                .takes_value(true)
+                                .value_name("DATE"),
+                        ),
                 ),
         )
         .subcommand(
@@ -460,6 +717,7 @@ pub(crate) fn cli() -> App<'static, 'static> {
                 .subcommand(
                     SubCommand::with_name("list")
                         .about("List installed and available targets")
+                        .arg(format_arg())
                         .arg(
                             Arg::with_name("installed")
                                 .long("--installed")
@@ -476,6 +734,7 @@ pub(crate) fn cli() -> App<'static, 'static> {
                     SubCommand::with_name("add")
                         .about("Add a target to a Rust toolchain")
                         .alias("install")
+                        .arg(format_arg())
                         .arg(Arg::with_name("target").required(true).multiple(true).help(
                             "List of targets to install; \
                              \"all\" installs all available targets",
@@ -485,18 +744,31 @@ pub(crate) fn cli() -> App<'static, 'static> {
                                 .help(TOOLCHAIN_ARG_HELP)
                                 .long("toolchain")
                                 .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("dry-run")
+                                .help("Show what would be installed and removed, without changing anything")
+                                .long("dry-run")
+                                .takes_value(false),
                         ),
                 )
                 .subcommand(
                     SubCommand::with_name("remove")
                         .about("Remove a target from a Rust toolchain")
                         .alias("uninstall")
+                        .arg(format_arg())
                         .arg(Arg::with_name("target").required(true).multiple(true))
                         .arg(
                             Arg::with_name("toolchain")
                                 .help(TOOLCHAIN_ARG_HELP)
                                 .long("toolchain")
                                 .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("dry-run")
+                                .help("Show what would be installed and removed, without changing anything")
+                                .long("dry-run")
+                                .takes_value(false),
                         ),
                 ),
         )
@@ -509,6 +781,7 @@ pub(crate) fn cli() -> App<'static, 'static> {
                 .subcommand(
                     SubCommand::with_name("list")
                         .about("List installed and available components")
+                        .arg(format_arg())
                         .arg(
                             Arg::with_name("installed")
                                 .long("--installed")
@@ -524,6 +797,7 @@ pub(crate) fn cli() -> App<'static, 'static> {
                 .subcommand(
                     SubCommand::with_name("add")
                         .about("Add a component to a Rust toolchain")
+                        .arg(format_arg())
                         .arg(Arg::with_name("component").required(true).multiple(true))
                         .arg(
                             Arg::with_name("toolchain")
@@ -531,11 +805,18 @@ pub(crate) fn cli() -> App<'static, 'static> {
                                 .long("toolchain")
                                 .takes_value(true),
                         )
-                        .arg(Arg::with_name("target").long("target").takes_value(true)),
+                        .arg(Arg::with_name("target").long("target").takes_value(true))
+                        .arg(
+                            Arg::with_name("dry-run")
+                                .help("Show what would be installed and removed, without changing anything")
+                                .long("dry-run")
+                                .takes_value(false),
+                        ),
                 )
                 .subcommand(
                     SubCommand::with_name("remove")
                         .about("Remove a component from a Rust toolchain")
+                        .arg(format_arg())
                         .arg(Arg::with_name("component").required(true).multiple(true))
                         .arg(
                             Arg::with_name("toolchain")
@@ -543,7 +824,13 @@ pub(crate) fn cli() -> App<'static, 'static> {
                                 .long("toolchain")
                                 .takes_value(true),
                         )
-                        .arg(Arg::with_name("target").long("target").takes_value(true)),
+                        .arg(Arg::with_name("target").long("target").takes_value(true))
+                        .arg(
+                            Arg::with_name("dry-run")
+                                .help("Show what would be installed and removed, without changing anything")
+                                .long("dry-run")
+                                .takes_value(false),
+                        ),
                 ),
         )
         .subcommand(
@@ -554,7 +841,9 @@ pub(crate) fn cli() -> App<'static, 'static> {
                 .setting(AppSettings::DeriveDisplayOrder)
                 .setting(AppSettings::SubcommandRequiredElseHelp)
                 .subcommand(
-                    SubCommand::with_name("list").about("List directory toolchain overrides"),
+                    SubCommand::with_name("list")
+                        .about("List directory toolchain overrides")
+                        .arg(format_arg()),
                 )
                 .subcommand(
                     SubCommand::with_name("set")
@@ -719,6 +1008,222 @@ pub(crate) fn cli() -> App<'static, 'static> {
                                 .possible_values(SelfUpdateMode::modes())
                                 .default_value(SelfUpdateMode::default_mode()),
                         ),
+                )
+                .subcommand(
+                    SubCommand::with_name("signature-policy")
+                        .about("What to do when the signature of a download does not verify")
+                        .arg(
+                            Arg::with_name("policy")
+                                .required(true)
+                                .possible_values(SignaturePolicy::names()),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("retained-versions")
+                        .about("How many previous versions of tracking toolchains to keep")
+                        .after_help(TOOLCHAIN_ROLLBACK_HELP)
+                        .arg(
+                            Arg::with_name("count")
+                                .required(true)
+                                .validator(|s| {
+                                    s.parse::<u64>()
+                                        .map(|_| ())
+                                        .map_err(|_| "the count must be a number".to_owned())
+                                }),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .about("Manage the PGP keys trusted to sign distributions")
+                .after_help(KEYS_HELP)
+                .setting(AppSettings::VersionlessSubcommands)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List the trusted keys, including every key in the keyring"),
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add a public key to the keyring")
+                        .arg(
+                            Arg::with_name("file")
+                                .help("File containing the key")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("dist-server")
+                                .help("Only trust the key for this dist server")
+                                .long("dist-server")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Remove a key from the keyring")
+                        .arg(
+                            Arg::with_name("fingerprint")
+                                .help("Fingerprint of the key, as shown by `rustup keys list`")
+                                .required(true)
+                                .multiple(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mirror")
+                .about("Maintain a mirror of the dist server")
+                .setting(AppSettings::VersionlessSubcommands)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("sync")
+                        .about("Download the latest releases of some channels into a mirror")
+                        .after_help(MIRROR_SYNC_HELP)
+                        .arg(
+                            Arg::with_name("channels")
+                                .help("The channels to mirror")
+                                .long("channels")
+                                .takes_value(true)
+                                .multiple(true)
+                                .use_delimiter(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("targets")
+                                .help("The targets to mirror [default: the default host]")
+                                .long("targets")
+                                .takes_value(true)
+                                .multiple(true)
+                                .use_delimiter(true),
+                        )
+                        .arg(
+                            Arg::with_name("dest")
+                                .help("The directory of the mirror")
+                                .long("dest")
+                                .takes_value(true)
+                                .value_name("DIR")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("keep-days")
+                                .help("Remove releases older than this many days from the mirror")
+                                .long("keep-days")
+                                .takes_value(true)
+                                .value_name("DAYS"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dist")
+                .about("Build distributions of Rust")
+                .setting(AppSettings::VersionlessSubcommands)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("build-channel")
+                        .about("Build a channel from rust-installer tarballs")
+                        .after_help(DIST_BUILD_CHANNEL_HELP)
+                        .arg(
+                            Arg::with_name("name")
+                                .help("The name of the channel, e.g. 'stable' or '1.53.0'")
+                                .long("name")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("from")
+                                .help("The directory of the tarballs")
+                                .long("from")
+                                .takes_value(true)
+                                .value_name("DIR")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("dest")
+                                .help("The directory to build the channel in [default: the current directory]")
+                                .long("dest")
+                                .takes_value(true)
+                                .value_name("DIR"),
+                        )
+                        .arg(
+                            Arg::with_name("date")
+                                .help("The release date of the channel [default: today]")
+                                .long("date")
+                                .takes_value(true)
+                                .value_name("YYYY-MM-DD"),
+                        )
+                        .arg(
+                            Arg::with_name("sign-key")
+                                .help("The secret PGP key to sign the channel with")
+                                .long("sign-key")
+                                .takes_value(true)
+                                .value_name("FILE"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Manage the cache of downloaded archives")
+                .after_help(CACHE_HELP)
+                .setting(AppSettings::VersionlessSubcommands)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List the cached archives, least recently used first"),
+                )
+                .subcommand(SubCommand::with_name("clear").about("Remove every cached archive"))
+                .subcommand(
+                    SubCommand::with_name("prune").about(
+                        "Remove the least recently used archives until the cache fits its size limit",
+                    ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Remove toolchains which are not used and files left behind")
+                .after_help(GC_HELP)
+                .arg(
+                    Arg::with_name("yes")
+                        .help("Remove everything without asking for confirmation")
+                        .short("y")
+                        .long("yes"),
+                )
+                .arg(
+                    Arg::with_name("older-than")
+                        .help("Only remove what has not been updated for AGE, e.g. '30d'")
+                        .long("older-than")
+                        .takes_value(true)
+                        .value_name("AGE"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("du")
+                .about("Show the disk space used by each toolchain and component")
+                .after_help(DU_HELP)
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("json")
+                        .help("Print the sizes as JSON")
+                        .long("json"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Show what rustup has installed, updated and changed")
+                .after_help(HISTORY_HELP)
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("toolchain")
+                        .help("Only show what was done to this toolchain")
+                        .long("toolchain")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .help("Print the history as JSON")
+                        .long("json"),
                 ),
         );
 
@@ -746,6 +1251,15 @@ pub(crate) fn cli() -> App<'static, 'static> {
     )
 }
 
+/// `--format`, taken by the inspection commands
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .help("Output format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(OutputFormat::VALUES)
+}
+
 fn maybe_upgrade_data(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<bool> {
     match m.subcommand() {
         ("self", Some(c)) => match c.subcommand() {
@@ -882,7 +1396,11 @@ fn default_(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     Ok(utils::ExitCode(0))
 }
 
-fn check_updates(cfg: &Cfg) -> Result<utils::ExitCode> {
+fn check_updates(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let format = OutputFormat::from_matches(m)?;
+    if format != OutputFormat::Human {
+        return check_updates_machine(cfg, format);
+    }
     let mut t = term2::stdout();
     let channels = cfg.list_channels()?;
 
@@ -923,7 +1441,73 @@ fn check_updates(cfg: &Cfg) -> Result<utils::ExitCode> {
         }
     }
 
-    check_rustup_update()?;
+    check_rustup_update(&cfg.retry_policy)?;
+
+    Ok(utils::ExitCode(0))
+}
+
+fn check_updates_machine(cfg: &Cfg, format: OutputFormat) -> Result<utils::ExitCode> {
+    let mut channels = Vec::new();
+    for (name, toolchain) in cfg.list_channels()? {
+        let toolchain = toolchain?;
+        let distributable = DistributableToolchain::new(&toolchain)?;
+        let current_version = distributable.show_version()?;
+        let dist_version = distributable.show_dist_version()?;
+        let status = match (&current_version, &dist_version) {
+            (None, None) => "unknown",
+            (Some(_), None) => "up-to-date",
+            (_, Some(_)) => "update-available",
+        };
+        channels.push((name, status, current_version, dist_version));
+    }
+
+    let rustup_version = env!("CARGO_PKG_VERSION");
+    let rustup_available = self_update::get_available_rustup_version(&cfg.retry_policy)?;
+    let rustup_status = if rustup_version != rustup_available {
+        "update-available"
+    } else {
+        "up-to-date"
+    };
+
+    if format == OutputFormat::Json {
+        let toolchains = channels
+            .into_iter()
+            .map(|(name, status, current_version, dist_version)| {
+                Value::object(vec![
+                    ("name", name.into()),
+                    ("status", status.into()),
+                    ("current_version", current_version.into()),
+                    ("available_version", dist_version.into()),
+                ])
+            })
+            .collect();
+        format::print_json(&Value::document(vec![
+            ("toolchains", Value::Array(toolchains)),
+            (
+                "rustup",
+                Value::object(vec![
+                    ("status", rustup_status.into()),
+                    ("current_version", rustup_version.into()),
+                    ("available_version", rustup_available.into()),
+                ]),
+            ),
+        ]))?;
+    } else {
+        for (name, status, current_version, dist_version) in channels {
+            format::print_tsv(&[
+                name,
+                status.to_owned(),
+                current_version.unwrap_or_default(),
+                dist_version.unwrap_or_default(),
+            ])?;
+        }
+        format::print_tsv(&[
+            "rustup",
+            rustup_status,
+            rustup_version,
+            rustup_available.as_str(),
+        ])?;
+    }
 
     Ok(utils::ExitCode(0))
 }
@@ -946,7 +1530,23 @@ fn update(cfg: &mut Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     if cfg.get_profile()? == Profile::Complete {
         warn!("{}", common::WARN_COMPLETE_PROFILE);
     }
-    if let Some(names) = m.values_of("toolchain") {
+    if m.is_present("dry-run") {
+        return update_dry_run(cfg, m);
+    }
+    if let Some(bundle) = m.value_of("from-bundle") {
+        let (name, status) = cfg.install_from_bundle(Path::new(bundle))?;
+        writeln!(process().stdout())?;
+        common::show_channel_update(cfg, &name, Ok(status))?;
+        // Bundles are for hosts which cannot reach the dist server, so
+        // there is no point in trying to update rustup itself
+    } else if m.is_present("locked") {
+        let (name, status) = cfg.install_locked_toolchain(&utils::current_dir()?)?;
+        writeln!(process().stdout())?;
+        common::show_channel_update(cfg, &name, Ok(status))?;
+        if self_update {
+            common::self_update(&cfg.retry_policy, || Ok(utils::ExitCode(0)))?;
+        }
+    } else if let Some(names) = m.values_of("toolchain") {
         for name in names {
             update_bare_triple_check(cfg, name)?;
 
@@ -1013,17 +1613,21 @@ fn update(cfg: &mut Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
             }
         }
         if self_update {
-            common::self_update(|| Ok(utils::ExitCode(0)))?;
+            common::self_update(&cfg.retry_policy, || Ok(utils::ExitCode(0)))?;
         }
     } else {
         common::update_all_channels(cfg, self_update, m.is_present("force"))?;
         info!("cleaning up downloads & tmp directories");
-        utils::delete_dir_contents(&cfg.download_dir);
+        if cfg.download_cache.is_some() {
+            cfg.prune_download_cache()?;
+        } else {
+            utils::delete_dir_contents(&cfg.download_dir);
+        }
         cfg.temp_cfg.clean();
     }
 
     if !self_update::NEVER_SELF_UPDATE && self_update_mode == SelfUpdateMode::CheckOnly {
-        check_rustup_update()?;
+        check_rustup_update(&cfg.retry_policy)?;
     }
 
     if self_update::NEVER_SELF_UPDATE {
@@ -1034,6 +1638,123 @@ fn update(cfg: &mut Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     Ok(utils::ExitCode(0))
 }
 
+/// Shows what `rustup update` or `rustup toolchain install` would do,
+/// without changing anything
+fn update_dry_run(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let format = OutputFormat::from_matches(m)?;
+    let force = m.is_present("force");
+    let mut plans = Vec::new();
+    if let Some(names) = m.values_of("toolchain") {
+        let components: Vec<_> = m
+            .values_of("components")
+            .map(|v| v.collect())
+            .unwrap_or_else(Vec::new);
+        let targets: Vec<_> = m
+            .values_of("targets")
+            .map(|v| v.collect())
+            .unwrap_or_else(Vec::new);
+        for name in names {
+            update_bare_triple_check(cfg, name)?;
+            let toolchain = cfg.get_toolchain(name, false)?;
+            if toolchain.is_custom() {
+                if !toolchain.exists() {
+                    bail!(RustupError::InvalidToolchainName(
+                        toolchain.name().to_string()
+                    ));
+                }
+                continue;
+            }
+            let distributable = DistributableToolchain::new(&toolchain)?;
+            let plan = distributable.plan_install_from_dist(
+                force,
+                m.is_present("allow-downgrade"),
+                &components,
+                &targets,
+            )?;
+            plans.push((toolchain.name().to_owned(), plan));
+        }
+    } else {
+        for (name, toolchain) in cfg.list_channels()? {
+            let toolchain = toolchain?;
+            let distributable = DistributableToolchain::new(&toolchain)?;
+            plans.push((
+                name,
+                distributable.plan_install_from_dist(force, false, &[], &[])?,
+            ));
+        }
+        if plans.is_empty() {
+            info!("no updatable toolchains installed");
+        }
+    }
+    show_plans(plans, format)
+}
+
+/// Shows what a dry run found would be done to each toolchain
+fn show_plans(plans: Vec<(String, Plan)>, format: OutputFormat) -> Result<utils::ExitCode> {
+    match format {
+        OutputFormat::Human => {
+            let mut t = term2::stdout();
+            for (toolchain, plan) in plans {
+                let _ = t.attr(term2::Attr::Bold);
+                write!(t, "{}", toolchain)?;
+                let _ = t.reset();
+                write!(t, " - release {}", plan.date)?;
+                if let Some(version) = &plan.rust_version {
+                    write!(t, ", rustc {}", version)?;
+                }
+                writeln!(t)?;
+                if plan.nothing_changes() {
+                    writeln!(t, "  nothing to change")?;
+                }
+                for (action, components) in [
+                    ("install", &plan.install),
+                    ("uninstall", &plan.uninstall),
+                    ("keep", &plan.keep),
+                ] {
+                    for component in components {
+                        writeln!(t, "  {:<9} {}", action, component)?;
+                    }
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let plans = plans
+                .into_iter()
+                .map(|(toolchain, plan)| {
+                    Value::object(vec![
+                        ("toolchain", toolchain.into()),
+                        ("date", plan.date.into()),
+                        ("rust_version", plan.rust_version.into()),
+                        ("install", plan.install.into()),
+                        ("uninstall", plan.uninstall.into()),
+                        ("keep", plan.keep.into()),
+                    ])
+                })
+                .collect();
+            format::print_json(&Value::document(vec![("plans", Value::Array(plans))]))?;
+        }
+        OutputFormat::Tsv => {
+            for (toolchain, plan) in plans {
+                for (action, components) in [
+                    ("install", &plan.install),
+                    ("uninstall", &plan.uninstall),
+                    ("keep", &plan.keep),
+                ] {
+                    for component in components {
+                        format::print_tsv(&[
+                            toolchain.as_str(),
+                            plan.date.as_str(),
+                            action,
+                            component.as_str(),
+                        ])?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(utils::ExitCode(0))
+}
+
 fn run(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     let toolchain = m.value_of("toolchain").unwrap();
     let args = m.values_of("command").unwrap();
@@ -1059,7 +1780,12 @@ fn which(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     Ok(utils::ExitCode(0))
 }
 
-fn show(cfg: &Cfg) -> Result<utils::ExitCode> {
+fn show(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let format = OutputFormat::from_matches(m)?;
+    if format != OutputFormat::Human {
+        return show_machine(cfg, format);
+    }
+
     // Print host triple
     {
         let mut t = term2::stdout();
@@ -1210,8 +1936,83 @@ fn show(cfg: &Cfg) -> Result<utils::ExitCode> {
     Ok(utils::ExitCode(0))
 }
 
+fn show_machine(cfg: &Cfg, format: OutputFormat) -> Result<utils::ExitCode> {
+    let default_host = cfg.get_default_host_triple()?.to_string();
+    let rustup_home = cfg.rustup_dir.display().to_string();
+    let default_name = cfg.get_default()?;
+    let installed_toolchains = cfg.list_toolchains()?;
+    let cwd = utils::current_dir()?;
+    let active_toolchain = match cfg.find_or_install_override_toolchain_or_default(&cwd) {
+        Ok(atc) => Some(atc),
+        Err(e) => match e.root_cause().downcast_ref::<RustupError>() {
+            Some(RustupError::ToolchainNotSelected) => None,
+            _ => return Err(e),
+        },
+    };
+    let active_targets: Vec<String> = active_toolchain
+        .as_ref()
+        .and_then(|(toolchain, _)| DistributableToolchain::new(toolchain).ok())
+        .and_then(|distributable| distributable.list_components().ok())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| c.component.short_name_in_manifest() == "rust-std")
+        .filter(|c| c.installed)
+        .map(|c| {
+            c.component
+                .target
+                .expect("rust-std should have a target")
+                .to_string()
+        })
+        .collect();
+
+    if format == OutputFormat::Json {
+        let toolchains = installed_toolchains
+            .into_iter()
+            .map(|name| {
+                let default = default_name.as_ref() == Some(&name);
+                Value::object(vec![("name", name.into()), ("default", default.into())])
+            })
+            .collect();
+        let active = active_toolchain.map(|(toolchain, reason)| {
+            Value::object(vec![
+                ("name", toolchain.name().into()),
+                ("reason", format::reason(reason.as_ref())),
+                ("rustc_version", toolchain.rustc_version().into()),
+            ])
+        });
+        format::print_json(&Value::document(vec![
+            ("default_host", default_host.into()),
+            ("rustup_home", rustup_home.into()),
+            ("installed_toolchains", Value::Array(toolchains)),
+            ("active_targets", active_targets.into()),
+            ("active_toolchain", active.into()),
+        ]))?;
+    } else {
+        format::print_tsv(&["default_host", default_host.as_str()])?;
+        format::print_tsv(&["rustup_home", rustup_home.as_str()])?;
+        for name in installed_toolchains {
+            let default = default_name.as_ref() == Some(&name);
+            format::print_tsv(&["installed_toolchain".to_owned(), name, default.to_string()])?;
+        }
+        for target in active_targets {
+            format::print_tsv(&["active_target", target.as_str()])?;
+        }
+        if let Some((toolchain, reason)) = active_toolchain {
+            format::print_tsv(&[
+                "active_toolchain",
+                toolchain.name(),
+                format::reason_kind(reason.as_ref()),
+                toolchain.rustc_version().as_str(),
+            ])?;
+        }
+    }
+
+    Ok(utils::ExitCode(0))
+}
+
 fn show_active_toolchain(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     let verbose = m.is_present("verbose");
+    let format = OutputFormat::from_matches(m)?;
     let cwd = utils::current_dir()?;
     match cfg.find_or_install_override_toolchain_or_default(&cwd) {
         Err(e) => {
@@ -1219,10 +2020,45 @@ fn show_active_toolchain(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCod
             if let Some(RustupError::ToolchainNotSelected) =
                 root_cause.downcast_ref::<RustupError>()
             {
+                match format {
+                    OutputFormat::Json => format::print_json(&Value::document(vec![(
+                        "active_toolchain",
+                        Value::Null,
+                    )]))?,
+                    // A record with every field missing
+                    OutputFormat::Tsv if verbose => format::print_tsv(&["", "", ""])?,
+                    OutputFormat::Tsv => format::print_tsv(&["", ""])?,
+                    OutputFormat::Human => {}
+                }
             } else {
                 return Err(e);
             }
         }
+        Ok((toolchain, reason)) if format == OutputFormat::Json => {
+            let rustc_version = if verbose {
+                Some(toolchain.rustc_version())
+            } else {
+                None
+            };
+            format::print_json(&Value::document(vec![(
+                "active_toolchain",
+                Value::object(vec![
+                    ("name", toolchain.name().into()),
+                    ("reason", format::reason(reason.as_ref())),
+                    ("rustc_version", rustc_version.into()),
+                ]),
+            )]))?;
+        }
+        Ok((toolchain, reason)) if format == OutputFormat::Tsv => {
+            let mut fields = vec![
+                toolchain.name().to_owned(),
+                format::reason_kind(reason.as_ref()).to_owned(),
+            ];
+            if verbose {
+                fields.push(toolchain.rustc_version());
+            }
+            format::print_tsv(&fields)?;
+        }
         Ok((toolchain, reason)) => {
             if let Some(reason) = reason {
                 writeln!(process().stdout(), "{} ({})", toolchain.name(), reason)?;
@@ -1245,10 +2081,12 @@ fn show_rustup_home(cfg: &Cfg) -> Result<utils::ExitCode> {
 fn target_list(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     let toolchain = explicit_or_dir_toolchain(cfg, m)?;
 
+    let format = OutputFormat::from_matches(m)?;
+
     if m.is_present("installed") {
-        common::list_installed_targets(&toolchain)
+        common::list_installed_targets(&toolchain, format)
     } else {
-        common::list_targets(&toolchain)
+        common::list_targets(&toolchain, format)
     }
 }
 
@@ -1291,12 +2129,23 @@ fn target_add(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
         }
     }
 
-    for target in &targets {
-        let new_component = Component::new(
+    let new_components = targets.iter().map(|target| {
+        Component::new(
             "rust-std".to_string(),
             Some(TargetTriple::new(target)),
             false,
+        )
+    });
+
+    if m.is_present("dry-run") {
+        let plan = distributable.plan_component_changes(new_components.collect(), vec![])?;
+        return show_plans(
+            vec![(toolchain.name().to_owned(), plan)],
+            OutputFormat::from_matches(m)?,
         );
+    }
+
+    for new_component in new_components {
         distributable.add_component(new_component)?;
     }
 
@@ -1306,14 +2155,26 @@ fn target_add(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
 fn target_remove(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     let toolchain = explicit_or_dir_toolchain(cfg, m)?;
 
-    for target in m.values_of("target").unwrap() {
-        let new_component = Component::new(
+    let components = m.values_of("target").unwrap().map(|target| {
+        Component::new(
             "rust-std".to_string(),
             Some(TargetTriple::new(target)),
             false,
+        )
+    });
+
+    if m.is_present("dry-run") {
+        let distributable = DistributableToolchain::new_for_components(&toolchain)?;
+        let plan = distributable.plan_component_changes(vec![], components.collect())?;
+        return show_plans(
+            vec![(toolchain.name().to_owned(), plan)],
+            OutputFormat::from_matches(m)?,
         );
+    }
+
+    for component in components {
         let distributable = DistributableToolchain::new_for_components(&toolchain)?;
-        distributable.remove_component(new_component)?;
+        distributable.remove_component(component)?;
     }
 
     Ok(utils::ExitCode(0))
@@ -1322,10 +2183,12 @@ fn target_remove(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
 fn component_list(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     let toolchain = explicit_or_dir_toolchain(cfg, m)?;
 
+    let format = OutputFormat::from_matches(m)?;
+
     if m.is_present("installed") {
-        common::list_installed_components(&toolchain)
+        common::list_installed_components(&toolchain, format)
     } else {
-        common::list_components(&toolchain)?;
+        common::list_components(&toolchain, format)?;
         Ok(utils::ExitCode(0))
     }
 }
@@ -1341,9 +2204,20 @@ fn component_add(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
             .map(|desc| desc.target.clone())
     });
 
-    for component in m.values_of("component").unwrap() {
-        let new_component = Component::new_with_target(component, false)
-            .unwrap_or_else(|| Component::new(component.to_string(), target.clone(), true));
+    let new_components = m.values_of("component").unwrap().map(|component| {
+        Component::new_with_target(component, false)
+            .unwrap_or_else(|| Component::new(component.to_string(), target.clone(), true))
+    });
+
+    if m.is_present("dry-run") {
+        let plan = distributable.plan_component_changes(new_components.collect(), vec![])?;
+        return show_plans(
+            vec![(toolchain.name().to_owned(), plan)],
+            OutputFormat::from_matches(m)?,
+        );
+    }
+
+    for new_component in new_components {
         distributable.add_component(new_component)?;
     }
 
@@ -1361,10 +2235,21 @@ fn component_remove(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
             .map(|desc| desc.target.clone())
     });
 
-    for component in m.values_of("component").unwrap() {
-        let new_component = Component::new_with_target(component, false)
-            .unwrap_or_else(|| Component::new(component.to_string(), target.clone(), true));
-        distributable.remove_component(new_component)?;
+    let components = m.values_of("component").unwrap().map(|component| {
+        Component::new_with_target(component, false)
+            .unwrap_or_else(|| Component::new(component.to_string(), target.clone(), true))
+    });
+
+    if m.is_present("dry-run") {
+        let plan = distributable.plan_component_changes(vec![], components.collect())?;
+        return show_plans(
+            vec![(toolchain.name().to_owned(), plan)],
+            OutputFormat::from_matches(m)?,
+        );
+    }
+
+    for component in components {
+        distributable.remove_component(component)?;
     }
 
     Ok(utils::ExitCode(0))
@@ -1384,7 +2269,7 @@ fn explicit_or_dir_toolchain<'a>(cfg: &'a Cfg, m: &ArgMatches<'_>) -> Result<Too
 }
 
 fn toolchain_list(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
-    common::list_toolchains(cfg, m.is_present("verbose"))
+    common::list_toolchains(cfg, m.is_present("verbose"), OutputFormat::from_matches(m)?)
 }
 
 fn toolchain_link(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
@@ -1403,6 +2288,27 @@ fn toolchain_link(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     }
 }
 
+fn toolchain_lock(cfg: &Cfg) -> Result<utils::ExitCode> {
+    cfg.lock_toolchain(&utils::current_dir()?)?;
+    Ok(utils::ExitCode(0))
+}
+
+fn toolchain_bundle(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let toolchain = m.value_of("toolchain").unwrap();
+    let output = Path::new(m.value_of("output").unwrap());
+    let profile = m.value_of("profile").map(Profile::from_str).transpose()?;
+    let components: Vec<_> = m
+        .values_of("components")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+    let targets: Vec<_> = m
+        .values_of("targets")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+    cfg.bundle_toolchain(toolchain, profile, &components, &targets, output)?;
+    Ok(utils::ExitCode(0))
+}
+
 fn toolchain_remove(cfg: &mut Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     for toolchain in m.values_of("toolchain").unwrap() {
         let toolchain = cfg.get_toolchain(toolchain, false)?;
@@ -1411,6 +2317,162 @@ fn toolchain_remove(cfg: &mut Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode
     Ok(utils::ExitCode(0))
 }
 
+fn toolchain_verify(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let names = match m.value_of("toolchain") {
+        Some(name) => vec![name.to_owned()],
+        None => cfg.list_toolchains()?,
+    };
+    let mut t = term2::stdout();
+    let mut damaged_toolchains = 0;
+    for name in names {
+        let toolchain = cfg.get_toolchain(&name, false)?;
+        if toolchain.is_custom() {
+            info!("skipping custom toolchain '{}'", toolchain.name());
+            continue;
+        }
+        if !toolchain.exists() {
+            return Err(RustupError::ToolchainNotInstalled(toolchain.name().to_owned()).into());
+        }
+        let distributable = DistributableToolchain::new_for_components(&toolchain)?;
+
+        let _ = t.attr(term2::Attr::Bold);
+        writeln!(t, "{}", toolchain.name())?;
+        let _ = t.reset();
+        let mut damaged = Vec::new();
+        for (component, damage) in distributable.verify()? {
+            let damage = match damage {
+                Some(damage) => damage,
+                None => {
+                    writeln!(t, "  {}: no hashes were recorded", component)?;
+                    continue;
+                }
+            };
+            if damage.is_empty() {
+                writeln!(t, "  {}: ok", component)?;
+                continue;
+            }
+            writeln!(t, "  {}: damaged", component)?;
+            for (kind, paths) in &[
+                ("missing", &damage.missing),
+                ("modified", &damage.modified),
+                ("unexpected", &damage.unexpected),
+            ] {
+                for path in paths.iter() {
+                    writeln!(t, "    {}: {}", kind, path.display())?;
+                }
+            }
+            damaged.push(component);
+        }
+
+        if damaged.is_empty() {
+            continue;
+        }
+        if m.is_present("repair") {
+            distributable.repair(&damaged)?;
+            info!("repaired toolchain '{}'", toolchain.name());
+        } else {
+            damaged_toolchains += 1;
+        }
+    }
+
+    if damaged_toolchains > 0 {
+        info!("run 'rustup toolchain verify --repair' to reinstall the damaged components");
+        return Ok(utils::ExitCode(1));
+    }
+    Ok(utils::ExitCode(0))
+}
+
+fn toolchain_versions(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let format = OutputFormat::from_matches(m)?;
+    let toolchain = cfg.get_toolchain(m.value_of("toolchain").unwrap(), false)?;
+    if !toolchain.exists() {
+        return Err(RustupError::ToolchainNotInstalled(toolchain.name().to_owned()).into());
+    }
+    let distributable = DistributableToolchain::new(&toolchain)?;
+    // The installed version comes first, then the kept ones
+    let versions: Vec<_> = distributable
+        .current_version()?
+        .into_iter()
+        .map(|v| (v, true))
+        .chain(
+            distributable
+                .kept_versions()?
+                .into_iter()
+                .map(|v| (v, false)),
+        )
+        .collect();
+
+    match format {
+        OutputFormat::Human => {
+            let mut t = term2::stdout();
+            for (version, installed) in &versions {
+                write!(
+                    t,
+                    "{}  {}",
+                    version.date,
+                    version
+                        .rust_version
+                        .as_deref()
+                        .unwrap_or("(unknown version)")
+                )?;
+                if *installed {
+                    let _ = t.attr(term2::Attr::Bold);
+                    write!(t, " (installed)")?;
+                    let _ = t.reset();
+                }
+                writeln!(t)?;
+            }
+            if versions.len() < 2 {
+                info!(
+                    "no previous versions of '{}' are kept, see 'rustup set retained-versions'",
+                    toolchain.name()
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let versions = versions
+                .into_iter()
+                .map(|(version, installed)| {
+                    Value::object(vec![
+                        ("date", version.date.into()),
+                        ("rust_version", version.rust_version.into()),
+                        ("installed", installed.into()),
+                    ])
+                })
+                .collect();
+            format::print_json(&Value::document(vec![
+                ("toolchain", toolchain.name().into()),
+                ("versions", Value::Array(versions)),
+            ]))?;
+        }
+        OutputFormat::Tsv => {
+            for (version, installed) in versions {
+                format::print_tsv(&[
+                    version.date,
+                    version.rust_version.unwrap_or_default(),
+                    installed.to_string(),
+                ])?;
+            }
+        }
+    }
+    Ok(utils::ExitCode(0))
+}
+
+fn toolchain_rollback(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let toolchain = cfg.get_toolchain(m.value_of("toolchain").unwrap(), false)?;
+    if !toolchain.exists() {
+        return Err(RustupError::ToolchainNotInstalled(toolchain.name().to_owned()).into());
+    }
+    let distributable = DistributableToolchain::new(&toolchain)?;
+    let version = distributable.roll_back(m.value_of("to"))?;
+    info!(
+        "rolled back toolchain '{}' to {}",
+        toolchain.name(),
+        version.date
+    );
+    Ok(utils::ExitCode(0))
+}
+
 fn override_add(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
     let toolchain = m.value_of("toolchain").unwrap();
     let toolchain = cfg.get_toolchain(toolchain, false)?;
@@ -1601,20 +2663,359 @@ fn set_auto_self_update(cfg: &mut Cfg, m: &ArgMatches<'_>) -> Result<utils::Exit
     Ok(utils::ExitCode(0))
 }
 
+fn set_signature_policy(cfg: &mut Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    cfg.set_signature_policy(m.value_of("policy").unwrap())?;
+    Ok(utils::ExitCode(0))
+}
+
+fn set_retained_versions(cfg: &mut Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    cfg.set_retained_versions(m.value_of("count").unwrap().parse()?)?;
+    Ok(utils::ExitCode(0))
+}
+
 fn show_profile(cfg: &Cfg) -> Result<utils::ExitCode> {
     writeln!(process().stdout(), "{}", cfg.get_profile()?)?;
     Ok(utils::ExitCode(0))
 }
 
 fn show_keys(cfg: &Cfg) -> Result<utils::ExitCode> {
+    // Keys which expire within this many days are reported
+    const EXPIRY_WARNING_DAYS: u64 = 30;
+
+    let now = SystemTime::now();
+    let soon = now + Duration::from_secs(EXPIRY_WARNING_DAYS * 24 * 60 * 60);
     for key in cfg.get_pgp_keys() {
         for l in key.show_key()? {
             info!("{}", l);
         }
+        if let Some(expiry) = key.expiration_time()? {
+            let date = chrono::DateTime::<chrono::Utc>::from(expiry).format("%Y-%m-%d");
+            if expiry <= now {
+                warn!("{} expired on {}", key, date);
+            } else if expiry <= soon {
+                warn!("{} expires on {}", key, date);
+            }
+        }
+    }
+    Ok(utils::ExitCode(0))
+}
+
+fn keys_list(cfg: &Cfg) -> Result<utils::ExitCode> {
+    // Keys in the keyring which are scoped to another dist server are not
+    // in use, but are listed all the same so that they can be removed. Those
+    // which cannot be read have been warned about when `cfg` was set up.
+    let mut keys = cfg
+        .get_pgp_keys()
+        .iter()
+        .filter(|key| !matches!(key, PgpPublicKey::FromKeyring(..)))
+        .map(|key| (key.fingerprint(), key.to_string()))
+        .collect::<Vec<_>>();
+    keys.extend(
+        cfg.keyring
+            .keys(&|_| {})?
+            .iter()
+            .map(|key| (key.fingerprint(), key.to_string())),
+    );
+
+    let mut t = term2::stdout();
+    for (fingerprint, description) in keys {
+        let _ = t.attr(term2::Attr::Bold);
+        write!(t, "{}", fingerprint)?;
+        let _ = t.reset();
+        writeln!(t, " {}", description)?;
+    }
+    Ok(utils::ExitCode(0))
+}
+
+fn keys_add(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let file = Path::new(m.value_of("file").unwrap());
+    cfg.add_pgp_key(file, m.value_of("dist-server"))?;
+    Ok(utils::ExitCode(0))
+}
+
+fn keys_remove(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    for fingerprint in m.values_of("fingerprint").unwrap() {
+        cfg.remove_pgp_key(fingerprint)?;
+    }
+    Ok(utils::ExitCode(0))
+}
+
+fn mirror_sync(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let channels: Vec<_> = m.values_of("channels").unwrap().collect();
+    let targets: Vec<_> = m
+        .values_of("targets")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+    let dest = utils::current_dir()?.join(m.value_of("dest").unwrap());
+    let keep_days = m
+        .value_of("keep-days")
+        .map(|d| {
+            d.parse()
+                .map_err(|_| anyhow!("invalid number of days: '{}'", d))
+        })
+        .transpose()?;
+    cfg.sync_mirror(&channels, &targets, &dest, keep_days)?;
+    Ok(utils::ExitCode(0))
+}
+
+fn dist_build_channel(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let current_dir = utils::current_dir()?;
+    let from = current_dir.join(m.value_of("from").unwrap());
+    let dest = current_dir.join(m.value_of("dest").unwrap_or("."));
+    let sign_key = m.value_of("sign-key").map(Path::new);
+    cfg.build_channel(
+        m.value_of("name").unwrap(),
+        m.value_of("date"),
+        &from,
+        &dest,
+        sign_key,
+    )?;
+    Ok(utils::ExitCode(0))
+}
+
+fn cache_list(cfg: &Cfg) -> Result<utils::ExitCode> {
+    let files = cfg.cached_downloads()?;
+    let mut t = term2::stdout();
+    for file in &files {
+        let last_used = chrono::DateTime::<chrono::Utc>::from(file.last_used);
+        let _ = t.attr(term2::Attr::Bold);
+        write!(t, "{}", file.hash)?;
+        let _ = t.reset();
+        writeln!(
+            t,
+            " {} last used {}",
+            size(file.size),
+            last_used.format("%Y-%m-%d")
+        )?;
+    }
+    let total: u64 = files.iter().map(|file| file.size).sum();
+    write!(t, "total: {}", size(total).trim())?;
+    match cfg.download_cache.as_ref().and_then(|cache| cache.max_size) {
+        Some(max_size) => writeln!(t, " of at most {}", size(max_size).trim())?,
+        None => writeln!(t)?,
+    }
+    Ok(utils::ExitCode(0))
+}
+
+fn cache_clear(cfg: &Cfg) -> Result<utils::ExitCode> {
+    let files = cfg.clear_download_cache()?;
+    let freed: u64 = files.iter().map(|file| file.size).sum();
+    info!("removed {} from the download cache", size(freed).trim());
+    Ok(utils::ExitCode(0))
+}
+
+fn cache_prune(cfg: &Cfg) -> Result<utils::ExitCode> {
+    let files = cfg.prune_download_cache()?;
+    let freed: u64 = files.iter().map(|file| file.size).sum();
+    info!("removed {} from the download cache", size(freed).trim());
+    Ok(utils::ExitCode(0))
+}
+
+fn collect_garbage(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let older_than = m.value_of("older-than").map(gc::parse_age).transpose()?;
+    let items = gc::find(cfg, older_than)?;
+    if items.is_empty() {
+        info!("nothing to clean up");
+        return Ok(utils::ExitCode(0));
+    }
+
+    let mut t = term2::stdout();
+    for item in &items {
+        writeln!(t, "{} {}", size(item.size), item.garbage)?;
+    }
+    let total: u64 = items.iter().map(|item| item.size).sum();
+    writeln!(t, "total: {}", size(total).trim())?;
+
+    if !m.is_present("yes") && !common::confirm("\nContinue? (y/N)", false)? {
+        info!("aborting garbage collection");
+        return Ok(utils::ExitCode(0));
+    }
+    gc::collect(cfg, &items)?;
+    info!("freed {}", size(total).trim());
+    Ok(utils::ExitCode(0))
+}
+
+fn disk_usage(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let format = if m.is_present("json") {
+        OutputFormat::Json
+    } else {
+        OutputFormat::from_matches(m)?
+    };
+    let mut toolchains = Vec::new();
+    for name in cfg.list_toolchains()? {
+        let toolchain = cfg.get_toolchain(&name, false)?;
+        // Custom toolchains have no record of what they contain
+        let components = match DistributableToolchain::new(&toolchain) {
+            Ok(toolchain) => toolchain.component_sizes()?,
+            Err(_) => Vec::new(),
+        };
+        let bytes = utils::disk_usage(toolchain.path());
+        toolchains.push((name, bytes, components));
+    }
+    let downloads = utils::disk_usage(&cfg.download_dir);
+    let tmp = utils::disk_usage(cfg.temp_cfg.root_directory());
+    let total = utils::disk_usage(&cfg.rustup_dir);
+
+    match format {
+        OutputFormat::Human => {
+            let mut t = term2::stdout();
+            for (name, bytes, components) in toolchains {
+                write!(t, "{} ", size(bytes))?;
+                let _ = t.attr(term2::Attr::Bold);
+                writeln!(t, "{}", name)?;
+                let _ = t.reset();
+                for (component, bytes) in components {
+                    writeln!(t, "  {} {}", size(bytes), component)?;
+                }
+            }
+            writeln!(t, "{} downloads", size(downloads))?;
+            writeln!(t, "{} tmp", size(tmp))?;
+            writeln!(t, "total: {}", size(total).trim())?;
+        }
+        OutputFormat::Json => {
+            let toolchains = toolchains
+                .into_iter()
+                .map(|(name, size, components)| {
+                    let components = components
+                        .into_iter()
+                        .map(|(name, size)| {
+                            Value::object(vec![("name", name.into()), ("size", size.into())])
+                        })
+                        .collect();
+                    Value::object(vec![
+                        ("name", name.into()),
+                        ("size", size.into()),
+                        ("components", Value::Array(components)),
+                    ])
+                })
+                .collect();
+            format::print_json(&Value::document(vec![
+                ("toolchains", Value::Array(toolchains)),
+                ("downloads", downloads.into()),
+                ("tmp", tmp.into()),
+                ("total", total.into()),
+            ]))?;
+        }
+        OutputFormat::Tsv => {
+            for (name, size, components) in toolchains {
+                format::print_tsv(&["toolchain".to_owned(), name.clone(), size.to_string()])?;
+                for (component, size) in components {
+                    format::print_tsv(&[
+                        "component".to_owned(),
+                        name.clone(),
+                        component,
+                        size.to_string(),
+                    ])?;
+                }
+            }
+            format::print_tsv(&["downloads".to_owned(), downloads.to_string()])?;
+            format::print_tsv(&["tmp".to_owned(), tmp.to_string()])?;
+            format::print_tsv(&["total".to_owned(), total.to_string()])?;
+        }
     }
     Ok(utils::ExitCode(0))
 }
 
+fn history(cfg: &Cfg, m: &ArgMatches<'_>) -> Result<utils::ExitCode> {
+    let format = if m.is_present("json") {
+        OutputFormat::Json
+    } else {
+        OutputFormat::from_matches(m)?
+    };
+    let toolchain = match m.value_of("toolchain") {
+        Some(name) => Some(cfg.get_toolchain(name, false)?.name().to_owned()),
+        None => None,
+    };
+    let entries: Vec<_> = cfg
+        .history
+        .entries()?
+        .into_iter()
+        .filter(|e| toolchain.is_none() || e.toolchain == toolchain)
+        .collect();
+
+    match format {
+        OutputFormat::Human => {
+            let mut t = term2::stdout();
+            for entry in entries {
+                let time = i64::try_from(entry.time)
+                    .ok()
+                    .and_then(|secs| chrono::NaiveDateTime::from_timestamp_opt(secs, 0));
+                match time {
+                    Some(time) => write!(t, "{} ", time.format("%Y-%m-%d %H:%M:%S UTC"))?,
+                    // Not a time chrono can show, as from a clock which was wrong
+                    None => write!(t, "{} ", entry.time)?,
+                }
+                let _ = t.attr(term2::Attr::Bold);
+                write!(t, "{}", entry.operation)?;
+                let _ = t.reset();
+                if let Some(toolchain) = &entry.toolchain {
+                    write!(t, " {}", toolchain)?;
+                }
+                if entry.status != 0 {
+                    write!(t, " (failed with exit status {})", entry.status)?;
+                }
+                writeln!(t)?;
+                match (&entry.rustc_before, &entry.rustc_after) {
+                    (Some(before), Some(after)) if before != after => {
+                        writeln!(t, "  rustc: {} -> {}", before, after)?
+                    }
+                    (_, Some(after)) => writeln!(t, "  rustc: {}", after)?,
+                    _ => {}
+                }
+                if let Some(date) = &entry.manifest_date {
+                    write!(t, "  release: {}", date)?;
+                    if let Some(hash) = &entry.manifest_hash {
+                        write!(t, " (manifest {})", hash)?;
+                    }
+                    writeln!(t)?;
+                }
+                if !entry.components.is_empty() {
+                    writeln!(t, "  components: {}", entry.components.join(" "))?;
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let entries = entries
+                .into_iter()
+                .map(|entry| {
+                    Value::object(vec![
+                        ("time", entry.time.into()),
+                        ("operation", entry.operation.into()),
+                        ("toolchain", entry.toolchain.into()),
+                        ("manifest_date", entry.manifest_date.into()),
+                        ("manifest_hash", entry.manifest_hash.into()),
+                        ("rustc_before", entry.rustc_before.into()),
+                        ("rustc_after", entry.rustc_after.into()),
+                        ("components", entry.components.into()),
+                        ("status", u64::from(entry.status).into()),
+                    ])
+                })
+                .collect();
+            format::print_json(&Value::document(vec![("entries", Value::Array(entries))]))?;
+        }
+        OutputFormat::Tsv => {
+            for entry in entries {
+                format::print_tsv(&[
+                    entry.time.to_string(),
+                    entry.operation,
+                    entry.toolchain.unwrap_or_default(),
+                    entry.manifest_date.unwrap_or_default(),
+                    entry.manifest_hash.unwrap_or_default(),
+                    entry.rustc_before.unwrap_or_default(),
+                    entry.rustc_after.unwrap_or_default(),
+                    entry.components.join(","),
+                    entry.status.to_string(),
+                ])?;
+            }
+        }
+    }
+    Ok(utils::ExitCode(0))
+}
+
+fn size(bytes: u64) -> String {
+    Size::new(bytes as usize, Unit::B, UnitMode::Norm).to_string()
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub(crate) enum CompletionCommand {
     Rustup,
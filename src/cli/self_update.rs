@@ -65,6 +65,7 @@ use crate::cli::term2::Terminal;
 use crate::dist::dist::{self, Profile, TargetTriple};
 use crate::process;
 use crate::toolchain::{DistributableToolchain, Toolchain};
+use crate::utils::retry_policy::RetryPolicy;
 use crate::utils::utils;
 use crate::utils::Notification;
 use crate::{Cfg, UpdateStatus};
@@ -1033,7 +1034,7 @@ pub(crate) fn update(cfg: &Cfg) -> Result<utils::ExitCode> {
         Permit => {}
     }
 
-    match prepare_update()? {
+    match prepare_update(&cfg.retry_policy)? {
         Some(setup_path) => {
             let version = match get_new_rustup_version(&setup_path) {
                 Some(new_version) => parse_new_rustup_version(new_version),
@@ -1082,7 +1083,7 @@ fn parse_new_rustup_version(version: String) -> String {
     String::from(matched_version)
 }
 
-pub(crate) fn prepare_update() -> Result<Option<PathBuf>> {
+pub(crate) fn prepare_update(retry_policy: &RetryPolicy) -> Result<Option<PathBuf>> {
     let cargo_home = utils::cargo_home()?;
     let rustup_path = cargo_home.join(&format!("bin{}rustup{}", MAIN_SEPARATOR, EXE_SUFFIX));
     let setup_path = cargo_home.join(&format!("bin{}rustup-init{}", MAIN_SEPARATOR, EXE_SUFFIX));
@@ -1117,7 +1118,7 @@ pub(crate) fn prepare_update() -> Result<Option<PathBuf>> {
 
     // Get available version
     info!("checking for self-updates");
-    let available_version = get_available_rustup_version()?;
+    let available_version = get_available_rustup_version(retry_policy)?;
 
     // If up-to-date
     if available_version == current_version {
@@ -1135,7 +1136,7 @@ pub(crate) fn prepare_update() -> Result<Option<PathBuf>> {
 
     // Download new version
     info!("downloading self-update");
-    utils::download_file(&download_url, &setup_path, None, &|_| ())?;
+    utils::download_file(&download_url, &setup_path, None, retry_policy, &|_| ())?;
 
     // Mark as executable
     utils::make_executable(&setup_path)?;
@@ -1143,7 +1144,7 @@ pub(crate) fn prepare_update() -> Result<Option<PathBuf>> {
     Ok(Some(setup_path))
 }
 
-pub(crate) fn get_available_rustup_version() -> Result<String> {
+pub(crate) fn get_available_rustup_version(retry_policy: &RetryPolicy) -> Result<String> {
     let update_root = process()
         .var("RUSTUP_UPDATE_ROOT")
         .unwrap_or_else(|_| String::from(UPDATE_ROOT));
@@ -1156,7 +1157,13 @@ pub(crate) fn get_available_rustup_version() -> Result<String> {
     let release_file_url = format!("{}/release-stable.toml", update_root);
     let release_file_url = utils::parse_url(&release_file_url)?;
     let release_file = tempdir.path().join("release-stable.toml");
-    utils::download_file(&release_file_url, &release_file, None, &|_| ())?;
+    utils::download_file(
+        &release_file_url,
+        &release_file,
+        None,
+        retry_policy,
+        &|_| (),
+    )?;
     let release_toml_str = utils::read_file("rustup release", &release_file)?;
     let release_toml: toml::Value =
         toml::from_str(&release_toml_str).context("unable to parse rustup release file")?;
@@ -1184,13 +1191,13 @@ pub(crate) fn get_available_rustup_version() -> Result<String> {
     Ok(String::from(available_version))
 }
 
-pub(crate) fn check_rustup_update() -> Result<()> {
+pub(crate) fn check_rustup_update(retry_policy: &RetryPolicy) -> Result<()> {
     let mut t = term2::stdout();
     // Get current rustup version
     let current_version = env!("CARGO_PKG_VERSION");
 
     // Get available rustup version
-    let available_version = get_available_rustup_version()?;
+    let available_version = get_available_rustup_version(retry_policy)?;
 
     let _ = t.attr(term2::Attr::Bold);
     write!(t, "rustup - ")?;
@@ -14,6 +14,7 @@ use super::{install_bins, InstallOpts};
 use crate::cli::download_tracker::DownloadTracker;
 use crate::dist::dist::TargetTriple;
 use crate::process;
+use crate::utils::retry_policy::RetryPolicy;
 use crate::utils::utils;
 use crate::utils::Notification;
 
@@ -112,13 +113,21 @@ pub(crate) fn try_install_msvc() -> Result<()> {
     download_tracker.borrow_mut().download_finished();
 
     info!("downloading Visual Studio installer");
-    utils::download_file(&visual_studio_url, &visual_studio, None, &move |n| {
-        download_tracker
-            .borrow_mut()
-            .handle_notification(&crate::Notification::Install(
-                crate::dist::Notification::Utils(n),
-            ));
-    })?;
+    // Settings do not exist yet while rustup is being installed
+    let retry_policy = RetryPolicy::configured(None);
+    utils::download_file(
+        &visual_studio_url,
+        &visual_studio,
+        None,
+        &retry_policy,
+        &move |n| {
+            download_tracker
+                .borrow_mut()
+                .handle_notification(&crate::Notification::Install(
+                    crate::dist::Notification::Utils(n),
+                ));
+        },
+    )?;
 
     // Run the installer. Arguments are documented at:
     // https://docs.microsoft.com/en-us/visualstudio/install/use-command-line-parameters-to-install-visual-studio
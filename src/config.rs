@@ -1,10 +1,13 @@
-use std::borrow::Cow;
+use std::cell::RefCell;
 use std::fmt::{self, Display};
+use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use anyhow::{anyhow, bail, Context, Result};
 use sequoia_openpgp::{parse::Parse, policy, Cert};
@@ -14,15 +17,27 @@ use thiserror::Error as ThisError;
 use crate::cli::self_update::SelfUpdateMode;
 use crate::dist::download::DownloadCfg;
 use crate::dist::{
+    bundle::{self, Bundle},
+    cache::{self, CachedFile, DownloadCache},
+    channel,
+    component::{has_transaction_journal, recover_transaction},
     dist::{self, Profile},
-    temp,
+    lockfile::{Lockfile, LOCKFILE_NAME},
+    mirror,
+    prefix::InstallPrefix,
+    signatures::SignaturePolicy,
+    staging, temp,
 };
 use crate::errors::RustupError;
 use crate::fallback_settings::FallbackSettings;
+use crate::history::History;
+use crate::keyring::{normalize_fingerprint, normalize_url, Keyring};
 use crate::notifications::*;
 use crate::process;
 use crate::settings::{Settings, SettingsFile, DEFAULT_METADATA_VERSION};
 use crate::toolchain::{DistributableToolchain, Toolchain, UpdateStatus};
+use crate::utils::lock::Lock;
+use crate::utils::retry_policy::RetryPolicy;
 use crate::utils::utils;
 
 #[derive(Debug, ThisError)]
@@ -165,6 +180,8 @@ pub enum PgpPublicKey {
     Builtin,
     FromEnvironment(PathBuf, Cert),
     FromConfiguration(PathBuf, Cert),
+    /// A key from the keyring, optionally restricted to one dist server
+    FromKeyring(PathBuf, Cert, Option<String>),
 }
 
 impl PgpPublicKey {
@@ -174,9 +191,25 @@ impl PgpPublicKey {
             Self::Builtin => &*BUILTIN_PGP_KEY,
             Self::FromEnvironment(_, k) => k,
             Self::FromConfiguration(_, k) => k,
+            Self::FromKeyring(_, k, _) => k,
         }
     }
 
+    /// When the key expires, if it ever does
+    pub(crate) fn expiration_time(&self) -> Result<Option<SystemTime>> {
+        let p = policy::StandardPolicy::new();
+        Ok(self
+            .cert()
+            .with_policy(&p, None)?
+            .primary_key()
+            .key_expiration_time())
+    }
+
+    /// The fingerprint of the key, as uppercase hex without separators.
+    pub(crate) fn fingerprint(&self) -> String {
+        self.cert().fingerprint().to_hex()
+    }
+
     /// Display the key in detail for the user
     pub(crate) fn show_key(&self) -> Result<Vec<String>> {
         fn format_hex(bytes: &[u8], separator: &str, every: usize) -> Result<String> {
@@ -220,12 +253,24 @@ impl Display for PgpPublicKey {
             Self::FromConfiguration(p, _) => {
                 write!(f, "key specified in configuration file ({})", p.display())
             }
+            Self::FromKeyring(p, _, None) => write!(f, "key from the keyring ({})", p.display()),
+            Self::FromKeyring(p, _, Some(url)) => {
+                write!(
+                    f,
+                    "key from the keyring ({}) for dist server {}",
+                    p.display(),
+                    url
+                )
+            }
         }
     }
 }
 
 pub(crate) const UNIX_FALLBACK_SETTINGS: &str = "/etc/rustup/settings.toml";
 
+/// Where the locks which rustup processes sharing a home take are kept
+const LOCKS_DIR: &str = "locks";
+
 pub struct Cfg {
     profile_override: Option<dist::Profile>,
     pub rustup_dir: PathBuf,
@@ -234,12 +279,21 @@ pub struct Cfg {
     pub toolchains_dir: PathBuf,
     pub update_hash_dir: PathBuf,
     pub download_dir: PathBuf,
+    pub keyring: Keyring,
     pub temp_cfg: temp::Cfg,
     pgp_keys: Vec<PgpPublicKey>,
+    signature_policy: SignaturePolicy,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) download_cache: Option<DownloadCache>,
     pub toolchain_override: Option<String>,
     pub env_override: Option<String>,
     pub dist_root_url: String,
+    dist_servers: Vec<String>,
     pub notify_handler: Arc<dyn Fn(Notification<'_>)>,
+    /// What this process does, as it goes into the history
+    pub(crate) history: Rc<History>,
+    /// Locks on the toolchains this process runs, held until it exits
+    held_locks: RefCell<Vec<Lock>>,
 }
 
 impl Cfg {
@@ -247,9 +301,22 @@ impl Cfg {
         // Set up the rustup home directory
         let rustup_dir = utils::rustup_home()?;
 
+        // What is done is learnt from what is notified
+        let history = Rc::new(History::new(rustup_dir.join("history")));
+        let notify_handler: Arc<dyn Fn(Notification<'_>)> = {
+            let history = Rc::clone(&history);
+            Arc::new(move |n: Notification<'_>| {
+                history.observe(&n);
+                notify_handler(n)
+            })
+        };
+
         utils::ensure_dir_exists("home", &rustup_dir, notify_handler.as_ref())?;
 
-        let settings_file = SettingsFile::new(rustup_dir.join("settings.toml"));
+        let settings_file = SettingsFile::new(
+            rustup_dir.join("settings.toml"),
+            rustup_dir.join(LOCKS_DIR).join("settings.lock"),
+        );
 
         // Centralised file for multi-user systems to provide admin/distributor set initial values.
         let fallback_settings = if cfg!(not(windows)) {
@@ -268,6 +335,7 @@ impl Cfg {
         let toolchains_dir = rustup_dir.join("toolchains");
         let update_hash_dir = rustup_dir.join("update-hashes");
         let download_dir = rustup_dir.join("downloads");
+        let keyring = Keyring::new(rustup_dir.join("keyring"));
 
         // PGP keys
         let mut pgp_keys: Vec<PgpPublicKey> = vec![PgpPublicKey::Builtin];
@@ -298,34 +366,80 @@ impl Cfg {
             Ok(())
         })?;
 
+        // An unset or empty RUSTUP_SIGNATURE_POLICY defers to the settings file
+        let signature_policy = match process()
+            .var("RUSTUP_SIGNATURE_POLICY")
+            .ok()
+            .and_then(utils::if_not_empty)
+        {
+            Some(policy) => SignaturePolicy::from_str(&policy)
+                .context("invalid value for RUSTUP_SIGNATURE_POLICY")?,
+            None => settings_file
+                .with(|s| Ok(s.signature_policy))?
+                .unwrap_or_default(),
+        };
+
+        let retry_policy =
+            RetryPolicy::configured(settings_file.with(|s| Ok(s.retry_policy.clone()))?);
+        let download_cache = settings_file.with(|s| Ok(s.cache.clone()))?;
+
         // Environment override
         let env_override = process()
             .var("RUSTUP_TOOLCHAIN")
             .ok()
             .and_then(utils::if_not_empty);
 
-        let dist_root_server = match process().var("RUSTUP_DIST_SERVER") {
-            Ok(ref s) if !s.is_empty() => s.clone(),
-            _ => {
+        // The dist server, then the mirrors to fall back to
+        let dist_servers = match process().var("RUSTUP_DIST_SERVER") {
+            // A local directory may be given as a path
+            Ok(ref s) if !s.is_empty() => vec![utils::url_or_path(s)
+                .with_context(|| format!("invalid RUSTUP_DIST_SERVER '{}'", s))?],
+            _ => match process()
+                .var("RUSTUP_DIST_ROOT")
+                .ok()
+                .and_then(utils::if_not_empty)
+            {
                 // For backward compatibility
-                process()
-                    .var("RUSTUP_DIST_ROOT")
-                    .ok()
-                    .and_then(utils::if_not_empty)
-                    .map_or(Cow::Borrowed(dist::DEFAULT_DIST_ROOT), Cow::Owned)
-                    .as_ref()
-                    .trim_end_matches("/dist")
-                    .to_owned()
-            }
+                Some(root) => vec![root.trim_end_matches("/dist").to_owned()],
+                None => {
+                    let servers = settings_file.with(|s| Ok(s.dist_servers.clone()))?;
+                    if servers.is_empty() {
+                        vec![dist::DEFAULT_DIST_SERVER.to_owned()]
+                    } else {
+                        servers
+                            .iter()
+                            .map(|s| {
+                                utils::url_or_path(s.trim_end_matches('/')).with_context(|| {
+                                    format!("invalid dist server '{}' in settings", s)
+                                })
+                            })
+                            .collect::<Result<_>>()?
+                    }
+                }
+            },
         };
 
+        // Keys from the keyring, except those meant for another dist server
+        pgp_keys.extend(
+            keyring
+                .keys(notify_handler.as_ref())?
+                .into_iter()
+                .filter(|key| match key {
+                    PgpPublicKey::FromKeyring(_, _, Some(url)) => dist_servers
+                        .iter()
+                        .any(|server| normalize_url(url) == normalize_url(server)),
+                    _ => true,
+                }),
+        );
+
         let notify_clone = notify_handler.clone();
-        let temp_cfg = temp::Cfg::new(
+        let mut temp_cfg = temp::Cfg::new(
             rustup_dir.join("tmp"),
-            dist_root_server.as_str(),
+            dist_servers[0].as_str(),
             Box::new(move |n| (notify_clone)(n.into())),
         );
-        let dist_root = dist_root_server + "/dist";
+        temp_cfg.read_in_place = true;
+        let dist_root = format!("{}/dist", dist_servers[0]);
 
         let cfg = Self {
             profile_override: None,
@@ -335,14 +449,23 @@ impl Cfg {
             toolchains_dir,
             update_hash_dir,
             download_dir,
+            keyring,
             temp_cfg,
             pgp_keys,
+            signature_policy,
+            retry_policy,
+            download_cache,
             notify_handler,
             toolchain_override: None,
             env_override,
             dist_root_url: dist_root,
+            dist_servers,
+            history,
+            held_locks: RefCell::new(Vec::new()),
         };
 
+        cfg.recover_interrupted_installs()?;
+
         // Run some basic checks against the constructed configuration
         // For now, that means simply checking that 'stable' can resolve
         // for the current configuration.
@@ -352,6 +475,63 @@ impl Cfg {
         Ok(cfg)
     }
 
+    /// Rolls back the changes of installs which rustup was interrupted in
+    /// the middle of, and finishes swapping in toolchains which it was
+    /// interrupted swapping, so that no toolchain is left half installed
+    fn recover_interrupted_installs(&self) -> Result<()> {
+        if !utils::is_directory(&self.toolchains_dir) {
+            return Ok(());
+        }
+        for entry in utils::read_dir("toolchains", &self.toolchains_dir)? {
+            let entry = entry.with_context(|| RustupError::ReadingDirectory {
+                name: "toolchains",
+                path: self.toolchains_dir.clone(),
+            })?;
+            // Custom toolchains are links, and not installed by rustup
+            if !entry.file_type().map_or(false, |t| t.is_dir()) {
+                continue;
+            }
+            // A toolchain which is locked is being installed by a process
+            // which is still running
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(toolchain) = staging::staged_toolchain(&name) {
+                let live = self.toolchains_dir.join(toolchain);
+                if utils::path_exists(&live) {
+                    continue;
+                }
+                if let Some(_lock) = Lock::try_exclusive(&self.toolchain_lock_path(toolchain))? {
+                    staging::finish_interrupted_swap(&entry.path(), &live, &|n| {
+                        (self.notify_handler)(n.into())
+                    })?;
+                }
+                continue;
+            }
+            let prefix = InstallPrefix::from(entry.path());
+            if staging::is_internal_dir(&name) || !has_transaction_journal(&prefix) {
+                continue;
+            }
+            if let Some(_lock) = Lock::try_exclusive(&self.toolchain_lock_path(&name))? {
+                recover_transaction(&prefix, &|n| (self.notify_handler)(n.into()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The lock which is held while the toolchain called `name` is changed
+    pub(crate) fn toolchain_lock_path(&self, name: &str) -> PathBuf {
+        self.rustup_dir
+            .join(LOCKS_DIR)
+            .join(format!("{}.lock", name))
+    }
+
+    /// Keeps `lock` held for as long as this process runs, including as
+    /// the program it goes on to `exec`
+    fn hold_lock(&self, lock: Lock) -> Result<()> {
+        lock.keep_on_exec()?;
+        self.held_locks.borrow_mut().push(lock);
+        Ok(())
+    }
+
     /// construct a download configuration
     pub(crate) fn download_cfg<'a>(
         &'a self,
@@ -359,10 +539,38 @@ impl Cfg {
     ) -> DownloadCfg<'a> {
         DownloadCfg {
             dist_root: &self.dist_root_url,
+            dist_server: &self.dist_servers[0],
+            mirrors: &self.dist_servers[1..],
             temp_cfg: &self.temp_cfg,
             download_dir: &self.download_dir,
             notify_handler,
             pgp_keys: self.get_pgp_keys(),
+            signature_policy: self.signature_policy,
+            retry_policy: &self.retry_policy,
+            cache: self.download_cache.as_ref(),
+        }
+    }
+
+    /// The archives in the download cache, least recently used first
+    pub(crate) fn cached_downloads(&self) -> Result<Vec<CachedFile>> {
+        cache::list(&self.download_dir)
+    }
+
+    pub(crate) fn clear_download_cache(&self) -> Result<Vec<CachedFile>> {
+        cache::clear(&self.download_dir)
+    }
+
+    /// Evicts what does not fit from the download cache, or without a cache
+    /// removes every archive, since they are only kept with one
+    pub(crate) fn prune_download_cache(&self) -> Result<Vec<CachedFile>> {
+        let notify_handler = |n: crate::dist::Notification<'_>| (self.notify_handler)(n.into());
+        match &self.download_cache {
+            Some(download_cache) => download_cache.prune(&self.download_dir, &notify_handler),
+            None => DownloadCache {
+                max_size: Some(0),
+                shared_dir: None,
+            }
+            .prune(&self.download_dir, &notify_handler),
         }
     }
 
@@ -416,6 +624,51 @@ impl Cfg {
         }
     }
 
+    pub(crate) fn set_signature_policy(&mut self, policy: &str) -> Result<()> {
+        let signature_policy = SignaturePolicy::from_str(policy)?;
+        self.settings_file.with_mut(|s| {
+            s.signature_policy = Some(signature_policy);
+            Ok(())
+        })?;
+        (self.notify_handler)(Notification::SetSignaturePolicy(policy));
+        Ok(())
+    }
+
+    pub(crate) fn set_retained_versions(&mut self, count: u64) -> Result<()> {
+        self.settings_file.with_mut(|s| {
+            s.retained_versions = Some(count);
+            Ok(())
+        })?;
+        (self.notify_handler)(Notification::SetRetainedVersions(count));
+        Ok(())
+    }
+
+    /// How many previous versions of a tracking toolchain to keep when it
+    /// is updated
+    pub(crate) fn retained_versions(&self) -> Result<usize> {
+        self.settings_file
+            .with(|s| Ok(s.retained_versions.unwrap_or(0) as usize))
+    }
+
+    /// Add the key in `file` to the keyring, optionally for one dist server only
+    pub(crate) fn add_pgp_key(&self, file: &Path, dist_server: Option<&str>) -> Result<()> {
+        let fingerprint = self.keyring.add(file, dist_server)?;
+        (self.notify_handler)(Notification::AddedPgpKey(&fingerprint));
+        Ok(())
+    }
+
+    pub(crate) fn remove_pgp_key(&self, fingerprint: &str) -> Result<()> {
+        let fingerprint = normalize_fingerprint(fingerprint);
+        if fingerprint == PgpPublicKey::Builtin.fingerprint() {
+            bail!("the builtin Rust release key cannot be removed");
+        }
+        if !self.keyring.remove(&fingerprint)? {
+            return Err(RustupError::PgpKeyNotFound(fingerprint).into());
+        }
+        (self.notify_handler)(Notification::RemovedPgpKey(&fingerprint));
+        Ok(())
+    }
+
     pub(crate) fn set_toolchain_override(&mut self, toolchain_override: &str) {
         self.toolchain_override = Some(toolchain_override.to_owned());
     }
@@ -723,6 +976,32 @@ impl Cfg {
         }
     }
 
+    /// The toolchains named by the `rust-toolchain` and `rust-toolchain.toml`
+    /// files under the project roots of the settings file
+    pub(crate) fn project_toolchains(&self) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        for root in self.settings_file.with(|s| Ok(s.project_roots.clone()))? {
+            find_toolchain_files(Path::new(&root), &mut files);
+        }
+        let mut toolchains = Vec::new();
+        for (path, parse_mode) in files {
+            // A toolchain file which cannot be read names no toolchain
+            let override_file = utils::read_file("toolchain file", &path)
+                .and_then(|contents| Cfg::parse_override_file(contents, parse_mode));
+            if let Ok(OverrideFile {
+                toolchain:
+                    ToolchainSection {
+                        channel: Some(channel),
+                        ..
+                    },
+            }) = override_file
+            {
+                toolchains.push(self.resolve_toolchain(&channel)?);
+            }
+        }
+        Ok(toolchains)
+    }
+
     pub(crate) fn find_or_install_override_toolchain_or_default(
         &self,
         path: &Path,
@@ -807,7 +1086,10 @@ impl Cfg {
                 let targets: Vec<_> = targets.iter().map(AsRef::as_ref).collect();
 
                 let distributable = DistributableToolchain::new(&toolchain)?;
-                if !toolchain.exists() || !components_exist(&distributable, &components, &targets)?
+                if let Some(lockfile) = Self::find_lockfile(reason.as_ref())? {
+                    distributable.install_from_lockfile(&lockfile)?;
+                } else if !toolchain.exists()
+                    || !components_exist(&distributable, &components, &targets)?
                 {
                     distributable.install_from_dist(true, false, &components, &targets, profile)?;
                 }
@@ -820,6 +1102,169 @@ impl Cfg {
         }
     }
 
+    /// The lockfile next to the toolchain file which is the reason for
+    /// using a toolchain, if there is one
+    fn find_lockfile(reason: Option<&OverrideReason>) -> Result<Option<Lockfile>> {
+        if let Some(OverrideReason::ToolchainFile(toolchain_file)) = reason {
+            let path = toolchain_file.with_file_name(LOCKFILE_NAME);
+            if utils::is_file(&path) {
+                return Lockfile::load(&path).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// The override for `path`, which must come from a toolchain file naming
+    /// a distributable toolchain, and the path of that toolchain file
+    fn find_lockable_override(&self, path: &Path) -> Result<(OverrideCfg<'_>, PathBuf)> {
+        match self.find_override_config(path)? {
+            Some((override_cfg, OverrideReason::ToolchainFile(toolchain_file))) => {
+                match &override_cfg.toolchain {
+                    Some(toolchain) if !toolchain.is_custom() => {}
+                    Some(toolchain) => bail!(
+                        "the custom toolchain '{}' selected by '{}' cannot be locked",
+                        toolchain.name(),
+                        toolchain_file.display()
+                    ),
+                    None => bail!(
+                        "the toolchain file '{}' does not specify a channel",
+                        toolchain_file.display()
+                    ),
+                }
+                Ok((override_cfg, toolchain_file))
+            }
+            _ => bail!(
+                "no toolchain file selects the toolchain for '{}'",
+                path.display()
+            ),
+        }
+    }
+
+    /// Install the toolchain pinned by the lockfile next to the toolchain
+    /// file for `path`
+    pub(crate) fn install_locked_toolchain(&self, path: &Path) -> Result<(String, UpdateStatus)> {
+        let (override_cfg, toolchain_file) = self.find_lockable_override(path)?;
+        let toolchain = override_cfg.toolchain.unwrap();
+        let lockfile_path = toolchain_file.with_file_name(LOCKFILE_NAME);
+        if !utils::is_file(&lockfile_path) {
+            bail!(
+                "there is no lockfile at '{}'; run `rustup toolchain lock` to create it",
+                lockfile_path.display()
+            );
+        }
+        let lockfile = Lockfile::load(&lockfile_path)?;
+        let status = DistributableToolchain::new(&toolchain)?.install_from_lockfile(&lockfile)?;
+        Ok((toolchain.name().to_owned(), status))
+    }
+
+    /// Resolve the toolchain file for `path` against the dist server and
+    /// write the result to the lockfile next to it
+    pub(crate) fn lock_toolchain(&self, path: &Path) -> Result<PathBuf> {
+        let (override_cfg, toolchain_file) = self.find_lockable_override(path)?;
+        let toolchain = override_cfg.toolchain.unwrap();
+        let desc = dist::ToolchainDesc::from_str(toolchain.name())?;
+        let profile = match override_cfg.profile {
+            Some(profile) => profile,
+            None => self.get_profile()?,
+        };
+        let components: Vec<_> = override_cfg.components.iter().map(AsRef::as_ref).collect();
+        let targets: Vec<_> = override_cfg.targets.iter().map(AsRef::as_ref).collect();
+
+        let notify_handler = |n: crate::dist::Notification<'_>| (self.notify_handler)(n.into());
+        let download_cfg = self.download_cfg(&notify_handler);
+        notify_handler(crate::dist::Notification::DownloadingManifest(
+            &desc.to_string(),
+        ));
+        // Without an update hash the manifest is always downloaded
+        let (manifest, hash) = dist::with_mirrors(download_cfg, |download| {
+            dist::dl_v2_manifest(download, None, &desc)
+        })?
+        .unwrap();
+        let locked =
+            dist::requested_components(&manifest, &desc, Some(profile), &components, &targets)?;
+        let lockfile = Lockfile::new(&desc, &manifest, hash, &locked)?;
+
+        let lockfile_path = toolchain_file.with_file_name(LOCKFILE_NAME);
+        (self.notify_handler)(Notification::LockingToolchain(
+            &lockfile.toolchain,
+            &lockfile_path,
+        ));
+        lockfile.save(&lockfile_path)?;
+        Ok(lockfile_path)
+    }
+
+    /// Download everything needed to install a toolchain into a bundle at
+    /// `output`, for installing it where there is no network access
+    pub(crate) fn bundle_toolchain(
+        &self,
+        name: &str,
+        profile: Option<Profile>,
+        components: &[&str],
+        targets: &[&str],
+        output: &Path,
+    ) -> Result<()> {
+        let desc = dist::ToolchainDesc::from_str(&self.resolve_toolchain(name)?)?;
+        let profile = match profile {
+            Some(profile) => profile,
+            None => self.get_profile()?,
+        };
+        let notify_handler = |n: crate::dist::Notification<'_>| (self.notify_handler)(n.into());
+        let lockfile = dist::with_mirrors(self.download_cfg(&notify_handler), |download| {
+            bundle::create_bundle(download, &desc, profile, components, targets, output)
+        })?;
+        (self.notify_handler)(Notification::BundledToolchain(&lockfile.toolchain, output));
+        Ok(())
+    }
+
+    /// Sync the mirror of the dist server at `dest`, for `targets` or else
+    /// for the default host
+    pub(crate) fn sync_mirror(
+        &self,
+        channels: &[&str],
+        targets: &[&str],
+        dest: &Path,
+        keep_days: Option<u32>,
+    ) -> Result<()> {
+        let targets = if targets.is_empty() {
+            vec![self.get_default_host_triple()?]
+        } else {
+            targets.iter().map(|t| dist::TargetTriple::new(t)).collect()
+        };
+        let notify_handler = |n: crate::dist::Notification<'_>| (self.notify_handler)(n.into());
+        mirror::sync(
+            self.download_cfg(&notify_handler),
+            channels,
+            &targets,
+            dest,
+            keep_days,
+        )
+    }
+
+    /// Build the channel `name` from the rust-installer tarballs in `from`
+    /// into the dist server layout at `dest`
+    pub(crate) fn build_channel(
+        &self,
+        name: &str,
+        date: Option<&str>,
+        from: &Path,
+        dest: &Path,
+        sign_key: Option<&Path>,
+    ) -> Result<()> {
+        let notify_handler = |n: crate::dist::Notification<'_>| (self.notify_handler)(n.into());
+        let manifest = channel::build(name, date, from, dest, sign_key, &notify_handler)?;
+        (self.notify_handler)(Notification::BuiltChannel(name, &manifest));
+        Ok(())
+    }
+
+    /// Install the toolchain in the bundle at `path`, without going to the
+    /// dist server
+    pub(crate) fn install_from_bundle(&self, path: &Path) -> Result<(String, UpdateStatus)> {
+        let bundle = Bundle::open(path, &self.temp_cfg)?;
+        let toolchain = self.get_toolchain(&bundle.lockfile.toolchain, false)?;
+        let status = DistributableToolchain::new(&toolchain)?.install_from_bundle(&bundle)?;
+        Ok((toolchain.name().to_owned(), status))
+    }
+
     pub(crate) fn get_default(&self) -> Result<Option<String>> {
         let user_opt = self.settings_file.with(|s| Ok(s.default_toolchain.clone()));
         if let Some(fallback_settings) = &self.fallback_settings {
@@ -837,6 +1282,7 @@ impl Cfg {
                 .filter_map(io::Result::ok)
                 .filter(|e| e.file_type().map(|f| !f.is_file()).unwrap_or(false))
                 .filter_map(|e| e.file_name().into_string().ok())
+                .filter(|name| !staging::is_internal_dir(name))
                 .collect();
 
             utils::toolchain_sort(&mut toolchains);
@@ -908,6 +1354,9 @@ impl Cfg {
 
     pub(crate) fn create_command_for_dir(&self, path: &Path, binary: &str) -> Result<Command> {
         let (ref toolchain, _) = self.toolchain_for_dir(path)?;
+        if let Some(lock) = toolchain.lock_in_use()? {
+            self.hold_lock(lock)?;
+        }
 
         if let Some(cmd) = self.maybe_do_cargo_fallback(toolchain, binary)? {
             Ok(cmd)
@@ -930,6 +1379,9 @@ impl Cfg {
             let distributable = DistributableToolchain::new(&toolchain)?;
             distributable.install_from_dist(true, false, &[], &[], None)?;
         }
+        if let Some(lock) = toolchain.lock_in_use()? {
+            self.hold_lock(lock)?;
+        }
 
         if let Some(cmd) = self.maybe_do_cargo_fallback(&toolchain, binary)? {
             Ok(cmd)
@@ -1010,6 +1462,34 @@ impl Cfg {
     }
 }
 
+/// Adds the `rust-toolchain` and `rust-toolchain.toml` files under `dir` to
+/// `files`, skipping hidden directories, `target` directories and
+/// directories which cannot be read
+fn find_toolchain_files(dir: &Path, files: &mut Vec<(PathBuf, ParseMode)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(io::Result::ok) {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        match entry.file_type() {
+            // Symbolic links are not followed, since they may well loop
+            Ok(t) if t.is_dir() => {
+                if !name.starts_with('.') && name != "target" {
+                    find_toolchain_files(&entry.path(), files);
+                }
+            }
+            Ok(t) if t.is_file() => match &*name {
+                "rust-toolchain" => files.push((entry.path(), ParseMode::Both)),
+                "rust-toolchain.toml" => files.push((entry.path(), ParseMode::OnlyToml)),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
 /// Specifies how a `rust-toolchain`/`rust-toolchain.toml` configuration file should be parsed.
 enum ParseMode {
     /// Only permit TOML format in a configuration file.
@@ -0,0 +1,164 @@
+//! Toolchain bundles, for installing toolchains on hosts without network
+//! access.
+//!
+//! A bundle is a tarball holding everything needed to install one dated
+//! toolchain: a lockfile pinning its components, the channel manifest with
+//! its checksum and signature, laid out as on the dist server, and the
+//! component archives with their signatures, named by hash as in the
+//! download directory. Installing from a bundle points the download
+//! configuration at its contents, so that the manifest and every archive go
+//! through the same hash and signature checks as when they are downloaded.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::dist::{self, Profile, ToolchainDesc, DEFAULT_DIST_SERVER};
+use super::download::{file_url, with_suffix, DownloadCfg, SIGNED_SUFFIXES};
+use super::lockfile::{self, Lockfile, LOCKFILE_NAME};
+use super::manifest::Manifest;
+use super::notifications::Notification;
+use super::temp;
+use crate::errors::RustupError;
+use crate::utils::utils;
+
+const DIST_DIR: &str = "dist";
+const DOWNLOADS_DIR: &str = "downloads";
+
+/// Download `toolchain` with the components of `profile` and the extra
+/// `components` and `targets` into a bundle at `output`
+pub(crate) fn create_bundle(
+    download: DownloadCfg<'_>,
+    toolchain: &ToolchainDesc,
+    profile: Profile,
+    components: &[&str],
+    targets: &[&str],
+    output: &Path,
+) -> Result<Lockfile> {
+    let staging = download.temp_cfg.new_directory()?;
+    let dist_root = file_url(&staging.join(DIST_DIR))?;
+    let download_dir = staging.join(DOWNLOADS_DIR);
+
+    (download.notify_handler)(Notification::DownloadingManifest(&toolchain.to_string()));
+    let fetched = staging.join("manifest.toml");
+    download.download_signed(&toolchain.manifest_v2_url(download.dist_root), &fetched)?;
+
+    // The bundle holds the dated manifest, which is still found at the same
+    // place after the channel has moved on
+    let date = Manifest::parse(&utils::read_file("manifest", &fetched)?)?.date;
+    let toolchain = ToolchainDesc {
+        date: Some(date),
+        ..toolchain.clone()
+    };
+    let manifest_path = staging.join(toolchain.manifest_v2_url(DIST_DIR));
+    utils::ensure_dir_exists(
+        "bundle",
+        manifest_path.parent().unwrap(),
+        &download.notify_handler,
+    )?;
+    for suffix in SIGNED_SUFFIXES {
+        let src = with_suffix(&fetched, suffix);
+        if utils::is_file(&src) {
+            let dest = with_suffix(&manifest_path, suffix);
+            utils::rename_file("manifest", &src, &dest, &download.notify_handler)?;
+        }
+    }
+
+    // Check the manifest in the bundle exactly as it is checked on install
+    let bundled = DownloadCfg {
+        dist_root: &dist_root,
+        mirrors: &[],
+        download_dir: &download_dir,
+        ..download
+    };
+    let (manifest, hash) = dist::dl_v2_manifest(bundled, None, &toolchain)?.unwrap();
+    (download.notify_handler)(Notification::DownloadedManifest(
+        &manifest.date,
+        manifest.get_rust_version().ok(),
+    ));
+    let locked =
+        dist::requested_components(&manifest, &toolchain, Some(profile), components, targets)?;
+    let lockfile = Lockfile::new(&toolchain, &manifest, hash, &locked)?;
+
+    let altered = download.dist_server != DEFAULT_DIST_SERVER;
+    for locked in &lockfile.components {
+        let component = &locked.component;
+        (download.notify_handler)(Notification::DownloadingComponent(
+            &component.short_name(&manifest),
+            &toolchain.target,
+            component.target.as_ref(),
+        ));
+        // `Lockfile::new` checked that every component has an archive
+        let url = &lockfile::archive(&manifest, component)?.unwrap().url;
+        let url = if altered {
+            url.replace(DEFAULT_DIST_SERVER, download.dist_server)
+        } else {
+            url.clone()
+        };
+        // Both the archive and its signature end up in the download
+        // directory of the bundle, which is where the install looks for them
+        let file = bundled.download_to_dir(&utils::parse_url(&url)?, &locked.hash)?;
+        bundled.verify_signature(&url, &file)?;
+    }
+
+    lockfile.clone().save(&staging.join(LOCKFILE_NAME))?;
+
+    let file = fs::File::create(output).with_context(|| RustupError::WritingFile {
+        name: "bundle",
+        path: output.to_owned(),
+    })?;
+    let mut builder = tar::Builder::new(file);
+    builder
+        .append_dir_all(".", &*staging)
+        .and_then(|_| builder.finish())
+        .with_context(|| RustupError::WritingFile {
+            name: "bundle",
+            path: output.to_owned(),
+        })?;
+
+    Ok(lockfile)
+}
+
+/// A bundle, unpacked into a temporary directory for as long as it is open
+pub(crate) struct Bundle<'a> {
+    _dir: temp::Dir<'a>,
+    dist_server: String,
+    dist_root: String,
+    download_dir: PathBuf,
+    pub lockfile: Lockfile,
+}
+
+impl<'a> Bundle<'a> {
+    pub(crate) fn open(path: &Path, temp_cfg: &'a temp::Cfg) -> Result<Self> {
+        let dir = temp_cfg.new_directory()?;
+        tar::Archive::new(utils::open_file("bundle", path)?)
+            .unpack(&*dir)
+            .with_context(|| format!("failed to unpack bundle '{}'", path.display()))?;
+        let lockfile = Lockfile::load(&dir.join(LOCKFILE_NAME))
+            .with_context(|| format!("'{}' is not a toolchain bundle", path.display()))?;
+
+        Ok(Self {
+            dist_server: file_url(&dir)?,
+            dist_root: file_url(&dir.join(DIST_DIR))?,
+            download_dir: dir.join(DOWNLOADS_DIR),
+            _dir: dir,
+            lockfile,
+        })
+    }
+
+    /// `download`, taking the manifest and the archives from the bundle alone.
+    /// The URLs of the archives are rewritten to point into the bundle too,
+    /// so that nothing missing from it, such as the signature of an
+    /// archive, is looked for on the network; it is missing as far as the
+    /// signature policy is concerned.
+    pub(crate) fn download_cfg<'b>(&'b self, download: DownloadCfg<'b>) -> DownloadCfg<'b> {
+        DownloadCfg {
+            dist_root: &self.dist_root,
+            dist_server: &self.dist_server,
+            mirrors: &[],
+            download_dir: &self.download_dir,
+            ..download
+        }
+    }
+}
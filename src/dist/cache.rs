@@ -0,0 +1,268 @@
+//! The download cache.
+//!
+//! Archives are downloaded into `RUSTUP_HOME/downloads`, named after the
+//! SHA-256 hash of their content. Unless the settings file has a `[cache]`
+//! table, they are deleted as soon as they have been installed. With one,
+//! they are kept for the next toolchain which needs them, and once the
+//! cache grows past `max_size_mb` the archives which were used least
+//! recently are evicted. When an archive was last used is the modification
+//! time of its file. A `shared_dir` laid out the same way, e.g. on a
+//! read-only network mount, is looked in before going to the network.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+
+use super::download::{signature_path, with_suffix};
+use super::notifications::Notification;
+use crate::errors::RustupError;
+use crate::toml_utils::*;
+use crate::utils::lock::Lock;
+use crate::utils::utils;
+
+const MB: u64 = 1024 * 1024;
+
+/// How downloaded archives are kept once they have been installed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DownloadCache {
+    /// How large the cache may grow, in bytes, before archives are evicted
+    pub max_size: Option<u64>,
+    /// A cache to look in before downloading, which is never written to
+    pub shared_dir: Option<PathBuf>,
+}
+
+/// An archive in the cache
+pub(crate) struct CachedFile {
+    pub hash: String,
+    pub path: PathBuf,
+    /// The size of the archive, with its signature if that is cached too
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+impl DownloadCache {
+    /// The archive with content `hash` in the shared cache, if it has one
+    pub(crate) fn shared_file(&self, hash: &str) -> Option<PathBuf> {
+        self.shared_dir
+            .as_ref()
+            .map(|dir| dir.join(hash))
+            .filter(|path| utils::is_file(path))
+    }
+
+    /// Evicts the archives of the cache at `dir` which were used least
+    /// recently, until it is no larger than `max_size`. Returns the
+    /// evicted archives.
+    pub(crate) fn prune(
+        &self,
+        dir: &Path,
+        notify_handler: &dyn Fn(Notification<'_>),
+    ) -> Result<Vec<CachedFile>> {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return Ok(Vec::new()),
+        };
+        let files = list(dir)?;
+        let mut size: u64 = files.iter().map(|file| file.size).sum();
+        let mut evicted = Vec::new();
+        for file in files {
+            if size <= max_size {
+                break;
+            }
+            notify_handler(Notification::EvictingCachedFile(&file.hash));
+            remove(&file)?;
+            size -= file.size;
+            evicted.push(file);
+        }
+        Ok(evicted)
+    }
+
+    pub(crate) fn from_toml(mut table: toml::value::Table, path: &str) -> Result<Self> {
+        Ok(Self {
+            // A limit too large to count in bytes is as good as none
+            max_size: get_opt_u64(&mut table, "max_size_mb", path)?.map(|mb| mb.saturating_mul(MB)),
+            shared_dir: get_opt_string(&mut table, "shared_dir", path)?.map(PathBuf::from),
+        })
+    }
+
+    pub(crate) fn into_toml(self) -> toml::value::Table {
+        let mut result = toml::value::Table::new();
+        if let Some(max_size) = self.max_size {
+            result.insert(
+                "max_size_mb".to_owned(),
+                toml::Value::Integer((max_size / MB) as i64),
+            );
+        }
+        if let Some(shared_dir) = self.shared_dir {
+            result.insert(
+                "shared_dir".to_owned(),
+                toml::Value::String(shared_dir.display().to_string()),
+            );
+        }
+        result
+    }
+}
+
+/// The archives in the cache at `dir`, least recently used first
+pub(crate) fn list(dir: &Path) -> Result<Vec<CachedFile>> {
+    let mut files = Vec::new();
+    if !utils::is_directory(dir) {
+        return Ok(files);
+    }
+    for entry in utils::read_dir("download cache", dir)? {
+        let entry = entry.with_context(|| RustupError::ReadingDirectory {
+            name: "download cache",
+            path: dir.to_owned(),
+        })?;
+        // Partial downloads and signatures have a suffix
+        let hash = entry.file_name().to_string_lossy().into_owned();
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            continue;
+        }
+        let path = entry.path();
+        let metadata = entry
+            .metadata()
+            .and_then(|m| Ok((m.len(), m.modified()?)))
+            .with_context(|| RustupError::ReadingFile {
+                name: "cached download",
+                path: path.clone(),
+            })?;
+        let signature = signature_path(&path).metadata().map_or(0, |m| m.len());
+        files.push(CachedFile {
+            hash,
+            path,
+            size: metadata.0 + signature,
+            last_used: metadata.1,
+        });
+    }
+    files.sort_by_key(|file| file.last_used);
+    Ok(files)
+}
+
+/// Empties the cache at `dir` of its archives and their signatures. Partial
+/// downloads are left alone, as are archives being downloaded by another
+/// process, which holds the lock on them. Returns the archives removed.
+pub(crate) fn clear(dir: &Path) -> Result<Vec<CachedFile>> {
+    let mut removed = Vec::new();
+    for file in list(dir)? {
+        let _lock = match Lock::try_exclusive(&with_suffix(&file.path, ".lock"))? {
+            Some(lock) => lock,
+            None => continue,
+        };
+        remove(&file)?;
+        removed.push(file);
+    }
+    Ok(removed)
+}
+
+/// Records that the archive at `path` has just been used, so that it is
+/// evicted last
+pub(crate) fn mark_used(path: &Path) {
+    // A cache which cannot be written to is still worth reading from
+    let _ = filetime::set_file_mtime(path, FileTime::now());
+}
+
+fn remove(file: &CachedFile) -> Result<()> {
+    utils::ensure_file_removed("cached download", &file.path)?;
+    utils::ensure_file_removed("cached signature", &signature_path(&file.path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn cache_file(dir: &Path, n: u8, size: usize, used: i64) -> String {
+        let hash = format!("{:064x}", n);
+        let path = dir.join(&hash);
+        fs::write(&path, vec![0; size]).unwrap();
+        filetime::set_file_mtime(&path, FileTime::from_unix_time(used, 0)).unwrap();
+        hash
+    }
+
+    #[test]
+    fn prune_evicts_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = cache_file(dir.path(), 1, 100, 1_000);
+        let new = cache_file(dir.path(), 2, 100, 3_000);
+        let middle = cache_file(dir.path(), 3, 100, 2_000);
+        fs::write(dir.path().join(format!("{}.partial", old)), "partial").unwrap();
+
+        let cache = DownloadCache {
+            max_size: Some(150),
+            shared_dir: None,
+        };
+        let evicted = cache.prune(dir.path(), &|_: Notification<'_>| ()).unwrap();
+        let evicted: Vec<_> = evicted.into_iter().map(|file| file.hash).collect();
+        assert_eq!(evicted, [old, middle]);
+        let kept: Vec<_> = list(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|f| f.hash)
+            .collect();
+        assert_eq!(kept, [new]);
+    }
+
+    #[test]
+    fn max_size_mb_evicts_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = cache_file(dir.path(), 1, MB as usize, 1_000);
+        let new = cache_file(dir.path(), 2, MB as usize, 2_000);
+
+        let mut table = toml::value::Table::new();
+        table.insert("max_size_mb".to_owned(), toml::Value::Integer(1));
+        let cache = DownloadCache::from_toml(table, "cache.").unwrap();
+        let evicted = cache.prune(dir.path(), &|_: Notification<'_>| ()).unwrap();
+        let evicted: Vec<_> = evicted.into_iter().map(|file| file.hash).collect();
+        assert_eq!(evicted, [old]);
+        let kept: Vec<_> = list(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|f| f.hash)
+            .collect();
+        assert_eq!(kept, [new]);
+    }
+
+    #[test]
+    fn huge_max_size_mb_saturates() {
+        let mut table = toml::value::Table::new();
+        table.insert("max_size_mb".to_owned(), toml::Value::Integer(i64::MAX));
+        let cache = DownloadCache::from_toml(table, "cache.").unwrap();
+        assert_eq!(cache.max_size, Some(u64::MAX));
+    }
+
+    #[test]
+    fn clear_leaves_downloads_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let idle = cache_file(dir.path(), 1, 100, 1_000);
+        let busy = cache_file(dir.path(), 2, 100, 2_000);
+        let partial = dir.path().join(format!("{:064x}.partial", 3));
+        fs::write(&partial, "partial").unwrap();
+        let idle_path = dir.path().join(&idle);
+        fs::write(signature_path(&idle_path), "signature").unwrap();
+
+        let lock_path = with_suffix(&dir.path().join(&busy), ".lock");
+        let _lock = Lock::try_exclusive(&lock_path).unwrap().unwrap();
+        let removed = clear(dir.path()).unwrap();
+        let removed: Vec<_> = removed.into_iter().map(|file| file.hash).collect();
+        assert_eq!(removed, [idle]);
+        assert!(!idle_path.exists());
+        assert!(!signature_path(&idle_path).exists());
+        assert!(dir.path().join(&busy).exists());
+        assert!(partial.exists());
+        assert!(lock_path.exists());
+    }
+
+    #[test]
+    fn prune_without_max_size_keeps_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        cache_file(dir.path(), 1, 100, 1_000);
+        let evicted = DownloadCache::default()
+            .prune(dir.path(), &|_: Notification<'_>| ())
+            .unwrap();
+        assert!(evicted.is_empty());
+        assert_eq!(list(dir.path()).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,494 @@
+//! Private channels, built from rust-installer tarballs.
+//!
+//! `rustup dist build-channel` lays out a directory like the dist server,
+//! with a v2 manifest listing the tarballs found in a directory of build
+//! artifacts. The tarballs are named as on the dist server,
+//! `<package>-<release>[-<target>].tar.<ext>`, where the release is the name
+//! of the channel or a version number, and packages without a target are
+//! available on every target. Every target with a `rustc` tarball is a host,
+//! whose `rust` package lists the other packages for that host, and the
+//! standard library of every target, as components or extensions. Each file
+//! gets a checksum and, given a secret key, a detached signature, so that
+//! the channel installs like any mirror once `RUSTUP_DIST_SERVER` points at
+//! it.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{NaiveDate, Utc};
+use sequoia_openpgp::packet::key::{SecretParts, UnspecifiedRole};
+use sequoia_openpgp::packet::Key;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::policy::StandardPolicy;
+use sequoia_openpgp::serialize::stream::{Armorer, Message, Signer};
+use sequoia_openpgp::Cert;
+
+use super::component::{INSTALLER_VERSION, VERSION_FILE};
+use super::dist::{Profile, TargetTriple, DEFAULT_DIST_SERVER};
+use super::download::{file_hash, with_suffix};
+use super::manifest::{
+    Component, CompressionKind, HashedBinary, Manifest, Package, PackageTargets, TargetedPackage,
+};
+use super::notifications::Notification;
+use crate::errors::RustupError;
+use crate::utils::utils;
+
+const DIST_DIR: &str = "dist";
+const COMPONENTS_FILE: &str = "components";
+/// The version of the package, e.g. `1.53.0 (53cb7b09b 2021-06-17)`
+const PACKAGE_VERSION_FILE: &str = "version";
+
+const ARCHIVE_EXTENSIONS: [(&str, CompressionKind); 3] = [
+    (".tar.gz", CompressionKind::GZip),
+    (".tar.xz", CompressionKind::XZ),
+    (".tar.zst", CompressionKind::ZStd),
+];
+
+/// The packages which make a host toolchain, the others being extensions
+const HOST_PACKAGES: &[&str] = &["rustc", "cargo", "rust-std", "rust-mingw", "rust-docs"];
+/// The packages of the minimal profile, which the default profile extends
+/// with `DEFAULT_PACKAGES`
+const MINIMAL_PACKAGES: &[&str] = &["rustc", "cargo", "rust-std", "rust-mingw"];
+const DEFAULT_PACKAGES: &[&str] = &["rust-docs", "rustfmt", "clippy"];
+
+/// A tarball of the channel
+struct Archive {
+    path: PathBuf,
+    kind: CompressionKind,
+    package: String,
+    target: Option<TargetTriple>,
+    version: String,
+}
+
+/// Build the channel `name`, released on `date` or else today, from the
+/// tarballs in `from` into the dist server layout at `dest`, signing it with
+/// the secret key in `sign_key`. Returns the path of the manifest.
+pub(crate) fn build(
+    name: &str,
+    date: Option<&str>,
+    from: &Path,
+    dest: &Path,
+    sign_key: Option<&Path>,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<PathBuf> {
+    // The name goes into the names of the manifest and of the tarballs
+    let is_file_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._".contains(c));
+    if !is_file_name {
+        bail!(
+            "invalid channel name '{}'; expected letters, digits, '-', '.' and '_'",
+            name
+        );
+    }
+    let date = match date {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|_| anyhow!("invalid date '{}'; expected YYYY-MM-DD", date))?,
+        None => Utc::today().naive_utc(),
+    }
+    .format("%Y-%m-%d")
+    .to_string();
+    let key = sign_key.map(signing_key).transpose()?;
+
+    let mut archives = Vec::new();
+    for entry in utils::read_dir("artifacts", from)? {
+        let entry = entry.with_context(|| RustupError::ReadingDirectory {
+            name: "artifacts",
+            path: from.to_owned(),
+        })?;
+        if let Some(archive) = Archive::open(name, &entry.path())? {
+            archives.push(archive);
+        }
+    }
+    if archives.is_empty() {
+        bail!("no rust-installer tarballs found in '{}'", from.display());
+    }
+    archives.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let dist_dir = dest.join(DIST_DIR);
+    let archive_dir = dist_dir.join(&date);
+    utils::ensure_dir_exists("channel", &archive_dir, &notify_handler)?;
+    let in_place = utils::canonicalize_path(from, notify_handler)
+        == utils::canonicalize_path(&archive_dir, notify_handler);
+
+    let mut tarballs = HashMap::<String, (String, HashMap<_, TargetedPackage>)>::new();
+    for archive in archives {
+        let file_name = archive.path.file_name().unwrap().to_string_lossy();
+        (notify_handler)(Notification::AddingToChannel(&file_name));
+        let path = archive_dir.join(&*file_name);
+        if !in_place {
+            utils::copy_file(&archive.path, &path)?;
+        }
+        let hash = file_hash(&path, notify_handler)?;
+        utils::write_file(
+            "checksum",
+            &with_suffix(&path, ".sha256"),
+            &format!("{}  {}\n", hash, file_name),
+        )?;
+        if let Some(key) = &key {
+            sign(key, &path)?;
+        }
+
+        // The URLs are those of the official dist server, which rustup
+        // replaces with `RUSTUP_DIST_SERVER` as it does for mirrors
+        let url = format!(
+            "{}/{}/{}/{}",
+            DEFAULT_DIST_SERVER, DIST_DIR, date, file_name
+        );
+        let (_, package) = tarballs
+            .entry(archive.package)
+            .or_insert_with(|| (archive.version, HashMap::new()));
+        package
+            .entry(archive.target)
+            .or_insert_with(|| TargetedPackage {
+                bins: Vec::new(),
+                components: Vec::new(),
+            })
+            .bins
+            .push((archive.kind, HashedBinary { url, hash }));
+    }
+
+    let mut packages = HashMap::new();
+    for (package, (version, mut targets)) in tarballs {
+        let targets = match targets.remove(&None) {
+            Some(wildcard) if targets.is_empty() => PackageTargets::Wildcard(wildcard),
+            Some(_) => bail!(
+                "'{}' has tarballs both for every target and for some targets",
+                package
+            ),
+            None => PackageTargets::Targeted(
+                targets
+                    .into_iter()
+                    .map(|(target, package)| (target.unwrap(), package))
+                    .collect(),
+            ),
+        };
+        packages.insert(package, Package { version, targets });
+    }
+
+    let rust = rust_package(&packages)?;
+    let renames = renames(&packages);
+    let profiles = profiles(&packages);
+    packages.insert("rust".to_owned(), rust);
+
+    // Check the manifest as rustup reads it
+    let data = Manifest::new(date, packages, renames, profiles).stringify();
+    Manifest::parse(&data).context("the built manifest is invalid")?;
+
+    let manifest_name = format!("channel-rust-{}.toml", name);
+    for dir in [archive_dir, dist_dir] {
+        let path = dir.join(&manifest_name);
+        utils::write_file("manifest", &path, &data)?;
+        let hash = file_hash(&path, notify_handler)?;
+        utils::write_file(
+            "checksum",
+            &with_suffix(&path, ".sha256"),
+            &format!("{}  {}\n", hash, manifest_name),
+        )?;
+        if let Some(key) = &key {
+            sign(key, &path)?;
+        }
+    }
+    Ok(dest.join(DIST_DIR).join(manifest_name))
+}
+
+impl Archive {
+    /// The tarball at `path` of the channel `channel`, or `None` if `path`
+    /// is not a tarball
+    fn open(channel: &str, path: &Path) -> Result<Option<Self>> {
+        let file_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(file_name) => file_name,
+            None => return Ok(None),
+        };
+        let (stem, kind) = match ARCHIVE_EXTENSIONS
+            .iter()
+            .find_map(|(ext, kind)| file_name.strip_suffix(ext).map(|stem| (stem, *kind)))
+        {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if !utils::is_file(path) {
+            return Ok(None);
+        }
+
+        let (package, release, target) = split_name(channel, stem).ok_or_else(|| {
+            anyhow!(
+                "cannot tell the package of '{}'; expected a name like '<package>-{}-<target>.tar.gz'",
+                file_name,
+                channel
+            )
+        })?;
+        // Combined installers are not needed, as rustup installs components
+        if package == "rust" {
+            return Ok(None);
+        }
+        let version = read_version(path, kind)?.unwrap_or_else(|| release.to_owned());
+        Ok(Some(Self {
+            path: path.to_owned(),
+            kind,
+            package: package.to_owned(),
+            target: target.map(TargetTriple::new),
+            version,
+        }))
+    }
+}
+
+/// Split the name of a tarball into its package, release and target. The
+/// release is the first part after the package which is the name of the
+/// channel, which may itself contain dashes, or starts with a digit, as
+/// package names are made of words.
+fn split_name<'a>(channel: &str, stem: &'a str) -> Option<(&'a str, &'a str, Option<&'a str>)> {
+    let mut start = 0;
+    for part in stem.split('-') {
+        if start > 0 {
+            let is_channel = stem[start..]
+                .strip_prefix(channel)
+                .map_or(false, |after| after.is_empty() || after.starts_with('-'));
+            let release_len = if is_channel {
+                Some(channel.len())
+            } else if part.starts_with(|c: char| c.is_ascii_digit()) {
+                Some(part.len())
+            } else {
+                None
+            };
+            if let Some(len) = release_len {
+                let end = start + len;
+                let target = stem.get(end + 1..).filter(|t| !t.is_empty());
+                return Some((&stem[..start - 1], &stem[start..end], target));
+            }
+        }
+        start += part.len() + 1;
+    }
+    None
+}
+
+/// Check that the tarball at `path` is a rust-installer package, and return
+/// the version it records, if any
+fn read_version(path: &Path, kind: CompressionKind) -> Result<Option<String>> {
+    let file = utils::open_file("package", path)?;
+    let stream: Box<dyn Read> = match kind {
+        CompressionKind::GZip => Box::new(flate2::read::GzDecoder::new(file)),
+        CompressionKind::XZ => Box::new(xz2::read::XzDecoder::new(file)),
+        CompressionKind::ZStd => Box::new(zstd::stream::read::Decoder::new(file)?),
+    };
+    let reading = || RustupError::ReadingFile {
+        name: "package",
+        path: path.to_owned(),
+    };
+
+    let mut installer_version = None;
+    let mut components = None;
+    let mut version = None;
+    let mut archive = tar::Archive::new(stream);
+    for entry in archive.entries().with_context(reading)? {
+        let mut entry = entry.with_context(reading)?;
+        // The metadata is at the top of the directory the package unpacks to
+        let name = match top_level_name(&entry.path().with_context(reading)?) {
+            Some(name) => name,
+            None => continue,
+        };
+        let slot = match &*name {
+            VERSION_FILE => &mut installer_version,
+            COMPONENTS_FILE => &mut components,
+            PACKAGE_VERSION_FILE => &mut version,
+            _ => continue,
+        };
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).with_context(reading)?;
+        *slot = Some(contents.trim().to_owned());
+        if installer_version.is_some() && components.is_some() && version.is_some() {
+            break;
+        }
+    }
+
+    match installer_version.as_deref() {
+        Some(INSTALLER_VERSION) => {}
+        Some(v) => bail!(
+            "'{}' has unsupported installer version {}",
+            path.display(),
+            v
+        ),
+        None => bail!("'{}' is not a rust-installer package", path.display()),
+    }
+    if components.map_or(true, |c| c.is_empty()) {
+        bail!("'{}' has no components", path.display());
+    }
+    Ok(version)
+}
+
+/// The name of a file directly in the top-level directory of a tarball
+fn top_level_name(path: &Path) -> Option<String> {
+    let mut parts = path.components();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(_), Some(name), None) => Some(name.as_os_str().to_string_lossy().into_owned()),
+        _ => None,
+    }
+}
+
+/// The `rust` package, which lists the components of each host
+fn rust_package(packages: &HashMap<String, Package>) -> Result<Package> {
+    let rustc = packages
+        .get("rustc")
+        .ok_or_else(|| anyhow!("no 'rustc' tarball found; a channel needs at least one host"))?;
+    let hosts = match &rustc.targets {
+        PackageTargets::Targeted(hosts) => hosts.keys(),
+        PackageTargets::Wildcard(_) => bail!("'rustc' tarballs must be built for a target"),
+    };
+
+    let mut names: Vec<_> = packages.keys().collect();
+    names.sort();
+    let mut targets = HashMap::new();
+    for host in hosts {
+        let mut components = Vec::new();
+        for name in &names {
+            match &packages[*name].targets {
+                PackageTargets::Wildcard(_) => {
+                    components.push(Component::new(name.to_string(), None, true))
+                }
+                PackageTargets::Targeted(package_targets) => {
+                    for target in package_targets.keys() {
+                        // Only the standard library is useful on other hosts
+                        if target != host && *name != "rust-std" {
+                            continue;
+                        }
+                        let is_extension =
+                            target != host || !HOST_PACKAGES.contains(&name.as_str());
+                        components.push(Component::new(
+                            name.to_string(),
+                            Some(target.clone()),
+                            is_extension,
+                        ));
+                    }
+                }
+            }
+        }
+        components.sort();
+        targets.insert(
+            host.clone(),
+            TargetedPackage {
+                bins: Vec::new(),
+                components,
+            },
+        );
+    }
+
+    Ok(Package {
+        version: rustc.version.clone(),
+        targets: PackageTargets::Targeted(targets),
+    })
+}
+
+/// `<package>-preview` packages are also known by their name without the
+/// suffix, unless there is a package of that name
+fn renames(packages: &HashMap<String, Package>) -> HashMap<String, String> {
+    packages
+        .keys()
+        .filter_map(|name| {
+            let base = name.strip_suffix("-preview")?;
+            (!packages.contains_key(base)).then(|| (base.to_owned(), name.clone()))
+        })
+        .collect()
+}
+
+fn profiles(packages: &HashMap<String, Package>) -> HashMap<Profile, Vec<String>> {
+    let present = |name: &&str| {
+        [name.to_string(), format!("{}-preview", name)]
+            .into_iter()
+            .find(|name| packages.contains_key(name))
+    };
+    let minimal: Vec<_> = MINIMAL_PACKAGES.iter().filter_map(present).collect();
+    let default = minimal
+        .iter()
+        .cloned()
+        .chain(DEFAULT_PACKAGES.iter().filter_map(present))
+        .collect();
+    let mut complete: Vec<_> = packages.keys().cloned().collect();
+    complete.sort();
+
+    let mut profiles = HashMap::new();
+    profiles.insert(Profile::Minimal, minimal);
+    profiles.insert(Profile::Default, default);
+    profiles.insert(Profile::Complete, complete);
+    profiles
+}
+
+type SigningKey = Key<SecretParts, UnspecifiedRole>;
+
+/// The secret key in `path` which signs the channel
+fn signing_key(path: &Path) -> Result<SigningKey> {
+    let cert = Cert::from_reader(utils::open_file("signing key", path)?).map_err(|error| {
+        RustupError::InvalidPgpKey {
+            path: path.to_owned(),
+            source: error,
+        }
+    })?;
+    let policy = StandardPolicy::new();
+    let key = cert
+        .with_policy(&policy, None)?
+        .keys()
+        .secret()
+        .supported()
+        .alive()
+        .revoked(false)
+        .for_signing()
+        .next()
+        .ok_or_else(|| anyhow!("'{}' has no secret key for signing", path.display()))?;
+    let key = key.key().clone();
+    // Fail before building anything if the key cannot sign
+    key.clone().into_keypair().with_context(|| {
+        format!(
+            "cannot sign with the key in '{}'; keys protected by a passphrase are not supported",
+            path.display()
+        )
+    })?;
+    Ok(key)
+}
+
+/// Write the detached, armored signature of the file at `path` next to it
+fn sign(key: &SigningKey, path: &Path) -> Result<()> {
+    let mut signature = Vec::new();
+    let message = Armorer::new(Message::new(&mut signature)).build()?;
+    let mut message = Signer::new(message, key.clone().into_keypair()?)
+        .detached()
+        .build()?;
+    io::copy(&mut utils::open_file("signed file", path)?, &mut message)
+        .with_context(|| format!("failed to sign '{}'", path.display()))?;
+    message.finalize()?;
+    utils::write_file(
+        "signature",
+        &with_suffix(path, ".asc"),
+        &String::from_utf8(signature)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tarball_names_are_split() {
+        assert_eq!(
+            split_name("stable", "rust-std-1.53.0-x86_64-unknown-linux-gnu"),
+            Some(("rust-std", "1.53.0", Some("x86_64-unknown-linux-gnu")))
+        );
+        assert_eq!(
+            split_name("nightly", "rls-preview-nightly-aarch64-apple-darwin"),
+            Some(("rls-preview", "nightly", Some("aarch64-apple-darwin")))
+        );
+        assert_eq!(
+            split_name("nightly", "rust-src-nightly"),
+            Some(("rust-src", "nightly", None))
+        );
+        assert_eq!(
+            split_name(
+                "acme-stable",
+                "rust-std-acme-stable-x86_64-unknown-linux-gnu"
+            ),
+            Some(("rust-std", "acme-stable", Some("x86_64-unknown-linux-gnu")))
+        );
+        assert_eq!(split_name("beta", "rustc-x86_64-unknown-linux-gnu"), None);
+    }
+}
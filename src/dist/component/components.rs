@@ -2,9 +2,13 @@
 //! `Components` and `DirectoryPackage` are the two sides of the
 //! installation / uninstallation process.
 
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
 use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
 
 use crate::dist::component::package::{INSTALLER_VERSION, VERSION_FILE};
 use crate::dist::component::transaction::Transaction;
@@ -41,6 +45,9 @@ impl Components {
     fn rel_component_manifest(&self, name: &str) -> PathBuf {
         self.prefix.rel_manifest_file(&format!("manifest-{}", name))
     }
+    fn rel_component_hashes(&self, name: &str) -> PathBuf {
+        self.prefix.rel_manifest_file(&format!("hashes-{}", name))
+    }
     fn read_version(&self) -> Result<Option<String>> {
         let p = self.prefix.manifest_file(VERSION_FILE);
         if utils::is_file(&p) {
@@ -51,7 +58,7 @@ impl Components {
     }
     fn write_version(&self, tx: &mut Transaction<'_>) -> Result<()> {
         tx.modify_file(self.prefix.rel_manifest_file(VERSION_FILE))?;
-        utils::write_file(
+        utils::write_file_atomically(
             VERSION_FILE,
             &self.prefix.manifest_file(VERSION_FILE),
             INSTALLER_VERSION,
@@ -123,17 +130,39 @@ impl<'a> ComponentBuilder<'a> {
         let path = self.components.rel_component_manifest(&self.name);
         let abs_path = self.components.prefix.abs_path(&path);
         let mut file = self.tx.add_file(&self.name, path)?;
-        for part in self.parts {
+        for part in &self.parts {
             // FIXME: This writes relative paths to the component manifest,
             // but rust-installer writes absolute paths.
             utils::write_line("component", &mut file, &abs_path, &part.encode())?;
         }
 
+        // Record what every installed file should contain, for `rustup
+        // toolchain verify`. rust-installer does not know of this file.
+        let path = self.components.rel_component_hashes(&self.name);
+        let abs_path = self.components.prefix.abs_path(&path);
+        let mut file = self.tx.add_file(&self.name, path)?;
+        for part in &self.parts {
+            let mut files = Vec::new();
+            installed_files(&self.components.prefix, &part.1, &mut files)?;
+            for path in files {
+                let hash = hash_file(&self.components.prefix.abs_path(&path))?;
+                let line = format!("{}  {}", hash, path.to_string_lossy());
+                utils::write_line("component hashes", &mut file, &abs_path, &line)?;
+            }
+        }
+
         // Add component to components file
         let path = self.components.rel_components_file();
         let abs_path = self.components.prefix.abs_path(&path);
         self.tx.modify_file(path)?;
-        utils::append_file("components", &abs_path, &self.name)?;
+        let mut components = if utils::is_file(&abs_path) {
+            utils::read_file("components", &abs_path)?
+        } else {
+            String::new()
+        };
+        components.push_str(&self.name);
+        components.push('\n');
+        utils::write_file_atomically("components", &abs_path, &components)?;
 
         // Drop in the version file for future use
         self.components.write_version(&mut self.tx)?;
@@ -142,6 +171,22 @@ impl<'a> ComponentBuilder<'a> {
     }
 }
 
+/// How the files of an installed component differ from the files it
+/// installed
+#[derive(Debug, Default)]
+pub struct Damage {
+    pub missing: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    /// Files in a directory of the component which it did not install
+    pub unexpected: Vec<PathBuf>,
+}
+
+impl Damage {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty() && self.unexpected.is_empty()
+    }
+}
+
 #[derive(Debug)]
 pub struct ComponentPart(pub String, pub PathBuf);
 
@@ -186,14 +231,74 @@ impl Component {
         }
         Ok(result)
     }
-    pub fn uninstall<'a>(&self, mut tx: Transaction<'a>) -> Result<Transaction<'a>> {
+    /// Compares the files of the component with the hashes recorded when
+    /// it was installed. Components installed by older versions of rustup
+    /// have no hashes, so cannot be verified.
+    pub fn verify(&self) -> Result<Option<Damage>> {
+        let recorded = match self.recorded_hashes()? {
+            Some(recorded) => recorded,
+            None => return Ok(None),
+        };
+
+        let prefix = &self.components.prefix;
+        let mut damage = Damage::default();
+        let mut files = Vec::new();
+        for part in self.parts()? {
+            installed_files(prefix, &part.1, &mut files)?;
+        }
+        files.sort();
+        damage.unexpected = files
+            .into_iter()
+            .filter(|path| !recorded.contains_key(path))
+            .collect();
+        for (path, hash) in recorded {
+            let abs_path = prefix.abs_path(&path);
+            if !utils::is_file(&abs_path) {
+                damage.missing.push(path);
+            } else if hash_file(&abs_path)? != hash {
+                damage.modified.push(path);
+            }
+        }
+        Ok(Some(damage))
+    }
+    /// The hashes of the files of the component, by their paths relative
+    /// to the prefix, as recorded when it was installed
+    pub(crate) fn recorded_hashes(&self) -> Result<Option<BTreeMap<PathBuf, String>>> {
+        let hashes_file = self
+            .components
+            .prefix
+            .abs_path(self.components.rel_component_hashes(&self.name));
+        if !utils::is_file(&hashes_file) {
+            return Ok(None);
+        }
+        let mut recorded = BTreeMap::new();
+        for line in utils::read_file("component hashes", &hashes_file)?.lines() {
+            let (hash, path) = line
+                .split_once("  ")
+                .ok_or_else(|| RustupError::CorruptComponent(self.name.clone()))?;
+            recorded.insert(PathBuf::from(path), hash.to_owned());
+        }
+        Ok(Some(recorded))
+    }
+    pub fn uninstall<'a>(&self, tx: Transaction<'a>) -> Result<Transaction<'a>> {
+        self.uninstall_(tx, false)
+    }
+    /// Uninstalls a component some of whose files may be missing, so that
+    /// it can be installed again
+    pub fn uninstall_damaged<'a>(&self, tx: Transaction<'a>) -> Result<Transaction<'a>> {
+        self.uninstall_(tx, true)
+    }
+    fn uninstall_<'a>(&self, mut tx: Transaction<'a>, damaged: bool) -> Result<Transaction<'a>> {
         // Update components file
         let path = self.components.rel_components_file();
         let abs_path = self.components.prefix.abs_path(&path);
-        let temp = tx.temp().new_file()?;
-        utils::filter_file("components", &abs_path, &temp, |l| l != self.name)?;
+        let components: String = utils::read_file("components", &abs_path)?
+            .lines()
+            .filter(|l| *l != self.name)
+            .map(|l| format!("{}\n", l))
+            .collect();
         tx.modify_file(path)?;
-        utils::rename_file("components", &temp, &abs_path, tx.notify_handler())?;
+        utils::write_file_atomically("components", &abs_path, &components)?;
 
         // TODO: If this is the last component remove the components file
         // and the version file.
@@ -299,6 +404,9 @@ impl Component {
             prefix: self.components.prefix.abs_path(""),
         };
         for part in self.parts()?.into_iter().rev() {
+            if damaged && !utils::path_exists(self.components.prefix.abs_path(&part.1)) {
+                continue;
+            }
             match &*part.0 {
                 "file" => tx.remove_file(&self.name, part.1.clone())?,
                 "dir" => tx.remove_dir(&self.name, part.1.clone())?,
@@ -312,7 +420,43 @@ impl Component {
 
         // Remove component manifest
         tx.remove_file(&self.name, self.rel_manifest_file())?;
+        let hashes_file = self.components.rel_component_hashes(&self.name);
+        if utils::is_file(self.components.prefix.abs_path(&hashes_file)) {
+            tx.remove_file(&self.name, hashes_file)?;
+        }
 
         Ok(tx)
     }
 }
+
+/// Adds `path`, or the files under it if it is a directory, to `files`.
+/// Symbolic links are not followed.
+fn installed_files(prefix: &InstallPrefix, path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let abs_path = prefix.abs_path(path);
+    match fs::symlink_metadata(&abs_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            for entry in utils::read_dir("component", &abs_path)? {
+                let entry = entry.with_context(|| RustupError::ReadingDirectory {
+                    name: "component",
+                    path: abs_path.clone(),
+                })?;
+                installed_files(prefix, &path.join(entry.file_name()), files)?;
+            }
+        }
+        Ok(_) => files.push(path.to_owned()),
+        // Missing files are found by comparing with the recorded hashes
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    File::open(path)
+        .and_then(|mut file| io::copy(&mut file, &mut hasher))
+        .with_context(|| RustupError::ReadingFile {
+            name: "component",
+            path: path.to_owned(),
+        })?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -6,10 +6,15 @@
 //! operations. If the Transaction is dropped without committing then
 //! it will *attempt* to roll back the transaction.
 //!
-//! FIXME: This uses ensure_dir_exists in some places but rollback
-//! does not remove any dirs created by it.
+//! Every change is recorded in a journal in the install prefix before
+//! it is made, so that a transaction which never got to commit or roll
+//! back, because rustup was killed or the machine lost power, is rolled
+//! back by `recover_transaction` the next time rustup runs. A transaction
+//! holds a lock next to its journal for as long as it runs, which is how
+//! an interrupted one is told apart from one which is still running.
 
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
@@ -18,24 +23,29 @@ use crate::dist::notifications::*;
 use crate::dist::prefix::InstallPrefix;
 use crate::dist::temp;
 use crate::errors::*;
+use crate::utils::lock::Lock;
 use crate::utils::utils;
 
+const JOURNAL_FILE: &str = "rustup-journal";
+const JOURNAL_LOCK: &str = "rustup-journal.lock";
+const BACKUP_DIR: &str = "rustup-journal-backups";
+
 /// A Transaction tracks changes to the file system, allowing them to
 /// be rolled back in case of an error. Instead of deleting or
-/// overwriting file, the old copies are moved to a temporary
-/// folder. If the transaction is rolled back, they will be moved back
-/// into place. If the transaction is committed, these files are
-/// automatically cleaned up using the temp system.
+/// overwriting file, the old copies are moved to a directory next to
+/// the journal. If the transaction is rolled back, they will be moved
+/// back into place. Either way they are deleted along with the journal.
 ///
 /// All operations that create files will automatically create any
 /// intermediate directories in the path to the file if they do not
-/// already exist.
+/// already exist. Rolling back removes those directories again.
 ///
 /// All operations that create files will fail if the destination
 /// already exists.
 pub struct Transaction<'a> {
     prefix: InstallPrefix,
-    changes: Vec<ChangedItem<'a>>,
+    changes: Vec<Entry>,
+    journal: Journal,
     temp_cfg: &'a temp::Cfg,
     notify_handler: &'a dyn Fn(Notification<'_>),
     committed: bool,
@@ -48,6 +58,7 @@ impl<'a> Transaction<'a> {
         notify_handler: &'a dyn Fn(Notification<'_>),
     ) -> Self {
         Transaction {
+            journal: Journal::new(&prefix),
             prefix,
             changes: Vec::new(),
             temp_cfg,
@@ -59,10 +70,11 @@ impl<'a> Transaction<'a> {
     /// Commit must be called for all successful transactions. If not
     /// called the transaction will be rolled back on drop.
     pub fn commit(mut self) {
+        self.journal.commit();
         self.committed = true;
     }
 
-    fn change(&mut self, item: ChangedItem<'a>) {
+    fn change(&mut self, item: Entry) {
         self.changes.push(item);
     }
 
@@ -71,38 +83,50 @@ impl<'a> Transaction<'a> {
     /// contents.
     pub fn add_file(&mut self, component: &str, relpath: PathBuf) -> Result<File> {
         assert!(relpath.is_relative());
-        let (item, file) = ChangedItem::add_file(&self.prefix, component, relpath)?;
-        self.change(item);
+        let abs_path = self.dest_abs_path(component, &relpath)?;
+        self.journal.record(&Entry::AddedFile(relpath.clone()))?;
+        let file = File::create(&abs_path)
+            .with_context(|| format!("error creating file '{}'", abs_path.display()))?;
+        self.change(Entry::AddedFile(relpath));
         Ok(file)
     }
 
     /// Copy a file to a relative path of the install prefix.
     pub fn copy_file(&mut self, component: &str, relpath: PathBuf, src: &Path) -> Result<()> {
         assert!(relpath.is_relative());
-        let item = ChangedItem::copy_file(&self.prefix, component, relpath, src)?;
-        self.change(item);
+        let abs_path = self.dest_abs_path(component, &relpath)?;
+        self.journal.record(&Entry::AddedFile(relpath.clone()))?;
+        utils::copy_file(src, &abs_path)?;
+        self.change(Entry::AddedFile(relpath));
         Ok(())
     }
 
     /// Recursively copy a directory to a relative path of the install prefix.
     pub fn copy_dir(&mut self, component: &str, relpath: PathBuf, src: &Path) -> Result<()> {
         assert!(relpath.is_relative());
-        let item = ChangedItem::copy_dir(&self.prefix, component, relpath, src)?;
-        self.change(item);
+        let abs_path = self.dest_abs_path(component, &relpath)?;
+        self.journal.record(&Entry::AddedDir(relpath.clone()))?;
+        utils::copy_dir(src, &abs_path, &|_: Notification<'_>| ())?;
+        self.change(Entry::AddedDir(relpath));
         Ok(())
     }
 
     /// Remove a file from a relative path to the install prefix.
     pub fn remove_file(&mut self, component: &str, relpath: PathBuf) -> Result<()> {
         assert!(relpath.is_relative());
-        let item = ChangedItem::remove_file(
-            &self.prefix,
-            component,
-            relpath,
-            self.temp_cfg,
-            self.notify_handler(),
-        )?;
-        self.change(item);
+        let abs_path = self.prefix.abs_path(&relpath);
+        if !utils::path_exists(&abs_path) {
+            return Err(RustupError::ComponentMissingFile {
+                name: component.to_owned(),
+                path: relpath,
+            }
+            .into());
+        }
+        let backup = self.journal.backup_path()?;
+        self.journal
+            .record(&Entry::RemovedFile(relpath.clone(), backup.clone()))?;
+        utils::rename_file("component", &abs_path, &backup, self.notify_handler)?;
+        self.change(Entry::RemovedFile(relpath, backup));
         Ok(())
     }
 
@@ -110,23 +134,26 @@ impl<'a> Transaction<'a> {
     /// install prefix.
     pub fn remove_dir(&mut self, component: &str, relpath: PathBuf) -> Result<()> {
         assert!(relpath.is_relative());
-        let item = ChangedItem::remove_dir(
-            &self.prefix,
-            component,
-            relpath,
-            self.temp_cfg,
-            self.notify_handler(),
-        )?;
-        self.change(item);
+        let abs_path = self.prefix.abs_path(&relpath);
+        if !utils::path_exists(&abs_path) {
+            return Err(RustupError::ComponentMissingDir {
+                name: component.to_owned(),
+                path: relpath,
+            }
+            .into());
+        }
+        let backup = self.journal.backup_path()?;
+        self.journal
+            .record(&Entry::RemovedDir(relpath.clone(), backup.clone()))?;
+        utils::rename_dir("component", &abs_path, &backup, self.notify_handler)?;
+        self.change(Entry::RemovedDir(relpath, backup));
         Ok(())
     }
 
     /// Create a new file with string contents at a relative path to
     /// the install prefix.
     pub fn write_file(&mut self, component: &str, relpath: PathBuf, content: String) -> Result<()> {
-        assert!(relpath.is_relative());
-        let (item, mut file) = ChangedItem::add_file(&self.prefix, component, relpath.clone())?;
-        self.change(item);
+        let mut file = self.add_file(component, relpath.clone())?;
         utils::write_str(
             "component",
             &mut file,
@@ -142,8 +169,21 @@ impl<'a> Transaction<'a> {
     /// This is used for arbitrarily manipulating a file.
     pub fn modify_file(&mut self, relpath: PathBuf) -> Result<()> {
         assert!(relpath.is_relative());
-        let item = ChangedItem::modify_file(&self.prefix, relpath, self.temp_cfg)?;
-        self.change(item);
+        let abs_path = self.prefix.abs_path(&relpath);
+
+        if utils::is_file(&abs_path) {
+            let backup = self.journal.backup_path()?;
+            utils::copy_file(&abs_path, &backup)?;
+            // Only a complete backup may be restored
+            self.journal
+                .record(&Entry::ModifiedFile(relpath.clone(), Some(backup.clone())))?;
+            self.change(Entry::ModifiedFile(relpath, Some(backup)));
+        } else {
+            self.create_parent_dirs(&relpath)?;
+            self.journal
+                .record(&Entry::ModifiedFile(relpath.clone(), None))?;
+            self.change(Entry::ModifiedFile(relpath, None));
+        }
         Ok(())
     }
 
@@ -155,18 +195,20 @@ impl<'a> Transaction<'a> {
         src: &Path,
     ) -> Result<()> {
         assert!(relpath.is_relative());
-        let item =
-            ChangedItem::move_file(&self.prefix, component, relpath, src, self.notify_handler())?;
-        self.change(item);
+        let abs_path = self.dest_abs_path(component, &relpath)?;
+        self.journal.record(&Entry::AddedFile(relpath.clone()))?;
+        utils::rename_file("component", src, &abs_path, self.notify_handler)?;
+        self.change(Entry::AddedFile(relpath));
         Ok(())
     }
 
     /// Recursively move a directory to a relative path of the install prefix.
     pub(crate) fn move_dir(&mut self, component: &str, relpath: PathBuf, src: &Path) -> Result<()> {
         assert!(relpath.is_relative());
-        let item =
-            ChangedItem::move_dir(&self.prefix, component, relpath, src, self.notify_handler())?;
-        self.change(item);
+        let abs_path = self.dest_abs_path(component, &relpath)?;
+        self.journal.record(&Entry::AddedDir(relpath.clone()))?;
+        utils::rename_dir("component", src, &abs_path, self.notify_handler)?;
+        self.change(Entry::AddedDir(relpath));
         Ok(())
     }
 
@@ -176,6 +218,49 @@ impl<'a> Transaction<'a> {
     pub(crate) fn notify_handler(&self) -> &'a dyn Fn(Notification<'_>) {
         self.notify_handler
     }
+
+    /// The absolute path of `relpath`, which must not exist yet, after
+    /// creating the directories it is in
+    fn dest_abs_path(&mut self, component: &str, relpath: &Path) -> Result<PathBuf> {
+        let abs_path = self.prefix.abs_path(relpath);
+        if utils::path_exists(&abs_path) {
+            Err(anyhow!(RustupError::ComponentConflict {
+                name: component.to_owned(),
+                path: relpath.to_path_buf(),
+            }))
+        } else {
+            self.create_parent_dirs(relpath)?;
+            Ok(abs_path)
+        }
+    }
+
+    /// Creates the directories `relpath` is in, recording the outermost
+    /// one which did not exist so that rolling back removes them all
+    fn create_parent_dirs(&mut self, relpath: &Path) -> Result<()> {
+        let parent = match relpath.parent() {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+        // The journal makes the directories it is in itself
+        self.journal.open()?;
+        let outermost_missing = parent
+            .ancestors()
+            .take_while(|dir| {
+                !dir.as_os_str().is_empty() && !utils::path_exists(self.prefix.abs_path(dir))
+            })
+            .last();
+        if let Some(dir) = outermost_missing {
+            let dir = dir.to_owned();
+            self.journal.record(&Entry::AddedDir(dir.clone()))?;
+            utils::ensure_dir_exists(
+                "component",
+                &self.prefix.abs_path(parent),
+                &|_: Notification<'_>| (),
+            )?;
+            self.change(Entry::AddedDir(dir));
+        }
+        Ok(())
+    }
 }
 
 /// If a Transaction is dropped without being committed, the changes
@@ -194,6 +279,7 @@ impl<'a> Drop for Transaction<'a> {
                     }
                 }
             }
+            self.journal.discard();
         }
     }
 }
@@ -202,158 +288,248 @@ impl<'a> Drop for Transaction<'a> {
 /// Transaction. More complicated operations, such as installing a
 /// package, or updating a component, distill down into a series of
 /// these primitives.
+///
+/// Each is recorded in the journal, with the path relative to the install
+/// prefix and where its backup is kept, before the change is made, so
+/// rolling it back must cope with the change never having been made.
 #[derive(Debug)]
-enum ChangedItem<'a> {
+enum Entry {
     AddedFile(PathBuf),
     AddedDir(PathBuf),
-    RemovedFile(PathBuf, temp::File<'a>),
-    RemovedDir(PathBuf, temp::Dir<'a>),
-    ModifiedFile(PathBuf, Option<temp::File<'a>>),
+    RemovedFile(PathBuf, PathBuf),
+    RemovedDir(PathBuf, PathBuf),
+    ModifiedFile(PathBuf, Option<PathBuf>),
 }
 
-impl<'a> ChangedItem<'a> {
-    fn roll_back(
-        &self,
-        prefix: &InstallPrefix,
-        notify: &'a dyn Fn(Notification<'_>),
-    ) -> Result<()> {
-        use self::ChangedItem::*;
+impl Entry {
+    fn roll_back(&self, prefix: &InstallPrefix, notify: &dyn Fn(Notification<'_>)) -> Result<()> {
+        use self::Entry::*;
         match self {
-            AddedFile(path) => utils::remove_file("component", &prefix.abs_path(path))?,
-            AddedDir(path) => utils::remove_dir("component", &prefix.abs_path(path), notify)?,
-            RemovedFile(path, tmp) | ModifiedFile(path, Some(tmp)) => {
-                utils::rename_file("component", tmp, &prefix.abs_path(path), notify)?
-            }
-            RemovedDir(path, tmp) => {
-                utils::rename_dir("component", &tmp.join("bk"), &prefix.abs_path(path), notify)?
-            }
-            ModifiedFile(path, None) => {
+            AddedFile(path) | ModifiedFile(path, None) => {
                 let abs_path = prefix.abs_path(path);
                 if utils::is_file(&abs_path) {
                     utils::remove_file("component", &abs_path)?;
                 }
             }
+            AddedDir(path) => {
+                let abs_path = prefix.abs_path(path);
+                if utils::path_exists(&abs_path) {
+                    utils::remove_dir("component", &abs_path, notify)?;
+                }
+            }
+            RemovedFile(path, tmp) => {
+                let abs_path = prefix.abs_path(path);
+                if !utils::path_exists(&abs_path) && utils::is_file(tmp) {
+                    utils::rename_file("component", tmp, &abs_path, notify)?;
+                }
+            }
+            ModifiedFile(path, Some(tmp)) => {
+                if utils::is_file(tmp) {
+                    utils::rename_file("component", tmp, &prefix.abs_path(path), notify)?;
+                }
+            }
+            RemovedDir(path, tmp) => {
+                let abs_path = prefix.abs_path(path);
+                if !utils::path_exists(&abs_path) && utils::path_exists(tmp) {
+                    utils::rename_dir("component", tmp, &abs_path, notify)?;
+                }
+            }
         }
         Ok(())
     }
-    fn dest_abs_path(prefix: &InstallPrefix, component: &str, relpath: &Path) -> Result<PathBuf> {
-        let abs_path = prefix.abs_path(relpath);
-        if utils::path_exists(&abs_path) {
-            Err(anyhow!(RustupError::ComponentConflict {
-                name: component.to_owned(),
-                path: relpath.to_path_buf(),
-            }))
-        } else {
-            if let Some(p) = abs_path.parent() {
-                utils::ensure_dir_exists("component", p, &|_: Notification<'_>| ())?;
-            }
-            Ok(abs_path)
+
+    fn encode(&self) -> String {
+        use self::Entry::*;
+        let (kind, path, tmp) = match self {
+            AddedFile(path) => ("added-file", path, None),
+            AddedDir(path) => ("added-dir", path, None),
+            RemovedFile(path, tmp) => ("removed-file", path, Some(tmp)),
+            RemovedDir(path, tmp) => ("removed-dir", path, Some(tmp)),
+            ModifiedFile(path, tmp) => ("modified-file", path, tmp.as_ref()),
+        };
+        let mut line = format!("{}\t{}", kind, path.to_string_lossy());
+        if let Some(tmp) = tmp {
+            line.push('\t');
+            line.push_str(&tmp.to_string_lossy());
         }
+        line
     }
-    fn add_file(prefix: &InstallPrefix, component: &str, relpath: PathBuf) -> Result<(Self, File)> {
-        let abs_path = ChangedItem::dest_abs_path(prefix, component, &relpath)?;
-        let file = File::create(&abs_path)
-            .with_context(|| format!("error creating file '{}'", abs_path.display()))?;
-        Ok((ChangedItem::AddedFile(relpath), file))
+
+    fn decode(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let kind = fields.next()?;
+        let path = PathBuf::from(fields.next()?);
+        let tmp = fields.next().map(PathBuf::from);
+        Some(match (kind, tmp) {
+            ("added-file", None) => Entry::AddedFile(path),
+            ("added-dir", None) => Entry::AddedDir(path),
+            ("removed-file", Some(tmp)) => Entry::RemovedFile(path, tmp),
+            ("removed-dir", Some(tmp)) => Entry::RemovedDir(path, tmp),
+            ("modified-file", tmp) => Entry::ModifiedFile(path, tmp),
+            _ => return None,
+        })
     }
-    fn copy_file(
-        prefix: &InstallPrefix,
-        component: &str,
-        relpath: PathBuf,
-        src: &Path,
-    ) -> Result<Self> {
-        let abs_path = ChangedItem::dest_abs_path(prefix, component, &relpath)?;
-        utils::copy_file(src, &abs_path)?;
-        Ok(ChangedItem::AddedFile(relpath))
+}
+
+/// The write-ahead journal of a transaction, in the `lib/rustlib` directory
+/// of the install prefix, along with the lock held while the transaction
+/// runs and the backups of what it changes. It is only created once the
+/// transaction changes something.
+struct Journal {
+    path: PathBuf,
+    lock_path: PathBuf,
+    backup_dir: PathBuf,
+    file: Option<File>,
+    lock: Option<Lock>,
+    backups: usize,
+}
+
+impl Journal {
+    fn new(prefix: &InstallPrefix) -> Self {
+        Self {
+            path: prefix.manifest_file(JOURNAL_FILE),
+            lock_path: prefix.manifest_file(JOURNAL_LOCK),
+            backup_dir: prefix.manifest_file(BACKUP_DIR),
+            file: None,
+            lock: None,
+            backups: 0,
+        }
     }
-    fn copy_dir(
-        prefix: &InstallPrefix,
-        component: &str,
-        relpath: PathBuf,
-        src: &Path,
-    ) -> Result<Self> {
-        let abs_path = ChangedItem::dest_abs_path(prefix, component, &relpath)?;
-        utils::copy_dir(src, &abs_path, &|_: Notification<'_>| ())?;
-        Ok(ChangedItem::AddedDir(relpath))
+
+    /// Appends `entry` to the journal, and waits for it to reach the disk
+    fn record(&mut self, entry: &Entry) -> Result<()> {
+        self.write_line(&entry.encode())
     }
-    fn remove_file(
-        prefix: &InstallPrefix,
-        component: &str,
-        relpath: PathBuf,
-        temp_cfg: &'a temp::Cfg,
-        notify: &'a dyn Fn(Notification<'_>),
-    ) -> Result<Self> {
-        let abs_path = prefix.abs_path(&relpath);
-        let backup = temp_cfg.new_file()?;
-        if !utils::path_exists(&abs_path) {
-            Err(RustupError::ComponentMissingFile {
-                name: component.to_owned(),
-                path: relpath,
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let path = self.path.clone();
+        let file = self.open()?;
+        writeln!(file, "{}", line)
+            .and_then(|()| file.sync_data())
+            .with_context(|| RustupError::WritingFile {
+                name: "transaction journal",
+                path,
+            })
+    }
+
+    /// Creates the journal, if it has not been yet
+    fn open(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            let notify = |_: Notification<'_>| ();
+            // Taking the lock makes the directories the journal is in
+            self.lock = Some(Lock::exclusive(&self.lock_path, &notify)?);
+            // Left behind by a transaction whose backups could not be deleted
+            if utils::path_exists(&self.backup_dir) {
+                utils::remove_dir("transaction backups", &self.backup_dir, &notify)?;
             }
-            .into())
-        } else {
-            utils::rename_file("component", &abs_path, &backup, notify)?;
-            Ok(ChangedItem::RemovedFile(relpath, backup))
+            let file = File::create(&self.path).with_context(|| RustupError::WritingFile {
+                name: "transaction journal",
+                path: self.path.clone(),
+            })?;
+            self.file = Some(file);
         }
+        Ok(self.file.as_mut().unwrap())
     }
-    fn remove_dir(
-        prefix: &InstallPrefix,
-        component: &str,
-        relpath: PathBuf,
-        temp_cfg: &'a temp::Cfg,
-        notify: &'a dyn Fn(Notification<'_>),
-    ) -> Result<Self> {
-        let abs_path = prefix.abs_path(&relpath);
-        let backup = temp_cfg.new_directory()?;
-        if !utils::path_exists(&abs_path) {
-            Err(RustupError::ComponentMissingDir {
-                name: component.to_owned(),
-                path: relpath,
+
+    /// Where to keep the next backup
+    fn backup_path(&mut self) -> Result<PathBuf> {
+        self.open()?;
+        let notify = |_: Notification<'_>| ();
+        utils::ensure_dir_exists("transaction backups", &self.backup_dir, &notify)?;
+        self.backups += 1;
+        Ok(self.backup_dir.join(self.backups.to_string()))
+    }
+
+    /// Marks the transaction as finished and deletes the journal. Should
+    /// deleting fail, the mark tells `recover_transaction` not to roll it back.
+    fn commit(&mut self) {
+        if self.file.is_some() && self.write_line("commit").is_ok() {
+            self.discard();
+        }
+    }
+
+    fn discard(&mut self) {
+        self.file = None;
+        if let Some(lock) = self.lock.take() {
+            // The backups go first, as nothing else would delete them
+            let _ = remove_dir_all::remove_dir_all(&self.backup_dir);
+            let _ = fs::remove_file(&self.path);
+            drop(lock);
+            let _ = fs::remove_file(&self.lock_path);
+            // Along with the directories made for it, if nothing else is in them
+            for dir in self.path.ancestors().skip(1).take(2) {
+                let _ = fs::remove_dir(dir);
             }
-            .into())
-        } else {
-            utils::rename_dir("component", &abs_path, &backup.join("bk"), notify)?;
-            Ok(ChangedItem::RemovedDir(relpath, backup))
         }
     }
-    fn modify_file(
-        prefix: &InstallPrefix,
-        relpath: PathBuf,
-        temp_cfg: &'a temp::Cfg,
-    ) -> Result<Self> {
-        let abs_path = prefix.abs_path(&relpath);
+}
 
-        if utils::is_file(&abs_path) {
-            let backup = temp_cfg.new_file()?;
-            utils::copy_file(&abs_path, &backup)?;
-            Ok(ChangedItem::ModifiedFile(relpath, Some(backup)))
-        } else {
-            if let Some(p) = abs_path.parent() {
-                utils::ensure_dir_exists("component", p, &|_: Notification<'_>| {})?;
+/// Whether a transaction on `prefix` is running, or was interrupted
+pub fn has_transaction_journal(prefix: &InstallPrefix) -> bool {
+    utils::is_file(prefix.manifest_file(JOURNAL_FILE))
+}
+
+/// Rolls back the transaction on `prefix` which a run of rustup which
+/// has since died started but never finished, or finishes cleaning up
+/// after it if it did commit. Returns whether there was one.
+///
+/// Should rolling back leave no files in the prefix at all, the
+/// transaction was a fresh install, and the prefix is removed.
+pub fn recover_transaction(
+    prefix: &InstallPrefix,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<bool> {
+    let mut journal = Journal::new(prefix);
+    if !utils::is_file(&journal.path) {
+        return Ok(false);
+    }
+    journal.lock = match Lock::try_exclusive(&journal.lock_path)? {
+        Some(lock) => Some(lock),
+        // The transaction is still running
+        None => return Ok(false),
+    };
+    // Or it finished before the lock was taken
+    if !utils::is_file(&journal.path) {
+        journal.discard();
+        return Ok(false);
+    }
+    let contents = utils::read_file("transaction journal", &journal.path)?;
+
+    let mut committed = false;
+    let mut entries = Vec::new();
+    for line in contents.lines() {
+        if line == "commit" {
+            committed = true;
+        } else if let Some(entry) = Entry::decode(line) {
+            entries.push(entry);
+        }
+        // The last line may have been cut short, in which case its change
+        // was never made
+    }
+
+    if committed {
+        notify_handler(Notification::FinishingInterrupted(prefix.path()));
+    } else {
+        notify_handler(Notification::RollingBackInterrupted(prefix.path()));
+        for entry in entries.iter().rev() {
+            if let Err(e) = entry.roll_back(prefix, notify_handler) {
+                notify_handler(Notification::NonFatalError(&e));
             }
-            Ok(ChangedItem::ModifiedFile(relpath, None))
         }
     }
-    fn move_file(
-        prefix: &InstallPrefix,
-        component: &str,
-        relpath: PathBuf,
-        src: &Path,
-        notify: &'a dyn Fn(Notification<'_>),
-    ) -> Result<Self> {
-        let abs_path = ChangedItem::dest_abs_path(prefix, component, &relpath)?;
-        utils::rename_file("component", src, &abs_path, notify)?;
-        Ok(ChangedItem::AddedFile(relpath))
-    }
-    fn move_dir(
-        prefix: &InstallPrefix,
-        component: &str,
-        relpath: PathBuf,
-        src: &Path,
-        notify: &'a dyn Fn(Notification<'_>),
-    ) -> Result<Self> {
-        let abs_path = ChangedItem::dest_abs_path(prefix, component, &relpath)?;
-        utils::rename_dir("component", src, &abs_path, notify)?;
-        Ok(ChangedItem::AddedDir(relpath))
+    utils::ensure_file_removed("transaction journal", &journal.path)?;
+    journal.discard();
+
+    if !committed && !has_files(prefix.path()) {
+        utils::remove_dir("toolchain", prefix.path(), notify_handler)?;
     }
+    Ok(true)
+}
+
+fn has_files(dir: &Path) -> bool {
+    fs::read_dir(dir).map_or(false, |entries| {
+        entries.filter_map(Result::ok).any(|entry| {
+            !entry.file_type().map_or(false, |t| t.is_dir()) || has_files(&entry.path())
+        })
+    })
 }
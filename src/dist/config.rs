@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use anyhow::{bail, Context, Result};
 
 use super::manifest::Component;
@@ -11,6 +13,9 @@ pub(crate) const DEFAULT_CONFIG_VERSION: &str = "1";
 pub struct Config {
     pub config_version: String,
     pub components: Vec<Component>,
+    /// Fingerprints of the keys which verified the signatures of the
+    /// installed components' archives, keyed by their manifest name.
+    pub signatures: BTreeMap<String, String>,
 }
 
 impl Config {
@@ -24,9 +29,18 @@ impl Config {
         let components =
             Self::toml_to_components(components, &format!("{}{}.", path, "components"))?;
 
+        let signatures = get_table(&mut table, "signatures", path)?
+            .into_iter()
+            .filter_map(|(k, v)| match v {
+                toml::Value::String(s) => Some((k, s)),
+                _ => None,
+            })
+            .collect();
+
         Ok(Self {
             config_version,
             components,
+            signatures,
         })
     }
     pub(crate) fn into_toml(self) -> toml::value::Table {
@@ -39,6 +53,14 @@ impl Config {
         if !components.is_empty() {
             result.insert("components".to_owned(), toml::Value::Array(components));
         }
+        if !self.signatures.is_empty() {
+            let signatures = self
+                .signatures
+                .into_iter()
+                .map(|(k, v)| (k, toml::Value::String(v)))
+                .collect();
+            result.insert("signatures".to_owned(), toml::Value::Table(signatures));
+        }
         result
     }
 
@@ -82,6 +104,7 @@ impl Default for Config {
         Self {
             config_version: DEFAULT_CONFIG_VERSION.to_owned(),
             components: Vec::new(),
+            signatures: BTreeMap::new(),
         }
     }
 }
@@ -12,11 +12,13 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use thiserror::Error as ThisError;
 
-use crate::dist::download::DownloadCfg;
+use crate::dist::download::{to_update_hash, DownloadCfg};
+use crate::dist::lockfile::Lockfile;
 use crate::dist::manifest::{Component, Manifest as ManifestV2};
-use crate::dist::manifestation::{Changes, Manifestation, UpdateStatus};
+use crate::dist::manifestation::{Changes, Manifestation, Plan, UpdateStatus};
 use crate::dist::notifications::*;
 use crate::dist::prefix::InstallPrefix;
+use crate::dist::staging::Staging;
 use crate::dist::temp;
 pub(crate) use crate::dist::triple::*;
 use crate::errors::RustupError;
@@ -25,9 +27,6 @@ use crate::utils::utils;
 
 pub static DEFAULT_DIST_SERVER: &str = "https://static.rust-lang.org";
 
-// Deprecated
-pub(crate) static DEFAULT_DIST_ROOT: &str = "https://static.rust-lang.org/dist";
-
 // The channel patterns we support
 static TOOLCHAIN_CHANNELS: &[&str] = &[
     "nightly",
@@ -596,7 +595,9 @@ pub(crate) fn valid_profile_names() -> String {
 
 // Installs or updates a toolchain from a dist server. If an initial
 // install then it will be installed with the default components. If
-// an upgrade then all the existing components will be upgraded.
+// an upgrade then all the existing components will be upgraded. The
+// changes are made in a staged copy of the toolchain, which replaces it
+// once they are all done.
 //
 // Returns the manifest's hash if anything changed.
 pub(crate) fn update_from_dist<'a>(
@@ -610,6 +611,7 @@ pub(crate) fn update_from_dist<'a>(
     old_date: Option<&str>,
     components: &[&str],
     targets: &[&str],
+    retained_versions: usize,
 ) -> Result<Option<String>> {
     let fresh_install = !prefix.path().exists();
     let hash_exists = update_hash.map(Path::exists).unwrap_or(false);
@@ -621,30 +623,39 @@ pub(crate) fn update_from_dist<'a>(
         std::fs::remove_file(update_hash.unwrap())?;
     }
 
-    let res = update_from_dist_(
+    let mut staging = Staging::new(prefix.path(), download.notify_handler)?;
+    staging.keep_versions(retained_versions);
+    let hash = with_backtracking(
         download,
-        update_hash,
         toolchain,
-        profile,
         prefix,
-        force_update,
         allow_downgrade,
         old_date,
-        components,
-        targets,
-    );
-
-    // Don't leave behind an empty / broken installation directory
-    if res.is_err() && fresh_install {
-        // FIXME Ignoring cascading errors
-        let _ = utils::remove_dir("toolchain", prefix.path(), download.notify_handler);
+        |download, toolchain, fetched| {
+            try_update_from_dist_(
+                download,
+                update_hash,
+                toolchain,
+                profile,
+                &mut staging,
+                force_update,
+                components,
+                targets,
+                fetched,
+            )
+        },
+    )?;
+    if hash.is_some() {
+        staging.commit()?;
     }
-
-    res
+    Ok(hash)
 }
 
-fn update_from_dist_<'a>(
-    download: DownloadCfg<'a>,
+/// Works out what `update_from_dist` would do, backtracking over nightlies
+/// the same way, without downloading any component or touching the
+/// toolchain. Returns `None` if the manifest has not changed.
+pub(crate) fn plan_update_from_dist(
+    download: DownloadCfg<'_>,
     update_hash: Option<&Path>,
     toolchain: &ToolchainDesc,
     profile: Option<Profile>,
@@ -654,7 +665,41 @@ fn update_from_dist_<'a>(
     old_date: Option<&str>,
     components: &[&str],
     targets: &[&str],
-) -> Result<Option<String>> {
+) -> Result<Option<Plan>> {
+    with_backtracking(
+        download,
+        toolchain,
+        prefix,
+        allow_downgrade,
+        old_date,
+        |download, toolchain, fetched| {
+            try_plan_update_from_dist(
+                download,
+                update_hash,
+                toolchain,
+                profile,
+                prefix,
+                force_update,
+                components,
+                targets,
+                fetched,
+            )
+        },
+    )
+}
+
+/// Calls `attempt` with the toolchain `toolchain`, and for a nightly with
+/// no date, with the nightlies before it for as long as the ones tried
+/// lack components or are missing. `attempt` is given where to put the
+/// date of the manifest it fetched.
+fn with_backtracking<T>(
+    download: DownloadCfg<'_>,
+    toolchain: &ToolchainDesc,
+    prefix: &InstallPrefix,
+    allow_downgrade: bool,
+    old_date: Option<&str>,
+    mut attempt: impl FnMut(DownloadCfg<'_>, &ToolchainDesc, &mut String) -> Result<Option<T>>,
+) -> Result<Option<T>> {
     let mut toolchain = toolchain.clone();
     let mut fetched = String::new();
     let mut first_err = None;
@@ -699,17 +744,9 @@ fn update_from_dist_<'a>(
     };
 
     loop {
-        match try_update_from_dist_(
-            download,
-            update_hash,
-            &toolchain,
-            profile,
-            prefix,
-            force_update,
-            components,
-            targets,
-            &mut fetched,
-        ) {
+        match with_mirrors(download, |download| {
+            attempt(download, &toolchain, &mut fetched)
+        }) {
             Ok(v) => break Ok(v),
             Err(e) => {
                 if !backtrack {
@@ -783,14 +820,13 @@ fn try_update_from_dist_<'a>(
     update_hash: Option<&Path>,
     toolchain: &ToolchainDesc,
     profile: Option<Profile>,
-    prefix: &InstallPrefix,
+    staging: &mut Staging<'_>,
     force_update: bool,
     components: &[&str],
     targets: &[&str],
     fetched: &mut String,
 ) -> Result<Option<String>> {
     let toolchain_str = toolchain.to_string();
-    let manifestation = Manifestation::open(prefix.clone(), toolchain.target.clone())?;
 
     // TODO: Add a notification about which manifest version is going to be used
     (download.notify_handler)(Notification::DownloadingManifest(&toolchain_str));
@@ -812,52 +848,17 @@ fn try_update_from_dist_<'a>(
                 m.get_rust_version().ok(),
             ));
 
-            let profile_components = match profile {
-                Some(profile) => m.get_profile_components(profile, &toolchain.target)?,
-                None => Vec::new(),
-            };
-
-            let mut all_components: HashSet<Component> = profile_components.into_iter().collect();
-
-            let rust_package = m.get_package("rust")?;
-            let rust_target_package = rust_package.get_target(Some(&toolchain.target.clone()))?;
-
-            for component in components {
-                let mut component =
-                    Component::new(component.to_string(), Some(toolchain.target.clone()), false);
-                if let Some(renamed) = m.rename_component(&component) {
-                    component = renamed;
-                }
-                // Look up the newly constructed/renamed component and ensure that
-                // if it's a wildcard component we note such, otherwise we end up
-                // exacerbating the problem we thought we'd fixed with #2087 and #2115
-                if let Some(c) = rust_target_package
-                    .components
-                    .iter()
-                    .find(|c| c.short_name_in_manifest() == component.short_name_in_manifest())
-                {
-                    if c.target.is_none() {
-                        component = component.wildcard();
-                    }
-                }
-                all_components.insert(component);
-            }
-
-            for target in targets {
-                let triple = TargetTriple::new(target);
-                all_components.insert(Component::new("rust-std".to_string(), Some(triple), false));
-            }
-
-            let mut explicit_add_components: Vec<_> = all_components.into_iter().collect();
-            explicit_add_components.sort();
-
             let changes = Changes {
-                explicit_add_components,
+                explicit_add_components: requested_components(
+                    &m, toolchain, profile, components, targets,
+                )?,
                 remove_components: Vec::new(),
+                repair_components: Vec::new(),
             };
 
             *fetched = m.date.clone();
 
+            let manifestation = Manifestation::open(staging.prefix()?, toolchain.target.clone())?;
             return match manifestation.update(
                 &m,
                 changes,
@@ -869,20 +870,9 @@ fn try_update_from_dist_<'a>(
             ) {
                 Ok(status) => match status {
                     UpdateStatus::Unchanged => Ok(None),
-                    UpdateStatus::Changed => Ok(Some(hash)),
-                },
-                Err(err) => match err.downcast_ref::<RustupError>() {
-                    Some(RustupError::RequestedComponentsUnavailable {
-                        components,
-                        manifest,
-                        toolchain,
-                    }) => Err(anyhow!(DistError::ToolchainComponentsMissing(
-                        components.to_owned(),
-                        Box::new(manifest.to_owned()),
-                        toolchain.to_owned(),
-                    ))),
-                    Some(_) | None => Err(err),
+                    UpdateStatus::Changed => Ok(Some(to_update_hash(&hash))),
                 },
+                Err(err) => Err(components_missing(err)),
             };
         }
         Ok(None) => return Ok(None),
@@ -898,12 +888,13 @@ fn try_update_from_dist_<'a>(
                 _ => Cases::Other,
             };
             match case {
-                Cases::CF => return Ok(None),
+                // Unless a mirror may have the update already
+                Cases::CF if download.mirrors.is_empty() => return Ok(None),
                 Cases::DNE => {
                     // Proceed to try v1 as a fallback
                     (download.notify_handler)(Notification::DownloadingLegacyManifest);
                 }
-                Cases::Other => return Err(any),
+                Cases::CF | Cases::Other => return Err(any),
             }
         }
     }
@@ -940,12 +931,16 @@ fn try_update_from_dist_<'a>(
             }
         }
     };
+    let manifestation = Manifestation::open(staging.prefix()?, toolchain.target.clone())?;
     let result = manifestation.update_v1(
         &manifest,
         update_hash,
         download.temp_cfg,
+        download.dist_server,
         &download.notify_handler,
         download.pgp_keys,
+        download.signature_policy,
+        download.retry_policy,
     );
     // inspect, determine what context to add, then process afterwards.
     let mut download_not_exists = false;
@@ -969,6 +964,241 @@ fn try_update_from_dist_<'a>(
     }
 }
 
+fn try_plan_update_from_dist(
+    download: DownloadCfg<'_>,
+    update_hash: Option<&Path>,
+    toolchain: &ToolchainDesc,
+    profile: Option<Profile>,
+    prefix: &InstallPrefix,
+    force_update: bool,
+    components: &[&str],
+    targets: &[&str],
+    fetched: &mut String,
+) -> Result<Option<Plan>> {
+    (download.notify_handler)(Notification::DownloadingManifest(&toolchain.to_string()));
+    // The manifest is fetched even if it has not changed when there are
+    // components or targets to add, as in `try_update_from_dist_`
+    let update_hash = if components.is_empty() && targets.is_empty() {
+        update_hash
+    } else {
+        None
+    };
+    let m = match dl_v2_manifest(download, update_hash, toolchain) {
+        Ok(Some((m, _))) => m,
+        Ok(None) => return Ok(None),
+        Err(any) => {
+            return match any.downcast_ref::<RustupError>() {
+                Some(RustupError::ChecksumFailed { .. }) if download.mirrors.is_empty() => Ok(None),
+                // Only releases with a v2 manifest can be planned, there
+                // being nothing to plan with in a v1 one
+                Some(RustupError::DownloadNotExists { .. }) => Err(anyhow!(
+                    DistError::MissingReleaseForToolchain(toolchain.manifest_name())
+                )),
+                _ => Err(any),
+            };
+        }
+    };
+    (download.notify_handler)(Notification::DownloadedManifest(
+        &m.date,
+        m.get_rust_version().ok(),
+    ));
+
+    let changes = Changes {
+        explicit_add_components: requested_components(&m, toolchain, profile, components, targets)?,
+        remove_components: Vec::new(),
+        repair_components: Vec::new(),
+    };
+
+    *fetched = m.date.clone();
+
+    let manifestation = Manifestation::open(prefix.clone(), toolchain.target.clone())?;
+    match manifestation.plan(
+        &m,
+        &changes,
+        force_update,
+        download.notify_handler,
+        &toolchain.manifest_name(),
+    ) {
+        Ok(plan) => Ok(Some(plan)),
+        Err(err) => Err(components_missing(err)),
+    }
+}
+
+/// Turns the failure of a manifestation to find the components requested
+/// into the error on which nightlies are backtracked over
+fn components_missing(err: anyhow::Error) -> anyhow::Error {
+    match err.downcast_ref::<RustupError>() {
+        Some(RustupError::RequestedComponentsUnavailable {
+            components,
+            manifest,
+            toolchain,
+        }) => anyhow!(DistError::ToolchainComponentsMissing(
+            components.to_owned(),
+            Box::new(manifest.to_owned()),
+            toolchain.to_owned(),
+        )),
+        Some(_) | None => err,
+    }
+}
+
+/// The components requested by a profile, extra components and extra
+/// targets, as they are named in the manifest `m`
+pub(crate) fn requested_components(
+    m: &ManifestV2,
+    toolchain: &ToolchainDesc,
+    profile: Option<Profile>,
+    components: &[&str],
+    targets: &[&str],
+) -> Result<Vec<Component>> {
+    let profile_components = match profile {
+        Some(profile) => m.get_profile_components(profile, &toolchain.target)?,
+        None => Vec::new(),
+    };
+
+    let mut all_components: HashSet<Component> = profile_components.into_iter().collect();
+
+    let rust_package = m.get_package("rust")?;
+    let rust_target_package = rust_package.get_target(Some(&toolchain.target.clone()))?;
+
+    for component in components {
+        let mut component =
+            Component::new(component.to_string(), Some(toolchain.target.clone()), false);
+        if let Some(renamed) = m.rename_component(&component) {
+            component = renamed;
+        }
+        // Look up the newly constructed/renamed component and ensure that
+        // if it's a wildcard component we note such, otherwise we end up
+        // exacerbating the problem we thought we'd fixed with #2087 and #2115
+        if let Some(c) = rust_target_package
+            .components
+            .iter()
+            .find(|c| c.short_name_in_manifest() == component.short_name_in_manifest())
+        {
+            if c.target.is_none() {
+                component = component.wildcard();
+            }
+        }
+        all_components.insert(component);
+    }
+
+    for target in targets {
+        let triple = TargetTriple::new(target);
+        all_components.insert(Component::new("rust-std".to_string(), Some(triple), false));
+    }
+
+    let mut all_components: Vec<_> = all_components.into_iter().collect();
+    all_components.sort();
+    Ok(all_components)
+}
+
+// Installs exactly the components pinned by a lockfile, from the dated
+// manifest it was generated from, removing any other component.
+//
+// Returns the manifest's update hash.
+pub(crate) fn update_from_lockfile(
+    download: DownloadCfg<'_>,
+    lockfile: &Lockfile,
+    prefix: &InstallPrefix,
+) -> Result<Option<String>> {
+    let mut staging = Staging::new(prefix.path(), download.notify_handler)?;
+    let hash = with_mirrors(download, |download| {
+        update_from_lockfile_(download, lockfile, &mut staging)
+    })?;
+    staging.commit()?;
+    Ok(hash)
+}
+
+fn update_from_lockfile_(
+    download: DownloadCfg<'_>,
+    lockfile: &Lockfile,
+    staging: &mut Staging<'_>,
+) -> Result<Option<String>> {
+    let toolchain = lockfile.toolchain_desc()?;
+
+    (download.notify_handler)(Notification::DownloadingManifest(&lockfile.toolchain));
+    // Without an update hash the manifest is always downloaded
+    let (m, hash) = dl_v2_manifest(download, None, &toolchain)?.unwrap();
+    lockfile.verify(&m, &hash)?;
+    (download.notify_handler)(Notification::DownloadedManifest(
+        &m.date,
+        m.get_rust_version().ok(),
+    ));
+
+    let manifestation = Manifestation::open(staging.prefix()?, toolchain.target.clone())?;
+    let explicit_add_components = lockfile.locked_components();
+    let remove_components = manifestation
+        .read_config()?
+        .map(|c| c.components)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| !explicit_add_components.contains(c))
+        .collect();
+    let changes = Changes {
+        explicit_add_components,
+        remove_components,
+        repair_components: Vec::new(),
+    };
+
+    manifestation.update(
+        &m,
+        changes,
+        false,
+        &download,
+        &download.notify_handler,
+        &toolchain.manifest_name(),
+        true,
+    )?;
+
+    Ok(Some(to_update_hash(&hash)))
+}
+
+/// Runs `f` against the dist server of `download`, then against each of its
+/// mirrors in turn for as long as the server tried last could not provide
+/// what `f` downloads.
+///
+/// Everything `f` downloads comes from a single server, so that the
+/// components of a manifest come from the mirror which served it.
+pub(crate) fn with_mirrors<T>(
+    download: DownloadCfg<'_>,
+    mut f: impl FnMut(DownloadCfg<'_>) -> Result<T>,
+) -> Result<T> {
+    let mut result = f(download);
+    let mut server = download.dist_server;
+    for (i, mirror) in download.mirrors.iter().enumerate() {
+        match &result {
+            Err(e) if is_unavailable(e) => {}
+            _ => break,
+        }
+        (download.notify_handler)(Notification::FallingBackToMirror(server, mirror));
+        let dist_root = format!("{}/dist", mirror);
+        result = f(DownloadCfg {
+            dist_root: &dist_root,
+            dist_server: mirror,
+            mirrors: &download.mirrors[i + 1..],
+            ..download
+        });
+        server = mirror.as_str();
+    }
+    result
+}
+
+/// Whether `e` is a server's failure to provide a file, which another server
+/// may well provide: it could not be reached, does not have the file, or
+/// served a corrupt one
+fn is_unavailable(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<RustupError>(),
+            Some(RustupError::DownloadingFile { .. })
+                | Some(RustupError::DownloadNotExists { .. })
+                | Some(RustupError::ChecksumFailed { .. })
+        ) || matches!(
+            cause.downcast_ref::<DistError>(),
+            Some(DistError::MissingReleaseForToolchain(..))
+        )
+    })
+}
+
 pub(crate) fn dl_v2_manifest<'a>(
     download: DownloadCfg<'a>,
     update_hash: Option<&Path>,
@@ -990,8 +1220,11 @@ pub(crate) fn dl_v2_manifest<'a>(
         }
         Err(any) => {
             if let Some(RustupError::ChecksumFailed { .. }) = any.downcast_ref::<RustupError>() {
-                // Checksum failed - issue warning to try again later
-                (download.notify_handler)(Notification::ManifestChecksumFailedHack);
+                // Checksum failed - issue warning to try again later, unless
+                // a mirror is tried next
+                if download.mirrors.is_empty() {
+                    (download.notify_handler)(Notification::ManifestChecksumFailedHack);
+                }
             }
             Err(any)
         }
@@ -1,26 +1,48 @@
 use std::fs;
+use std::io::{self, Read};
 use std::ops;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
+use download::Backend;
 use sha2::{Digest, Sha256};
 use url::Url;
 
 use crate::config::PgpPublicKey;
+use crate::dist::cache::{self, DownloadCache};
 use crate::dist::notifications::*;
+use crate::dist::signatures::SignaturePolicy;
 use crate::dist::temp;
 use crate::errors::*;
+use crate::utils::lock::Lock;
+use crate::utils::retry_policy::RetryPolicy;
 use crate::utils::utils;
 
 const UPDATE_HASH_LEN: usize = 20;
 
+/// A file on the dist server is accompanied by its checksum and signature
+pub(crate) const SIGNED_SUFFIXES: [&str; 3] = ["", ".sha256", ".asc"];
+
+/// How long `download_sizes` may take altogether
+const SIZE_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Copy, Clone)]
 pub struct DownloadCfg<'a> {
     pub dist_root: &'a str,
+    /// The server which the URLs in the manifests are rewritten to
+    pub dist_server: &'a str,
+    /// The servers to fall back to, in order, when `dist_server` fails
+    pub mirrors: &'a [String],
     pub temp_cfg: &'a temp::Cfg,
     pub download_dir: &'a PathBuf,
     pub notify_handler: &'a dyn Fn(Notification<'_>),
     pub pgp_keys: &'a [PgpPublicKey],
+    pub signature_policy: SignaturePolicy,
+    pub retry_policy: &'a RetryPolicy,
+    /// How downloads are kept once installed, if they are kept at all
+    pub cache: Option<&'a DownloadCache>,
 }
 
 pub(crate) struct File {
@@ -39,88 +61,302 @@ impl<'a> DownloadCfg<'a> {
     /// Downloads a file and validates its hash. Resumes interrupted downloads.
     /// Partial downloads are stored in `self.download_dir`, keyed by hash. If the
     /// target file already exists, then the hash is checked and it is returned
-    /// immediately without re-downloading.
+    /// immediately without re-downloading. So is a file in the shared cache.
     pub(crate) fn download(&self, url: &Url, hash: &str) -> Result<File> {
+        if let Some(file) = self.shared_file(url, hash) {
+            return Ok(file);
+        }
         utils::ensure_dir_exists(
             "Download Directory",
             self.download_dir,
             &self.notify_handler,
         )?;
-        let target_file = self.download_dir.join(Path::new(hash));
+        download_to_cache(
+            self.download_dir,
+            utils::download_backend(),
+            url,
+            hash,
+            self.retry_policy,
+            self.notify_handler,
+        )
+    }
 
-        if target_file.exists() {
-            let cached_result = file_hash(&target_file, self.notify_handler)?;
-            if hash == cached_result {
-                (self.notify_handler)(Notification::FileAlreadyDownloaded);
-                (self.notify_handler)(Notification::ChecksumValid(url.as_ref()));
-                return Ok(File { path: target_file });
-            } else {
-                (self.notify_handler)(Notification::CachedFileChecksumFailed);
-                fs::remove_file(&target_file).context("cleaning up previous download")?;
-            }
+    /// `download`, but always into `self.download_dir`: a file found
+    /// elsewhere, such as in the shared cache, is copied there together with
+    /// its signature, for callers which go on to move or package the file
+    pub(crate) fn download_to_dir(&self, url: &Url, hash: &str) -> Result<File> {
+        let file = self.download(url, hash)?;
+        let target_file = self.download_dir.join(hash);
+        if file.path == target_file {
+            return Ok(file);
         }
+        utils::ensure_dir_exists(
+            "Download Directory",
+            self.download_dir,
+            &self.notify_handler,
+        )?;
+        let lock_path = with_suffix(&target_file, ".lock");
+        let _lock = Lock::exclusive(&lock_path, &self.notify_handler)?;
+        let signature = signature_path(&file);
+        if utils::is_file(&signature) {
+            utils::copy_file(&signature, &signature_path(&target_file))?;
+        }
+        // Copied under another name first, so that the file is complete
+        // whenever it is there
+        let partial_file = with_suffix(&target_file, ".partial");
+        utils::copy_file(&file, &partial_file)?;
+        utils::rename_file(
+            "downloaded",
+            &partial_file,
+            &target_file,
+            &self.notify_handler,
+        )?;
+        Ok(File { path: target_file })
+    }
 
-        let partial_file_path = target_file.with_file_name(
-            target_file
-                .file_name()
-                .map(|s| s.to_str().unwrap_or("_"))
-                .unwrap_or("_")
-                .to_owned()
-                + ".partial",
-        );
+    /// `download` for each of `files`, a URL with the hash of its content,
+    /// running up to `concurrency` downloads at a time. A file on the local
+    /// disk, as served by a dist server which is a local directory, is
+    /// checked and used in place rather than copied into `self.download_dir`,
+    /// unless it is already there, even partially. So is a file in the shared
+    /// cache. A download which fails in
+    /// a way that may not happen again is retried by `self.retry_policy`.
+    /// The progress of all the downloads is reported as that of a single
+    /// one. This returns as soon as the downloads are running, so that each
+    /// file can be used as it arrives.
+    pub(crate) fn start_downloads(
+        &self,
+        files: Vec<(Url, String)>,
+        concurrency: usize,
+    ) -> Result<Downloads<'a>> {
+        let mut results: Vec<Option<Result<File>>> = files.iter().map(|_| None).collect();
+        let mut queued = Vec::new();
+        for (index, (url, hash)) in files.iter().enumerate() {
+            match self.local_file(url, hash) {
+                Some(path) => results[index] = Some(self.read_in_place(url, hash, path)),
+                None => match self.shared_file(url, hash) {
+                    Some(file) => results[index] = Some(Ok(file)),
+                    None => queued.push(index),
+                },
+            }
+        }
 
-        let partial_file_existed = partial_file_path.exists();
+        let (tx, rx) = channel();
+        let mut downloads = Downloads {
+            cfg: *self,
+            done: results.iter().map(Option::is_some).collect(),
+            results,
+            started: vec![false; files.len()],
+            received: vec![0; files.len()],
+            restarted: vec![false; files.len()],
+            lengths: vec![0; files.len()],
+            downloading: !queued.is_empty(),
+            rx,
+            _pool: None,
+            files,
+        };
+        if queued.is_empty() {
+            return Ok(downloads);
+        }
 
-        let mut hasher = Sha256::new();
+        utils::ensure_dir_exists(
+            "Download Directory",
+            self.download_dir,
+            &self.notify_handler,
+        )?;
+        // The current process is only available on this thread
+        let backend = utils::download_backend();
+        (self.notify_handler)(Notification::Utils(match backend {
+            Backend::Curl => crate::utils::Notification::UsingCurl,
+            Backend::Reqwest(_) => crate::utils::Notification::UsingReqwest,
+        }));
+
+        let pool = threadpool::Builder::new()
+            .thread_name("Download".into())
+            .num_threads(concurrency.max(1).min(queued.len()))
+            .build();
+        for &index in &queued {
+            let (url, hash) = downloads.files[index].clone();
+            let download_dir = self.download_dir.clone();
+            let retry_policy = self.retry_policy.clone();
+            let tx = tx.clone();
+            pool.execute(move || {
+                let notify_handler = |n: Notification<'_>| {
+                    if let Some(progress) = Progress::of(index, n) {
+                        let _ = tx.send(progress);
+                    }
+                };
+                // Retries happen here alone: each attempt makes a single
+                // request, and a damaged partial file is retried from scratch
+                let once = RetryPolicy {
+                    max_retries: 0,
+                    ..retry_policy.clone()
+                };
+                let result = retry_policy.run(
+                    || {
+                        download_to_cache(
+                            &download_dir,
+                            backend,
+                            &url,
+                            &hash,
+                            &once,
+                            &notify_handler,
+                        )
+                    },
+                    |e| is_broken_partial_file(e) || retry_policy.is_retryable(e),
+                    |_| {
+                        let _ = tx.send(Progress::Retrying(index));
+                    },
+                );
+                let _ = tx.send(Progress::Done(index, result));
+            });
+        }
+        downloads._pool = Some(pool);
+        Ok(downloads)
+    }
 
-        if let Err(e) = utils::download_file_with_resume(
-            url,
-            &partial_file_path,
-            Some(&mut hasher),
-            true,
-            &|n| (self.notify_handler)(n.into()),
-        ) {
-            let err = Err(e);
-            if partial_file_existed {
-                return err.context(RustupError::BrokenPartialFile);
+    /// The size of each file with content `hash` at `url`, and how much of it
+    /// `start_downloads` would download, where the size can be found out.
+    /// The size of a file which is not on the disk already is asked for with
+    /// up to `concurrency` requests at a time, which are retried by
+    /// `self.retry_policy` and fall back to each of `self.mirrors` in turn.
+    /// Returns `None` if the answers take longer than `SIZE_PROBE_TIMEOUT`
+    /// altogether, rather than hold up the install.
+    pub(crate) fn download_sizes(
+        &self,
+        files: &[(Url, String)],
+        concurrency: usize,
+    ) -> Option<Vec<Option<(u64, u64)>>> {
+        let mut sizes = vec![None; files.len()];
+        let mut queued = Vec::new();
+        for (index, (url, hash)) in files.iter().enumerate() {
+            let target_file = self.download_dir.join(hash);
+            let partial_file = with_suffix(&target_file, ".partial");
+            let on_disk = if target_file.exists() {
+                Some(target_file)
+            } else if partial_file.exists() {
+                None
             } else {
-                return err;
+                self.local_file(url, hash)
+                    .or_else(|| self.cache?.shared_file(hash))
+            };
+            match on_disk {
+                Some(path) => sizes[index] = fs::metadata(path).ok().map(|m| (m.len(), 0)),
+                None => {
+                    let downloaded = fs::metadata(&partial_file).map_or(0, |m| m.len());
+                    queued.push((index, self.mirror_urls(url), downloaded));
+                }
             }
-        };
+        }
+        if queued.is_empty() {
+            return Some(sizes);
+        }
 
-        let actual_hash = format!("{:x}", hasher.finalize());
+        // The current process is only available on this thread
+        let backend = utils::download_backend();
+        let (tx, rx) = channel();
+        let pool = threadpool::Builder::new()
+            .thread_name("Download size".into())
+            .num_threads(concurrency.max(1).min(queued.len()))
+            .build();
+        for (index, urls, downloaded) in queued {
+            let retry_policy = self.retry_policy.clone();
+            let tx = tx.clone();
+            pool.execute(move || {
+                let size = urls.iter().find_map(|url| {
+                    utils::content_length(backend, url, &retry_policy)
+                        .ok()
+                        .flatten()
+                });
+                let _ = tx.send((
+                    index,
+                    size.map(|size| (size, size.saturating_sub(downloaded))),
+                ));
+            });
+        }
+        drop(tx);
+        // The requests still running are left to finish on their own
+        let deadline = Instant::now() + SIZE_PROBE_TIMEOUT;
+        loop {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((index, size)) => sizes[index] = size,
+                Err(RecvTimeoutError::Timeout) => return None,
+                Err(RecvTimeoutError::Disconnected) => return Some(sizes),
+            }
+        }
+    }
+
+    /// `url`, followed by the same file on each of `self.mirrors`
+    fn mirror_urls(&self, url: &Url) -> Vec<Url> {
+        let path = url.as_str().strip_prefix(self.dist_server);
+        let mirrored = self
+            .mirrors
+            .iter()
+            .filter_map(|mirror| utils::parse_url(&format!("{}{}", mirror, path?)).ok());
+        std::iter::once(url.clone()).chain(mirrored).collect()
+    }
 
+    /// The file on the local disk which `url` refers to, if it is to be read
+    /// in place rather than downloaded
+    fn local_file(&self, url: &Url, hash: &str) -> Option<PathBuf> {
+        if !self.temp_cfg.read_in_place {
+            return None;
+        }
+        let target_file = self.download_dir.join(hash);
+        let downloading = target_file.exists() || with_suffix(&target_file, ".partial").exists();
+        utils::local_path(url).filter(|path| utils::is_file(path) && !downloading)
+    }
+
+    /// The file with content `hash` in the shared cache, once its hash has
+    /// been checked, unless it is in `self.download_dir` already, even
+    /// partially
+    fn shared_file(&self, url: &Url, hash: &str) -> Option<File> {
+        let target_file = self.download_dir.join(hash);
+        if target_file.exists() || with_suffix(&target_file, ".partial").exists() {
+            return None;
+        }
+        let path = self.cache?.shared_file(hash)?;
+        match self.read_in_place(url, hash, path) {
+            Ok(file) => {
+                (self.notify_handler)(Notification::FileAlreadyDownloaded);
+                Some(file)
+            }
+            Err(_) => {
+                (self.notify_handler)(Notification::CachedFileChecksumFailed);
+                None
+            }
+        }
+    }
+
+    fn read_in_place(&self, url: &Url, hash: &str, path: PathBuf) -> Result<File> {
+        let actual_hash = file_hash(&path, self.notify_handler)?;
         if hash != actual_hash {
-            // Incorrect hash
-            if partial_file_existed {
-                self.clean(&[hash.to_string() + ".partial"])?;
-                Err(anyhow!(RustupError::BrokenPartialFile))
-            } else {
-                Err(RustupError::ChecksumFailed {
-                    url: url.to_string(),
-                    expected: hash.to_string(),
-                    calculated: actual_hash,
-                }
-                .into())
+            return Err(RustupError::ChecksumFailed {
+                url: url.to_string(),
+                expected: hash.to_string(),
+                calculated: actual_hash,
             }
-        } else {
-            (self.notify_handler)(Notification::ChecksumValid(url.as_ref()));
-
-            utils::rename_file(
-                "downloaded",
-                &partial_file_path,
-                &target_file,
-                self.notify_handler,
-            )?;
-            Ok(File { path: target_file })
+            .into());
         }
+        (self.notify_handler)(Notification::ChecksumValid(url.as_ref()));
+        Ok(File { path })
     }
 
+    /// Deletes the downloads of `hashes` once they have been installed, or
+    /// with a cache, makes room in it for them
     pub(crate) fn clean(&self, hashes: &[String]) -> Result<()> {
+        if let Some(cache) = self.cache {
+            cache.prune(self.download_dir, self.notify_handler)?;
+            return Ok(());
+        }
         for hash in hashes.iter() {
             let used_file = self.download_dir.join(hash);
             if self.download_dir.join(&used_file).exists() {
-                fs::remove_file(used_file).context("cleaning up cached downloads")?;
+                fs::remove_file(&used_file).context("cleaning up cached downloads")?;
+            }
+            let used_signature = signature_path(&used_file);
+            if used_signature.exists() {
+                fs::remove_file(used_signature).context("cleaning up cached downloads")?;
             }
         }
         Ok(())
@@ -130,7 +366,7 @@ impl<'a> DownloadCfg<'a> {
         let hash_url = utils::parse_url(&(url.to_owned() + ".sha256"))?;
         let hash_file = self.temp_cfg.new_file()?;
 
-        utils::download_file(&hash_url, &hash_file, None, &|n| {
+        utils::download_file(&hash_url, &hash_file, None, self.retry_policy, &|n| {
             (self.notify_handler)(n.into())
         })?;
 
@@ -141,32 +377,47 @@ impl<'a> DownloadCfg<'a> {
         let sig_url = utils::parse_url(&(url.to_owned() + ".asc"))?;
         let sig_file = self.temp_cfg.new_file()?;
 
-        utils::download_file(&sig_url, &sig_file, None, &|n| {
+        utils::download_file(&sig_url, &sig_file, None, self.retry_policy, &|n| {
             (self.notify_handler)(n.into())
         })?;
 
         utils::read_file("signature", &sig_file)
     }
 
-    fn check_signature(&self, url: &str, file: &temp::File<'_>) -> Result<&PgpPublicKey> {
+    fn check_signature(&self, url: &str, file_path: &Path) -> Result<&PgpPublicKey> {
         assert!(
             !self.pgp_keys.is_empty(),
             "At least the builtin key must be present"
         );
 
-        let signature = self
-            .download_signature(url)
-            .with_context(|| format!("failed to download signature file {}", url))?;
+        // Signatures of files in the download directory are kept next to
+        // them, so that they can be checked again without the network
+        let cached_signature = signature_path(file_path);
+        let dir = file_path.parent();
+        let in_download_dir = dir == Some(self.download_dir.as_path());
+        let in_shared_cache = self
+            .cache
+            .and_then(|cache| cache.shared_dir.as_deref())
+            .map_or(false, |shared_dir| dir == Some(shared_dir));
+        let is_cached = (in_download_dir || in_shared_cache) && utils::is_file(&cached_signature);
+        let signature = if is_cached {
+            utils::read_file("signature", &cached_signature)?
+        } else {
+            self.download_signature(url)
+                .with_context(|| format!("failed to download signature file {}", url))?
+        };
 
-        let file_path: &Path = file;
         let content = std::fs::File::open(file_path).with_context(|| RustupError::ReadingFile {
-            name: "channel data",
+            name: "signed",
             path: PathBuf::from(file_path),
         })?;
 
         let sig_result =
             crate::dist::signatures::verify_signature(content, &signature, self.pgp_keys)?;
         if let Some(keyidx) = sig_result {
+            if in_download_dir && !is_cached {
+                utils::write_file("signature", &cached_signature, &signature)?;
+            }
             let key = &self.pgp_keys[keyidx];
             Ok(key)
         } else {
@@ -177,11 +428,58 @@ impl<'a> DownloadCfg<'a> {
         }
     }
 
+    /// Verifies the signature found at `url` with a `.asc` suffix against the
+    /// downloaded `file`, according to the `signature_policy`. Returns the key
+    /// which verified the signature, or `None` if the file is unverified but
+    /// the policy allows it to be used anyway. The signature of a file in the
+    /// download directory is cached next to it once it has verified.
+    pub(crate) fn verify_signature(&self, url: &str, file: &Path) -> Result<Option<&PgpPublicKey>> {
+        if self.signature_policy == SignaturePolicy::Off {
+            return Ok(None);
+        }
+        match self.check_signature(url, file) {
+            Ok(key) => {
+                (self.notify_handler)(Notification::SignatureValid(url, key));
+                Ok(Some(key))
+            }
+            Err(e) if self.signature_policy == SignaturePolicy::Require => {
+                Err(e.context(RustupError::SignatureVerificationFailed {
+                    url: url.to_owned(),
+                }))
+            }
+            Err(_) => {
+                (self.notify_handler)(Notification::SignatureInvalid(url));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Downloads `url`, together with the checksum and signature published
+    /// next to it, to `path`. Nothing is checked; a missing signature is left
+    /// for the signature policy to deal with when the file is checked.
+    pub(crate) fn download_signed(&self, url: &str, path: &Path) -> Result<()> {
+        for suffix in SIGNED_SUFFIXES {
+            let src = utils::parse_url(&format!("{}{}", url, suffix))?;
+            let dest = with_suffix(path, suffix);
+            if let Err(e) = utils::download_file(&src, &dest, None, self.retry_policy, &|n| {
+                (self.notify_handler)(n.into())
+            }) {
+                utils::ensure_file_removed("downloaded", &dest)?;
+                if suffix != ".asc" {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Downloads a file, sourcing its hash from the same url with a `.sha256` suffix.
     /// If `update_hash` is present, then that will be compared to the downloaded hash,
-    /// and if they match, the download is skipped.
-    /// Verifies the signature found at the same url with a `.asc` suffix, and prints a
-    /// warning when the signature does not verify, or is not found.
+    /// and if they match, the download is skipped. Returns the file with its
+    /// full hash, of which `to_update_hash` is what is kept as an update hash.
+    /// Verifies the signature found at the same url with a `.asc` suffix. What happens
+    /// when the signature does not verify, or is not found, depends on the
+    /// `signature_policy`.
     pub(crate) fn download_and_check(
         &self,
         url_str: &str,
@@ -189,12 +487,11 @@ impl<'a> DownloadCfg<'a> {
         ext: &str,
     ) -> Result<Option<(temp::File<'a>, String)>> {
         let hash = self.download_hash(url_str)?;
-        let partial_hash: String = hash.chars().take(UPDATE_HASH_LEN).collect();
 
         if let Some(hash_file) = update_hash {
             if utils::is_file(hash_file) {
                 if let Ok(contents) = utils::read_file("update hash", hash_file) {
-                    if contents == partial_hash {
+                    if contents == to_update_hash(&hash) {
                         // Skip download, update hash matches
                         return Ok(None);
                     }
@@ -210,7 +507,7 @@ impl<'a> DownloadCfg<'a> {
         let file = self.temp_cfg.new_file_with_ext("", ext)?;
 
         let mut hasher = Sha256::new();
-        utils::download_file(&url, &file, Some(&mut hasher), &|n| {
+        utils::download_file(&url, &file, Some(&mut hasher), self.retry_policy, &|n| {
             (self.notify_handler)(n.into())
         })?;
         let actual_hash = format!("{:x}", hasher.finalize());
@@ -227,25 +524,365 @@ impl<'a> DownloadCfg<'a> {
             (self.notify_handler)(Notification::ChecksumValid(url_str));
         }
 
-        // No signatures for tarballs for now.
-        if !url_str.ends_with(".tar.gz") && !url_str.ends_with(".tar.xz") {
-            match self.check_signature(url_str, &file) {
-                Ok(key) => (self.notify_handler)(Notification::SignatureValid(url_str, key)),
-                Err(_) => (self.notify_handler)(Notification::SignatureInvalid(url_str)),
+        self.verify_signature(url_str, &file)?;
+
+        Ok(Some((file, actual_hash)))
+    }
+}
+
+/// Downloads running on worker threads, started by
+/// `DownloadCfg::start_downloads`. Their progress is reported on the thread
+/// which started them, whenever it waits for one of them or reads one.
+pub(crate) struct Downloads<'a> {
+    cfg: DownloadCfg<'a>,
+    files: Vec<(Url, String)>,
+    results: Vec<Option<Result<File>>>,
+    done: Vec<bool>,
+    /// Whether each download has started writing its partial file
+    started: Vec<bool>,
+    /// How much of each partial file has been written
+    received: Vec<u64>,
+    /// Whether each download has been retried, which may have rewritten its
+    /// partial file after some of it was read
+    restarted: Vec<bool>,
+    lengths: Vec<u64>,
+    downloading: bool,
+    rx: Receiver<Progress>,
+    _pool: Option<threadpool::ThreadPool>,
+}
+
+impl<'a> Downloads<'a> {
+    /// Waits for download `index` to finish, returning the file once its
+    /// hash has been checked
+    pub(crate) fn wait(&mut self, index: usize) -> Result<File> {
+        while !self.done[index] && self.poll() {}
+        self.results[index].take().unwrap_or_else(|| {
+            Err(anyhow!(
+                "download of '{}' did not finish",
+                self.files[index].0
+            ))
+        })
+    }
+
+    /// The content of download `index`, read as it arrives. Nothing read
+    /// from the stream can be trusted until `wait` has checked the hash of
+    /// the download, and not even then if `restarted` says so.
+    pub(crate) fn stream(&mut self, index: usize) -> Stream<'_, 'a> {
+        // The download tracker may have been reset since the length of the
+        // downloads was last reported
+        let len: u64 = self.lengths.iter().sum();
+        if self.downloading && len > 0 {
+            (self.cfg.notify_handler)(
+                crate::utils::Notification::DownloadContentLengthReceived(len).into(),
+            );
+        }
+        Stream {
+            downloads: self,
+            index,
+            file: None,
+            pos: 0,
+        }
+    }
+
+    /// Whether download `index` was retried, so that what was read from its
+    /// stream may not be what was downloaded in the end
+    pub(crate) fn restarted(&self, index: usize) -> bool {
+        self.restarted[index]
+    }
+
+    /// Waits for every download to finish
+    pub(crate) fn finish(&mut self) {
+        while self.poll() {}
+        if self.downloading {
+            self.downloading = false;
+            (self.cfg.notify_handler)(crate::utils::Notification::DownloadFinished.into());
+        }
+    }
+
+    /// Waits for the progress of any download and reports it. Returns false
+    /// once every download is done.
+    fn poll(&mut self) -> bool {
+        let progress = match self.rx.recv() {
+            Ok(progress) => progress,
+            Err(_) => {
+                self.done.iter_mut().for_each(|done| *done = true);
+                return false;
+            }
+        };
+        let notification: Notification<'_> = match progress {
+            Progress::Started(index) => {
+                self.started[index] = true;
+                self.received[index] = 0;
+                return true;
+            }
+            Progress::ContentLength(index, len) => {
+                self.lengths[index] = len;
+                crate::utils::Notification::DownloadContentLengthReceived(self.lengths.iter().sum())
+                    .into()
             }
+            Progress::Data(index, len) => {
+                self.received[index] += len as u64;
+                crate::utils::Notification::DownloadBytesReceived(len).into()
+            }
+            Progress::Resuming => crate::utils::Notification::ResumingPartialDownload.into(),
+            Progress::Waiting(index, delay) => {
+                crate::utils::Notification::RetryingDownload(&self.files[index].0, delay).into()
+            }
+            Progress::AlreadyDownloaded => Notification::FileAlreadyDownloaded,
+            Progress::CachedChecksumFailed => Notification::CachedFileChecksumFailed,
+            Progress::ChecksumValid(index) => {
+                Notification::ChecksumValid(self.files[index].0.as_ref())
+            }
+            Progress::Retrying(index) => {
+                self.restarted[index] = true;
+                Notification::RetryingDownload(self.files[index].0.as_ref())
+            }
+            Progress::WaitingForLock(ref path, pid) => {
+                crate::utils::Notification::WaitingForLock(path, pid).into()
+            }
+            Progress::Done(index, result) => {
+                self.results[index] = Some(result);
+                self.done[index] = true;
+                return true;
+            }
+        };
+        (self.cfg.notify_handler)(notification);
+        true
+    }
+
+    fn partial_file(&self, index: usize) -> PathBuf {
+        with_suffix(
+            &self.cfg.download_dir.join(&self.files[index].1),
+            ".partial",
+        )
+    }
+}
+
+/// The content of a download, read from its partial file as it is written
+pub(crate) struct Stream<'d, 'a> {
+    downloads: &'d mut Downloads<'a>,
+    index: usize,
+    file: Option<fs::File>,
+    pos: u64,
+}
+
+impl<'d, 'a> io::Read for Stream<'d, 'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
         }
+        let index = self.index;
+        loop {
+            let downloads = &mut *self.downloads;
+            if downloads.restarted[index] {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "the download was restarted",
+                ));
+            }
+            let done = downloads.done[index];
+            if self.file.is_none() {
+                if done {
+                    // Whatever was not downloaded, because it was cached or
+                    // is read in place, is read from where it is
+                    match &downloads.results[index] {
+                        Some(Ok(file)) => self.file = Some(fs::File::open(&file.path)?),
+                        _ => return Err(io::Error::new(io::ErrorKind::Other, "download failed")),
+                    }
+                } else if downloads.started[index] && downloads.received[index] > 0 {
+                    self.file = fs::File::open(downloads.partial_file(index)).ok();
+                }
+            }
+            if let Some(file) = &mut self.file {
+                let available = if done {
+                    buf.len()
+                } else {
+                    (buf.len() as u64).min(downloads.received[index].saturating_sub(self.pos))
+                        as usize
+                };
+                if available > 0 {
+                    let n = file.read(&mut buf[..available])?;
+                    if n > 0 || done {
+                        self.pos += n as u64;
+                        return Ok(n);
+                    }
+                }
+            }
+            downloads.poll();
+        }
+    }
+}
+
+/// How a download on a worker thread of `DownloadCfg::start_downloads` is
+/// going, for the thread which started it to report
+enum Progress {
+    Started(usize),
+    Waiting(usize, Duration),
+    ContentLength(usize, u64),
+    Data(usize, usize),
+    Resuming,
+    AlreadyDownloaded,
+    CachedChecksumFailed,
+    ChecksumValid(usize),
+    Retrying(usize),
+    WaitingForLock(PathBuf, Option<u32>),
+    Done(usize, Result<File>),
+}
 
-        Ok(Some((file, partial_hash)))
+impl Progress {
+    /// The progress of download `index` which `n` tells of, if any
+    fn of(index: usize, n: Notification<'_>) -> Option<Self> {
+        use crate::utils::Notification as Un;
+        Some(match n {
+            Notification::Utils(Un::DownloadContentLengthReceived(len)) => {
+                Self::ContentLength(index, len)
+            }
+            Notification::Utils(Un::DownloadingFile(_, _)) => Self::Started(index),
+            Notification::Utils(Un::DownloadDataReceived(data)) => Self::Data(index, data.len()),
+            Notification::Utils(Un::DownloadBytesReceived(len)) => Self::Data(index, len),
+            Notification::Utils(Un::ResumingPartialDownload) => Self::Resuming,
+            Notification::Utils(Un::RetryingDownload(_, delay)) => Self::Waiting(index, delay),
+            Notification::Utils(Un::WaitingForLock(path, pid)) => {
+                Self::WaitingForLock(path.to_owned(), pid)
+            }
+            Notification::FileAlreadyDownloaded => Self::AlreadyDownloaded,
+            Notification::CachedFileChecksumFailed => Self::CachedChecksumFailed,
+            Notification::ChecksumValid(_) => Self::ChecksumValid(index),
+            _ => return None,
+        })
     }
 }
 
-fn file_hash(path: &Path, notify_handler: &dyn Fn(Notification<'_>)) -> Result<String> {
+fn is_broken_partial_file(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<RustupError>(),
+        Some(RustupError::BrokenPartialFile)
+    )
+}
+
+/// `DownloadCfg::download` into `download_dir`, which must exist, with
+/// `backend`. This does not look at the current process, so it can run on
+/// any thread: the partial file is renamed within `download_dir`, which
+/// never takes the copy across file systems that `utils::rename_file` asks
+/// the process about.
+fn download_to_cache(
+    download_dir: &Path,
+    backend: Backend,
+    url: &Url,
+    hash: &str,
+    retry_policy: &RetryPolicy,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<File> {
+    let target_file = download_dir.join(Path::new(hash));
+    // Another process, or another thread of this one, downloading the same
+    // file would write to the same partial file
+    let lock_path = with_suffix(&target_file, ".lock");
+    let _lock = Lock::exclusive(&lock_path, &|n: crate::utils::Notification<'_>| {
+        notify_handler(n.into())
+    })?;
+
+    if target_file.exists() {
+        let cached_result = file_hash(&target_file, notify_handler)?;
+        if hash == cached_result {
+            cache::mark_used(&target_file);
+            notify_handler(Notification::FileAlreadyDownloaded);
+            notify_handler(Notification::ChecksumValid(url.as_ref()));
+            return Ok(File { path: target_file });
+        } else {
+            notify_handler(Notification::CachedFileChecksumFailed);
+            fs::remove_file(&target_file).context("cleaning up previous download")?;
+        }
+    }
+
+    let partial_file_path = target_file.with_file_name(
+        target_file
+            .file_name()
+            .map(|s| s.to_str().unwrap_or("_"))
+            .unwrap_or("_")
+            .to_owned()
+            + ".partial",
+    );
+
+    let partial_file_existed = partial_file_path.exists();
+
+    let mut hasher = Sha256::new();
+
+    if let Err(e) = utils::download_file_with_backend(
+        backend,
+        url,
+        &partial_file_path,
+        Some(&mut hasher),
+        true,
+        retry_policy,
+        &|n| notify_handler(n.into()),
+    ) {
+        let err = Err(e);
+        if partial_file_existed {
+            return err.context(RustupError::BrokenPartialFile);
+        } else {
+            return err;
+        }
+    };
+
+    let actual_hash = format!("{:x}", hasher.finalize());
+
+    if hash != actual_hash {
+        // Incorrect hash
+        if partial_file_existed {
+            fs::remove_file(&partial_file_path).context("cleaning up cached downloads")?;
+            Err(anyhow!(RustupError::BrokenPartialFile))
+        } else {
+            Err(RustupError::ChecksumFailed {
+                url: url.to_string(),
+                expected: hash.to_string(),
+                calculated: actual_hash,
+            }
+            .into())
+        }
+    } else {
+        notify_handler(Notification::ChecksumValid(url.as_ref()));
+
+        utils::rename_file(
+            "downloaded",
+            &partial_file_path,
+            &target_file,
+            notify_handler,
+        )?;
+        Ok(File { path: target_file })
+    }
+}
+
+/// The part of the hash of a manifest which is kept as the update hash of a
+/// toolchain
+pub(crate) fn to_update_hash(hash: &str) -> String {
+    hash.chars().take(UPDATE_HASH_LEN).collect()
+}
+
+/// Where the signature of a file in the download directory is kept
+pub(crate) fn signature_path(file: &Path) -> PathBuf {
+    with_suffix(file, ".asc")
+}
+
+/// `path` with `suffix` appended to its file name, e.g. `.sha256`
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// The `file:` URL of a local directory or file, for downloading from it
+pub(crate) fn file_url(path: &Path) -> Result<String> {
+    Url::from_file_path(path)
+        .map(String::from)
+        .map_err(|()| anyhow!("cannot make a URL of the path '{}'", path.display()))
+}
+
+pub(crate) fn file_hash(path: &Path, notify_handler: &dyn Fn(Notification<'_>)) -> Result<String> {
     let mut hasher = Sha256::new();
     let notification_converter = |notification: crate::utils::Notification<'_>| {
         notify_handler(notification.into());
     };
     let mut downloaded = utils::FileReaderWithProgress::new_file(path, &notification_converter)?;
-    use std::io::Read;
     let mut buf = vec![0; 32768];
     while let Ok(n) = downloaded.read(&mut buf) {
         if n == 0 {
@@ -0,0 +1,288 @@
+//! `rustup-toolchain.lock`, which pins the toolchain selected by a
+//! `rust-toolchain.toml` to the exact archives of one dated channel.
+//!
+//! The lockfile records the dated toolchain the channel resolved to, the
+//! SHA-256 of its manifest and every installed component together with the
+//! hash of its archive, all taken from the v2 manifest. Installing from a
+//! lockfile fails unless the dist server still serves exactly those bytes.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::dist::ToolchainDesc;
+use super::manifest::{Component, HashedBinary, Manifest};
+use crate::errors::*;
+use crate::utils::toml_utils::*;
+use crate::utils::utils;
+
+pub(crate) const LOCKFILE_NAME: &str = "rustup-toolchain.lock";
+pub(crate) const SUPPORTED_LOCKFILE_VERSIONS: [&str; 1] = ["1"];
+pub(crate) const DEFAULT_LOCKFILE_VERSION: &str = "1";
+
+const LOCKFILE_HEADER: &str =
+    "# This file is generated by `rustup toolchain lock`. Do not edit it by hand.\n";
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Lockfile {
+    pub lockfile_version: String,
+    /// The dated toolchain, e.g. `nightly-2021-06-17-x86_64-unknown-linux-gnu`
+    pub toolchain: String,
+    /// The SHA-256 of the channel manifest
+    pub manifest_hash: String,
+    pub components: Vec<LockedComponent>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LockedComponent {
+    pub component: Component,
+    /// The SHA-256 of the archive the component is installed from
+    pub hash: String,
+}
+
+impl Lockfile {
+    /// Lock `components` of `toolchain` to the archives listed in `manifest`
+    pub(crate) fn new(
+        toolchain: &ToolchainDesc,
+        manifest: &Manifest,
+        manifest_hash: String,
+        components: &[Component],
+    ) -> Result<Self> {
+        let dated = ToolchainDesc {
+            date: Some(manifest.date.clone()),
+            ..toolchain.clone()
+        };
+        let mut unavailable = Vec::new();
+        let mut locked = Vec::new();
+        for component in components {
+            match archive(manifest, component)? {
+                Some(bin) => locked.push(LockedComponent {
+                    component: component.clone(),
+                    hash: bin.hash.clone(),
+                }),
+                None => unavailable.push(component.clone()),
+            }
+        }
+        if !unavailable.is_empty() {
+            bail!(RustupError::RequestedComponentsUnavailable {
+                components: unavailable,
+                manifest: manifest.clone(),
+                toolchain: dated.manifest_name(),
+            });
+        }
+        locked.sort_by(|a, b| a.component.cmp(&b.component));
+
+        Ok(Self {
+            lockfile_version: DEFAULT_LOCKFILE_VERSION.to_owned(),
+            toolchain: dated.to_string(),
+            manifest_hash,
+            components: locked,
+        })
+    }
+
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let data = utils::read_file("lockfile", path)?;
+        Self::parse(&data).with_context(|| format!("invalid lockfile '{}'", path.display()))
+    }
+
+    pub(crate) fn save(self, path: &Path) -> Result<()> {
+        utils::write_file("lockfile", path, &self.stringify())
+    }
+
+    /// The dated toolchain this lockfile pins
+    pub(crate) fn toolchain_desc(&self) -> Result<ToolchainDesc> {
+        ToolchainDesc::from_str(&self.toolchain)
+    }
+
+    pub(crate) fn locked_components(&self) -> Vec<Component> {
+        self.components
+            .iter()
+            .map(|c| c.component.clone())
+            .collect()
+    }
+
+    /// Check that the lockfile was generated for `toolchain`, which is the
+    /// toolchain named by the toolchain file next to it
+    pub(crate) fn check_toolchain(&self, toolchain: &ToolchainDesc) -> Result<()> {
+        let locked = self.toolchain_desc()?;
+        let date_matches = toolchain.date.is_none() || toolchain.date == locked.date;
+        if locked.channel != toolchain.channel || locked.target != toolchain.target || !date_matches
+        {
+            bail!(
+                "{} pins '{}', which is not a release of '{}'; run `rustup toolchain lock` to update it",
+                LOCKFILE_NAME,
+                self.toolchain,
+                toolchain
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the manifest downloaded from the dist server is the one
+    /// which was locked, and that it still lists the locked archives
+    pub(crate) fn verify(&self, manifest: &Manifest, manifest_hash: &str) -> Result<()> {
+        if manifest_hash != self.manifest_hash {
+            bail!(RustupError::LockfileMismatch {
+                what: format!("the manifest for '{}'", self.toolchain),
+                expected: self.manifest_hash.clone(),
+                calculated: manifest_hash.to_owned(),
+            });
+        }
+        for locked in &self.components {
+            let hash = archive(manifest, &locked.component)?
+                .map(|bin| bin.hash.clone())
+                .unwrap_or_default();
+            if hash != locked.hash {
+                bail!(RustupError::LockfileMismatch {
+                    what: locked.component.description(manifest),
+                    expected: locked.hash.clone(),
+                    calculated: hash,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn from_toml(mut table: toml::value::Table, path: &str) -> Result<Self> {
+        let lockfile_version = get_string(&mut table, "lockfile_version", path)?;
+        if !SUPPORTED_LOCKFILE_VERSIONS.contains(&&*lockfile_version) {
+            bail!(RustupError::UnsupportedVersion(lockfile_version));
+        }
+        let toolchain = get_string(&mut table, "toolchain", path)?;
+        let manifest_hash = get_string(&mut table, "manifest_hash", path)?;
+
+        let mut components = Vec::new();
+        let array = get_array(&mut table, "components", path)?;
+        for (i, v) in array.into_iter().enumerate() {
+            let path = format!("{}components.[{}]", path, i);
+            let mut t = match v {
+                toml::Value::Table(t) => t,
+                _ => return Err(anyhow!("expected type: 'table' for '{}'", path)),
+            };
+            let hash = get_string(&mut t, "hash", &path)?;
+            let component = Component::from_toml(t, &path, false)?;
+            components.push(LockedComponent { component, hash });
+        }
+
+        Ok(Self {
+            lockfile_version,
+            toolchain,
+            manifest_hash,
+            components,
+        })
+    }
+
+    pub(crate) fn into_toml(self) -> toml::value::Table {
+        let mut result = toml::value::Table::new();
+        result.insert(
+            "lockfile_version".to_owned(),
+            toml::Value::String(self.lockfile_version),
+        );
+        result.insert("toolchain".to_owned(), toml::Value::String(self.toolchain));
+        result.insert(
+            "manifest_hash".to_owned(),
+            toml::Value::String(self.manifest_hash),
+        );
+        let components = self
+            .components
+            .into_iter()
+            .map(|c| {
+                let mut t = c.component.into_toml();
+                t.insert("hash".to_owned(), toml::Value::String(c.hash));
+                toml::Value::Table(t)
+            })
+            .collect();
+        result.insert("components".to_owned(), toml::Value::Array(components));
+        result
+    }
+
+    pub(crate) fn parse(data: &str) -> Result<Self> {
+        let value = toml::from_str(data).context("error parsing lockfile")?;
+        Self::from_toml(value, "")
+    }
+
+    pub(crate) fn stringify(self) -> String {
+        format!(
+            "{}{}",
+            LOCKFILE_HEADER,
+            toml::Value::Table(self.into_toml())
+        )
+    }
+}
+
+/// The archive a component is installed from, which is the first one in
+/// order of preference, or `None` if it is unavailable
+pub(crate) fn archive<'m>(
+    manifest: &'m Manifest,
+    component: &Component,
+) -> Result<Option<&'m HashedBinary>> {
+    let package = manifest.get_package(component.short_name_in_manifest())?;
+    let target_package = package.get_target(component.target.as_ref())?;
+    Ok(target_package.bins.first().map(|(_, bin)| bin))
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::dist::dist::TargetTriple;
+
+    #[test]
+    fn lockfile_round_trip() {
+        let lockfile = Lockfile {
+            lockfile_version: DEFAULT_LOCKFILE_VERSION.to_owned(),
+            toolchain: "nightly-2021-06-17-x86_64-unknown-linux-gnu".to_owned(),
+            manifest_hash: "05b3abf2579a5eb66403cd78be557fd860633a1fe2103c7642030defe32c657f"
+                .to_owned(),
+            components: vec![
+                LockedComponent {
+                    component: Component::new(
+                        "rustc".to_owned(),
+                        Some(TargetTriple::new("x86_64-unknown-linux-gnu")),
+                        false,
+                    ),
+                    hash: "6fcd351889eb0caf54c459904e996ac7480a7843a5e8a25c8c6b59a0f975ca19"
+                        .to_owned(),
+                },
+                LockedComponent {
+                    component: Component::new("rust-src".to_owned(), None, false),
+                    hash: "99d5fd97c8630a67352336f1997f2c75e5cadc15426c5e25922326854585dcb5"
+                        .to_owned(),
+                },
+            ],
+        };
+        let data = lockfile.clone().stringify();
+        assert!(data.starts_with(LOCKFILE_HEADER));
+        assert_eq!(Lockfile::parse(&data).unwrap(), lockfile);
+    }
+
+    #[test]
+    fn unsupported_lockfile_version() {
+        let data = r#"
+lockfile_version = "2"
+toolchain = "stable-2021-06-17-x86_64-unknown-linux-gnu"
+manifest_hash = "05b3abf2579a5eb66403cd78be557fd860633a1fe2103c7642030defe32c657f"
+"#;
+        assert!(Lockfile::parse(data).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_another_manifest() {
+        let data = include_str!("../../tests/channel-rust-nightly-example.toml");
+        let manifest = Manifest::parse(data).unwrap();
+        let hash = format!("{:x}", Sha256::digest(data.as_bytes()));
+        let toolchain = ToolchainDesc::from_str("nightly-x86_64-unknown-linux-gnu").unwrap();
+        let lockfile = Lockfile::new(&toolchain, &manifest, hash.clone(), &[]).unwrap();
+        assert_eq!(lockfile.manifest_hash.len(), 64);
+        lockfile.verify(&manifest, &hash).unwrap();
+
+        let other = format!("{:x}", Sha256::digest(format!("{}\n", data).as_bytes()));
+        let e = lockfile.verify(&manifest, &other).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<RustupError>(),
+            Some(RustupError::LockfileMismatch { .. })
+        ));
+    }
+}
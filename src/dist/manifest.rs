@@ -109,6 +109,27 @@ impl Hash for Component {
 }
 
 impl Manifest {
+    /// A new manifest of the supported version, for building channels
+    pub(crate) fn new(
+        date: String,
+        packages: HashMap<String, Package>,
+        renames: HashMap<String, String>,
+        profiles: HashMap<Profile, Vec<String>>,
+    ) -> Self {
+        let reverse_renames = renames
+            .iter()
+            .map(|(from, to)| (to.clone(), from.clone()))
+            .collect();
+        Self {
+            manifest_version: SUPPORTED_MANIFEST_VERSIONS[0].to_owned(),
+            date,
+            packages,
+            renames,
+            reverse_renames,
+            profiles,
+        }
+    }
+
     pub fn parse(data: &str) -> Result<Self> {
         let value = toml::from_str(data).context("error parsing manifest")?;
         let manifest = Self::from_toml(value, "")?;
@@ -465,14 +486,17 @@ impl TargetedPackage {
     pub(crate) fn into_toml(self) -> toml::value::Table {
         let mut result = toml::value::Table::new();
         let (components, extensions) = Self::components_to_toml(self.components);
+        let has_components = !components.is_empty() || !extensions.is_empty();
         if !components.is_empty() {
             result.insert("components".to_owned(), toml::Value::Array(components));
         }
         if !extensions.is_empty() {
             result.insert("extensions".to_owned(), toml::Value::Array(extensions));
         }
+        // A package with components but no archive of its own, like `rust`
+        // in a channel without combined installers, is still available
         if self.bins.is_empty() {
-            result.insert("available".to_owned(), toml::Value::Boolean(false));
+            result.insert("available".to_owned(), toml::Value::Boolean(has_components));
         } else {
             for (kind, bin) in self.bins {
                 let url_key = format!("{}url", kind.key_prefix());
@@ -1,11 +1,10 @@
 //! Maintains a Rust installation by installing individual Rust
 //! platform components from a distribution server.
 
+use std::io::Read;
 use std::path::Path;
 
 use anyhow::{anyhow, bail, Context, Result};
-use retry::delay::NoDelay;
-use retry::{retry, OperationResult};
 
 use crate::config::PgpPublicKey;
 use crate::dist::component::{
@@ -13,13 +12,16 @@ use crate::dist::component::{
 };
 use crate::dist::config::Config;
 use crate::dist::dist::{Profile, TargetTriple, DEFAULT_DIST_SERVER};
-use crate::dist::download::{DownloadCfg, File};
+use crate::dist::download::{to_update_hash, DownloadCfg, Downloads, File};
 use crate::dist::manifest::{Component, CompressionKind, Manifest, TargetedPackage};
 use crate::dist::notifications::*;
 use crate::dist::prefix::InstallPrefix;
+use crate::dist::signatures::SignaturePolicy;
+use crate::dist::space::Estimate;
 use crate::dist::temp;
-use crate::errors::{OperationError, RustupError};
+use crate::errors::RustupError;
 use crate::process;
+use crate::utils::retry_policy::RetryPolicy;
 use crate::utils::utils;
 
 pub(crate) const DIST_MANIFEST: &str = "multirust-channel-manifest.toml";
@@ -35,6 +37,9 @@ pub struct Manifestation {
 pub struct Changes {
     pub explicit_add_components: Vec<Component>,
     pub remove_components: Vec<Component>,
+    /// Installed components whose files have been damaged, which are
+    /// installed again even if the manifest has not changed
+    pub repair_components: Vec<Component>,
 }
 
 impl Changes {
@@ -48,6 +53,11 @@ impl Changes {
                 bail!("can't both add and remove components");
             }
         }
+        for component_to_repair in &self.repair_components {
+            if self.remove_components.contains(component_to_repair) {
+                bail!("can't both repair and remove components");
+            }
+        }
         for component_to_remove in &self.remove_components {
             let config = config
                 .as_ref()
@@ -123,79 +133,91 @@ impl Manifestation {
         }
 
         // Validate that the requested components are available
-        match update.unavailable_components(new_manifest, toolchain_str) {
-            Ok(_) => {}
-            Err(e) => {
-                if force_update {
-                    if let Ok(RustupError::RequestedComponentsUnavailable { components, .. }) =
-                        e.downcast::<RustupError>()
-                    {
-                        for component in &components {
-                            notify_handler(Notification::ForcingUnavailableComponent(
-                                component.name(new_manifest).as_str(),
-                            ));
-                        }
-                        update.drop_components_to_install(&components);
-                    }
-                } else {
-                    return Err(e);
-                }
-            }
-        }
+        update.check_available(new_manifest, force_update, notify_handler, toolchain_str)?;
 
-        let altered = temp_cfg.dist_server != DEFAULT_DIST_SERVER;
+        let altered = download_cfg.dist_server != DEFAULT_DIST_SERVER;
 
-        // Download component packages and validate hashes
-        let mut things_to_install: Vec<(Component, CompressionKind, File)> = Vec::new();
+        // Download component packages and validate hashes and signatures
+        let mut things_to_install: Vec<(usize, Component, CompressionKind, Option<File>)> =
+            Vec::new();
         let mut things_downloaded: Vec<String> = Vec::new();
+        let mut things_verified: Vec<(String, Option<String>)> = Vec::new();
         let components = update.components_urls_and_hashes(new_manifest)?;
 
-        const DEFAULT_MAX_RETRIES: usize = 3;
-        let max_retries: usize = process()
-            .var("RUSTUP_MAX_RETRIES")
+        const DEFAULT_CONCURRENT_DOWNLOADS: usize = 4;
+        let concurrent_downloads: usize = process()
+            .var("RUSTUP_CONCURRENT_DOWNLOADS")
             .ok()
             .and_then(|s| s.parse().ok())
-            .unwrap_or(DEFAULT_MAX_RETRIES);
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_CONCURRENT_DOWNLOADS);
+        // Components are unpacked while they download, and installed once
+        // their hashes and signatures have been checked
+        let stream_install = process().var_os("RUSTUP_STREAM_INSTALL").is_some();
+
+        let mut urls = Vec::new();
+        let mut files = Vec::new();
+        for (_, _, url, hash) in &components {
+            let url = if altered {
+                url.replace(DEFAULT_DIST_SERVER, download_cfg.dist_server)
+            } else {
+                url.clone()
+            };
+            files.push((utils::parse_url(&url)?, hash.clone()));
+            things_downloaded.push(hash.clone());
+            urls.push(url);
+        }
 
-        for (component, format, url, hash) in components {
+        // Make sure the change fits on the disk before starting on it, unless
+        // the dist server is too slow to tell the sizes
+        if let Some(sizes) = download_cfg.download_sizes(&files, concurrent_downloads) {
+            let mut estimate = Estimate::default();
+            for ((component, format, _, _), archive) in components.iter().zip(sizes) {
+                estimate.add(&self.installation, component, *format, archive)?;
+            }
+            notify_handler(Notification::EstimatedSize(
+                estimate.download,
+                estimate.installed,
+                estimate.incomplete,
+            ));
+            estimate.check(
+                download_cfg.download_dir,
+                temp_cfg.root_directory(),
+                prefix.path(),
+            )?;
+        }
+
+        for (component, _, _, _) in &components {
             notify_handler(Notification::DownloadingComponent(
                 &component.short_name(new_manifest),
                 &self.target_triple,
                 component.target.as_ref(),
             ));
-            let url = if altered {
-                url.replace(DEFAULT_DIST_SERVER, temp_cfg.dist_server.as_str())
+        }
+
+        let mut downloads = download_cfg.start_downloads(files, concurrent_downloads)?;
+        let mut checked_download =
+            |downloads: &mut Downloads<'_>, index: usize, component: &Component| -> Result<File> {
+                let downloaded_file = downloads.wait(index).with_context(|| {
+                    RustupError::ComponentDownloadFailed(component.name(new_manifest))
+                })?;
+                let key = download_cfg.verify_signature(&urls[index], &downloaded_file)?;
+                things_verified.push((
+                    component.name_in_manifest(),
+                    key.map(PgpPublicKey::fingerprint),
+                ));
+                Ok(downloaded_file)
+            };
+        if !stream_install {
+            downloads.finish();
+        }
+        for (index, (component, format, _, _)) in components.into_iter().enumerate() {
+            let downloaded_file = if stream_install {
+                None
             } else {
-                url
+                Some(checked_download(&mut downloads, index, &component)?)
             };
-
-            let url_url = utils::parse_url(&url)?;
-
-            let downloaded_file = retry(NoDelay.take(max_retries), || {
-                match download_cfg.download(&url_url, &hash) {
-                    Ok(f) => OperationResult::Ok(f),
-                    Err(e) => {
-                        match e.downcast_ref::<RustupError>() {
-                            Some(RustupError::BrokenPartialFile) => {
-                                notify_handler(Notification::RetryingDownload(&url));
-                                return OperationResult::Retry(OperationError(e));
-                            }
-                            Some(RustupError::DownloadingFile { .. }) => {
-                                notify_handler(Notification::RetryingDownload(&url));
-                                return OperationResult::Retry(OperationError(e));
-                            }
-                            Some(_) => return OperationResult::Err(OperationError(e)),
-                            None => (),
-                        };
-                        OperationResult::Err(OperationError(e))
-                    }
-                }
-            })
-            .with_context(|| RustupError::ComponentDownloadFailed(component.name(new_manifest)))?;
-
-            things_downloaded.push(hash);
-
-            things_to_install.push((component, format, downloaded_file));
+            things_to_install.push((index, component, format, downloaded_file));
         }
 
         // Begin transaction
@@ -218,11 +240,12 @@ impl Manifestation {
                 component.target.as_ref(),
             ));
 
-            tx = self.uninstall_component(component, new_manifest, tx, &notify_handler)?;
+            let damaged = changes.repair_components.contains(component);
+            tx = self.uninstall_component(component, new_manifest, tx, &notify_handler, damaged)?;
         }
 
         // Install components
-        for (component, format, installer_file) in things_to_install {
+        for (index, component, format, installer_file) in things_to_install {
             // For historical reasons, the rust-installer component
             // names are not the same as the dist manifest component
             // names. Some are just the component name some are the
@@ -240,23 +263,35 @@ impl Manifestation {
             let notification_converter = |notification: crate::utils::Notification<'_>| {
                 notify_handler(notification.into());
             };
-            let gz;
-            let xz;
-            let zst;
-            let reader =
-                utils::FileReaderWithProgress::new_file(&installer_file, &notification_converter)?;
-            let package: &dyn Package = match format {
-                CompressionKind::GZip => {
-                    gz = TarGzPackage::new(reader, temp_cfg, Some(&notification_converter))?;
-                    &gz
-                }
-                CompressionKind::XZ => {
-                    xz = TarXzPackage::new(reader, temp_cfg, Some(&notification_converter))?;
-                    &xz
+            let package = match installer_file {
+                Some(installer_file) => {
+                    let reader = utils::FileReaderWithProgress::new_file(
+                        &installer_file,
+                        &notification_converter,
+                    )?;
+                    unpack(reader, format, temp_cfg, &notification_converter)?
                 }
-                CompressionKind::ZStd => {
-                    zst = TarZStdPackage::new(reader, temp_cfg, Some(&notification_converter))?;
-                    &zst
+                None => {
+                    let streamed = unpack(
+                        downloads.stream(index),
+                        format,
+                        temp_cfg,
+                        &notification_converter,
+                    );
+                    let installer_file = checked_download(&mut downloads, index, &component)?;
+                    match streamed {
+                        Ok(package) if !downloads.restarted(index) => package,
+                        Err(e) if !downloads.restarted(index) => return Err(e),
+                        // What was unpacked may not be what was downloaded
+                        // in the end, so unpack that instead
+                        _ => {
+                            let reader = utils::FileReaderWithProgress::new_file(
+                                &installer_file,
+                                &notification_converter,
+                            )?;
+                            unpack(reader, format, temp_cfg, &notification_converter)?
+                        }
+                    }
                 }
             };
 
@@ -268,11 +303,12 @@ impl Manifestation {
 
             tx = package.install(&self.installation, &pkg_name, Some(short_pkg_name), tx)?;
         }
+        downloads.finish();
 
         // Install new distribution manifest
         let new_manifest_str = new_manifest.clone().stringify();
         tx.modify_file(rel_installed_manifest_path)?;
-        utils::write_file("manifest", &installed_manifest_path, &new_manifest_str)?;
+        utils::write_file_atomically("manifest", &installed_manifest_path, &new_manifest_str)?;
 
         // Write configuration.
         //
@@ -282,11 +318,26 @@ impl Manifestation {
         // name/target. Needs to be fixed in rust-installer.
         let mut new_config = Config::new();
         new_config.components = update.final_component_list;
+        let mut signatures = config.map(|c| c.signatures).unwrap_or_default();
+        for (name, fingerprint) in things_verified {
+            if let Some(fingerprint) = fingerprint {
+                signatures.insert(name, fingerprint);
+            } else {
+                signatures.remove(&name);
+            }
+        }
+        signatures.retain(|name, _| {
+            new_config
+                .components
+                .iter()
+                .any(|c| c.name_in_manifest() == *name)
+        });
+        new_config.signatures = signatures;
         let config_str = new_config.stringify();
         let rel_config_path = prefix.rel_manifest_file(CONFIG_FILE);
         let config_path = prefix.path().join(&rel_config_path);
         tx.modify_file(rel_config_path)?;
-        utils::write_file("dist config", &config_path, &config_str)?;
+        utils::write_file_atomically("dist config", &config_path, &config_str)?;
 
         // End transaction
         tx.commit();
@@ -296,6 +347,25 @@ impl Manifestation {
         Ok(UpdateStatus::Changed)
     }
 
+    /// Works out what `update` would do with the same arguments, without
+    /// downloading or changing anything
+    pub(crate) fn plan(
+        &self,
+        new_manifest: &Manifest,
+        changes: &Changes,
+        force_update: bool,
+        notify_handler: &dyn Fn(Notification<'_>),
+        toolchain_str: &str,
+    ) -> Result<Plan> {
+        let config = self.read_config()?;
+        let mut update =
+            Update::build_update(self, new_manifest, changes, &config, notify_handler)?;
+        if !update.nothing_changes() {
+            update.check_available(new_manifest, force_update, notify_handler, toolchain_str)?;
+        }
+        Ok(update.plan(new_manifest))
+    }
+
     pub fn uninstall(
         &self,
         manifest: &Manifest,
@@ -313,7 +383,7 @@ impl Manifestation {
         tx.remove_file("dist config", rel_config_path)?;
 
         for component in config.components {
-            tx = self.uninstall_component(&component, manifest, tx, notify_handler)?;
+            tx = self.uninstall_component(&component, manifest, tx, notify_handler, false)?;
         }
         tx.commit();
 
@@ -326,6 +396,7 @@ impl Manifestation {
         manifest: &Manifest,
         mut tx: Transaction<'a>,
         notify_handler: &dyn Fn(Notification<'_>),
+        damaged: bool,
     ) -> Result<Transaction<'a>> {
         // For historical reasons, the rust-installer component
         // names are not the same as the dist manifest component
@@ -333,10 +404,16 @@ impl Manifestation {
         // component name plus the target triple.
         let name = component.name_in_manifest();
         let short_name = component.short_name_in_manifest();
-        if let Some(c) = self.installation.find(&name)? {
-            tx = c.uninstall(tx)?;
-        } else if let Some(c) = self.installation.find(short_name)? {
-            tx = c.uninstall(tx)?;
+        let installed = match self.installation.find(&name)? {
+            Some(c) => Some(c),
+            None => self.installation.find(short_name)?,
+        };
+        if let Some(c) = installed {
+            tx = if damaged {
+                c.uninstall_damaged(tx)?
+            } else {
+                c.uninstall(tx)?
+            };
         } else {
             notify_handler(Notification::MissingInstalledComponent(
                 &component.short_name(manifest),
@@ -377,8 +454,11 @@ impl Manifestation {
         new_manifest: &[String],
         update_hash: Option<&Path>,
         temp_cfg: &temp::Cfg,
+        dist_server: &str,
         notify_handler: &dyn Fn(Notification<'_>),
         pgp_keys: &[PgpPublicKey],
+        signature_policy: SignaturePolicy,
+        retry_policy: &RetryPolicy,
     ) -> Result<Option<String>> {
         // If there's already a v2 installation then something has gone wrong
         if self.read_config()?.is_some() {
@@ -397,9 +477,7 @@ impl Manifestation {
             ));
         }
         // Only replace once. The cost is inexpensive.
-        let url = url
-            .unwrap()
-            .replace(DEFAULT_DIST_SERVER, temp_cfg.dist_server.as_str());
+        let url = url.unwrap().replace(DEFAULT_DIST_SERVER, dist_server);
 
         notify_handler(Notification::DownloadingComponent(
             "rust",
@@ -411,10 +489,15 @@ impl Manifestation {
         let dld_dir = PathBuf::from("bogus");
         let dlcfg = DownloadCfg {
             dist_root: "bogus",
+            dist_server,
+            mirrors: &[],
             download_dir: &dld_dir,
             temp_cfg,
             notify_handler,
             pgp_keys,
+            signature_policy,
+            retry_policy,
+            cache: None,
         };
 
         let dl = dlcfg.download_and_check(&url, update_hash, ".tar.gz")?;
@@ -456,7 +539,7 @@ impl Manifestation {
         // End transaction
         tx.commit();
 
-        Ok(Some(installer_hash))
+        Ok(Some(to_update_hash(&installer_hash)))
     }
 
     // If the previous installation was from a v1 manifest, then it
@@ -483,6 +566,43 @@ impl Manifestation {
     }
 }
 
+/// Unpack the component archive read from `reader`, compressed with `format`
+fn unpack<'a, R: Read>(
+    reader: R,
+    format: CompressionKind,
+    temp_cfg: &'a temp::Cfg,
+    notify_handler: &'a dyn Fn(crate::utils::Notification<'_>),
+) -> Result<Box<dyn Package + 'a>> {
+    Ok(match format {
+        CompressionKind::GZip => {
+            Box::new(TarGzPackage::new(reader, temp_cfg, Some(notify_handler))?)
+        }
+        CompressionKind::XZ => Box::new(TarXzPackage::new(reader, temp_cfg, Some(notify_handler))?),
+        CompressionKind::ZStd => {
+            Box::new(TarZStdPackage::new(reader, temp_cfg, Some(notify_handler))?)
+        }
+    })
+}
+
+/// What an update would do to an installation, as worked out by
+/// `Manifestation::plan`
+#[derive(Debug)]
+pub(crate) struct Plan {
+    /// The date of the release the installation would be at
+    pub(crate) date: String,
+    pub(crate) rust_version: Option<String>,
+    pub(crate) install: Vec<String>,
+    pub(crate) uninstall: Vec<String>,
+    /// The components left as they are
+    pub(crate) keep: Vec<String>,
+}
+
+impl Plan {
+    pub(crate) fn nothing_changes(&self) -> bool {
+        self.install.is_empty() && self.uninstall.is_empty()
+    }
+}
+
 #[derive(Debug)]
 struct Update {
     components_to_uninstall: Vec<Component>,
@@ -560,6 +680,9 @@ impl Update {
             for component in &result.final_component_list {
                 if !starting_list.contains(component) {
                     result.components_to_install.push(component.clone());
+                } else if changes.repair_components.contains(component) {
+                    result.components_to_uninstall.push(component.clone());
+                    result.components_to_install.push(component.clone());
                 } else if changes.explicit_add_components.contains(component) {
                     notify_handler(Notification::ComponentAlreadyInstalled(
                         &component.description(new_manifest),
@@ -664,6 +787,53 @@ impl Update {
         Ok(())
     }
 
+    /// Fails unless every component to install is available, or drops
+    /// those which are not if `force_update` is set
+    fn check_available(
+        &mut self,
+        new_manifest: &Manifest,
+        force_update: bool,
+        notify_handler: &dyn Fn(Notification<'_>),
+        toolchain_str: &str,
+    ) -> Result<()> {
+        match self.unavailable_components(new_manifest, toolchain_str) {
+            Ok(_) => {}
+            Err(e) => {
+                if force_update {
+                    if let Ok(RustupError::RequestedComponentsUnavailable { components, .. }) =
+                        e.downcast::<RustupError>()
+                    {
+                        for component in &components {
+                            notify_handler(Notification::ForcingUnavailableComponent(
+                                component.name(new_manifest).as_str(),
+                            ));
+                        }
+                        self.drop_components_to_install(&components);
+                    }
+                } else {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn plan(&self, new_manifest: &Manifest) -> Plan {
+        let name = |c: &Component| c.name(new_manifest);
+        Plan {
+            date: new_manifest.date.clone(),
+            rust_version: new_manifest.get_rust_version().ok().map(str::to_owned),
+            install: self.components_to_install.iter().map(name).collect(),
+            uninstall: self.components_to_uninstall.iter().map(name).collect(),
+            keep: self
+                .final_component_list
+                .iter()
+                .filter(|c| !self.components_to_install.contains(c))
+                .map(name)
+                .collect(),
+        }
+    }
+
     fn drop_components_to_install(&mut self, to_drop: &[Component]) {
         let components: Vec<_> = self
             .components_to_install
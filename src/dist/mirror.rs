@@ -0,0 +1,213 @@
+//! Mirrors of the dist server.
+//!
+//! `rustup mirror sync` copies the manifests of some channels, and the
+//! archives those manifests list for some targets, into a directory laid out
+//! like the dist server, ready to be served and used as `RUSTUP_DIST_SERVER`.
+//! Archives are only moved into place once their hash and signature have
+//! been checked, so whatever is in the mirror is complete, and syncing again
+//! only downloads what is missing. Manifests are written last, so a mirror
+//! never serves a manifest listing archives it does not have.
+
+use std::collections::HashSet;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Duration, NaiveDate, Utc};
+
+use super::dist::{self, PartialToolchainDesc, TargetTriple, DEFAULT_DIST_SERVER};
+use super::download::{file_url, with_suffix, DownloadCfg, SIGNED_SUFFIXES};
+use super::manifest::{HashedBinary, Manifest};
+use super::notifications::Notification;
+use crate::errors::RustupError;
+use crate::utils::utils;
+
+const DIST_DIR: &str = "dist";
+/// Downloads in progress are kept in the mirror, so they can be moved into
+/// place without copying
+const DOWNLOADS_DIR: &str = ".downloads";
+
+/// Sync `channels` for `targets` into the mirror at `dest`, then remove the
+/// dated directories which are more than `keep_days` old
+pub(crate) fn sync(
+    download: DownloadCfg<'_>,
+    channels: &[&str],
+    targets: &[TargetTriple],
+    dest: &Path,
+    keep_days: Option<u32>,
+) -> Result<()> {
+    let download_dir = dest.join(DOWNLOADS_DIR);
+    let download = DownloadCfg {
+        download_dir: &download_dir,
+        ..download
+    };
+    utils::ensure_dir_exists("mirror", &download_dir, &download.notify_handler)?;
+
+    for channel in channels {
+        dist::with_mirrors(download, |download| {
+            sync_channel(download, channel, targets, dest)
+        })?;
+    }
+    if let Some(keep_days) = keep_days {
+        prune(download, &dest.join(DIST_DIR), keep_days)?;
+    }
+    Ok(())
+}
+
+fn sync_channel(
+    download: DownloadCfg<'_>,
+    channel: &str,
+    targets: &[TargetTriple],
+    dest: &Path,
+) -> Result<()> {
+    let desc = PartialToolchainDesc::from_str(channel)?;
+    if desc.date.is_some() || desc.has_triple() {
+        bail!(
+            "'{}' is not a channel; expected a name like 'stable' or '1.53.0'",
+            channel
+        );
+    }
+    let name = format!("channel-rust-{}.toml", channel);
+
+    (download.notify_handler)(Notification::DownloadingManifest(channel));
+    let staged = download.download_dir.join(&name);
+    download.download_signed(&format!("{}/{}", download.dist_root, name), &staged)?;
+    // Check the manifest as it is checked when installing from the mirror
+    download.download_and_check(&file_url(&staged)?, None, ".toml")?;
+    let manifest = Manifest::parse(&utils::read_file("manifest", &staged)?)?;
+    (download.notify_handler)(Notification::DownloadedManifest(
+        &manifest.date,
+        manifest.get_rust_version().ok(),
+    ));
+
+    for archive in archives(&manifest, targets) {
+        sync_archive(download, archive, dest)?;
+    }
+
+    let dist_dir = dest.join(DIST_DIR);
+    for dir in [dist_dir.join(&manifest.date), dist_dir] {
+        utils::ensure_dir_exists("mirror", &dir, &download.notify_handler)?;
+        for suffix in SIGNED_SUFFIXES {
+            let src = with_suffix(&staged, suffix);
+            if utils::is_file(&src) {
+                utils::copy_file(&src, &with_suffix(&dir.join(&name), suffix))?;
+            }
+        }
+    }
+    for suffix in SIGNED_SUFFIXES {
+        utils::ensure_file_removed("manifest", &with_suffix(&staged, suffix))?;
+    }
+    Ok(())
+}
+
+/// The archives of every component which `rustup` may install on any of
+/// `targets`, in every compression format
+fn archives<'m>(manifest: &'m Manifest, targets: &[TargetTriple]) -> Vec<&'m HashedBinary> {
+    let mut seen = HashSet::new();
+    let mut archives = Vec::new();
+    let rust = match manifest.get_package("rust") {
+        Ok(rust) => rust,
+        Err(_) => return archives,
+    };
+    for target in targets {
+        // Targets which are not hosts only have their standard library,
+        // which is a component of the host toolchains
+        let host = match rust.get_target(Some(target)) {
+            Ok(host) => host,
+            Err(_) => continue,
+        };
+        for component in &host.components {
+            if !component
+                .target
+                .as_ref()
+                .map_or(true, |t| targets.contains(t))
+            {
+                continue;
+            }
+            let package = manifest
+                .get_package(component.short_name_in_manifest())
+                .and_then(|p| p.get_target(component.target.as_ref()));
+            for (_, bin) in package.iter().flat_map(|p| &p.bins) {
+                if seen.insert(&bin.hash) {
+                    archives.push(bin);
+                }
+            }
+        }
+    }
+    archives
+}
+
+fn sync_archive(download: DownloadCfg<'_>, archive: &HashedBinary, dest: &Path) -> Result<()> {
+    let dist_server = download.dist_server;
+    let url = if dist_server != DEFAULT_DIST_SERVER {
+        archive.url.replace(DEFAULT_DIST_SERVER, dist_server)
+    } else {
+        archive.url.clone()
+    };
+    // The archive goes to the same place in the mirror as on the dist server
+    let relative = url
+        .strip_prefix(dist_server)
+        .map(|p| Path::new(p.trim_start_matches('/')))
+        .filter(|p| p.components().all(|c| matches!(c, Component::Normal(_))))
+        .ok_or_else(|| anyhow!("'{}' is not on the dist server '{}'", url, dist_server))?;
+    let path = dest.join(relative);
+    if utils::is_file(&path) {
+        return Ok(());
+    }
+
+    (download.notify_handler)(Notification::MirroringArchive(&url));
+    let file = download.download_to_dir(&utils::parse_url(&url)?, &archive.hash)?;
+    download.verify_signature(&url, &file)?;
+
+    utils::ensure_dir_exists("mirror", path.parent().unwrap(), &download.notify_handler)?;
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    utils::write_file(
+        "checksum",
+        &with_suffix(&path, ".sha256"),
+        &format!("{}  {}\n", archive.hash, file_name),
+    )?;
+    let signature = with_suffix(&file, ".asc");
+    if utils::is_file(&signature) {
+        utils::rename_file(
+            "signature",
+            &signature,
+            &with_suffix(&path, ".asc"),
+            &download.notify_handler,
+        )?;
+    }
+    // Last, as the archive being there means that it is complete
+    utils::rename_file("archive", &file, &path, &download.notify_handler)
+}
+
+/// Remove the dated directories older than `keep_days`, except for the
+/// dates of the manifests the mirror currently serves
+fn prune(download: DownloadCfg<'_>, dist_dir: &Path, keep_days: u32) -> Result<()> {
+    let mut entries = Vec::new();
+    for entry in utils::read_dir("mirror", dist_dir)? {
+        let entry = entry.with_context(|| RustupError::ReadingDirectory {
+            name: "mirror",
+            path: dist_dir.to_owned(),
+        })?;
+        entries.push(entry.path());
+    }
+
+    let mut current = HashSet::new();
+    for path in &entries {
+        let name = path.file_name().unwrap().to_string_lossy();
+        if name.starts_with("channel-rust-") && name.ends_with(".toml") {
+            let manifest = Manifest::parse(&utils::read_file("manifest", path)?)?;
+            current.insert(manifest.date);
+        }
+    }
+
+    let oldest = Utc::today().naive_utc() - Duration::days(keep_days.into());
+    for path in &entries {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let expired = NaiveDate::parse_from_str(&name, "%Y-%m-%d").map_or(false, |d| d < oldest);
+        if expired && utils::is_directory(path) && !current.contains(&*name) {
+            (download.notify_handler)(Notification::PruningMirror(path));
+            utils::remove_dir("mirror", path, &download.notify_handler)?;
+        }
+    }
+    Ok(())
+}
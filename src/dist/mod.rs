@@ -1,17 +1,26 @@
 //! Installation from a Rust distribution server
 
 pub use crate::dist::notifications::Notification;
+pub use crate::dist::signatures::SignaturePolicy;
 
 pub mod temp;
 
+pub(crate) mod bundle;
+pub(crate) mod cache;
+pub(crate) mod channel;
 pub mod component;
 pub(crate) mod config;
 #[allow(clippy::module_inception)]
 pub mod dist;
 pub mod download;
+pub(crate) mod lockfile;
 pub mod manifest;
 pub mod manifestation;
+pub(crate) mod mirror;
 pub(crate) mod notifications;
 pub mod prefix;
 pub(crate) mod signatures;
+pub(crate) mod space;
+pub(crate) mod staging;
 pub(crate) mod triple;
+pub(crate) mod versions;
@@ -3,6 +3,7 @@ use crate::dist::dist::{TargetTriple, ToolchainDesc};
 use crate::dist::manifest::Component;
 use crate::dist::temp;
 use crate::utils::notify::NotificationLevel;
+use crate::utils::units::human_size;
 use std::fmt::{self, Display};
 use std::path::Path;
 
@@ -25,6 +26,9 @@ pub enum Notification<'a> {
     ExtensionNotInstalled(&'a str),
     NonFatalError(&'a anyhow::Error),
     MissingInstalledComponent(&'a str),
+    /// The bytes left to download and the bytes installed, and whether the
+    /// size of some archive is unknown
+    EstimatedSize(u64, u64, bool),
     DownloadingComponent(&'a str, &'a TargetTriple, Option<&'a TargetTriple>),
     InstallingComponent(&'a str, &'a TargetTriple, Option<&'a TargetTriple>),
     RemovingComponent(&'a str, &'a TargetTriple, Option<&'a TargetTriple>),
@@ -39,6 +43,15 @@ pub enum Notification<'a> {
     StrayHash(&'a Path),
     SignatureInvalid(&'a str),
     RetryingDownload(&'a str),
+    MirroringArchive(&'a str),
+    PruningMirror(&'a Path),
+    AddingToChannel(&'a str),
+    FallingBackToMirror(&'a str, &'a str),
+    EvictingCachedFile(&'a str),
+    RollingBackInterrupted(&'a Path),
+    FinishingInterrupted(&'a Path),
+    StagingToolchain(&'a Path),
+    KeepingRetiredToolchain(&'a Path),
 }
 
 impl<'a> From<crate::utils::Notification<'a>> for Notification<'a> {
@@ -63,8 +76,13 @@ impl<'a> Notification<'a> {
             | SignatureValid(_, _)
             | NoUpdateHash(_)
             | FileAlreadyDownloaded
+            | EvictingCachedFile(_)
+            | FinishingInterrupted(_)
+            | StagingToolchain(_)
+            | KeepingRetiredToolchain(_)
             | DownloadingLegacyManifest => NotificationLevel::Verbose,
             Extracting(_, _)
+            | EstimatedSize(_, _, _)
             | DownloadingComponent(_, _, _)
             | InstallingComponent(_, _, _)
             | RemovingComponent(_, _, _)
@@ -75,6 +93,9 @@ impl<'a> Notification<'a> {
             | DownloadingManifest(_)
             | SkippingNightlyMissingComponent(_, _, _)
             | RetryingDownload(_)
+            | MirroringArchive(_)
+            | PruningMirror(_)
+            | AddingToChannel(_)
             | DownloadedManifest(_, _) => NotificationLevel::Info,
             CantReadUpdateHash(_)
             | ExtensionNotInstalled(_)
@@ -82,6 +103,8 @@ impl<'a> Notification<'a> {
             | CachedFileChecksumFailed
             | ComponentUnavailable(_, _)
             | ForcingUnavailableComponent(_)
+            | FallingBackToMirror(_, _)
+            | RollingBackInterrupted(_)
             | StrayHash(_) => NotificationLevel::Warn,
             NonFatalError(_) => NotificationLevel::Error,
             SignatureInvalid(_) => NotificationLevel::Warn,
@@ -114,11 +137,39 @@ impl<'a> Display for Notification<'a> {
             FileAlreadyDownloaded => write!(f, "reusing previously downloaded file"),
             CachedFileChecksumFailed => write!(f, "bad checksum for cached download"),
             RollingBack => write!(f, "rolling back changes"),
+            RollingBackInterrupted(path) => write!(
+                f,
+                "rolling back an interrupted installation in '{}'",
+                path.display()
+            ),
+            FinishingInterrupted(path) => write!(
+                f,
+                "finishing an interrupted installation in '{}'",
+                path.display()
+            ),
+            StagingToolchain(path) => write!(f, "staging changes to '{}'", path.display()),
+            KeepingRetiredToolchain(path) => write!(
+                f,
+                "keeping '{}' until the processes running it exit",
+                path.display()
+            ),
             ExtensionNotInstalled(c) => write!(f, "extension '{}' was not installed", c),
             NonFatalError(e) => write!(f, "{}", e),
             MissingInstalledComponent(c) => {
                 write!(f, "during uninstall component {} was not found", c)
             }
+            EstimatedSize(download, installed, incomplete) => {
+                if *download == 0 {
+                    write!(f, "nothing to download")?;
+                } else {
+                    write!(f, "{} to download", human_size(*download))?;
+                }
+                write!(f, ", about {} once installed", human_size(*installed))?;
+                if *incomplete {
+                    write!(f, " (the size of some components is unknown)")?;
+                }
+                Ok(())
+            }
             DownloadingComponent(c, h, t) => {
                 if Some(h) == t.as_ref() || t.is_none() {
                     write!(f, "downloading component '{}'", c)
@@ -196,6 +247,15 @@ impl<'a> Display for Notification<'a> {
             }
             SignatureInvalid(url) => write!(f, "Signature verification failed for '{}'", url),
             RetryingDownload(url) => write!(f, "retrying download for '{}'", url),
+            MirroringArchive(url) => write!(f, "mirroring '{}'", url),
+            PruningMirror(path) => write!(f, "removing '{}' from the mirror", path.display()),
+            AddingToChannel(file) => write!(f, "adding '{}' to the channel", file),
+            FallingBackToMirror(server, mirror) => write!(
+                f,
+                "could not download from '{}', falling back to '{}'",
+                server, mirror
+            ),
+            EvictingCachedFile(hash) => write!(f, "evicting '{}' from the download cache", hash),
         }
     }
 }
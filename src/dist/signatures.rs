@@ -2,9 +2,11 @@
 
 // TODO: Determine whether we want external keyring support
 
+use std::fmt;
 use std::io::Read;
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use sequoia_openpgp::{
     parse::{stream::*, Parse},
@@ -13,14 +15,66 @@ use sequoia_openpgp::{
 
 use crate::config::PgpPublicKey;
 
+/// What to do when a signature does not verify, or cannot be found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Print a warning and carry on
+    Warn,
+    /// Refuse to use the unverified file
+    Require,
+    /// Don't check signatures at all
+    Off,
+}
+
+impl SignaturePolicy {
+    pub(crate) fn names() -> &'static [&'static str] {
+        &["warn", "require", "off"]
+    }
+}
+
+impl Default for SignaturePolicy {
+    fn default() -> Self {
+        Self::Warn
+    }
+}
+
+impl FromStr for SignaturePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "warn" => Ok(Self::Warn),
+            "require" => Ok(Self::Require),
+            "off" => Ok(Self::Off),
+            _ => Err(anyhow!(
+                "unknown signature policy: '{}'; valid policies are: {}",
+                name,
+                Self::names()
+                    .iter()
+                    .map(|s| format!("'{}'", s))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SignaturePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Warn => write!(f, "warn"),
+            Self::Require => write!(f, "require"),
+            Self::Off => write!(f, "off"),
+        }
+    }
+}
+
 /// Returns the index of the cert in `certs` that verifies a
 /// signature.
 ///
-/// Ignores any signatures that are bad for any reason.  If no
-/// signature could be verified, returns `None`.
-// XXX: This is a bit of an odd policy.  Shouldn't we fail if we
-// encounter a single bad signature (bad as in checksum doesn't check
-// out, not bad as in we don't have the key)?
+/// Fails if any signature is bad, i.e. malformed or not matching the
+/// content. Signatures made by keys we don't have, or can't use, are
+/// ignored. If no signature could be verified, returns `None`.
 pub(crate) fn verify_signature<T: Read + Send + Sync>(
     content: T,
     signature: &str,
@@ -56,13 +110,25 @@ impl VerificationHelper for Helper<'_> {
         for layer in structure.into_iter() {
             match layer {
                 MessageLayer::SignatureGroup { results } => {
-                    // We ignore any errors for now.
-                    for GoodChecksum { ka, .. } in results.into_iter().flatten() {
-                        // A good signature!  Find the index
-                        // of the signer key and return
-                        // success.
-                        self.index = self.certs.iter().position(|c| c.cert() == ka.cert());
-                        assert!(self.index.is_some());
+                    for result in results {
+                        match result {
+                            Ok(GoodChecksum { ka, .. }) => {
+                                // A good signature!  Find the index
+                                // of the signer key.
+                                self.index = self.certs.iter().position(|c| c.cert() == ka.cert());
+                                assert!(self.index.is_some());
+                            }
+                            Err(VerificationError::BadSignature { error, .. }) => {
+                                return Err(error.context("bad signature"));
+                            }
+                            Err(VerificationError::MalformedSignature { error, .. }) => {
+                                return Err(error.context("malformed signature"));
+                            }
+                            // Signatures from keys we don't know, or
+                            // which aren't usable, don't make the
+                            // content any less trustworthy.
+                            Err(_) => {}
+                        }
                     }
                 }
                 MessageLayer::Compression { .. } => {
@@ -0,0 +1,124 @@
+//! How much room a change to a toolchain takes, worked out before anything
+//! is downloaded, so that a change which does not fit on the disk fails at
+//! once rather than halfway through unpacking.
+//!
+//! The size of each download is the length of the file when it is on the
+//! disk already, in the download directory, the shared cache or a dist
+//! server which is a local directory, and otherwise the Content-Length the
+//! server, or failing that one of its mirrors, answers a HEAD request with.
+//! The room a component takes once installed is what the version it
+//! replaces takes, or failing that a guess from the size of its archive.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::dist::component::Components;
+use crate::dist::manifest::{Component, CompressionKind};
+use crate::errors::RustupError;
+use crate::utils::raw::FileSystem;
+use crate::utils::utils;
+
+/// Roughly how many times larger a component is once unpacked than an
+/// archive of `kind` of it
+fn unpacked_ratio(kind: CompressionKind) -> u64 {
+    match kind {
+        CompressionKind::GZip => 3,
+        CompressionKind::ZStd => 4,
+        CompressionKind::XZ => 5,
+    }
+}
+
+/// The sizes of a change to a toolchain
+#[derive(Debug, Default)]
+pub(crate) struct Estimate {
+    /// The bytes left to download
+    pub(crate) download: u64,
+    /// The bytes the components installed take
+    pub(crate) installed: u64,
+    /// Whether the size of some archive could not be found out, which
+    /// leaves the totals short
+    pub(crate) incomplete: bool,
+    /// The bytes the largest component takes, as each one is unpacked on
+    /// its own before it is installed
+    largest: u64,
+}
+
+impl Estimate {
+    /// Counts in installing `component` in `installation` from an archive
+    /// of `kind`, given its size and how much of it is left to download
+    pub(crate) fn add(
+        &mut self,
+        installation: &Components,
+        component: &Component,
+        kind: CompressionKind,
+        archive: Option<(u64, u64)>,
+    ) -> Result<()> {
+        let prefix = installation.prefix();
+        let replaced: u64 = match installation.find(&component.name_in_manifest())? {
+            Some(installed) => installed
+                .parts()?
+                .iter()
+                .map(|part| utils::disk_usage(&prefix.abs_path(&part.1)))
+                .sum(),
+            None => 0,
+        };
+        if let Some((_, left)) = archive {
+            self.download += left;
+        }
+        let unpacked = match archive {
+            _ if replaced > 0 => replaced,
+            Some((size, _)) => size.saturating_mul(unpacked_ratio(kind)),
+            None => {
+                self.incomplete = true;
+                0
+            }
+        };
+        self.installed += unpacked;
+        self.largest = self.largest.max(unpacked);
+        Ok(())
+    }
+
+    /// Makes sure there is room for the downloads in `download_dir`, for
+    /// unpacking the components in `temp_dir` and for installing them in
+    /// `prefix`
+    pub(crate) fn check(&self, download_dir: &Path, temp_dir: &Path, prefix: &Path) -> Result<()> {
+        let (toolchain, temp, downloads) = match (
+            utils::file_system(prefix),
+            utils::file_system(temp_dir),
+            utils::file_system(download_dir),
+        ) {
+            (Ok(toolchain), Ok(temp), Ok(downloads)) => (toolchain, temp, downloads),
+            // Not every file system says how much room is left on it
+            _ => return Ok(()),
+        };
+        // Unpacked files are moved into the toolchain, which takes no more
+        // room when both are on the same file system
+        let unpacking = if temp.same_as(&toolchain) {
+            0
+        } else {
+            self.largest
+        };
+        let needs: [(&Path, &FileSystem, u64); 3] = [
+            (prefix, &toolchain, self.installed),
+            (temp_dir, &temp, unpacking),
+            (download_dir, &downloads, self.download),
+        ];
+        for &(path, file_system, _) in &needs {
+            let needed: u64 = needs
+                .iter()
+                .filter(|(_, other, _)| other.same_as(file_system))
+                .map(|&(_, _, bytes)| bytes)
+                .sum();
+            if needed > file_system.free {
+                return Err(RustupError::NotEnoughSpace {
+                    path: path.to_owned(),
+                    needed,
+                    free: file_system.free,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+//! Staging directories, in which a toolchain is changed before the change
+//! is swapped into place all at once.
+//!
+//! Changes to a toolchain are made in a copy of its tree called
+//! `toolchains/.staging-<name>`, whose files are hard links to those of the
+//! live tree wherever the file system allows it. Rustup never writes to an
+//! installed file, it only ever replaces it, so changing the copy leaves
+//! the live tree alone. Once the changes are done the live tree is renamed
+//! to `toolchains/.old-<name>-<n>` and the copy is renamed into its place,
+//! so that a process which starts running the toolchain sees either all of
+//! the old toolchain or all of the new one.
+//!
+//! Processes running a toolchain hold a shared lock on the
+//! `rustup-in-use.lock` file of its tree, which goes with the tree when it
+//! is retired. A retired tree is removed by the first rustup process to
+//! find that nobody holds that lock any more.
+//!
+//! Only the process holding the lock of a toolchain stages changes to it,
+//! so a staging directory left behind by a process which was killed is
+//! simply removed by the next one, unless the process was killed between
+//! the two renames of the swap. Then the staging directory is put in place
+//! the next time rustup starts.
+//!
+//! A live tree may be kept as a previous version of its toolchain rather
+//! than retired, see the `versions` module.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::dist::component::has_transaction_journal;
+use crate::dist::notifications::*;
+use crate::dist::prefix::InstallPrefix;
+use crate::dist::versions;
+use crate::utils::lock::Lock;
+use crate::utils::utils;
+
+const STAGING_PREFIX: &str = ".staging-";
+const RETIRED_PREFIX: &str = ".old-";
+const IN_USE_LOCK: &str = "rustup-in-use.lock";
+
+/// A copy of a toolchain tree in which to change it, which is removed when
+/// dropped unless it was committed
+pub(crate) struct Staging<'a> {
+    live: PathBuf,
+    path: PathBuf,
+    staged: bool,
+    versions: usize,
+    notify_handler: &'a dyn Fn(Notification<'_>),
+}
+
+impl<'a> Staging<'a> {
+    /// Prepares to change the toolchain tree at `live`, which need not
+    /// exist yet
+    pub(crate) fn new(live: &Path, notify_handler: &'a dyn Fn(Notification<'_>)) -> Result<Self> {
+        let path = sibling(live, STAGING_PREFIX, "");
+        remove_staged(&path, notify_handler)?;
+        Ok(Self {
+            live: live.to_owned(),
+            path,
+            staged: false,
+            versions: 0,
+            notify_handler,
+        })
+    }
+
+    /// Keeps the live tree as a previous version when the change replaces
+    /// it with another version, along with `count` versions in all
+    pub(crate) fn keep_versions(&mut self, count: usize) {
+        self.versions = count;
+    }
+
+    /// The prefix to make the changes in, which is copied from the live
+    /// tree the first time it is asked for
+    pub(crate) fn prefix(&mut self) -> Result<InstallPrefix> {
+        if !self.staged {
+            if utils::is_directory(&self.live) {
+                (self.notify_handler)(Notification::StagingToolchain(&self.live));
+                let in_use_lock = in_use_lock_path(&self.live);
+                utils::hardlink_dir(&self.live, &self.path, &|p: &Path| p == in_use_lock)?;
+            }
+            self.staged = true;
+        }
+        Ok(InstallPrefix::from(self.path.clone()))
+    }
+
+    /// Stages a copy of the toolchain tree `tree` to replace the live one
+    pub(crate) fn stage_copy_of(&mut self, tree: &Path) -> Result<()> {
+        remove_staged(&self.path, self.notify_handler)?;
+        (self.notify_handler)(Notification::StagingToolchain(&self.live));
+        let in_use_lock = in_use_lock_path(tree);
+        self.staged = true;
+        utils::hardlink_dir(tree, &self.path, &|p: &Path| p == in_use_lock)?;
+        Ok(())
+    }
+
+    /// Swaps the changed tree into the place of the live one, if anything
+    /// was changed, and removes the old tree unless it is still in use
+    pub(crate) fn commit(mut self) -> Result<()> {
+        if !self.staged || !utils::path_exists(&self.path) {
+            return Ok(());
+        }
+        let notify_handler = self.notify_handler;
+        let kept = if self.versions > 0 {
+            versions::keep_path(&self.live, &self.path, notify_handler)?
+        } else {
+            None
+        };
+        let retired = if utils::is_directory(&self.live) {
+            let retired = kept.clone().unwrap_or_else(|| retired_path(&self.live));
+            utils::rename_dir("toolchain", &self.live, &retired, notify_handler)?;
+            Some(retired)
+        } else {
+            None
+        };
+        if let Err(e) = utils::rename_dir("toolchain", &self.path, &self.live, notify_handler) {
+            // Better the old toolchain than none at all
+            if let Some(retired) = &retired {
+                let _ = utils::rename_dir("toolchain", retired, &self.live, notify_handler);
+            }
+            return Err(e);
+        }
+        self.staged = false;
+
+        // The toolchain is updated by now, whatever becomes of the old trees
+        if let Some(kept) = &kept {
+            if let Err(e) = versions::tidy(&self.live, kept, self.versions, notify_handler) {
+                notify_handler(Notification::NonFatalError(&e));
+            }
+        }
+        let toolchains = self.live.parent().unwrap_or_else(|| Path::new("."));
+        for tree in retired_trees(toolchains).unwrap_or_default() {
+            if let Err(e) = remove_if_unused(&tree, notify_handler) {
+                notify_handler(Notification::NonFatalError(&e));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Staging<'a> {
+    fn drop(&mut self) {
+        if self.staged {
+            if let Err(e) = remove_staged(&self.path, self.notify_handler) {
+                (self.notify_handler)(Notification::NonFatalError(&e));
+            }
+        }
+    }
+}
+
+/// Takes the toolchain tree at `tree` out of use, removing it unless a
+/// process is still running it, along with anything left of its staging
+/// directory
+pub(crate) fn retire(tree: &Path, notify_handler: &dyn Fn(Notification<'_>)) -> Result<()> {
+    remove_staged(&sibling(tree, STAGING_PREFIX, ""), notify_handler)?;
+    let retired = retired_path(tree);
+    utils::rename_dir("toolchain", tree, &retired, notify_handler)?;
+    remove_if_unused(&retired, notify_handler)?;
+    Ok(())
+}
+
+/// The trees in the toolchains directory `dir` which were replaced or
+/// uninstalled, but which may still be in use, including the previous
+/// versions which are no longer kept
+pub(crate) fn retired_trees(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut trees = retired_in(dir)?;
+    let versions = dir.join(versions::VERSIONS_DIR);
+    if utils::is_directory(&versions) {
+        for entry in utils::read_dir("versions", &versions)?.filter_map(io::Result::ok) {
+            trees.extend(retired_in(&entry.path())?);
+        }
+    }
+    Ok(trees)
+}
+
+fn retired_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !utils::is_directory(dir) {
+        return Ok(Vec::new());
+    }
+    Ok(utils::read_dir("toolchains", dir)?
+        .filter_map(io::Result::ok)
+        .filter(|e| e.file_name().to_string_lossy().starts_with(RETIRED_PREFIX))
+        .map(|e| e.path())
+        .collect())
+}
+
+/// Removes the retired tree `tree` if no process is running it, returning
+/// whether it did
+pub(crate) fn remove_if_unused(
+    tree: &Path,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<bool> {
+    if is_in_use(tree)? {
+        notify_handler(Notification::KeepingRetiredToolchain(tree));
+        return Ok(false);
+    }
+    utils::remove_dir("toolchain", tree, notify_handler)?;
+    Ok(true)
+}
+
+/// Whether a process is running the toolchain tree `tree`
+pub(crate) fn is_in_use(tree: &Path) -> Result<bool> {
+    let lock_path = in_use_lock_path(tree);
+    // Nothing has run a tree which has no lock file
+    Ok(utils::is_file(&lock_path) && Lock::try_exclusive(&lock_path)?.is_none())
+}
+
+/// The lock which processes running the toolchain tree `tree` share
+pub(crate) fn in_use_lock_path(tree: &Path) -> PathBuf {
+    InstallPrefix::from(tree.to_owned()).manifest_file(IN_USE_LOCK)
+}
+
+/// Puts the staging directory `path` in the place of the toolchain tree
+/// `live`, if rustup was interrupted swapping them after it had moved the
+/// live tree away, returning whether it did
+pub(crate) fn finish_interrupted_swap(
+    path: &Path,
+    live: &Path,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<bool> {
+    // The changes to a tree are done by the time it is swapped, so a tree
+    // with a transaction in it was never complete
+    if utils::path_exists(live) || has_transaction_journal(&InstallPrefix::from(path.to_owned())) {
+        return Ok(false);
+    }
+    notify_handler(Notification::FinishingInterrupted(live));
+    utils::rename_dir("toolchain", path, live, notify_handler)?;
+    Ok(true)
+}
+
+/// The name of the toolchain whose staging directory is called `name`, if
+/// it is one
+pub(crate) fn staged_toolchain(name: &str) -> Option<&str> {
+    name.strip_prefix(STAGING_PREFIX)
+}
+
+/// Whether the entry called `name` of the toolchains directory is a
+/// staging directory, a retired tree or the previous versions rather than
+/// a toolchain
+pub(crate) fn is_internal_dir(name: &str) -> bool {
+    name.starts_with(STAGING_PREFIX)
+        || name.starts_with(RETIRED_PREFIX)
+        || name == versions::VERSIONS_DIR
+}
+
+fn remove_staged(path: &Path, notify_handler: &dyn Fn(Notification<'_>)) -> Result<()> {
+    if utils::path_exists(path) {
+        utils::remove_dir("staging", path, notify_handler)?;
+    }
+    Ok(())
+}
+
+/// A path next to `tree`, with `prefix` and `suffix` around its name
+fn sibling(tree: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(prefix);
+    name.push(tree.file_name().unwrap_or_default());
+    name.push(suffix);
+    tree.with_file_name(name)
+}
+
+fn retired_path(tree: &Path) -> PathBuf {
+    (0..)
+        .map(|n| sibling(tree, RETIRED_PREFIX, &format!("-{}", n)))
+        .find(|path| !utils::path_exists(path))
+        .expect("there is a free name")
+}
@@ -33,6 +33,9 @@ pub enum Notification<'a> {
 pub struct Cfg {
     root_directory: PathBuf,
     pub dist_server: String,
+    /// Whether a file of a dist server which is a local directory is read
+    /// where it is, rather than downloaded; off unless it is turned on
+    pub read_in_place: bool,
     notify_handler: Box<dyn Fn(Notification<'_>)>,
 }
 
@@ -98,6 +101,7 @@ impl Cfg {
         Self {
             root_directory,
             dist_server: dist_server.to_owned(),
+            read_in_place: false,
             notify_handler,
         }
     }
@@ -157,6 +161,10 @@ impl Cfg {
         }
     }
 
+    pub(crate) fn root_directory(&self) -> &Path {
+        &self.root_directory
+    }
+
     pub(crate) fn clean(&self) {
         utils::delete_dir_contents(&self.root_directory);
     }
@@ -0,0 +1,208 @@
+//! Previous versions of tracking toolchains, kept so that an update can be
+//! rolled back without downloading anything.
+//!
+//! When the `retained_versions` setting is more than zero, updating a
+//! tracking toolchain such as `nightly` to a release of another date moves
+//! the tree it replaces to `toolchains/.versions/<name>/<date>` instead of
+//! removing it, `<date>` being the date of the release in that tree. Only
+//! the newest versions are kept, the others are removed like any replaced
+//! tree.
+//!
+//! An update reinstalls every component which changed, even when most of
+//! its files did not, so the files of a kept version which have the same
+//! hash as those of the live tree are replaced with hard links to them.
+//! Keeping a version then costs little more than what changed in it.
+//!
+//! Rolling back stages a copy of a kept version and swaps it into place
+//! the way an update does, keeping the tree it replaces in turn.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::dist::component::Components;
+use crate::dist::manifest::Manifest;
+use crate::dist::manifestation::DIST_MANIFEST;
+use crate::dist::notifications::*;
+use crate::dist::prefix::InstallPrefix;
+use crate::dist::staging::{self, Staging};
+use crate::utils::utils;
+
+pub(crate) const VERSIONS_DIR: &str = ".versions";
+
+/// A kept version of a toolchain
+#[derive(Clone, Debug)]
+pub(crate) struct Version {
+    pub(crate) date: String,
+    /// The version of rustc it holds, as its manifest gives it
+    pub(crate) rust_version: Option<String>,
+    pub(crate) path: PathBuf,
+}
+
+impl Version {
+    /// Reads which version the toolchain tree `tree` holds, if it was
+    /// installed from a v2 manifest
+    pub(crate) fn load(tree: &Path) -> Result<Option<Self>> {
+        let manifest_file = InstallPrefix::from(tree.to_owned()).manifest_file(DIST_MANIFEST);
+        if !utils::is_file(&manifest_file) {
+            return Ok(None);
+        }
+        let manifest = Manifest::parse(&utils::read_file("installed manifest", &manifest_file)?)?;
+        Ok(Some(Self {
+            rust_version: manifest.get_rust_version().ok().map(str::to_owned),
+            date: manifest.date,
+            path: tree.to_owned(),
+        }))
+    }
+}
+
+/// The directory in which the versions of the toolchain tree `live` are
+/// kept
+pub(crate) fn versions_dir(live: &Path) -> PathBuf {
+    live.with_file_name(VERSIONS_DIR)
+        .join(live.file_name().unwrap_or_default())
+}
+
+/// The kept versions of the toolchain tree `live`, newest first
+pub(crate) fn list(live: &Path) -> Result<Vec<Version>> {
+    let dir = versions_dir(live);
+    if !utils::is_directory(&dir) {
+        return Ok(Vec::new());
+    }
+    let mut versions = Vec::new();
+    for entry in utils::read_dir("versions", &dir)?.filter_map(io::Result::ok) {
+        if staging::is_internal_dir(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        if let Some(version) = Version::load(&entry.path())? {
+            versions.push(version);
+        }
+    }
+    versions.sort_by(|a, b| b.date.cmp(&a.date));
+    Ok(versions)
+}
+
+/// Where to keep the toolchain tree `live` when the tree `staged` replaces
+/// it, unless they hold the same version
+pub(crate) fn keep_path(
+    live: &Path,
+    staged: &Path,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<Option<PathBuf>> {
+    let old = match Version::load(live)? {
+        Some(old) => old,
+        None => return Ok(None),
+    };
+    match Version::load(staged)? {
+        Some(new) if new.date != old.date => {}
+        _ => return Ok(None),
+    }
+    let dir = versions_dir(live);
+    let path = dir.join(&old.date);
+    if utils::path_exists(&path) {
+        // Left from before a roll back to an older version
+        staging::retire(&path, notify_handler)?;
+    }
+    utils::ensure_dir_exists("versions", &dir, notify_handler)?;
+    Ok(Some(path))
+}
+
+/// Shares the files of the version just kept at `kept` with the toolchain
+/// tree `live`, and removes all but the newest `count` versions
+pub(crate) fn tidy(
+    live: &Path,
+    kept: &Path,
+    count: usize,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<()> {
+    share_files(live, kept)?;
+    // Keeping the version which is live again, after rolling back to it or
+    // updating to it once more, is no use
+    let live_date = Version::load(live)?.map(|v| v.date);
+    let (live_again, others): (Vec<_>, Vec<_>) = list(live)?
+        .into_iter()
+        .partition(|v| Some(&v.date) == live_date.as_ref());
+    for version in live_again.iter().chain(others.iter().skip(count)) {
+        staging::retire(&version.path, notify_handler)?;
+    }
+    Ok(())
+}
+
+/// Swaps a copy of `version` into the place of the toolchain tree `live`,
+/// keeping the tree it replaces along with `count` versions in all
+pub(crate) fn roll_back(
+    live: &Path,
+    version: &Version,
+    count: usize,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<()> {
+    let mut staging = Staging::new(live, notify_handler)?;
+    // The tree rolled back from is always kept, so that one can go forward
+    // to it again
+    staging.keep_versions(count.max(1));
+    staging.stage_copy_of(&version.path)?;
+    staging.commit()?;
+    // Tidying up removes it, unless that failed
+    if utils::path_exists(&version.path) {
+        staging::retire(&version.path, notify_handler)?;
+    }
+    Ok(())
+}
+
+/// Removes every kept version of the toolchain tree `live`
+pub(crate) fn retire_all(live: &Path, notify_handler: &dyn Fn(Notification<'_>)) -> Result<()> {
+    for version in list(live)? {
+        staging::retire(&version.path, notify_handler)?;
+    }
+    // Versions still in use stay in it until they are collected
+    let _ = fs::remove_dir(versions_dir(live));
+    Ok(())
+}
+
+/// Replaces the files of the tree `kept` which have the same recorded hash
+/// as those of the tree `live` with hard links to them
+fn share_files(live: &Path, kept: &Path) -> Result<()> {
+    let mut live_hashes = BTreeMap::new();
+    for component in Components::open(InstallPrefix::from(live.to_owned()))?.list()? {
+        live_hashes.extend(component.recorded_hashes()?.unwrap_or_default());
+    }
+    for component in Components::open(InstallPrefix::from(kept.to_owned()))?.list()? {
+        for (path, hash) in component.recorded_hashes()?.unwrap_or_default() {
+            if live_hashes.get(&path) == Some(&hash) {
+                // Failing to share a file only costs the space it takes
+                let _ = share_file(&live.join(&path), &kept.join(&path));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn share_file(src: &Path, dest: &Path) -> io::Result<()> {
+    let (src_metadata, dest_metadata) = (fs::metadata(src)?, fs::metadata(dest)?);
+    if src_metadata.len() != dest_metadata.len() || is_same_file(&src_metadata, &dest_metadata) {
+        return Ok(());
+    }
+    let mut link = dest.as_os_str().to_owned();
+    link.push(".link");
+    let link = PathBuf::from(link);
+    fs::hard_link(src, &link)?;
+    fs::rename(&link, dest).map_err(|e| {
+        let _ = fs::remove_file(&link);
+        e
+    })
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(windows)]
+fn is_same_file(_: &fs::Metadata, _: &fs::Metadata) -> bool {
+    // Linking the file again does no harm
+    false
+}
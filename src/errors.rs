@@ -10,6 +10,7 @@ use url::Url;
 
 use crate::currentprocess::process;
 use crate::dist::manifest::{Component, Manifest};
+use crate::utils::units::human_size;
 
 const TOOLSTATE_MSG: &str =
     "If you require these components, please install and use the latest successful build version,\n\
@@ -55,6 +56,12 @@ pub enum RustupError {
         expected: String,
         calculated: String,
     },
+    #[error("checksum of {what} does not match the lockfile, expected: '{expected}', calculated: '{calculated}'")]
+    LockfileMismatch {
+        what: String,
+        expected: String,
+        calculated: String,
+    },
     #[error("failed to install component: '{name}', detected conflict: '{}'", .path.display())]
     ComponentConflict { name: String, path: PathBuf },
     #[error("toolchain '{0}' does not support components")]
@@ -65,10 +72,29 @@ pub enum RustupError {
     DownloadingFile { url: Url, path: PathBuf },
     #[error("could not download file from '{url}' to '{}'", .path.display())]
     DownloadNotExists { url: Url, path: PathBuf },
+    #[error("could not lock '{}'", .0.display())]
+    LockingFile(PathBuf),
     #[error("Missing manifest in toolchain '{}'", .name)]
     MissingManifest { name: String },
     #[error("server sent a broken manifest: missing package for component {0}")]
     MissingPackageForComponent(String),
+    #[error(
+        "no previous versions of toolchain '{0}' are kept, see 'rustup set retained-versions'"
+    )]
+    NoRetainedVersions(String),
+    #[error(
+        "not enough disk space for '{}': about {} is needed, but only {} is free",
+        .path.display(),
+        human_size(*.needed),
+        human_size(*.free)
+    )]
+    NotEnoughSpace {
+        path: PathBuf,
+        needed: u64,
+        free: u64,
+    },
+    #[error("no key with fingerprint '{0}' in the keyring")]
+    PgpKeyNotFound(String),
     #[error("could not read {name} directory: '{}'", .path.display())]
     ReadingDirectory { name: &'static str, path: PathBuf },
     #[error("could not read {name} file: '{}'", .path.display())]
@@ -83,8 +109,12 @@ pub enum RustupError {
         manifest: Manifest,
         toolchain: String,
     },
+    #[error("no version of toolchain '{name}' from {date} is kept")]
+    RetainedVersionNotFound { name: String, date: String },
     #[error("command failed: '{}'", PathBuf::from(.name).display())]
     RunningCommand { name: OsString },
+    #[error("signature verification failed for '{url}'")]
+    SignatureVerificationFailed { url: String },
     #[error("toolchain '{0}' is not installable")]
     ToolchainNotInstallable(String),
     #[error("toolchain '{0}' is not installed")]
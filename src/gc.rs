@@ -0,0 +1,260 @@
+//! `rustup gc`, which finds what takes up space in the rustup home without
+//! being used, so that it can be removed.
+//!
+//! A toolchain is in use if it is the default toolchain, the toolchain of
+//! `RUSTUP_TOOLCHAIN`, a directory override, or the toolchain named by a
+//! `rust-toolchain` or `rust-toolchain.toml` file under one of the
+//! `project_roots` of the settings file. Custom toolchains are never
+//! collected, since they are not rustup's to remove. Neither is anything
+//! younger than the age given to `--older-than`; the age of a toolchain is
+//! how long ago it was last installed or updated.
+//!
+//! Other rustup processes may be running at the same time, so temporary
+//! files are only collected once they are a day old, whatever the age given,
+//! and partial downloads only when no process is downloading them.
+//!
+//! The trees which updated and uninstalled toolchains leave behind, for as
+//! long as processes run them, are collected once none do.
+
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+
+use crate::config::Cfg;
+use crate::dist::staging;
+use crate::toolchain::Toolchain;
+use crate::utils::lock::Lock;
+use crate::utils::utils;
+
+/// How old a temporary file must be before it is collected, since a rustup
+/// process running at the same time may be using it
+const MIN_TEMP_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Something which can be removed from the rustup home
+pub(crate) enum Garbage {
+    /// A toolchain which nothing uses
+    Toolchain(String),
+    /// The tree of a toolchain which was updated or uninstalled, and which
+    /// nothing runs any more
+    RetiredToolchain(PathBuf),
+    /// The update hash of a toolchain which is not installed
+    UpdateHash(PathBuf),
+    /// A file or directory left in the temporary directory by a run of
+    /// rustup which did not finish
+    Temp(PathBuf),
+    /// A download which was never finished
+    PartialDownload(PathBuf),
+}
+
+pub(crate) struct Item {
+    pub garbage: Garbage,
+    pub size: u64,
+}
+
+impl Display for Garbage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Garbage::Toolchain(name) => write!(f, "toolchain '{}'", name),
+            Garbage::RetiredToolchain(path) => {
+                write!(f, "replaced toolchain '{}'", path.display())
+            }
+            Garbage::UpdateHash(path) => write!(f, "update hash '{}'", path.display()),
+            Garbage::Temp(path) => write!(f, "temporary file '{}'", path.display()),
+            Garbage::PartialDownload(path) => {
+                write!(f, "partial download '{}'", path.display())
+            }
+        }
+    }
+}
+
+/// Everything which can be removed from the rustup home of `cfg`, leaving
+/// out whatever was last modified less than `older_than` ago
+pub(crate) fn find(cfg: &Cfg, older_than: Option<Duration>) -> Result<Vec<Item>> {
+    let now = SystemTime::now();
+    let is_older_than = |path: &Path, age: Option<Duration>| {
+        age.map_or(true, |age| {
+            fs::symlink_metadata(path)
+                .and_then(|m| m.modified())
+                .map_or(false, |modified| {
+                    now.duration_since(modified).map_or(false, |d| d >= age)
+                })
+        })
+    };
+    let is_old = |path: &Path| is_older_than(path, older_than);
+    let temp_age = older_than.map_or(MIN_TEMP_AGE, |age| age.max(MIN_TEMP_AGE));
+    let mut items = Vec::new();
+
+    let toolchains = cfg.list_toolchains()?;
+    let used = used_toolchains(cfg)?;
+    for name in &toolchains {
+        if used.contains(name) || Toolchain::is_custom_name(name) {
+            continue;
+        }
+        let path = cfg.toolchains_dir.join(name);
+        // The update hash is written whenever the toolchain is updated
+        let update_hash = cfg.update_hash_dir.join(name);
+        let updated = if utils::is_file(&update_hash) {
+            &update_hash
+        } else {
+            &path
+        };
+        if is_old(updated) {
+            items.push(Item {
+                garbage: Garbage::Toolchain(name.clone()),
+                size: utils::disk_usage(&path) + utils::disk_usage(&update_hash),
+            });
+        }
+    }
+
+    for path in staging::retired_trees(&cfg.toolchains_dir)? {
+        if !staging::is_in_use(&path)? {
+            items.push(Item {
+                size: utils::disk_usage(&path),
+                garbage: Garbage::RetiredToolchain(path),
+            });
+        }
+    }
+
+    for path in entries(&cfg.update_hash_dir)? {
+        let installed = path
+            .file_name()
+            .map_or(false, |name| toolchains.iter().any(|t| **t == *name));
+        if !installed && is_old(&path) {
+            items.push(Item {
+                size: utils::disk_usage(&path),
+                garbage: Garbage::UpdateHash(path),
+            });
+        }
+    }
+
+    for path in entries(cfg.temp_cfg.root_directory())? {
+        if is_older_than(&path, Some(temp_age)) {
+            items.push(Item {
+                size: utils::disk_usage(&path),
+                garbage: Garbage::Temp(path),
+            });
+        }
+    }
+
+    for path in entries(&cfg.download_dir)? {
+        let partial = path.extension().map_or(false, |ext| ext == "partial");
+        if partial && is_old(&path) && !is_downloading(&path)? {
+            items.push(Item {
+                size: utils::disk_usage(&path),
+                garbage: Garbage::PartialDownload(path),
+            });
+        }
+    }
+
+    Ok(items)
+}
+
+/// Removes everything in `items` from the rustup home of `cfg`
+pub(crate) fn collect(cfg: &Cfg, items: &[Item]) -> Result<()> {
+    for item in items {
+        match &item.garbage {
+            Garbage::Toolchain(name) => cfg.get_toolchain(name, false)?.remove()?,
+            Garbage::RetiredToolchain(path) => {
+                // It may have started being used since it was found
+                staging::remove_if_unused(path, &|n: crate::dist::Notification<'_>| {
+                    (cfg.notify_handler)(n.into())
+                })?;
+            }
+            Garbage::UpdateHash(path) => utils::ensure_file_removed("update hash", path)?,
+            Garbage::Temp(path) if utils::is_directory(path) => {
+                utils::remove_dir("temp", path, cfg.notify_handler.as_ref())?
+            }
+            Garbage::Temp(path) => utils::ensure_file_removed("temp", path)?,
+            Garbage::PartialDownload(path) => {
+                // Keeps it from being resumed while it is removed
+                if let Some(_lock) = Lock::try_exclusive(&download_lock_path(path))? {
+                    utils::ensure_file_removed("partial download", path)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses an age such as `30d`, in days, hours or weeks
+pub(crate) fn parse_age(age: &str) -> Result<Duration> {
+    const HOUR: u64 = 60 * 60;
+    let (n, unit) = age.split_at(age.len() - age.trim_start_matches(char::is_numeric).len());
+    let seconds = match unit {
+        "h" => HOUR,
+        "d" => 24 * HOUR,
+        "w" => 7 * 24 * HOUR,
+        _ => 0,
+    };
+    match n.parse::<u64>().ok().and_then(|n| n.checked_mul(seconds)) {
+        Some(secs) if seconds > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(anyhow!(
+            "invalid age: '{}'; expected a number of hours, days or weeks, e.g. '30d'",
+            age
+        )),
+    }
+}
+
+/// Whether a process is downloading to the partial download `path`
+fn is_downloading(path: &Path) -> Result<bool> {
+    Ok(Lock::try_exclusive(&download_lock_path(path))?.is_none())
+}
+
+/// The lock which is held while downloading to the partial download `path`
+fn download_lock_path(path: &Path) -> PathBuf {
+    path.with_extension("lock")
+}
+
+/// The names of the toolchains which are in use, resolved like the names
+/// of installed toolchains
+fn used_toolchains(cfg: &Cfg) -> Result<HashSet<String>> {
+    let mut names = cfg.project_toolchains()?;
+    names.extend(cfg.get_default()?);
+    names.extend(cfg.env_override.clone());
+    cfg.settings_file.with(|s| {
+        names.extend(s.overrides.values().cloned());
+        Ok(())
+    })?;
+    names
+        .iter()
+        .map(|name| cfg.resolve_toolchain(name))
+        .collect()
+}
+
+/// The paths of the entries of `dir`, if it exists
+fn entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !utils::is_directory(dir) {
+        return Ok(Vec::new());
+    }
+    Ok(utils::read_dir("rustup home", dir)?
+        .filter_map(io::Result::ok)
+        .map(|entry| entry.path())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages() {
+        assert_eq!(
+            parse_age("30d").unwrap(),
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+        assert_eq!(
+            parse_age("1w").unwrap(),
+            Duration::from_secs(7 * 24 * 60 * 60)
+        );
+        assert!(parse_age("30").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("-1d").is_err());
+        assert!(parse_age("99999999999999999w").is_err());
+    }
+}
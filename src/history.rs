@@ -0,0 +1,310 @@
+//! The history of what rustup did to a rustup home, for `rustup history`.
+//!
+//! Every command which changes something appends a line to the `history`
+//! file of the rustup home for each toolchain it changed, or a single line
+//! if it changed none. What a command did is learnt from the notifications
+//! it sends, which say which toolchain it is working on and which
+//! components it installs and removes, and from `InstallMethod::install`,
+//! which says which release it installed.
+//!
+//! `InstallMethod::install` writes the line of the toolchain it installs
+//! once it is done, so that toolchains which proxies such as `cargo`
+//! install are logged too, as an `install`. The lines of other changes are
+//! written once the command has finished, with its exit status.
+//!
+//! The file is only ever appended to, one whole line at a time, so rustup
+//! processes sharing a rustup home can write to it at once. Lines which
+//! cannot be read, say because a disk filled up in the middle of one, are
+//! skipped.
+
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::dist::dist::TargetTriple;
+use crate::dist::Notification as DistNotification;
+use crate::errors::RustupError;
+use crate::notifications::Notification;
+use crate::utils::utils;
+
+/// Something a command did, as logged
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Entry {
+    /// Seconds since the Unix epoch
+    pub(crate) time: u64,
+    /// The command, such as `update` or `component add`
+    pub(crate) operation: String,
+    pub(crate) toolchain: Option<String>,
+    /// The date of the release installed
+    pub(crate) manifest_date: Option<String>,
+    /// The hash of the manifest of the release installed
+    pub(crate) manifest_hash: Option<String>,
+    pub(crate) rustc_before: Option<String>,
+    pub(crate) rustc_after: Option<String>,
+    /// The components installed, as `+<name>`, and removed, as `-<name>`
+    pub(crate) components: Vec<String>,
+    pub(crate) status: u32,
+}
+
+impl Entry {
+    fn encode(&self) -> String {
+        let optional = |field: &Option<String>| field.as_deref().map_or(String::new(), clean);
+        [
+            self.time.to_string(),
+            clean(&self.operation),
+            optional(&self.toolchain),
+            optional(&self.manifest_date),
+            optional(&self.manifest_hash),
+            optional(&self.rustc_before),
+            optional(&self.rustc_after),
+            clean(&self.components.join(",")),
+            self.status.to_string(),
+        ]
+        .join("\t")
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        let fields: Vec<_> = line.split('\t').collect();
+        if fields.len() != 9 {
+            return None;
+        }
+        let optional = |field: &str| utils::if_not_empty(field.to_owned());
+        Some(Self {
+            time: fields[0].parse().ok()?,
+            operation: fields[1].to_owned(),
+            toolchain: optional(fields[2]),
+            manifest_date: optional(fields[3]),
+            manifest_hash: optional(fields[4]),
+            rustc_before: optional(fields[5]),
+            rustc_after: optional(fields[6]),
+            components: fields[7]
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            status: fields[8].parse().ok()?,
+        })
+    }
+}
+
+/// Keeps the fields of a line from running into one another
+fn clean(field: &str) -> String {
+    field.replace(&['\t', '\n', '\r'][..], " ")
+}
+
+/// A change the running command made, and whether it has been written
+struct Change {
+    entry: Entry,
+    written: bool,
+}
+
+/// The history of a rustup home, and what the running command has done to
+/// it so far
+pub(crate) struct History {
+    path: PathBuf,
+    /// The command being run, when it is one which is logged
+    operation: RefCell<Option<String>>,
+    changes: RefCell<Vec<Change>>,
+}
+
+impl History {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            operation: RefCell::new(None),
+            changes: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Makes `operation` the command the changes are logged as
+    pub(crate) fn begin(&self, operation: &str) {
+        *self.operation.borrow_mut() = Some(operation.to_owned());
+    }
+
+    /// Learns what the command is doing from the notification `n`
+    pub(crate) fn observe(&self, n: &Notification<'_>) {
+        match n {
+            Notification::InstallingToolchain(name)
+            | Notification::UpdatingToolchain(name)
+            | Notification::UninstallingToolchain(name)
+            | Notification::SetDefaultToolchain(name)
+            | Notification::SetOverrideToolchain(_, name) => {
+                self.change(Some(*name));
+            }
+            Notification::Install(DistNotification::InstallingComponent(name, _, target)) => {
+                self.touch('+', name, *target);
+            }
+            Notification::Install(DistNotification::RemovingComponent(name, _, target)) => {
+                self.touch('-', name, *target);
+            }
+            _ => {}
+        }
+    }
+
+    /// Records the release which the toolchain being changed was left at
+    pub(crate) fn installed(
+        &self,
+        manifest_date: Option<String>,
+        manifest_hash: Option<String>,
+        rustc_before: Option<String>,
+        rustc_after: String,
+    ) {
+        let mut changes = self.changes.borrow_mut();
+        if let Some(change) = changes.last_mut() {
+            change.entry.manifest_date = manifest_date;
+            change.entry.manifest_hash = manifest_hash;
+            change.entry.rustc_before = rustc_before;
+            change.entry.rustc_after = Some(rustc_after);
+        }
+    }
+
+    /// Appends the change to the toolchain `toolchain` to the history, now
+    /// that installing it has finished with `status`
+    pub(crate) fn write_toolchain(&self, toolchain: &str, status: u32) -> Result<()> {
+        let mut changes = self.changes.borrow_mut();
+        let change = changes
+            .iter_mut()
+            .rev()
+            .find(|c| !c.written && c.entry.toolchain.as_deref() == Some(toolchain));
+        match change {
+            Some(change) => {
+                change.written = true;
+                let line = self.line(&change.entry, status);
+                self.append(&line)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Appends what the command did which has not been written yet to the
+    /// history, now that it has finished with `status`
+    pub(crate) fn write(&self, status: u32) -> Result<()> {
+        let mut changes = self.changes.take();
+        if changes.is_empty() {
+            changes.push(Change {
+                entry: Entry::default(),
+                written: false,
+            });
+        }
+        let lines: String = changes
+            .iter()
+            .filter(|c| !c.written)
+            .map(|c| self.line(&c.entry, status))
+            .collect();
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.append(&lines)
+    }
+
+    /// Everything in the history, oldest first
+    pub(crate) fn entries(&self) -> Result<Vec<Entry>> {
+        if !utils::is_file(&self.path) {
+            return Ok(Vec::new());
+        }
+        Ok(utils::read_file("history", &self.path)?
+            .lines()
+            .filter_map(Entry::decode)
+            .collect())
+    }
+
+    /// Makes the change to the toolchain `toolchain` the one being recorded
+    fn change(&self, toolchain: Option<&str>) {
+        let mut changes = self.changes.borrow_mut();
+        // Installing a toolchain to make it the default is one change
+        if let Some(last) = changes.last() {
+            if last.entry.toolchain.as_deref() == toolchain {
+                return;
+            }
+        }
+        changes.push(Change {
+            entry: Entry {
+                toolchain: toolchain.map(str::to_owned),
+                ..Entry::default()
+            },
+            written: false,
+        });
+    }
+
+    fn touch(&self, sign: char, name: &str, target: Option<&TargetTriple>) {
+        let mut changes = self.changes.borrow_mut();
+        // Changes to a toolchain after its line was written go in another
+        let toolchain = match changes.last() {
+            Some(last) if !last.written => None,
+            last => Some(last.and_then(|last| last.entry.toolchain.clone())),
+        };
+        if let Some(toolchain) = toolchain {
+            changes.push(Change {
+                entry: Entry {
+                    toolchain,
+                    ..Entry::default()
+                },
+                written: false,
+            });
+        }
+        let component = match target {
+            Some(target) => format!("{}{}-{}", sign, name, target),
+            None => format!("{}{}", sign, name),
+        };
+        if let Some(change) = changes.last_mut() {
+            change.entry.components.push(component);
+        }
+    }
+
+    /// The line of the history which records `change`
+    fn line(&self, change: &Entry, status: u32) -> String {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let operation = self.operation.borrow();
+        let entry = Entry {
+            time,
+            // Nothing says why else a toolchain was installed
+            operation: operation.as_deref().unwrap_or("install").to_owned(),
+            status,
+            ..change.clone()
+        };
+        entry.encode() + "\n"
+    }
+
+    fn append(&self, lines: &str) -> Result<()> {
+        append(&self.path, lines).with_context(|| RustupError::WritingFile {
+            name: "history",
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// Appends `lines` to the file at `path` with a single write, which keeps
+/// them whole when other processes append to the file at the same time
+fn append(path: &Path, lines: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_round_trips() {
+        let entry = Entry {
+            time: 1_600_000_000,
+            operation: "update".to_owned(),
+            toolchain: Some("nightly-x86_64-unknown-linux-gnu".to_owned()),
+            manifest_date: Some("2015-01-02".to_owned()),
+            manifest_hash: None,
+            rustc_before: Some("1.2.0 (hash-nightly-1)".to_owned()),
+            rustc_after: Some("1.3.0 (hash-nightly-2)".to_owned()),
+            components: vec!["+rustc".to_owned(), "-rls".to_owned()],
+            status: 0,
+        };
+        assert_eq!(Entry::decode(&entry.encode()), Some(entry));
+        assert_eq!(Entry::decode("1600000000\tupdate"), None);
+    }
+}
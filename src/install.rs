@@ -6,7 +6,9 @@ use anyhow::Result;
 
 use crate::dist::dist;
 use crate::dist::download::DownloadCfg;
+use crate::dist::lockfile::Lockfile;
 use crate::dist::prefix::InstallPrefix;
+use crate::dist::versions::Version;
 use crate::dist::Notification;
 use crate::errors::RustupError;
 use crate::notifications::Notification as RootNotification;
@@ -35,12 +37,31 @@ pub(crate) enum InstallMethod<'a> {
         components: &'a [&'a str],
         // Extra targets to install from dist
         targets: &'a [&'a str],
+        // Install exactly what this lockfile pins instead
+        lockfile: Option<&'a Lockfile>,
+        // Previous versions to keep of a tracking toolchain
+        retained_versions: usize,
     },
 }
 
 impl<'a> InstallMethod<'a> {
     // Install a toolchain
     pub(crate) fn install(&self, toolchain: &Toolchain<'a>) -> Result<UpdateStatus> {
+        let result = self.install_locked(toolchain);
+        let status = if result.is_ok() { 0 } else { 1 };
+        if let Err(e) = toolchain
+            .cfg()
+            .history
+            .write_toolchain(toolchain.name(), status)
+        {
+            (toolchain.cfg().notify_handler)(RootNotification::NonFatalError(&e));
+        }
+        result
+    }
+
+    // Install a toolchain, holding its lock
+    fn install_locked(&self, toolchain: &Toolchain<'a>) -> Result<UpdateStatus> {
+        let _lock = toolchain.lock()?;
         let previous_version = if toolchain.exists() {
             Some(toolchain.rustc_version())
         } else {
@@ -69,18 +90,44 @@ impl<'a> InstallMethod<'a> {
             ));
         }
 
-        let status = match (updated, previous_version) {
+        // Final check, to ensure we're installed
+        if !toolchain.exists() {
+            return Err(RustupError::ToolchainNotInstallable(toolchain.name().to_string()).into());
+        }
+        self.record(toolchain, updated, previous_version.clone());
+
+        Ok(match (updated, previous_version) {
             (true, None) => UpdateStatus::Installed,
             (true, Some(v)) => UpdateStatus::Updated(v),
             (false, _) => UpdateStatus::Unchanged,
-        };
+        })
+    }
 
-        // Final check, to ensure we're installed
-        if !toolchain.exists() {
-            Err(RustupError::ToolchainNotInstallable(toolchain.name().to_string()).into())
-        } else {
-            Ok(status)
-        }
+    /// Records the release the toolchain was left at in the history
+    fn record(&self, toolchain: &Toolchain<'a>, updated: bool, previous_version: Option<String>) {
+        let manifest_hash = match *self {
+            InstallMethod::Dist {
+                update_hash: Some(path),
+                ..
+            } => utils::read_file("update hash", path)
+                .ok()
+                .map(|hash| hash.trim().to_owned()),
+            _ => None,
+        };
+        let manifest_date = Version::load(toolchain.path())
+            .ok()
+            .flatten()
+            .map(|version| version.date);
+        let rustc_after = match &previous_version {
+            Some(version) if !updated => version.clone(),
+            _ => toolchain.rustc_version(),
+        };
+        toolchain.cfg().history.installed(
+            manifest_date,
+            manifest_hash,
+            previous_version,
+            rustc_after,
+        );
     }
 
     pub(crate) fn run(
@@ -118,25 +165,31 @@ impl<'a> InstallMethod<'a> {
                 old_date,
                 components,
                 targets,
+                lockfile,
+                retained_versions,
                 ..
             } => {
                 let prefix = &InstallPrefix::from(path.to_owned());
-                let maybe_new_hash = dist::update_from_dist(
-                    dl_cfg,
-                    update_hash,
-                    desc,
-                    if exists { None } else { Some(profile) },
-                    prefix,
-                    force_update,
-                    allow_downgrade,
-                    old_date,
-                    components,
-                    targets,
-                )?;
+                let maybe_new_hash = match lockfile {
+                    Some(lockfile) => dist::update_from_lockfile(dl_cfg, lockfile, prefix)?,
+                    None => dist::update_from_dist(
+                        dl_cfg,
+                        update_hash,
+                        desc,
+                        if exists { None } else { Some(profile) },
+                        prefix,
+                        force_update,
+                        allow_downgrade,
+                        old_date,
+                        components,
+                        targets,
+                        retained_versions,
+                    )?,
+                };
 
                 if let Some(hash) = maybe_new_hash {
                     if let Some(hash_file) = update_hash {
-                        utils::write_file("update hash", hash_file, &hash)?;
+                        utils::write_file_atomically("update hash", hash_file, &hash)?;
                     }
 
                     Ok(true)
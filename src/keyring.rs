@@ -0,0 +1,159 @@
+//! The keyring of additional PGP keys trusted to sign distributions.
+//!
+//! Keys are stored in `RUSTUP_HOME/keyring`, one ASCII armored certificate
+//! per file, named after the fingerprint of the key. A key may be restricted
+//! to a single dist server, in which case the URL of that server is stored
+//! next to it in a file with the `dist-server` extension.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sequoia_openpgp::{parse::Parse, serialize::SerializeInto, Cert};
+
+use crate::config::PgpPublicKey;
+use crate::errors::RustupError;
+use crate::notifications::Notification;
+use crate::utils::utils;
+
+const KEY_EXTENSION: &str = "asc";
+const SCOPE_EXTENSION: &str = "dist-server";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyring {
+    path: PathBuf,
+}
+
+impl Keyring {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Every key in the keyring, ordered by fingerprint. A key which cannot
+    /// be read is left out, with a warning, rather than keeping rustup from
+    /// running at all.
+    pub(crate) fn keys(
+        &self,
+        notify_handler: &dyn Fn(Notification<'_>),
+    ) -> Result<Vec<PgpPublicKey>> {
+        if !utils::is_directory(&self.path) {
+            return Ok(Vec::new());
+        }
+        let mut keys = Vec::new();
+        for entry in utils::read_dir("keyring", &self.path)? {
+            let path = entry
+                .with_context(|| RustupError::ReadingDirectory {
+                    name: "keyring",
+                    path: self.path.clone(),
+                })?
+                .path();
+            if path.extension().and_then(|e| e.to_str()) != Some(KEY_EXTENSION) {
+                continue;
+            }
+            match self.key(path) {
+                Ok(key) => keys.push(key),
+                Err(e) => notify_handler(Notification::IgnoringKeyringKey(&e)),
+            }
+        }
+        keys.sort_by_key(PgpPublicKey::fingerprint);
+        Ok(keys)
+    }
+
+    fn key(&self, path: PathBuf) -> Result<PgpPublicKey> {
+        let file = utils::open_file("keyring key", &path)?;
+        let cert = Cert::from_reader(file).map_err(|error| RustupError::InvalidPgpKey {
+            path: path.clone(),
+            source: error,
+        })?;
+        let scope_path = path.with_extension(SCOPE_EXTENSION);
+        let dist_server = if utils::is_file(&scope_path) {
+            Some(
+                utils::read_file("keyring scope", &scope_path)?
+                    .trim()
+                    .to_owned(),
+            )
+        } else {
+            None
+        };
+        Ok(PgpPublicKey::FromKeyring(path, cert, dist_server))
+    }
+
+    /// Import the public part of the key in `file`, replacing any key with
+    /// the same fingerprint. Returns the fingerprint of the key.
+    pub(crate) fn add(&self, file: &Path, dist_server: Option<&str>) -> Result<String> {
+        let cert = Cert::from_reader(utils::open_file("PGP key", file)?).map_err(|error| {
+            RustupError::InvalidPgpKey {
+                path: file.to_owned(),
+                source: error,
+            }
+        })?;
+        let fingerprint = cert.fingerprint().to_hex();
+        // Only the public key is serialized, even if `file` holds a secret key
+        let armored = String::from_utf8(cert.armored().to_vec()?)?;
+
+        utils::ensure_dir_exists("keyring", &self.path, &|_: crate::Notification<'_>| {})?;
+        let key_path = self.key_path(&fingerprint);
+        utils::write_file("keyring key", &key_path, &armored)?;
+        let scope_path = key_path.with_extension(SCOPE_EXTENSION);
+        match dist_server {
+            Some(url) => utils::write_file("keyring scope", &scope_path, normalize_url(url))?,
+            None => utils::ensure_file_removed("keyring scope", &scope_path)?,
+        }
+        Ok(fingerprint)
+    }
+
+    /// Remove the key with the given fingerprint. Returns `false` if there
+    /// is no such key in the keyring.
+    pub(crate) fn remove(&self, fingerprint: &str) -> Result<bool> {
+        if !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(false);
+        }
+        let key_path = self.key_path(fingerprint);
+        if !utils::is_file(&key_path) {
+            return Ok(false);
+        }
+        utils::remove_file("keyring key", &key_path)?;
+        utils::ensure_file_removed("keyring scope", &key_path.with_extension(SCOPE_EXTENSION))?;
+        Ok(true)
+    }
+
+    fn key_path(&self, fingerprint: &str) -> PathBuf {
+        self.path.join(format!("{}.{}", fingerprint, KEY_EXTENSION))
+    }
+}
+
+/// Accept fingerprints as printed by `rustup show keys`, with spaces and in
+/// either case
+pub(crate) fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
+
+/// Dist server URLs are compared without any trailing slash
+pub(crate) fn normalize_url(url: &str) -> &str {
+    url.trim_end_matches('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_are_normalized() {
+        assert_eq!(
+            normalize_fingerprint("b695 EF92 be5c D24E"),
+            "B695EF92BE5CD24E"
+        );
+    }
+
+    #[test]
+    fn urls_are_normalized() {
+        assert_eq!(
+            normalize_url("https://example.com/rust/"),
+            "https://example.com/rust"
+        );
+        assert_eq!(normalize_url("https://example.com"), "https://example.com");
+    }
+}
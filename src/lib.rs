@@ -91,7 +91,10 @@ pub mod dist;
 pub mod env_var;
 pub mod errors;
 mod fallback_settings;
+mod gc;
+mod history;
 mod install;
+mod keyring;
 pub mod notifications;
 mod settings;
 pub mod test;
@@ -14,6 +14,13 @@ pub enum Notification<'a> {
     SetOverrideToolchain(&'a Path, &'a str),
     SetProfile(&'a str),
     SetSelfUpdate(&'a str),
+    SetSignaturePolicy(&'a str),
+    SetRetainedVersions(u64),
+    AddedPgpKey(&'a str),
+    RemovedPgpKey(&'a str),
+    LockingToolchain(&'a str, &'a Path),
+    BundledToolchain(&'a str, &'a Path),
+    BuiltChannel(&'a str, &'a Path),
     LookingForToolchain(&'a str),
     ToolchainDirectory(&'a Path, &'a str),
     UpdatingToolchain(&'a str),
@@ -29,6 +36,7 @@ pub enum Notification<'a> {
     WritingMetadataVersion(&'a str),
     ReadMetadataVersion(&'a str),
     NonFatalError(&'a anyhow::Error),
+    IgnoringKeyringKey(&'a anyhow::Error),
     UpgradeRemovesToolchains,
     MissingFileDuringSelfUninstall(PathBuf),
     PlainVerboseMessage(&'a str),
@@ -75,6 +83,13 @@ impl<'a> Notification<'a> {
             | SetOverrideToolchain(_, _)
             | SetProfile(_)
             | SetSelfUpdate(_)
+            | SetSignaturePolicy(_)
+            | SetRetainedVersions(_)
+            | AddedPgpKey(_)
+            | RemovedPgpKey(_)
+            | LockingToolchain(_, _)
+            | BundledToolchain(_, _)
+            | BuiltChannel(_, _)
             | UsingExistingToolchain(_)
             | UninstallingToolchain(_)
             | UninstalledToolchain(_)
@@ -82,7 +97,8 @@ impl<'a> Notification<'a> {
             | UpgradingMetadata(_, _)
             | MetadataUpgradeNotNeeded(_) => NotificationLevel::Info,
             NonFatalError(_) => NotificationLevel::Error,
-            UpgradeRemovesToolchains
+            IgnoringKeyringKey(_)
+            | UpgradeRemovesToolchains
             | MissingFileDuringSelfUninstall(_)
             | DuplicateToolchainFile { .. } => NotificationLevel::Warn,
         }
@@ -106,6 +122,32 @@ impl<'a> Display for Notification<'a> {
             ),
             SetProfile(name) => write!(f, "profile set to '{}'", name),
             SetSelfUpdate(mode) => write!(f, "auto-self-update mode set to '{}'", mode),
+            SetSignaturePolicy(policy) => write!(f, "signature policy set to '{}'", policy),
+            SetRetainedVersions(count) => {
+                write!(
+                    f,
+                    "retaining {} previous versions of tracking toolchains",
+                    count
+                )
+            }
+            AddedPgpKey(fingerprint) => write!(f, "added key {} to the keyring", fingerprint),
+            RemovedPgpKey(fingerprint) => {
+                write!(f, "removed key {} from the keyring", fingerprint)
+            }
+            LockingToolchain(name, path) => {
+                write!(f, "locking toolchain to '{}' in '{}'", name, path.display())
+            }
+            BundledToolchain(name, path) => {
+                write!(f, "bundled toolchain '{}' into '{}'", name, path.display())
+            }
+            BuiltChannel(name, path) => {
+                write!(
+                    f,
+                    "built channel '{}' with manifest '{}'",
+                    name,
+                    path.display()
+                )
+            }
             LookingForToolchain(name) => write!(f, "looking for installed toolchain '{}'", name),
             ToolchainDirectory(path, _) => write!(f, "toolchain directory: '{}'", path.display()),
             UpdatingToolchain(name) => write!(f, "updating existing install for '{}'", name),
@@ -129,6 +171,7 @@ impl<'a> Display for Notification<'a> {
             WritingMetadataVersion(ver) => write!(f, "writing metadata version: '{}'", ver),
             ReadMetadataVersion(ver) => write!(f, "read metadata version: '{}'", ver),
             NonFatalError(e) => write!(f, "{}", e),
+            IgnoringKeyringKey(e) => write!(f, "{}; ignoring it", e),
             UpgradeRemovesToolchains => write!(
                 f,
                 "this upgrade will remove all existing toolchains. you will need to reinstall them"
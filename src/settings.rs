@@ -6,10 +6,14 @@ use std::str::FromStr;
 use anyhow::{Context, Result};
 
 use crate::cli::self_update::SelfUpdateMode;
+use crate::dist::cache::DownloadCache;
 use crate::dist::dist::Profile;
+use crate::dist::signatures::SignaturePolicy;
 use crate::errors::*;
 use crate::notifications::*;
 use crate::toml_utils::*;
+use crate::utils::lock::Lock;
+use crate::utils::retry_policy::RetryPolicy;
 use crate::utils::utils;
 
 pub(crate) const SUPPORTED_METADATA_VERSIONS: [&str; 2] = ["2", "12"];
@@ -18,31 +22,64 @@ pub(crate) const DEFAULT_METADATA_VERSION: &str = "12";
 #[derive(Clone, Debug, PartialEq)]
 pub struct SettingsFile {
     path: PathBuf,
+    /// Held while the settings are changed, so that changes made by rustup
+    /// processes running at the same time are not lost
+    lock_path: PathBuf,
     cache: RefCell<Option<Settings>>,
 }
 
 impl SettingsFile {
-    pub(crate) fn new(path: PathBuf) -> Self {
+    pub(crate) fn new(path: PathBuf, lock_path: PathBuf) -> Self {
         Self {
             path,
+            lock_path,
             cache: RefCell::new(None),
         }
     }
 
+    /// A copy of the settings file, kept to restore it from should it be
+    /// damaged
+    fn backup_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".bak");
+        PathBuf::from(path)
+    }
+
     fn write_settings(&self) -> Result<()> {
-        let s = self.cache.borrow().as_ref().unwrap().clone();
-        utils::write_file("settings", &self.path, &s.stringify())?;
+        let s = self.cache.borrow().as_ref().unwrap().clone().stringify();
+        utils::write_file_atomically("settings", &self.path, &s)?;
+        utils::write_file_atomically("settings backup", &self.backup_path(), &s)?;
         Ok(())
     }
 
+    /// Reads the settings file, restoring it from its backup if it cannot
+    /// be parsed, e.g. because a version of rustup which wrote it in place
+    /// was interrupted
+    fn parse_settings(&self) -> Result<Settings> {
+        let error = match utils::read_file("settings", &self.path)
+            .and_then(|content| Settings::parse(&content))
+        {
+            Ok(settings) => return Ok(settings),
+            Err(error) => error,
+        };
+        let backup_path = self.backup_path();
+        let backup = match utils::read_file("settings backup", &backup_path) {
+            Ok(backup) if Settings::parse(&backup).is_ok() => backup,
+            _ => return Err(error),
+        };
+        warn!("{}: '{}'", error, self.path.display());
+        warn!("restoring the settings from '{}'", backup_path.display());
+        utils::write_file_atomically("settings", &self.path, &backup)?;
+        Settings::parse(&backup)
+    }
+
     fn read_settings(&self) -> Result<()> {
         let mut needs_save = false;
         {
             let mut b = self.cache.borrow_mut();
             if b.is_none() {
                 *b = Some(if utils::is_file(&self.path) {
-                    let content = utils::read_file("settings", &self.path)?;
-                    Settings::parse(&content)?
+                    self.parse_settings()?
                 } else {
                     needs_save = true;
                     Default::default()
@@ -63,6 +100,11 @@ impl SettingsFile {
     }
 
     pub(crate) fn with_mut<T, F: FnOnce(&mut Settings) -> Result<T>>(&self, f: F) -> Result<T> {
+        let _lock = Lock::exclusive(&self.lock_path, &|n: crate::utils::Notification<'_>| {
+            info!("{}", n)
+        })?;
+        // Another process may have changed the settings since they were read
+        *self.cache.borrow_mut() = None;
         self.read_settings()?;
 
         // Settings can no longer be None so it's OK to unwrap
@@ -81,6 +123,12 @@ pub struct Settings {
     pub overrides: BTreeMap<String, String>,
     pub pgp_keys: Option<String>,
     pub auto_self_update: Option<SelfUpdateMode>,
+    pub signature_policy: Option<SignaturePolicy>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub dist_servers: Vec<String>,
+    pub cache: Option<DownloadCache>,
+    pub project_roots: Vec<String>,
+    pub retained_versions: Option<u64>,
 }
 
 impl Default for Settings {
@@ -93,6 +141,12 @@ impl Default for Settings {
             overrides: BTreeMap::new(),
             pgp_keys: None,
             auto_self_update: None,
+            signature_policy: None,
+            retry_policy: None,
+            dist_servers: Vec::new(),
+            cache: None,
+            project_roots: Vec::new(),
+            retained_versions: None,
         }
     }
 }
@@ -155,6 +209,28 @@ impl Settings {
             .and_then(|mode| SelfUpdateMode::from_str(mode.as_str()).ok());
         let profile = get_opt_string(&mut table, "profile", path)?
             .and_then(|p| Profile::from_str(p.as_str()).ok());
+        let signature_policy =
+            get_opt_string(&mut table, "signature_policy", path)?.and_then(|p| {
+                match SignaturePolicy::from_str(p.as_str()) {
+                    Ok(policy) => Some(policy),
+                    Err(e) => {
+                        warn!("{}; using the default", e);
+                        None
+                    }
+                }
+            });
+        let retry_policy = if table.contains_key("retry") {
+            let retry = get_table(&mut table, "retry", path)?;
+            Some(RetryPolicy::from_toml(retry, &format!("{}retry.", path))?)
+        } else {
+            None
+        };
+        let cache = if table.contains_key("cache") {
+            let cache = get_table(&mut table, "cache", path)?;
+            Some(DownloadCache::from_toml(cache, &format!("{}cache.", path))?)
+        } else {
+            None
+        };
         Ok(Self {
             version,
             default_host_triple: get_opt_string(&mut table, "default_host_triple", path)?,
@@ -163,6 +239,12 @@ impl Settings {
             overrides: Self::table_to_overrides(&mut table, path)?,
             pgp_keys: get_opt_string(&mut table, "pgp_keys", path)?,
             auto_self_update,
+            signature_policy,
+            retry_policy,
+            dist_servers: get_string_array(&mut table, "dist_servers", path)?,
+            cache,
+            project_roots: get_string_array(&mut table, "project_roots", path)?,
+            retained_versions: get_opt_u64(&mut table, "retained_versions", path)?,
         })
     }
     pub(crate) fn into_toml(self) -> toml::value::Table {
@@ -193,6 +275,36 @@ impl Settings {
             );
         }
 
+        if let Some(v) = self.signature_policy {
+            result.insert(
+                "signature_policy".to_owned(),
+                toml::Value::String(v.to_string()),
+            );
+        }
+
+        if let Some(v) = self.retry_policy {
+            result.insert("retry".to_owned(), toml::Value::Table(v.into_toml()));
+        }
+
+        if !self.dist_servers.is_empty() {
+            result.insert("dist_servers".to_owned(), string_array(self.dist_servers));
+        }
+
+        if let Some(v) = self.cache {
+            result.insert("cache".to_owned(), toml::Value::Table(v.into_toml()));
+        }
+
+        if !self.project_roots.is_empty() {
+            result.insert("project_roots".to_owned(), string_array(self.project_roots));
+        }
+
+        if let Some(v) = self.retained_versions {
+            result.insert(
+                "retained_versions".to_owned(),
+                toml::Value::Integer(v as i64),
+            );
+        }
+
         let overrides = Self::overrides_to_table(self.overrides);
         result.insert("overrides".to_owned(), toml::Value::Table(overrides));
 
@@ -223,3 +335,7 @@ impl Settings {
         result
     }
 }
+
+fn string_array(strings: Vec<String>) -> toml::Value {
+    toml::Value::Array(strings.into_iter().map(toml::Value::String).collect())
+}
@@ -14,19 +14,25 @@ use wait_timeout::ChildExt;
 
 use crate::component_for_bin;
 use crate::config::Cfg;
+use crate::dist::bundle::Bundle;
+use crate::dist::component::{Components, Damage};
 use crate::dist::dist::Profile;
 use crate::dist::dist::TargetTriple;
 use crate::dist::dist::ToolchainDesc;
-use crate::dist::download::DownloadCfg;
+use crate::dist::download::{to_update_hash, DownloadCfg};
+use crate::dist::lockfile::Lockfile;
 use crate::dist::manifest::Component;
 use crate::dist::manifest::Manifest;
-use crate::dist::manifestation::{Changes, Manifestation};
+use crate::dist::manifestation::{Changes, Manifestation, Plan};
 use crate::dist::prefix::InstallPrefix;
+use crate::dist::staging::{self, Staging};
+use crate::dist::versions::{self, Version};
 use crate::env_var;
 use crate::errors::*;
 use crate::install::{self, InstallMethod};
 use crate::notifications::*;
 use crate::process;
+use crate::utils::lock::Lock;
 use crate::utils::utils;
 
 /// An installed toolchain
@@ -37,8 +43,18 @@ trait InstalledToolchain<'a> {
 
 /// Installed paths
 enum InstalledPath<'a> {
-    File { name: &'static str, path: PathBuf },
-    Dir { path: &'a Path },
+    File {
+        name: &'static str,
+        path: PathBuf,
+    },
+    Dir {
+        path: &'a Path,
+    },
+    /// The tree of a distributable toolchain, which is kept until the
+    /// processes running it exit
+    Tree {
+        path: &'a Path,
+    },
 }
 
 /// A fully resolved reference to a toolchain which may or may not exist
@@ -162,8 +178,38 @@ impl<'a> Toolchain<'a> {
     pub fn verify(&self) -> Result<()> {
         utils::assert_is_directory(&self.path)
     }
+    /// Keeps other rustup processes from changing the toolchain until the
+    /// lock is dropped, waiting for any which are
+    pub(crate) fn lock(&self) -> Result<Lock> {
+        Lock::exclusive(&self.lock_path(), &|n: crate::utils::Notification<'_>| {
+            (self.cfg.notify_handler)(n.into())
+        })
+    }
+    /// Keeps the tree of the toolchain from being removed once it is
+    /// replaced or uninstalled, until the lock is dropped. Custom
+    /// toolchains are never removed under anyone, so are not locked, and
+    /// nor are toolchains which cannot be written, which rustup cannot
+    /// change either.
+    pub(crate) fn lock_in_use(&self) -> Result<Option<Lock>> {
+        if self.is_custom() || !self.exists() {
+            return Ok(None);
+        }
+        let path = staging::in_use_lock_path(&self.path);
+        Lock::shared_if_writable(&path, &|n: crate::utils::Notification<'_>| {
+            (self.cfg.notify_handler)(n.into())
+        })
+    }
+    fn lock_path(&self) -> PathBuf {
+        // Toolchains given by path are locked by the name of their directory
+        let name = self
+            .path
+            .file_name()
+            .map_or_else(|| self.name.clone(), |n| n.to_string_lossy().into_owned());
+        self.cfg.toolchain_lock_path(&name)
+    }
     // Custom and Distributable. Installed only.
     pub fn remove(&self) -> Result<()> {
+        let _lock = self.lock()?;
         if self.exists() || self.is_symlink() {
             (self.cfg.notify_handler)(Notification::UninstallingToolchain(&self.name));
         } else {
@@ -177,6 +223,10 @@ impl<'a> Toolchain<'a> {
                 InstalledPath::Dir { path } => {
                     install::uninstall(path, &|n| (self.cfg.notify_handler)(n.into()))?
                 }
+                InstalledPath::Tree { path } => {
+                    staging::retire(path, &*self.dist_handler)?;
+                    versions::retire_all(path, &*self.dist_handler)?;
+                }
             }
         }
         if !self.exists() {
@@ -541,58 +591,16 @@ impl<'a> DistributableToolchain<'a> {
     }
 
     // Installed only.
-    pub(crate) fn add_component(&self, mut component: Component) -> Result<()> {
+    pub(crate) fn add_component(&self, component: Component) -> Result<()> {
+        let _lock = self.0.lock()?;
         if let Some(desc) = self.get_toolchain_desc_with_manifest()? {
-            // Rename the component if necessary.
-            if let Some(c) = desc.manifest.rename_component(&component) {
-                component = c;
-            }
-
-            // Validate the component name
-            let rust_pkg = desc
-                .manifest
-                .packages
-                .get("rust")
-                .expect("manifest should contain a rust package");
-            let targ_pkg = rust_pkg
-                .targets
-                .get(&desc.toolchain.target)
-                .expect("installed manifest should have a known target");
-
-            if !targ_pkg.components.contains(&component) {
-                let wildcard_component = component.wildcard();
-                if targ_pkg.components.contains(&wildcard_component) {
-                    component = wildcard_component;
-                } else {
-                    return Err(RustupError::UnknownComponent {
-                        name: self.0.name.to_string(),
-                        component: component.description(&desc.manifest),
-                        suggestion: self.get_component_suggestion(
-                            &component,
-                            &desc.manifest,
-                            false,
-                        ),
-                    }
-                    .into());
-                }
-            }
-
             let changes = Changes {
-                explicit_add_components: vec![component],
+                explicit_add_components: vec![self.component_to_add(&desc, component)?],
                 remove_components: vec![],
+                repair_components: vec![],
             };
 
-            desc.manifestation.update(
-                &desc.manifest,
-                changes,
-                false,
-                &self.download_cfg(),
-                &self.download_cfg().notify_handler,
-                &desc.toolchain.manifest_name(),
-                false,
-            )?;
-
-            Ok(())
+            self.update_staged(&desc, changes)
         } else {
             Err(RustupError::MissingManifest {
                 name: self.0.name.to_string(),
@@ -601,6 +609,45 @@ impl<'a> DistributableToolchain<'a> {
         }
     }
 
+    /// The component of the toolchain described by `desc` which adding
+    /// `component` adds
+    fn component_to_add(
+        &self,
+        desc: &ToolchainDescWithManifest,
+        mut component: Component,
+    ) -> Result<Component> {
+        // Rename the component if necessary.
+        if let Some(c) = desc.manifest.rename_component(&component) {
+            component = c;
+        }
+
+        // Validate the component name
+        let rust_pkg = desc
+            .manifest
+            .packages
+            .get("rust")
+            .expect("manifest should contain a rust package");
+        let targ_pkg = rust_pkg
+            .targets
+            .get(&desc.toolchain.target)
+            .expect("installed manifest should have a known target");
+
+        if !targ_pkg.components.contains(&component) {
+            let wildcard_component = component.wildcard();
+            if targ_pkg.components.contains(&wildcard_component) {
+                component = wildcard_component;
+            } else {
+                return Err(RustupError::UnknownComponent {
+                    name: self.0.name.to_string(),
+                    component: component.description(&desc.manifest),
+                    suggestion: self.get_component_suggestion(&component, &desc.manifest, false),
+                }
+                .into());
+            }
+        }
+        Ok(component)
+    }
+
     // Create a command as a fallback for another toolchain. This is used
     // to give custom toolchains access to cargo
     // Installed only.
@@ -772,10 +819,53 @@ impl<'a> DistributableToolchain<'a> {
             old_date: old_date.as_deref(),
             components,
             targets,
+            lockfile: None,
+            retained_versions: self.retained_versions()?,
         }
         .install(self.0)
     }
 
+    // Installed or not installed.
+    /// Works out what `install_from_dist` would do, fetching the manifest
+    /// but neither downloading any component nor touching the toolchain
+    pub(crate) fn plan_install_from_dist(
+        &self,
+        force_update: bool,
+        allow_downgrade: bool,
+        components: &[&str],
+        targets: &[&str],
+    ) -> Result<Plan> {
+        let exists = self.0.exists();
+        let update_hash = self.0.cfg.get_hash_file(&self.0.name, false)?;
+        let old_date = self.get_manifest().ok().and_then(|m| m.map(|m| m.date));
+        let plan = crate::dist::dist::plan_update_from_dist(
+            self.download_cfg(),
+            // A stray hash would be removed before installing
+            if exists {
+                Some(update_hash.as_path())
+            } else {
+                None
+            },
+            &self.desc()?,
+            if exists {
+                None
+            } else {
+                Some(self.0.cfg.get_profile()?)
+            },
+            &InstallPrefix::from(self.0.path.to_owned()),
+            force_update,
+            allow_downgrade,
+            old_date.as_deref(),
+            components,
+            targets,
+        )?;
+        match plan {
+            Some(plan) => Ok(plan),
+            // The installed release is still the latest
+            None => self.plan_component_changes(vec![], vec![]),
+        }
+    }
+
     // Installed or not installed.
     pub fn install_from_dist_if_not_installed(&self) -> Result<UpdateStatus> {
         let update_hash = self.update_hash()?;
@@ -792,6 +882,8 @@ impl<'a> DistributableToolchain<'a> {
                 old_date: None,
                 components: &[],
                 targets: &[],
+                lockfile: None,
+                retained_versions: 0,
             }
             .install(self.0)?)
         } else {
@@ -800,6 +892,64 @@ impl<'a> DistributableToolchain<'a> {
         }
     }
 
+    // Installed or not installed.
+    pub(crate) fn install_from_lockfile(&self, lockfile: &Lockfile) -> Result<UpdateStatus> {
+        self.install_locked(lockfile, self.download_cfg())
+    }
+
+    // Installed or not installed.
+    pub(crate) fn install_from_bundle(&self, bundle: &Bundle<'_>) -> Result<UpdateStatus> {
+        self.install_locked(&bundle.lockfile, bundle.download_cfg(self.download_cfg()))
+    }
+
+    fn install_locked(&self, lockfile: &Lockfile, dl_cfg: DownloadCfg<'_>) -> Result<UpdateStatus> {
+        let desc = self.desc()?;
+        lockfile.check_toolchain(&desc)?;
+        if self.matches_lockfile(lockfile)? {
+            (self.0.cfg.notify_handler)(Notification::UsingExistingToolchain(&self.0.name));
+            return Ok(UpdateStatus::Unchanged);
+        }
+        let update_hash = self.update_hash()?;
+        InstallMethod::Dist {
+            desc: &desc,
+            profile: self.0.cfg.get_profile()?,
+            update_hash: Some(&update_hash),
+            dl_cfg,
+            force_update: false,
+            allow_downgrade: false,
+            exists: self.0.exists(),
+            old_date: None,
+            components: &[],
+            targets: &[],
+            lockfile: Some(lockfile),
+            retained_versions: 0,
+        }
+        .install(self.0)
+    }
+
+    /// Whether the installed toolchain was built from the manifest pinned by
+    /// `lockfile`, with exactly the pinned components
+    fn matches_lockfile(&self, lockfile: &Lockfile) -> Result<bool> {
+        if !self.0.exists() {
+            return Ok(false);
+        }
+        let update_hash = self.update_hash()?;
+        let installed_hash = utils::read_file("update hash", &update_hash).unwrap_or_default();
+        if installed_hash.trim() != to_update_hash(&lockfile.manifest_hash) {
+            return Ok(false);
+        }
+        let prefix = InstallPrefix::from(self.0.path.to_owned());
+        let manifestation = Manifestation::open(prefix, self.desc()?.target)?;
+        let mut installed = manifestation
+            .read_config()?
+            .map(|c| c.components)
+            .unwrap_or_default();
+        let mut locked = lockfile.locked_components();
+        installed.sort();
+        locked.sort();
+        Ok(installed == locked)
+    }
+
     pub(crate) fn get_toolchain_desc_with_manifest(
         &self,
     ) -> Result<Option<ToolchainDescWithManifest>> {
@@ -830,44 +980,16 @@ impl<'a> DistributableToolchain<'a> {
     }
 
     // Installed only.
-    pub(crate) fn remove_component(&self, mut component: Component) -> Result<()> {
+    pub(crate) fn remove_component(&self, component: Component) -> Result<()> {
+        let _lock = self.0.lock()?;
         if let Some(desc) = self.get_toolchain_desc_with_manifest()? {
-            // Rename the component if necessary.
-            if let Some(c) = desc.manifest.rename_component(&component) {
-                component = c;
-            }
-
-            let dist_config = desc.manifestation.read_config()?.unwrap();
-            if !dist_config.components.contains(&component) {
-                let wildcard_component = component.wildcard();
-                if dist_config.components.contains(&wildcard_component) {
-                    component = wildcard_component;
-                } else {
-                    return Err(RustupError::UnknownComponent {
-                        name: self.0.name.to_string(),
-                        component: component.description(&desc.manifest),
-                        suggestion: self.get_component_suggestion(&component, &desc.manifest, true),
-                    }
-                    .into());
-                }
-            }
-
             let changes = Changes {
                 explicit_add_components: vec![],
-                remove_components: vec![component],
+                remove_components: vec![self.component_to_remove(&desc, component)?],
+                repair_components: vec![],
             };
 
-            desc.manifestation.update(
-                &desc.manifest,
-                changes,
-                false,
-                &self.download_cfg(),
-                &self.download_cfg().notify_handler,
-                &desc.toolchain.manifest_name(),
-                false,
-            )?;
-
-            Ok(())
+            self.update_staged(&desc, changes)
         } else {
             Err(RustupError::MissingManifest {
                 name: self.0.name.to_string(),
@@ -876,15 +998,83 @@ impl<'a> DistributableToolchain<'a> {
         }
     }
 
+    /// The component of the toolchain described by `desc` which removing
+    /// `component` removes
+    fn component_to_remove(
+        &self,
+        desc: &ToolchainDescWithManifest,
+        mut component: Component,
+    ) -> Result<Component> {
+        // Rename the component if necessary.
+        if let Some(c) = desc.manifest.rename_component(&component) {
+            component = c;
+        }
+
+        let dist_config = desc.manifestation.read_config()?.unwrap();
+        if !dist_config.components.contains(&component) {
+            let wildcard_component = component.wildcard();
+            if dist_config.components.contains(&wildcard_component) {
+                component = wildcard_component;
+            } else {
+                return Err(RustupError::UnknownComponent {
+                    name: self.0.name.to_string(),
+                    component: component.description(&desc.manifest),
+                    suggestion: self.get_component_suggestion(&component, &desc.manifest, true),
+                }
+                .into());
+            }
+        }
+        Ok(component)
+    }
+
+    // Installed only.
+    /// Works out what adding the components `add` and removing the
+    /// components `remove` would do, without changing the toolchain
+    pub(crate) fn plan_component_changes(
+        &self,
+        add: Vec<Component>,
+        remove: Vec<Component>,
+    ) -> Result<Plan> {
+        let desc = self.get_toolchain_desc_with_manifest()?.ok_or_else(|| {
+            RustupError::MissingManifest {
+                name: self.0.name.to_string(),
+            }
+        })?;
+        let mut changes = Changes {
+            explicit_add_components: vec![],
+            remove_components: vec![],
+            repair_components: vec![],
+        };
+        for component in add {
+            let component = self.component_to_add(&desc, component)?;
+            if !changes.explicit_add_components.contains(&component) {
+                changes.explicit_add_components.push(component);
+            }
+        }
+        for component in remove {
+            let component = self.component_to_remove(&desc, component)?;
+            if !changes.remove_components.contains(&component) {
+                changes.remove_components.push(component);
+            }
+        }
+        let download_cfg = self.download_cfg();
+        desc.manifestation.plan(
+            &desc.manifest,
+            &changes,
+            false,
+            download_cfg.notify_handler,
+            &desc.toolchain.manifest_name(),
+        )
+    }
+
     // Installed only.
     pub fn show_dist_version(&self) -> Result<Option<String>> {
         let update_hash = self.update_hash()?;
 
-        match crate::dist::dist::dl_v2_manifest(
-            self.download_cfg(),
-            Some(&update_hash),
-            &self.desc()?,
-        )? {
+        let desc = self.desc()?;
+        match crate::dist::dist::with_mirrors(self.download_cfg(), |download| {
+            crate::dist::dist::dl_v2_manifest(download, Some(&update_hash), &desc)
+        })? {
             Some((manifest, _)) => Ok(Some(manifest.get_rust_version()?.to_string())),
             None => Ok(None),
         }
@@ -898,11 +1088,149 @@ impl<'a> DistributableToolchain<'a> {
         }
     }
 
+    // Installed only.
+    /// The space taken up by each installed component, as recorded in the
+    /// list of files it installed
+    pub(crate) fn component_sizes(&self) -> Result<Vec<(String, u64)>> {
+        let prefix = InstallPrefix::from(self.0.path.to_owned());
+        let components = Components::open(prefix.clone())?;
+        let mut sizes = Vec::new();
+        for component in components.list()? {
+            let size = component
+                .parts()?
+                .iter()
+                .map(|part| utils::disk_usage(&prefix.abs_path(&part.1)))
+                .sum();
+            sizes.push((component.name().to_owned(), size));
+        }
+        Ok(sizes)
+    }
+
+    // Installed only.
+    /// How the files of each installed component differ from what was
+    /// installed, or `None` for components installed without hashes
+    pub(crate) fn verify(&self) -> Result<Vec<(String, Option<Damage>)>> {
+        let components = Components::open(InstallPrefix::from(self.0.path.to_owned()))?;
+        components
+            .list()?
+            .iter()
+            .map(|component| Ok((component.name().to_owned(), component.verify()?)))
+            .collect()
+    }
+
+    // Installed only.
+    /// Installs the components called `names` by `verify` again, from the
+    /// manifest they were installed from
+    pub(crate) fn repair(&self, names: &[String]) -> Result<()> {
+        let _lock = self.0.lock()?;
+        let desc = self.get_toolchain_desc_with_manifest()?.ok_or_else(|| {
+            RustupError::MissingManifest {
+                name: self.0.name.to_string(),
+            }
+        })?;
+        let repair_components = desc
+            .manifestation
+            .read_config()?
+            .map(|config| config.components)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|c| {
+                names.contains(&c.name_in_manifest()) || names.contains(c.short_name_in_manifest())
+            })
+            .collect();
+        let changes = Changes {
+            explicit_add_components: vec![],
+            remove_components: vec![],
+            repair_components,
+        };
+
+        self.update_staged(&desc, changes)
+    }
+
+    // Installed only.
+    /// The version of the toolchain installed, if it was installed from a
+    /// v2 manifest
+    pub(crate) fn current_version(&self) -> Result<Option<Version>> {
+        Version::load(&self.0.path)
+    }
+
+    // Installed only.
+    /// The previous versions of the toolchain which are kept, newest first
+    pub(crate) fn kept_versions(&self) -> Result<Vec<Version>> {
+        versions::list(&self.0.path)
+    }
+
+    // Installed only.
+    /// Puts the kept version from `date`, or else the newest one, in the
+    /// place of the toolchain, returning which version that was
+    pub(crate) fn roll_back(&self, date: Option<&str>) -> Result<Version> {
+        let _lock = self.0.lock()?;
+        let kept = versions::list(&self.0.path)?;
+        if kept.is_empty() {
+            return Err(RustupError::NoRetainedVersions(self.0.name.to_string()).into());
+        }
+        let version = match date {
+            Some(date) => kept.into_iter().find(|v| v.date == date).ok_or_else(|| {
+                RustupError::RetainedVersionNotFound {
+                    name: self.0.name.to_string(),
+                    date: date.to_owned(),
+                }
+            })?,
+            None => kept.into_iter().next().expect("versions are kept"),
+        };
+        (self.0.cfg.notify_handler)(Notification::UpdatingToolchain(&self.0.name));
+        let rustc_before = self.0.rustc_version();
+        versions::roll_back(
+            &self.0.path,
+            &version,
+            self.0.cfg.retained_versions()?,
+            &*self.0.dist_handler,
+        )?;
+        // Otherwise the next update would find nothing to do
+        utils::ensure_file_removed("update hash", &self.update_hash()?)?;
+        self.0.cfg.history.installed(
+            Some(version.date.clone()),
+            None,
+            Some(rustc_before),
+            self.0.rustc_version(),
+        );
+        Ok(version)
+    }
+
+    // Installed only.
+    /// Makes `changes` to a staged copy of the toolchain described by
+    /// `desc`, which then takes the place of the toolchain
+    fn update_staged(&self, desc: &ToolchainDescWithManifest, changes: Changes) -> Result<()> {
+        (self.0.cfg.notify_handler)(Notification::UpdatingToolchain(&self.0.name));
+        let download_cfg = self.download_cfg();
+        let mut staging = Staging::new(&self.0.path, download_cfg.notify_handler)?;
+        let manifestation = Manifestation::open(staging.prefix()?, desc.toolchain.target.clone())?;
+        manifestation.update(
+            &desc.manifest,
+            changes,
+            false,
+            &download_cfg,
+            &download_cfg.notify_handler,
+            &desc.toolchain.manifest_name(),
+            false,
+        )?;
+        staging.commit()
+    }
+
     // Installed only.
     fn update_hash(&self) -> Result<PathBuf> {
         self.0.cfg.get_hash_file(&self.0.name, true)
     }
 
+    /// How many previous versions of the toolchain to keep when updating it
+    fn retained_versions(&self) -> Result<usize> {
+        if self.0.is_tracking() {
+            self.0.cfg.retained_versions()
+        } else {
+            Ok(0)
+        }
+    }
+
     // Installed only.
     pub fn guess_v1_manifest(&self) -> bool {
         let prefix = InstallPrefix::from(self.0.path().to_owned());
@@ -991,7 +1319,7 @@ impl<'a> InstalledToolchain<'a> for DistributableToolchain<'a> {
                 name: "update hash",
                 path: self.update_hash()?,
             },
-            InstalledPath::Dir { path },
+            InstalledPath::Tree { path },
         ])
     }
 }
@@ -0,0 +1,409 @@
+//! Advisory file locks, which keep rustup processes sharing a rustup home
+//! from changing the same things at once.
+//!
+//! The locks are POSIX record locks on Unix and `LockFileEx` locks on
+//! Windows, both of which are honoured by NFS and SMB servers, unlike
+//! `flock`. A lock is held by the process which took it until the `Lock`
+//! is dropped or the process exits, however it exits. A POSIX record lock
+//! belongs to the whole process rather than to the handle it was taken
+//! through, and is released when the process closes *any* handle to the
+//! file, so the threads of a process also take turns, through `Claim`s.
+//!
+//! Whoever holds a lock writes their pid into the file, so that processes
+//! waiting for it can say who they are waiting for.
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+
+use crate::errors::RustupError;
+use crate::utils::notifications::Notification;
+use crate::utils::utils;
+
+/// A lock on a file, released when dropped
+#[derive(Debug)]
+pub(crate) struct Lock {
+    // Closed before the claim is released, as closing it releases the lock
+    file: File,
+    claim: Claim,
+}
+
+impl Lock {
+    /// Takes a lock on `path` which others may share, but which keeps
+    /// anyone from taking it exclusively, waiting for it if need be
+    pub(crate) fn shared<'a, N>(path: &'a Path, notify_handler: &dyn Fn(N)) -> Result<Self>
+    where
+        N: From<Notification<'a>>,
+    {
+        Self::acquire(path, false, notify_handler)
+    }
+
+    /// Takes a lock on `path` which nobody else may hold at the same time,
+    /// waiting for it if need be
+    pub(crate) fn exclusive<'a, N>(path: &'a Path, notify_handler: &dyn Fn(N)) -> Result<Self>
+    where
+        N: From<Notification<'a>>,
+    {
+        Self::acquire(path, true, notify_handler)
+    }
+
+    /// `shared`, unless the lock file cannot be written, as in a rustup home
+    /// which is read-only, when nothing is locked
+    pub(crate) fn shared_if_writable<'a, N>(
+        path: &'a Path,
+        notify_handler: &dyn Fn(N),
+    ) -> Result<Option<Self>>
+    where
+        N: From<Notification<'a>>,
+    {
+        let claim = Claim::wait_for(path, notify_handler);
+        let file = match open_file(path) {
+            Ok(file) => file,
+            Err(e) if is_read_only(&e) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| RustupError::LockingFile(path.to_owned())),
+        };
+        Self { file, claim }
+            .wait(path, false, notify_handler)
+            .map(Some)
+    }
+
+    /// Takes an exclusive lock on `path` if nobody holds a lock on it
+    pub(crate) fn try_exclusive(path: &Path) -> Result<Option<Self>> {
+        let claim = match Claim::try_new(path) {
+            Some(claim) => claim,
+            None => return Ok(None),
+        };
+        let lock = Self::open(path, claim, &|_: Notification<'_>| ())?;
+        if lock.try_lock(true)? {
+            lock.record_holder();
+            Ok(Some(lock))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Keeps the lock held by the program this process goes on to `exec`
+    pub(crate) fn keep_on_exec(&self) -> Result<()> {
+        sys::keep_on_exec(&self.file).with_context(|| self.error())
+    }
+
+    fn acquire<'a, N>(path: &'a Path, exclusive: bool, notify_handler: &dyn Fn(N)) -> Result<Self>
+    where
+        N: From<Notification<'a>>,
+    {
+        let claim = Claim::wait_for(path, notify_handler);
+        Self::open(path, claim, notify_handler)?.wait(path, exclusive, notify_handler)
+    }
+
+    fn wait<'a, N>(
+        self,
+        path: &'a Path,
+        exclusive: bool,
+        notify_handler: &dyn Fn(N),
+    ) -> Result<Self>
+    where
+        N: From<Notification<'a>>,
+    {
+        if !self.try_lock(exclusive)? {
+            let holder = sys::holder(&self.file, exclusive).or_else(|| self.recorded_holder());
+            notify_handler(Notification::WaitingForLock(path, holder).into());
+            sys::lock(&self.file, exclusive).with_context(|| self.error())?;
+        }
+        self.record_holder();
+        Ok(self)
+    }
+
+    fn open<'a, N>(path: &'a Path, claim: Claim, notify_handler: &dyn Fn(N)) -> Result<Self>
+    where
+        N: From<Notification<'a>>,
+    {
+        if let Some(dir) = path.parent() {
+            utils::ensure_dir_exists("locks", dir, notify_handler)?;
+        }
+        let file = open_file(path).with_context(|| RustupError::LockingFile(path.to_owned()))?;
+        Ok(Self { file, claim })
+    }
+
+    fn try_lock(&self, exclusive: bool) -> Result<bool> {
+        sys::try_lock(&self.file, exclusive).with_context(|| self.error())
+    }
+
+    fn record_holder(&self) {
+        // Only used for messages, so failing to write it does no harm
+        let mut file = &self.file;
+        let _ = file
+            .set_len(0)
+            .and_then(|()| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", std::process::id()));
+    }
+
+    fn recorded_holder(&self) -> Option<u32> {
+        let mut pid = String::new();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0)).ok()?;
+        file.read_to_string(&mut pid).ok()?;
+        pid.trim().parse().ok()
+    }
+
+    fn error(&self) -> RustupError {
+        RustupError::LockingFile(self.claim.0.clone())
+    }
+}
+
+lazy_static! {
+    /// The files which `Lock`s of this process are on, and a signal for
+    /// when one is let go of
+    static ref CLAIMED: (Mutex<HashSet<PathBuf>>, Condvar) = Default::default();
+}
+
+/// Keeps the other threads of this process from locking a file until
+/// dropped
+#[derive(Debug)]
+struct Claim(PathBuf);
+
+impl Claim {
+    fn wait_for<'a, N>(path: &'a Path, notify_handler: &dyn Fn(N)) -> Self
+    where
+        N: From<Notification<'a>>,
+    {
+        let (claimed, released) = &*CLAIMED;
+        let mut claimed = lock_claimed(claimed);
+        if claimed.contains(path) {
+            let holder = Some(std::process::id());
+            notify_handler(Notification::WaitingForLock(path, holder).into());
+            while claimed.contains(path) {
+                claimed = released
+                    .wait(claimed)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+        claimed.insert(path.to_owned());
+        Self(path.to_owned())
+    }
+
+    fn try_new(path: &Path) -> Option<Self> {
+        let mut claimed = lock_claimed(&CLAIMED.0);
+        if claimed.insert(path.to_owned()) {
+            Some(Self(path.to_owned()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let (claimed, released) = &*CLAIMED;
+        lock_claimed(claimed).remove(&self.0);
+        released.notify_all();
+    }
+}
+
+fn lock_claimed(claimed: &Mutex<HashSet<PathBuf>>) -> MutexGuard<'_, HashSet<PathBuf>> {
+    // The set is never left half changed
+    claimed.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+}
+
+/// Whether `e` says that a file cannot be written, rather than that
+/// something went wrong
+fn is_read_only(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        if e.raw_os_error() == Some(libc::EROFS) {
+            return true;
+        }
+    }
+    // Which both EACCES and EPERM are
+    e.kind() == io::ErrorKind::PermissionDenied
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    /// Locks the whole file, however long it grows
+    fn flock(exclusive: bool) -> libc::flock {
+        // Some platforms have more fields than the ones set here
+        let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+        flock.l_type = if exclusive {
+            libc::F_WRLCK
+        } else {
+            libc::F_RDLCK
+        } as _;
+        flock.l_whence = libc::SEEK_SET as _;
+        flock.l_start = 0;
+        flock.l_len = 0;
+        flock
+    }
+
+    pub(super) fn try_lock(file: &File, exclusive: bool) -> io::Result<bool> {
+        let flock = flock(exclusive);
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &flock as *const _) } == 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EAGAIN) => Ok(false),
+            _ => Err(error),
+        }
+    }
+
+    pub(super) fn lock(file: &File, exclusive: bool) -> io::Result<()> {
+        let flock = flock(exclusive);
+        loop {
+            if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLKW, &flock as *const _) } == 0 {
+                return Ok(());
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+
+    /// The process holding the lock which keeps `file` from being locked,
+    /// if the system knows it. It may not for a lock held on another
+    /// machine.
+    pub(super) fn holder(file: &File, exclusive: bool) -> Option<u32> {
+        let mut flock = flock(exclusive);
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut flock as *mut _) } != 0 {
+            return None;
+        }
+        if flock.l_type == libc::F_UNLCK as _ || flock.l_pid <= 0 {
+            None
+        } else {
+            Some(flock.l_pid as u32)
+        }
+    }
+
+    pub(super) fn keep_on_exec(file: &File) -> io::Result<()> {
+        // A process keeps its record locks across exec, as long as it keeps
+        // the file open
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFD, 0) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+
+    /// Locks on Windows keep others from reading what they cover, so the
+    /// byte locked is well past the pid written in the file
+    fn lock_file(file: &File, flags: u32) -> io::Result<()> {
+        let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+        unsafe {
+            let offset = overlapped.u.s_mut();
+            offset.Offset = 0;
+            offset.OffsetHigh = 1;
+        }
+        if unsafe { LockFileEx(file.as_raw_handle() as _, flags, 0, 1, 0, &mut overlapped) } != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn flags(exclusive: bool) -> u32 {
+        if exclusive {
+            LOCKFILE_EXCLUSIVE_LOCK
+        } else {
+            0
+        }
+    }
+
+    pub(super) fn try_lock(file: &File, exclusive: bool) -> io::Result<bool> {
+        match lock_file(file, flags(exclusive) | LOCKFILE_FAIL_IMMEDIATELY) {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(super) fn lock(file: &File, exclusive: bool) -> io::Result<()> {
+        lock_file(file, flags(exclusive))
+    }
+
+    /// Windows does not say who holds a lock
+    pub(super) fn holder(_: &File, _: bool) -> Option<u32> {
+        None
+    }
+
+    pub(super) fn keep_on_exec(_: &File) -> io::Result<()> {
+        // Proxies wait for the programs they run on Windows, holding the
+        // lock all along
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_records_holder_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locks").join("test.lock");
+        let notify = |_: Notification<'_>| ();
+
+        let lock = Lock::exclusive(&path, &notify).unwrap();
+        assert_eq!(lock.recorded_holder(), Some(std::process::id()));
+        drop(lock);
+
+        let lock = Lock::try_exclusive(&path).unwrap();
+        assert!(lock.is_some());
+    }
+
+    #[test]
+    fn threads_take_turns() {
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.lock");
+        let notify = |_: Notification<'_>| ();
+        let lock = Lock::exclusive(&path, &notify).unwrap();
+
+        let (tx, rx) = channel();
+        let other = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                tx.send(Lock::try_exclusive(&path).unwrap().is_some())
+                    .unwrap();
+                let notify = |_: Notification<'_>| ();
+                let _lock = Lock::exclusive(&path, &notify).unwrap();
+                tx.send(true).unwrap();
+            })
+        };
+        assert!(!rx.recv().unwrap());
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(lock);
+        assert!(rx.recv().unwrap());
+        other.join().unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 ///!  Utility functions for Rustup
+pub(crate) mod lock;
 pub(crate) mod notifications;
 pub mod raw;
+pub mod retry_policy;
 pub(crate) mod toml_utils;
 pub(crate) mod tty;
 pub(crate) mod units;
@@ -1,5 +1,6 @@
 use std::fmt::{self, Display};
 use std::path::Path;
+use std::time::Duration;
 
 use url::Url;
 
@@ -17,6 +18,8 @@ pub enum Notification<'a> {
     DownloadContentLengthReceived(u64),
     /// Received some data.
     DownloadDataReceived(&'a [u8]),
+    /// Received this many bytes of data, which went elsewhere.
+    DownloadBytesReceived(usize),
     /// Download has finished.
     DownloadFinished,
     /// The things we're tracking that are not counted in bytes.
@@ -27,6 +30,8 @@ pub enum Notification<'a> {
     DownloadPopUnit,
     NoCanonicalPath(&'a Path),
     ResumingPartialDownload,
+    /// A download failed, and is tried again after the delay
+    RetryingDownload(&'a Url, Duration),
     /// This would make more sense as a crate::notifications::Notification
     /// member, but the notification callback is already narrowed to
     /// utils::notifications by the time tar unpacking is called.
@@ -40,6 +45,9 @@ pub enum Notification<'a> {
     /// running programs like virus scanner are known to cause this
     /// the heuristic is quite good.
     RenameInUse(&'a Path, &'a Path),
+    /// Another process holds a lock which is needed, with the pid of that
+    /// process if it is known
+    WaitingForLock(&'a Path, Option<u32>),
 }
 
 impl<'a> Notification<'a> {
@@ -54,13 +62,16 @@ impl<'a> Notification<'a> {
             | DownloadingFile(_, _)
             | DownloadContentLengthReceived(_)
             | DownloadDataReceived(_)
+            | DownloadBytesReceived(_)
             | DownloadPushUnit(_)
             | DownloadPopUnit
             | DownloadFinished
             | ResumingPartialDownload
             | UsingCurl
             | UsingReqwest => NotificationLevel::Verbose,
-            RenameInUse(_, _) => NotificationLevel::Info,
+            RenameInUse(_, _) | RetryingDownload(_, _) | WaitingForLock(_, _) => {
+                NotificationLevel::Info
+            }
             NoCanonicalPath(_) => NotificationLevel::Warn,
             Error(_) => NotificationLevel::Error,
         }
@@ -86,6 +97,13 @@ impl<'a> Display for Notification<'a> {
                 src.display(),
                 dest.display()
             ),
+            WaitingForLock(path, Some(pid)) => write!(
+                f,
+                "waiting for lock held by pid {}: '{}'",
+                pid,
+                path.display()
+            ),
+            WaitingForLock(path, None) => write!(f, "waiting for lock: '{}'", path.display()),
             SetDefaultBufferSize(size) => write!(
                 f,
                 "using up to {} of RAM to unpack components",
@@ -94,11 +112,18 @@ impl<'a> Display for Notification<'a> {
             DownloadingFile(url, _) => write!(f, "downloading file from: '{}'", url),
             DownloadContentLengthReceived(len) => write!(f, "download size is: '{}'", len),
             DownloadDataReceived(data) => write!(f, "received some data of size {}", data.len()),
+            DownloadBytesReceived(len) => write!(f, "received some data of size {}", len),
             DownloadPushUnit(_) => Ok(()),
             DownloadPopUnit => Ok(()),
             DownloadFinished => write!(f, "download finished"),
             NoCanonicalPath(path) => write!(f, "could not canonicalize path: '{}'", path.display()),
             ResumingPartialDownload => write!(f, "resuming partial download"),
+            RetryingDownload(url, delay) => write!(
+                f,
+                "retrying download of '{}' in {:.1}s",
+                url,
+                delay.as_secs_f64()
+            ),
             UsingCurl => write!(f, "downloading with curl"),
             UsingReqwest => write!(f, "downloading with reqwest"),
         }
@@ -64,30 +64,18 @@ pub fn write_file(path: &Path, contents: &str) -> io::Result<()> {
     Ok(())
 }
 
-pub(crate) fn filter_file<F: FnMut(&str) -> bool>(
-    src: &Path,
-    dest: &Path,
-    mut filter: F,
-) -> io::Result<usize> {
-    let src_file = fs::File::open(src)?;
-    let dest_file = fs::File::create(dest)?;
-
-    let mut reader = io::BufReader::new(src_file);
-    let mut writer = io::BufWriter::new(dest_file);
-    let mut removed = 0;
-
-    for result in io::BufRead::lines(&mut reader) {
-        let line = result?;
-        if filter(&line) {
-            writeln!(writer, "{}", &line)?;
-        } else {
-            removed += 1;
-        }
+/// Makes sure that the entries of the directory which `path` is in, as
+/// changed by creating or renaming `path`, have reached the disk
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    // Directories cannot be opened as files on Windows
+    if cfg!(unix) {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()?;
     }
-
-    writer.flush()?;
-
-    Ok(removed)
+    Ok(())
 }
 
 pub fn append_file(dest: &Path, line: &str) -> io::Result<()> {
@@ -249,6 +237,95 @@ pub(crate) fn copy_dir(src: &Path, dest: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Recreates the tree at `src` at `dest`, hard linking its files where the
+/// file system allows it and copying them where it does not. Whatever
+/// `skip` holds true for is left out.
+pub(crate) fn hardlink_dir(
+    src: &Path,
+    dest: &Path,
+    skip: &dyn Fn(&Path) -> bool,
+) -> io::Result<()> {
+    fs::create_dir(dest)?;
+    for entry in src.read_dir()? {
+        let entry = entry?;
+        let src = entry.path();
+        if skip(&src) {
+            continue;
+        }
+        let dest = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            hardlink_dir(&src, &dest, skip)?;
+        } else if fs::hard_link(&src, &dest).is_err() {
+            fs::copy(&src, &dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// The file system holding a path
+#[derive(Debug)]
+pub(crate) struct FileSystem {
+    #[cfg(unix)]
+    id: u64,
+    /// The path of the volume
+    #[cfg(windows)]
+    id: std::ffi::OsString,
+    /// The bytes the user may still write to it
+    pub(crate) free: u64,
+}
+
+impl FileSystem {
+    pub(crate) fn same_as(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn file_system(path: &Path) -> io::Result<FileSystem> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let id = fs::metadata(path)?.dev();
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The fields are narrower on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    let free = (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64);
+    Ok(FileSystem { id, free })
+}
+
+#[cfg(windows)]
+pub(crate) fn file_system(path: &Path) -> io::Result<FileSystem> {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+    use std::ptr;
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::fileapi::{GetDiskFreeSpaceExW, GetVolumePathNameW};
+    use winapi::um::winnt::ULARGE_INTEGER;
+
+    let path = windows::to_u16s(path)?;
+    let mut volume = vec![0u16; 1024];
+    let mut free: ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+    unsafe {
+        if GetVolumePathNameW(path.as_ptr(), volume.as_mut_ptr(), volume.len() as DWORD) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if GetDiskFreeSpaceExW(path.as_ptr(), &mut free, ptr::null_mut(), ptr::null_mut()) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let len = volume.iter().position(|&c| c == 0).unwrap_or(volume.len());
+    Ok(FileSystem {
+        id: OsString::from_wide(&volume[..len]),
+        free: unsafe { *free.QuadPart() },
+    })
+}
+
 #[cfg(not(windows))]
 fn has_cmd(cmd: &str) -> bool {
     let cmd = format!("{}{}", cmd, env::consts::EXE_SUFFIX);
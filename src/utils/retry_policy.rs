@@ -0,0 +1,282 @@
+//! How failed downloads are retried.
+//!
+//! Every download, be it of a manifest, a checksum, a signature, a component
+//! or of rustup itself, is retried according to the same policy: after a
+//! delay which doubles with every retry, up to a maximum, and with random
+//! jitter so that clients which failed together do not retry together. Only
+//! failures which may not happen again are retried, and none once the
+//! deadline has passed. The policy is the `[retry]` table of the settings
+//! file, if there is one, with `RUSTUP_MAX_RETRIES` overriding how many
+//! times to retry.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use retry::delay::jitter;
+
+use crate::process;
+use crate::utils::toml_utils::*;
+
+const DEFAULT_MAX_RETRIES: u64 = 3;
+const DEFAULT_INITIAL_DELAY_MS: u64 = 500;
+const DEFAULT_MAX_DELAY_MS: u64 = 10_000;
+/// Timeouts, rate limiting and server errors, which tend to go away
+const DEFAULT_STATUSES: [u32; 6] = [408, 429, 500, 502, 503, 504];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a failed download is retried
+    pub max_retries: u64,
+    /// The delay before the first retry, doubled for each retry after it
+    pub initial_delay: Duration,
+    /// The longest delay before a retry
+    pub max_delay: Duration,
+    /// How long after a download is first tried it may still be retried
+    pub deadline: Option<Duration>,
+    /// The HTTP status codes which are worth retrying
+    pub statuses: Vec<u32>,
+    /// Whether connection and IO errors are worth retrying
+    pub io_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_delay: Duration::from_millis(DEFAULT_INITIAL_DELAY_MS),
+            max_delay: Duration::from_millis(DEFAULT_MAX_DELAY_MS),
+            deadline: None,
+            statuses: DEFAULT_STATUSES.to_vec(),
+            io_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The policy of the settings file, or the default one, with
+    /// `RUSTUP_MAX_RETRIES` overriding how many times to retry
+    pub(crate) fn configured(settings: Option<Self>) -> Self {
+        let mut policy = settings.unwrap_or_default();
+        if let Some(max_retries) = process()
+            .var("RUSTUP_MAX_RETRIES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            policy.max_retries = max_retries;
+        }
+        policy
+    }
+
+    /// Runs `f` until it succeeds, fails in a way which `is_retryable` says
+    /// is not worth retrying, or the policy gives up, returning the last
+    /// error. `on_retry` is told how long the wait before each retry is.
+    pub(crate) fn run<T>(
+        &self,
+        mut f: impl FnMut() -> Result<T>,
+        is_retryable: impl Fn(&anyhow::Error) -> bool,
+        on_retry: impl Fn(Duration),
+    ) -> Result<T> {
+        let start = Instant::now();
+        let mut delays = self.delays();
+        loop {
+            let e = match f() {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            let delay = match delays.next() {
+                Some(delay) if is_retryable(&e) => delay,
+                _ => return Err(e),
+            };
+            if let Some(deadline) = self.deadline {
+                if start.elapsed() + delay > deadline {
+                    return Err(e);
+                }
+            }
+            on_retry(delay);
+            thread::sleep(delay);
+        }
+    }
+
+    /// Whether a download which failed with `e` may succeed if it is tried
+    /// again
+    pub(crate) fn is_retryable(&self, e: &anyhow::Error) -> bool {
+        use download::DownloadError;
+        match e.chain().find_map(|e| e.downcast_ref::<DownloadError>()) {
+            Some(DownloadError::HttpStatus(status)) => self.statuses.contains(status),
+            Some(DownloadError::FileNotFound)
+            | Some(DownloadError::BackendUnavailable(_))
+            | Some(DownloadError::Message(_)) => false,
+            // Errors of the connection, or of reading and writing the data
+            Some(_) => self.io_errors,
+            None => false,
+        }
+    }
+
+    fn delays(&self) -> impl Iterator<Item = Duration> + '_ {
+        (0..self.max_retries).map(move |n| {
+            let delay = self
+                .initial_delay
+                .checked_mul(1 << n.min(31))
+                .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+            jitter(delay)
+        })
+    }
+
+    pub(crate) fn from_toml(mut table: toml::value::Table, path: &str) -> Result<Self> {
+        let default = Self::default();
+        let millis = |ms: Option<u64>, or: Duration| ms.map_or(or, Duration::from_millis);
+        let statuses = match table.remove("statuses") {
+            Some(toml::Value::Array(statuses)) => statuses
+                .into_iter()
+                .map(|status| match status {
+                    toml::Value::Integer(s @ 100..=599) => Ok(s as u32),
+                    _ => Err(anyhow!(
+                        "expected an HTTP status code in '{}statuses'",
+                        path
+                    )),
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => return Err(anyhow!("expected type: 'array' for '{}statuses'", path)),
+            None => default.statuses,
+        };
+        Ok(Self {
+            max_retries: get_opt_u64(&mut table, "max_retries", path)?
+                .unwrap_or(default.max_retries),
+            initial_delay: millis(
+                get_opt_u64(&mut table, "initial_delay_ms", path)?,
+                default.initial_delay,
+            ),
+            max_delay: millis(
+                get_opt_u64(&mut table, "max_delay_ms", path)?,
+                default.max_delay,
+            ),
+            deadline: get_opt_u64(&mut table, "deadline_secs", path)?.map(Duration::from_secs),
+            statuses,
+            io_errors: get_opt_bool(&mut table, "io_errors", path)?.unwrap_or(default.io_errors),
+        })
+    }
+
+    pub(crate) fn into_toml(self) -> toml::value::Table {
+        let mut result = toml::value::Table::new();
+        result.insert(
+            "max_retries".to_owned(),
+            toml::Value::Integer(self.max_retries as i64),
+        );
+        result.insert(
+            "initial_delay_ms".to_owned(),
+            toml::Value::Integer(self.initial_delay.as_millis() as i64),
+        );
+        result.insert(
+            "max_delay_ms".to_owned(),
+            toml::Value::Integer(self.max_delay.as_millis() as i64),
+        );
+        if let Some(deadline) = self.deadline {
+            result.insert(
+                "deadline_secs".to_owned(),
+                toml::Value::Integer(deadline.as_secs() as i64),
+            );
+        }
+        let statuses = self
+            .statuses
+            .into_iter()
+            .map(|s| toml::Value::Integer(s.into()))
+            .collect();
+        result.insert("statuses".to_owned(), toml::Value::Array(statuses));
+        result.insert("io_errors".to_owned(), toml::Value::Boolean(self.io_errors));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use download::DownloadError;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn delays_back_off_up_to_the_max_delay() {
+        let policy = RetryPolicy {
+            max_retries: 40,
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<_> = policy.delays().collect();
+        assert_eq!(delays.len(), 40);
+        assert!(delays.iter().all(|d| *d <= policy.max_delay));
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried() {
+        let policy = policy();
+        let status = |s| anyhow::Error::from(DownloadError::HttpStatus(s));
+        assert!(policy.is_retryable(&status(503)));
+        assert!(!policy.is_retryable(&status(404)));
+        assert!(!policy.is_retryable(&DownloadError::FileNotFound.into()));
+        let io = || {
+            anyhow::Error::from(DownloadError::IoError(
+                std::io::ErrorKind::ConnectionReset.into(),
+            ))
+            .context("failed to download")
+        };
+        assert!(policy.is_retryable(&io()));
+        let policy = RetryPolicy {
+            io_errors: false,
+            ..policy
+        };
+        assert!(!policy.is_retryable(&io()));
+    }
+
+    #[test]
+    fn run_retries_until_success_or_max_retries() {
+        let policy = policy();
+        let tries = Cell::new(0);
+        let retries = Cell::new(0);
+        let result = policy.run(
+            || {
+                tries.set(tries.get() + 1);
+                if tries.get() < 3 {
+                    Err(anyhow!("failed"))
+                } else {
+                    Ok(tries.get())
+                }
+            },
+            |_| true,
+            |_| retries.set(retries.get() + 1),
+        );
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(retries.get(), 2);
+
+        tries.set(0);
+        let result: Result<()> = policy.run(
+            || {
+                tries.set(tries.get() + 1);
+                Err(anyhow!("failed"))
+            },
+            |_| true,
+            |_| (),
+        );
+        assert!(result.is_err());
+        assert_eq!(tries.get() as u64, policy.max_retries + 1);
+    }
+
+    #[test]
+    fn retry_policy_round_trip() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            deadline: Some(Duration::from_secs(120)),
+            statuses: vec![503],
+            ..policy()
+        };
+        let table = policy.clone().into_toml();
+        assert_eq!(RetryPolicy::from_toml(table, "retry.").unwrap(), policy);
+    }
+}
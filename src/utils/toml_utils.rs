@@ -76,3 +76,41 @@ pub(crate) fn get_array(
         Ok(toml::value::Array::new())
     }
 }
+
+pub(crate) fn get_string_array(
+    table: &mut toml::value::Table,
+    key: &str,
+    path: &str,
+) -> Result<Vec<String>> {
+    get_array(table, key, path)?
+        .into_iter()
+        .map(|v| match v {
+            toml::Value::String(s) => Ok(s),
+            _ => Err(ExpectedType("string", format!("{}{}[]", path, key)).into()),
+        })
+        .collect()
+}
+
+pub(crate) fn get_opt_u64(
+    table: &mut toml::value::Table,
+    key: &str,
+    path: &str,
+) -> Result<Option<u64>> {
+    match table.remove(key) {
+        Some(toml::Value::Integer(i)) if i >= 0 => Ok(Some(i as u64)),
+        Some(_) => Err(ExpectedType("non-negative integer", path.to_owned() + key).into()),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn get_opt_bool(
+    table: &mut toml::value::Table,
+    key: &str,
+    path: &str,
+) -> Result<Option<bool>> {
+    match table.remove(key) {
+        Some(toml::Value::Boolean(b)) => Ok(Some(b)),
+        Some(_) => Err(ExpectedType("bool", path.to_owned() + key).into()),
+        None => Ok(None),
+    }
+}
@@ -75,6 +75,14 @@ impl Display for Size {
     }
 }
 
+/// A size of `bytes` to put in a message
+pub(crate) fn human_size(bytes: u64) -> String {
+    Size::new(bytes as usize, Unit::B, UnitMode::Norm)
+        .to_string()
+        .trim_start()
+        .to_owned()
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
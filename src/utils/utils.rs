@@ -1,4 +1,5 @@
 use std::cmp::Ord;
+use std::collections::HashSet;
 use std::env;
 use std::fs::{self, File};
 use std::io::{self, BufReader, Write};
@@ -14,6 +15,7 @@ use url::Url;
 use crate::errors::*;
 use crate::utils::notifications::Notification;
 use crate::utils::raw;
+use crate::utils::retry_policy::RetryPolicy;
 use crate::{home_process, process};
 
 #[cfg(not(windows))]
@@ -62,6 +64,24 @@ pub fn write_file(name: &'static str, path: &Path, contents: &str) -> Result<()>
     })
 }
 
+/// Replaces the file at `path` with one holding `contents`, written in full
+/// before it takes the place of the old one, so that should rustup be
+/// interrupted the file has either its old contents or its new
+pub(crate) fn write_file_atomically(name: &'static str, path: &Path, contents: &str) -> Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+    let result = write_file(name, &temp, contents)
+        .and_then(|()| rename_file(name, &temp, path, &|_: Notification<'_>| ()));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    raw::sync_parent_dir(path).with_context(|| RustupError::WritingFile {
+        name,
+        path: PathBuf::from(path),
+    })
+}
+
 pub(crate) fn append_file(name: &'static str, path: &Path, line: &str) -> Result<()> {
     raw::append_file(path, line).with_context(|| RustupError::WritingFile {
         name,
@@ -112,22 +132,6 @@ where
     rename(name, src, dest, notify)
 }
 
-pub(crate) fn filter_file<F: FnMut(&str) -> bool>(
-    name: &'static str,
-    src: &Path,
-    dest: &Path,
-    filter: F,
-) -> Result<usize> {
-    raw::filter_file(src, dest, filter).with_context(|| {
-        format!(
-            "could not copy {} file from '{}' to '{}'",
-            name,
-            src.display(),
-            dest.display()
-        )
-    })
-}
-
 pub(crate) fn canonicalize_path<'a, N>(path: &'a Path, notify_handler: &dyn Fn(N)) -> PathBuf
 where
     N: From<Notification<'a>>,
@@ -142,9 +146,10 @@ pub fn download_file(
     url: &Url,
     path: &Path,
     hasher: Option<&mut Sha256>,
+    retry_policy: &RetryPolicy,
     notify_handler: &dyn Fn(Notification<'_>),
 ) -> Result<()> {
-    download_file_with_resume(url, path, hasher, false, &notify_handler)
+    download_file_with_resume(url, path, hasher, false, retry_policy, &notify_handler)
 }
 
 pub(crate) fn download_file_with_resume(
@@ -152,10 +157,108 @@ pub(crate) fn download_file_with_resume(
     path: &Path,
     hasher: Option<&mut Sha256>,
     resume_from_partial: bool,
+    retry_policy: &RetryPolicy,
+    notify_handler: &dyn Fn(Notification<'_>),
+) -> Result<()> {
+    download_file_with_backend(
+        download_backend(),
+        url,
+        path,
+        hasher,
+        resume_from_partial,
+        retry_policy,
+        notify_handler,
+    )
+}
+
+/// The download backend selected by the environment
+pub(crate) fn download_backend() -> download::Backend {
+    use download::{Backend, TlsBackend};
+
+    // Keep the curl env var around for a bit
+    let use_curl_backend = process().var_os("RUSTUP_USE_CURL").is_some();
+    let use_rustls = process().var_os("RUSTUP_USE_RUSTLS").is_some();
+    if use_curl_backend {
+        Backend::Curl
+    } else {
+        let tls_backend = if use_rustls {
+            TlsBackend::Rustls
+        } else {
+            #[cfg(feature = "reqwest-default-tls")]
+            {
+                TlsBackend::Default
+            }
+            #[cfg(not(feature = "reqwest-default-tls"))]
+            {
+                TlsBackend::Rustls
+            }
+        };
+        Backend::Reqwest(tls_backend)
+    }
+}
+
+/// The size of what downloading `url` with `backend` would fetch, if the
+/// server says, found out without downloading it. This does not look at the
+/// current process, so can run on any thread, and failed requests are
+/// retried according to `retry_policy`.
+pub(crate) fn content_length(
+    backend: download::Backend,
+    url: &Url,
+    retry_policy: &RetryPolicy,
+) -> Result<Option<u64>> {
+    retry_policy
+        .run(
+            || download::content_length_with_backend(backend, url),
+            |e| retry_policy.is_retryable(e),
+            |_| {},
+        )
+        .with_context(|| format!("could not find the size of '{}'", url))
+}
+
+/// `download_file_with_resume` with the given backend, which does not look at
+/// the current process and so can run on any thread, unlike whatever then
+/// moves the downloaded file to another file system. Failed downloads are
+/// retried according to `retry_policy`.
+pub(crate) fn download_file_with_backend(
+    backend: download::Backend,
+    url: &Url,
+    path: &Path,
+    mut hasher: Option<&mut Sha256>,
+    resume_from_partial: bool,
+    retry_policy: &RetryPolicy,
     notify_handler: &dyn Fn(Notification<'_>),
 ) -> Result<()> {
     use download::DownloadError as DEK;
-    match download_file_(url, path, hasher, resume_from_partial, notify_handler) {
+    use sha2::Digest;
+    use std::cell::Cell;
+
+    let retrying = Cell::new(false);
+    let result = retry_policy.run(
+        || {
+            // Every try hashes the whole file, and one which does not resume
+            // writes it afresh
+            if let Some(hasher) = hasher.as_deref_mut() {
+                *hasher = Sha256::new();
+            }
+            if retrying.get() && !resume_from_partial {
+                ensure_file_removed("downloaded", path)?;
+            }
+            download_file_(
+                backend,
+                url,
+                path,
+                hasher.as_deref_mut(),
+                resume_from_partial,
+                notify_handler,
+            )
+        },
+        |e| retry_policy.is_retryable(e),
+        |delay| {
+            retrying.set(true);
+            notify_handler(Notification::RetryingDownload(url, delay));
+        },
+    );
+    match result {
         Ok(_) => Ok(()),
         Err(e) => {
             let is_client_error = match e.downcast_ref::<DEK>() {
@@ -183,6 +286,7 @@ pub(crate) fn download_file_with_resume(
 }
 
 fn download_file_(
+    backend: download::Backend,
     url: &Url,
     path: &Path,
     hasher: Option<&mut Sha256>,
@@ -190,7 +294,7 @@ fn download_file_(
     notify_handler: &dyn Fn(Notification<'_>),
 ) -> Result<()> {
     use download::download_to_path_with_backend;
-    use download::{Backend, Event, TlsBackend};
+    use download::{Backend, Event};
     use sha2::Digest;
     use std::cell::RefCell;
 
@@ -223,28 +327,10 @@ fn download_file_(
     };
 
     // Download the file
-
-    // Keep the curl env var around for a bit
-    let use_curl_backend = process().var_os("RUSTUP_USE_CURL").is_some();
-    let use_rustls = process().var_os("RUSTUP_USE_RUSTLS").is_some();
-    let (backend, notification) = if use_curl_backend {
-        (Backend::Curl, Notification::UsingCurl)
-    } else {
-        let tls_backend = if use_rustls {
-            TlsBackend::Rustls
-        } else {
-            #[cfg(feature = "reqwest-default-tls")]
-            {
-                TlsBackend::Default
-            }
-            #[cfg(not(feature = "reqwest-default-tls"))]
-            {
-                TlsBackend::Rustls
-            }
-        };
-        (Backend::Reqwest(tls_backend), Notification::UsingReqwest)
-    };
-    notify_handler(notification);
+    notify_handler(match backend {
+        Backend::Curl => Notification::UsingCurl,
+        Backend::Reqwest(_) => Notification::UsingReqwest,
+    });
     let res =
         download_to_path_with_backend(backend, url, path, resume_from_partial, Some(callback));
 
@@ -257,6 +343,28 @@ pub(crate) fn parse_url(url: &str) -> Result<Url> {
     Url::parse(url).with_context(|| format!("failed to parse url: {}", url))
 }
 
+/// The URL of `location`, which is a URL or a local path, either absolute or
+/// relative to the current directory
+pub(crate) fn url_or_path(location: &str) -> Result<String> {
+    let path = Path::new(location);
+    if Url::parse(location).is_ok() && !path.is_absolute() {
+        return Ok(location.to_owned());
+    }
+    let path = current_dir()?.join(path);
+    Url::from_file_path(&path)
+        .map(String::from)
+        .map_err(|()| anyhow!("cannot make a URL of the path '{}'", path.display()))
+}
+
+/// The path of a `file:` URL, which can be read in place
+pub(crate) fn local_path(url: &Url) -> Option<PathBuf> {
+    if url.scheme() == "file" {
+        url.to_file_path().ok()
+    } else {
+        None
+    }
+}
+
 pub(crate) fn assert_is_file(path: &Path) -> Result<()> {
     if !is_file(path) {
         Err(anyhow!(format!("not a file: '{}'", path.display())))
@@ -340,6 +448,23 @@ where
     })
 }
 
+pub(crate) fn hardlink_dir(src: &Path, dest: &Path, skip: &dyn Fn(&Path) -> bool) -> Result<()> {
+    raw::hardlink_dir(src, dest, skip).with_context(|| {
+        format!(
+            "could not copy directory from '{}' to '{}'",
+            src.display(),
+            dest.display()
+        )
+    })
+}
+
+/// The file system which `path` is on, or would be on once created
+pub(crate) fn file_system(path: &Path) -> Result<raw::FileSystem> {
+    let existing = path.ancestors().find(|p| path_exists(p)).unwrap_or(path);
+    raw::file_system(existing)
+        .with_context(|| format!("could not read the free space of '{}'", existing.display()))
+}
+
 pub(crate) fn copy_file(src: &Path, dest: &Path) -> Result<()> {
     let metadata = fs::symlink_metadata(src).with_context(|| RustupError::ReadingFile {
         name: "metadata for",
@@ -619,10 +744,11 @@ where
                     notify_handler(Notification::RenameInUse(src, dest).into());
                     OperationResult::Retry(e)
                 }
+                // Only a rename across file systems looks at the process
                 #[cfg(target_os = "linux")]
                 io::ErrorKind::Other
-                    if process().var_os("RUSTUP_PERMIT_COPY_RENAME").is_some()
-                        && Some(EXDEV) == e.raw_os_error() =>
+                    if Some(EXDEV) == e.raw_os_error()
+                        && process().var_os("RUSTUP_PERMIT_COPY_RENAME").is_some() =>
                 {
                     match copy_and_delete(name, src, dest, notify_handler) {
                         Ok(()) => OperationResult::Ok(()),
@@ -652,6 +778,38 @@ pub(crate) fn delete_dir_contents(dir_path: &Path) {
     }
 }
 
+/// The size of the file at `path`, or of everything in the directory at
+/// `path`. Symbolic links are not followed, a file with several hard links
+/// is counted once, and whatever cannot be read is not counted.
+pub(crate) fn disk_usage(path: &Path) -> u64 {
+    count_disk_usage(path, &mut HashSet::new())
+}
+
+/// `disk_usage`, leaving out the files with several hard links in `linked`,
+/// by device and inode, which have been counted already
+fn count_disk_usage(path: &Path, linked: &mut HashSet<(u64, u64)>) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if metadata.nlink() > 1 && !linked.insert((metadata.dev(), metadata.ino())) {
+                return 0;
+            }
+        }
+        return metadata.len();
+    }
+    fs::read_dir(path).map_or(0, |entries| {
+        entries
+            .filter_map(io::Result::ok)
+            .map(|entry| count_disk_usage(&entry.path(), linked))
+            .sum()
+    })
+}
+
 pub(crate) struct FileReaderWithProgress<'a> {
     fh: io::BufReader<File>,
     notify_handler: &'a dyn Fn(Notification<'_>),
@@ -744,6 +902,26 @@ pub(crate) fn home_dir_from_passwd() -> Option<PathBuf> {
 mod tests {
     use super::*;
 
+    #[test]
+    #[cfg(unix)]
+    fn disk_usage_counts_hard_links_once() {
+        let dir = tempfile::tempdir().unwrap();
+        write_file("test", &dir.path().join("a"), "contents").unwrap();
+        fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+        assert_eq!(disk_usage(dir.path()), 8);
+    }
+
+    #[test]
+    fn write_file_atomically_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.toml");
+        write_file("test", &path, "old").unwrap();
+        write_file_atomically("test", &path, "new").unwrap();
+        assert_eq!(read_file("test", &path).unwrap(), "new");
+        // The new contents are not left behind anywhere else
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
     #[test]
     fn test_toolchain_sort() {
         let expected = vec![
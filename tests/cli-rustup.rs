@@ -429,6 +429,27 @@ fn list_installed_targets() {
     });
 }
 
+#[test]
+fn list_installed_targets_json() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        expect_stdout_ok(
+            config,
+            &[
+                "rustup",
+                "target",
+                "list",
+                "--installed",
+                "--format",
+                "json",
+            ],
+            for_host!(
+                r#"{{"schema_version":1,"targets":[{{"name":"{0}","installed":true,"available":true}}]}}"#
+            ),
+        );
+    });
+}
+
 #[test]
 fn add_target_explicit() {
     setup(&|config| {
@@ -605,6 +626,22 @@ nightly-{0} (default)
     });
 }
 
+#[test]
+fn show_active_toolchain_json() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        expect_ok_ex(
+            config,
+            &["rustup", "show", "active-toolchain", "--format", "json"],
+            for_host!(
+                r#"{{"schema_version":1,"active_toolchain":{{"name":"nightly-{0}","reason":{{"kind":"default","path":null}},"rustc_version":null}}}}
+"#
+            ),
+            r"",
+        );
+    });
+}
+
 #[test]
 fn show_multiple_toolchains() {
     setup(&|config| {
@@ -788,6 +825,35 @@ fn list_default_and_override_toolchain() {
     });
 }
 
+#[test]
+fn list_toolchains_json() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        expect_stdout_ok(
+            config,
+            &["rustup", "toolchain", "list", "--format", "json"],
+            for_host!(r#"{{"schema_version":1,"toolchains":[{{"name":"nightly-{0}","path":"#),
+        );
+        expect_stdout_ok(
+            config,
+            &["rustup", "toolchain", "list", "--format", "json"],
+            r#""default":true,"override":null}]}"#,
+        );
+    });
+}
+
+#[test]
+fn list_override_toolchain_tsv() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "override", "set", "nightly"]);
+        expect_stdout_ok(
+            config,
+            &["rustup", "toolchain", "list", "--format", "tsv"],
+            for_host!("nightly-{0}\tfalse\tdirectory-override\t"),
+        );
+    });
+}
+
 #[test]
 fn heal_damaged_toolchain() {
     setup(&|config| {
@@ -1105,6 +1171,24 @@ fn show_active_toolchain_none() {
     });
 }
 
+#[test]
+fn show_active_toolchain_none_machine() {
+    setup(&|config| {
+        expect_ok_ex(
+            config,
+            &["rustup", "show", "active-toolchain", "--format", "json"],
+            "{\"schema_version\":1,\"active_toolchain\":null}\n",
+            r"",
+        );
+        expect_ok_ex(
+            config,
+            &["rustup", "show", "active-toolchain", "--format", "tsv"],
+            "\t\n",
+            r"",
+        );
+    });
+}
+
 #[test]
 fn show_profile() {
     setup(&|config| {
@@ -1782,6 +1866,443 @@ fn directory_override_beats_file_override() {
     });
 }
 
+#[test]
+fn lockfile_pins_toolchain_file() {
+    setup(&|config| {
+        let cwd = config.current_dir();
+        raw::write_file(
+            &cwd.join("rust-toolchain.toml"),
+            "[toolchain]\nchannel = \"nightly\"\n",
+        )
+        .unwrap();
+
+        set_current_dist_date(config, "2015-01-01");
+        expect_stderr_ok(
+            config,
+            &["rustup", "toolchain", "lock"],
+            for_host!("locking toolchain to 'nightly-2015-01-01-{0}'"),
+        );
+        let lockfile = fs::read_to_string(cwd.join("rustup-toolchain.lock")).unwrap();
+        assert!(lockfile.contains(for_host!(r#"toolchain = "nightly-2015-01-01-{0}""#)));
+        assert!(lockfile.contains("manifest_hash = "));
+
+        // The proxies stay on the pinned release after the channel moves on
+        set_current_dist_date(config, "2015-01-02");
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-1");
+
+        expect_ok(config, &["rustup", "toolchain", "lock"]);
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-2");
+    });
+}
+
+#[test]
+fn install_locked_toolchain() {
+    setup(&|config| {
+        let cwd = config.current_dir();
+        raw::write_file(&cwd.join("rust-toolchain"), "nightly").unwrap();
+
+        expect_err(
+            config,
+            &["rustup", "toolchain", "install", "--locked"],
+            "there is no lockfile at",
+        );
+
+        set_current_dist_date(config, "2015-01-01");
+        expect_ok(config, &["rustup", "toolchain", "lock"]);
+        set_current_dist_date(config, "2015-01-02");
+        expect_ok(config, &["rustup", "toolchain", "install", "--locked"]);
+        expect_stdout_ok(
+            config,
+            &["rustup", "run", "nightly", "rustc", "--version"],
+            "hash-nightly-1",
+        );
+    });
+}
+
+#[test]
+fn install_locked_toolchain_fails_on_mismatch() {
+    setup(&|config| {
+        let cwd = config.current_dir();
+        raw::write_file(&cwd.join("rust-toolchain"), "nightly").unwrap();
+        expect_ok(config, &["rustup", "toolchain", "lock"]);
+
+        let lockfile_path = cwd.join("rustup-toolchain.lock");
+        let lockfile = fs::read_to_string(&lockfile_path).unwrap();
+        let (head, tail) = lockfile.split_at(lockfile.find("manifest_hash = \"").unwrap());
+        let tampered = format!("{}manifest_hash = \"0{}", head, &tail[17..]);
+        raw::write_file(&lockfile_path, &tampered).unwrap();
+
+        expect_err(
+            config,
+            &["rustup", "toolchain", "install", "--locked"],
+            "checksum of the manifest for",
+        );
+        expect_err(
+            config,
+            &["rustc", "--version"],
+            "does not match the lockfile",
+        );
+    });
+}
+
+#[test]
+fn install_from_bundle_without_dist_server() {
+    setup(&|config| {
+        let bundle = config.current_dir().join("tc.tar");
+        let bundle = bundle.to_str().unwrap();
+        set_current_dist_date(config, "2015-01-01");
+        expect_ok(
+            config,
+            &[
+                "rustup",
+                "toolchain",
+                "bundle",
+                "nightly",
+                "--targets",
+                clitools::CROSS_ARCH1,
+                "-o",
+                bundle,
+            ],
+        );
+        set_current_dist_date(config, "2015-01-02");
+
+        let out = run(
+            config,
+            "rustup",
+            &["toolchain", "install", "--from-bundle", bundle],
+            &[("RUSTUP_DIST_SERVER", "file:///nonexistent")],
+        );
+        assert!(out.ok);
+        assert!(!out.stderr.contains("Signature verification failed"));
+
+        expect_stdout_ok(
+            config,
+            &["rustup", "run", "nightly-2015-01-01", "rustc", "--version"],
+            "hash-nightly-1",
+        );
+        expect_stdout_ok(
+            config,
+            &[
+                "rustup",
+                "target",
+                "list",
+                "--installed",
+                "--toolchain",
+                "nightly-2015-01-01",
+            ],
+            clitools::CROSS_ARCH1,
+        );
+    });
+}
+
+#[test]
+fn install_from_bundle_without_signatures_stays_offline() {
+    setup(&|config| {
+        set_current_dist_date(config, "2015-01-01");
+        let bundle = config.current_dir().join("tc.tar");
+        expect_ok(
+            config,
+            &[
+                "rustup",
+                "toolchain",
+                "bundle",
+                "nightly",
+                "-o",
+                bundle.to_str().unwrap(),
+            ],
+        );
+        // Repack the bundle without the signatures of its archives, which
+        // the dist server still has
+        let unpacked = config.current_dir().join("unpacked");
+        tar::Archive::new(fs::File::open(&bundle).unwrap())
+            .unpack(&unpacked)
+            .unwrap();
+        for entry in fs::read_dir(unpacked.join("downloads")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(false, |e| e == "asc") {
+                fs::remove_file(path).unwrap();
+            }
+        }
+        let mut builder = tar::Builder::new(fs::File::create(&bundle).unwrap());
+        builder.append_dir_all(".", &unpacked).unwrap();
+        builder.finish().unwrap();
+
+        expect_stderr_ok(
+            config,
+            &[
+                "rustup",
+                "toolchain",
+                "install",
+                "--from-bundle",
+                bundle.to_str().unwrap(),
+            ],
+            "Signature verification failed for 'file://",
+        );
+        expect_stdout_ok(
+            config,
+            &["rustup", "run", "nightly-2015-01-01", "rustc", "--version"],
+            "hash-nightly-1",
+        );
+    });
+}
+
+/// Points the settings at a shared download cache holding the archives of
+/// nightly, unpacked from a bundle of it
+fn setup_shared_cache(config: &Config) -> PathBuf {
+    let bundle = config.current_dir().join("shared.tar");
+    expect_ok(
+        config,
+        &[
+            "rustup",
+            "toolchain",
+            "bundle",
+            "nightly",
+            "-o",
+            bundle.to_str().unwrap(),
+        ],
+    );
+    let unpacked = config.current_dir().join("shared");
+    tar::Archive::new(fs::File::open(&bundle).unwrap())
+        .unpack(&unpacked)
+        .unwrap();
+    let shared_dir = unpacked.join("downloads");
+    let settings = config.rustupdir.join("settings.toml");
+    let contents =
+        fs::read_to_string(&settings).unwrap_or_else(|_| "version = \"12\"\n".to_owned());
+    fs::write(
+        &settings,
+        format!("{}\n[cache]\nshared_dir = {:?}\n", contents, shared_dir),
+    )
+    .unwrap();
+    shared_dir
+}
+
+#[test]
+fn bundle_holds_archives_from_shared_cache() {
+    setup(&|config| {
+        set_current_dist_date(config, "2015-01-01");
+        let shared_dir = setup_shared_cache(config);
+        let bundle = config.current_dir().join("tc.tar");
+        let bundle = bundle.to_str().unwrap();
+        expect_ok(
+            config,
+            &["rustup", "toolchain", "bundle", "nightly", "-o", bundle],
+        );
+        // The host installing from the bundle has no such cache
+        fs::remove_dir_all(&shared_dir).unwrap();
+
+        let out = run(
+            config,
+            "rustup",
+            &["toolchain", "install", "--from-bundle", bundle],
+            &[("RUSTUP_DIST_SERVER", "file:///nonexistent")],
+        );
+        assert!(out.ok, "{}", out.stderr);
+        expect_stdout_ok(
+            config,
+            &["rustup", "run", "nightly-2015-01-01", "rustc", "--version"],
+            "hash-nightly-1",
+        );
+    });
+}
+
+#[test]
+fn install_from_bundle_requires_a_bundle() {
+    setup(&|config| {
+        let cwd = config.current_dir();
+        raw::write_file(&cwd.join("tc.tar"), "").unwrap();
+        expect_err(
+            config,
+            &[
+                "rustup",
+                "toolchain",
+                "install",
+                "--from-bundle",
+                cwd.join("tc.tar").to_str().unwrap(),
+            ],
+            "is not a toolchain bundle",
+        );
+    });
+}
+
+#[test]
+fn mirror_sync_and_prune() {
+    setup(&|config| {
+        let mirror = config.current_dir().join("mirror");
+        let dist = mirror.join("dist");
+        let sync = |keep_days: Option<&str>| {
+            let mut args = vec![
+                "rustup",
+                "mirror",
+                "sync",
+                "--channels",
+                "nightly",
+                "--dest",
+                mirror.to_str().unwrap(),
+            ];
+            if let Some(keep_days) = keep_days {
+                args.extend(&["--keep-days", keep_days]);
+            }
+            expect_ok(config, &args);
+        };
+
+        set_current_dist_date(config, "2015-01-01");
+        sync(None);
+        for file in &[
+            "channel-rust-nightly.toml",
+            "channel-rust-nightly.toml.sha256",
+            "channel-rust-nightly.toml.asc",
+            "2015-01-01/channel-rust-nightly.toml",
+        ] {
+            assert!(dist.join(file).exists(), "{} is missing", file);
+        }
+        let rustc = format!("2015-01-01/rustc-nightly-{}.tar.gz", this_host_triple());
+        assert!(dist.join(&rustc).exists());
+        // The dist server, which is a local directory, keeps its archives
+        assert!(config.distdir.join("dist").join(&rustc).exists());
+        assert!(dist.join(rustc + ".asc").exists());
+
+        set_current_dist_date(config, "2015-01-02");
+        sync(Some("1"));
+        assert!(dist.join("2015-01-02/channel-rust-nightly.toml").exists());
+        assert!(!dist.join("2015-01-01").exists());
+        let manifest = fs::read_to_string(dist.join("channel-rust-nightly.toml")).unwrap();
+        assert!(manifest.contains("date = \"2015-01-02\""));
+    });
+}
+
+#[test]
+fn mirror_sync_leaves_shared_cache_alone() {
+    setup(&|config| {
+        set_current_dist_date(config, "2015-01-01");
+        let shared_dir = setup_shared_cache(config);
+        let cached = |dir: &PathBuf| {
+            let mut names: Vec<_> = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = cached(&shared_dir);
+
+        let mirror = config.current_dir().join("mirror");
+        expect_ok(
+            config,
+            &[
+                "rustup",
+                "mirror",
+                "sync",
+                "--channels",
+                "nightly",
+                "--dest",
+                mirror.to_str().unwrap(),
+            ],
+        );
+        let rustc = format!(
+            "dist/2015-01-01/rustc-nightly-{}.tar.gz",
+            this_host_triple()
+        );
+        assert!(mirror.join(&rustc).exists());
+        assert!(mirror.join(rustc + ".asc").exists());
+        assert_eq!(cached(&shared_dir), before);
+    });
+}
+
+#[test]
+fn build_channel_and_install_from_it() {
+    setup(&|config| {
+        set_current_dist_date(config, "2015-01-02");
+        let archives = config.distdir.join("dist").join("2015-01-02");
+        let artifacts = config.current_dir().join("artifacts");
+        fs::create_dir_all(&artifacts).unwrap();
+        for package in &["rustc", "cargo", "rust-std", "rust-docs"] {
+            let name = format!("{}-nightly-{}.tar.gz", package, this_host_triple());
+            fs::copy(archives.join(&name), artifacts.join(&name)).unwrap();
+        }
+        let channel = config.current_dir().join("channel");
+        let sign_key = std::env::current_dir()
+            .unwrap()
+            .join("tests/mock/signing-key.asc");
+        expect_ok(
+            config,
+            &[
+                "rustup",
+                "dist",
+                "build-channel",
+                "--name",
+                "nightly",
+                "--from",
+                artifacts.to_str().unwrap(),
+                "--dest",
+                channel.to_str().unwrap(),
+                "--date",
+                "2021-06-17",
+                "--sign-key",
+                sign_key.to_str().unwrap(),
+            ],
+        );
+        assert!(channel
+            .join("dist/2021-06-17/channel-rust-nightly.toml.asc")
+            .exists());
+
+        let dist_server = format!("file://{}", channel.to_string_lossy());
+        let out = run(
+            config,
+            "rustup",
+            &["toolchain", "install", "nightly"],
+            &[("RUSTUP_DIST_SERVER", &dist_server)],
+        );
+        assert!(out.ok, "{}", out.stderr);
+        assert!(!out.stderr.contains("Signature verification failed"));
+        expect_stdout_ok(
+            config,
+            &["rustup", "run", "nightly", "rustc", "--version"],
+            "hash-nightly-2",
+        );
+    });
+}
+
+#[test]
+fn build_channel_with_custom_name() {
+    setup(&|config| {
+        let archives = config.distdir.join("dist").join("2015-01-02");
+        let artifacts = config.current_dir().join("artifacts");
+        fs::create_dir_all(&artifacts).unwrap();
+        for package in &["rustc", "cargo", "rust-std"] {
+            let from = format!("{}-nightly-{}.tar.gz", package, this_host_triple());
+            let to = format!("{}-acme-stable-{}.tar.gz", package, this_host_triple());
+            fs::copy(archives.join(from), artifacts.join(to)).unwrap();
+        }
+        let channel = config.current_dir().join("channel");
+        expect_ok(
+            config,
+            &[
+                "rustup",
+                "dist",
+                "build-channel",
+                "--name",
+                "acme-stable",
+                "--from",
+                artifacts.to_str().unwrap(),
+                "--dest",
+                channel.to_str().unwrap(),
+                "--date",
+                "2021-06-17",
+            ],
+        );
+        let manifest =
+            fs::read_to_string(channel.join("dist/channel-rust-acme-stable.toml")).unwrap();
+        assert!(manifest.contains(&format!(
+            "/dist/2021-06-17/rustc-acme-stable-{}.tar.gz",
+            this_host_triple()
+        )));
+        assert!(channel
+            .join("dist/channel-rust-acme-stable.toml.sha256")
+            .exists());
+    });
+}
+
 #[test]
 fn close_file_override_beats_far_directory_override() {
     setup(&|config| {
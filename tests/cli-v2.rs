@@ -3,6 +3,7 @@
 
 pub mod mock;
 
+use std::env::consts::EXE_SUFFIX;
 use std::fs;
 use std::io::Write;
 
@@ -12,8 +13,9 @@ use rustup::test::this_host_triple;
 
 use crate::mock::clitools::{
     self, expect_component_executable, expect_component_not_executable, expect_err,
-    expect_not_stderr_err, expect_not_stdout_ok, expect_ok, expect_ok_ex, expect_stderr_ok,
-    expect_stdout_ok, set_current_dist_date, Config, Scenario,
+    expect_not_stderr_err, expect_not_stderr_ok, expect_not_stdout_ok, expect_ok, expect_ok_ex,
+    expect_stderr_ok, expect_stdout_ok, run, run_with_estimates, set_current_dist_date, Config,
+    Scenario,
 };
 
 pub fn setup(f: &dyn Fn(&mut Config)) {
@@ -134,6 +136,142 @@ fn update_channel() {
     });
 }
 
+#[test]
+fn update_channel_swaps_in_staged_toolchain() {
+    clitools::setup(Scenario::ArchivesV2, &|config| {
+        set_current_dist_date(config, "2015-01-01");
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        set_current_dist_date(config, "2015-01-02");
+        expect_ok(config, &["rustup", "update", "nightly"]);
+
+        // Nothing is left of the staged copy or the old tree
+        let toolchains = config.rustupdir.join("toolchains");
+        let entries: Vec<_> = fs::read_dir(&toolchains)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(entries, [for_host!("nightly-{}").to_owned()]);
+
+        // As left by rustup being killed between the renames of the swap
+        let live = toolchains.join(for_host!("nightly-{}"));
+        fs::rename(&live, toolchains.join(for_host!(".staging-nightly-{}"))).unwrap();
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-2");
+        assert!(live.is_dir());
+    });
+}
+
+#[test]
+#[cfg(unix)]
+fn proxy_runs_read_only_toolchain() {
+    use std::os::unix::fs::PermissionsExt;
+
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        let rustlib = config
+            .rustupdir
+            .join("toolchains")
+            .join(for_host!("nightly-{}"))
+            .join("lib")
+            .join("rustlib");
+        let _ = fs::remove_file(rustlib.join("rustup-in-use.lock"));
+        fs::set_permissions(&rustlib, fs::Permissions::from_mode(0o555)).unwrap();
+        // The toolchain is run without being locked
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-2");
+        fs::set_permissions(&rustlib, fs::Permissions::from_mode(0o755)).unwrap();
+    });
+}
+
+#[test]
+fn rollback_restores_retained_version() {
+    clitools::setup(Scenario::ArchivesV2, &|config| {
+        expect_ok(config, &["rustup", "set", "retained-versions", "1"]);
+        set_current_dist_date(config, "2015-01-01");
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        set_current_dist_date(config, "2015-01-02");
+        expect_ok(config, &["rustup", "update", "nightly"]);
+        expect_stdout_ok(
+            config,
+            &["rustup", "toolchain", "versions", "nightly"],
+            "2015-01-01  1.2.0 (hash-nightly-1)\n",
+        );
+
+        expect_ok(config, &["rustup", "toolchain", "rollback", "nightly"]);
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-1");
+        expect_stdout_ok(
+            config,
+            &["rustup", "toolchain", "versions", "nightly"],
+            "2015-01-02  1.3.0 (hash-nightly-2)\n",
+        );
+
+        // The next update moves forward again
+        expect_ok(config, &["rustup", "update", "nightly"]);
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-2");
+    });
+}
+
+#[test]
+fn rollback_without_retained_versions() {
+    clitools::setup(Scenario::ArchivesV2, &|config| {
+        set_current_dist_date(config, "2015-01-01");
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        set_current_dist_date(config, "2015-01-02");
+        expect_ok(config, &["rustup", "update", "nightly"]);
+        expect_err(
+            config,
+            &["rustup", "toolchain", "rollback", "nightly"],
+            "no previous versions of toolchain",
+        );
+    });
+}
+
+#[test]
+fn history_logs_updates() {
+    clitools::setup(Scenario::ArchivesV2, &|config| {
+        set_current_dist_date(config, "2015-01-01");
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        set_current_dist_date(config, "2015-01-02");
+        expect_ok(config, &["rustup", "update", "nightly"]);
+        expect_stdout_ok(
+            config,
+            &["rustup", "history", "--toolchain", "nightly"],
+            "1.2.0 (hash-nightly-1) -> 1.3.0 (hash-nightly-2)",
+        );
+        expect_stdout_ok(
+            config,
+            &["rustup", "history", "--json"],
+            r#""operation":"update""#,
+        );
+        expect_stdout_ok(
+            config,
+            &["rustup", "history", "--json"],
+            r#""manifest_date":"2015-01-02""#,
+        );
+    });
+}
+
+#[test]
+fn history_logs_installs_by_proxies() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "stable"]);
+        fs::write(config.current_dir().join("rust-toolchain"), "nightly").unwrap();
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-2");
+        expect_stdout_ok(
+            config,
+            &["rustup", "history", "--toolchain", "nightly"],
+            for_host!("install nightly-{}"),
+        );
+    });
+}
+
+#[test]
+fn history_shows_times_out_of_range() {
+    setup(&|config| {
+        let line = "99999999999999999\tupdate\t\t\t\t\t\t\t0\n";
+        fs::write(config.rustupdir.join("history"), line).unwrap();
+        expect_stdout_ok(config, &["rustup", "history"], "99999999999999999 update");
+    });
+}
+
 #[test]
 fn list_toolchains() {
     clitools::setup(Scenario::ArchivesV2, &|config| {
@@ -358,6 +496,267 @@ fn bad_sha_on_installer() {
     });
 }
 
+/// Invalidates the signatures on the component archives of the nightly channel.
+fn make_installer_signatures_invalid(config: &Config) {
+    use crate::mock::dist::{create_signature, write_file};
+    let signature = create_signature(b"hello invalid").unwrap();
+    let dir = config.distdir.join("dist/2015-01-02");
+    for file in fs::read_dir(&dir).unwrap() {
+        let path = file.unwrap().path();
+        let filename = path.to_string_lossy();
+        if filename.ends_with(".tar.gz.asc")
+            || filename.ends_with(".tar.xz.asc")
+            || filename.ends_with(".tar.zst.asc")
+        {
+            write_file(&path, &signature);
+        }
+    }
+}
+
+#[test]
+fn warn_on_invalid_installer_signature() {
+    setup(&|config| {
+        make_installer_signatures_invalid(config);
+        expect_stderr_ok(
+            config,
+            &["rustup", "default", "nightly"],
+            &format!(
+                "warning: Signature verification failed for 'file://{}/dist/2015-01-02/rustc-nightly-{}.tar",
+                config.distdir.display(),
+                this_host_triple(),
+            ),
+        );
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-2");
+    });
+}
+
+#[test]
+fn require_valid_installer_signature() {
+    setup(&|config| {
+        make_installer_signatures_invalid(config);
+        let out = run(
+            config,
+            "rustup",
+            &["default", "nightly"],
+            &[("RUSTUP_SIGNATURE_POLICY", "require")],
+        );
+        assert!(!out.ok);
+        assert!(out.stderr.contains(&format!(
+            "signature verification failed for 'file://{}/dist/2015-01-02/",
+            config.distdir.display(),
+        )));
+    });
+}
+
+#[test]
+fn unknown_signature_policy_setting_is_ignored() {
+    setup(&|config| {
+        fs::write(
+            config.rustupdir.join("settings.toml"),
+            "version = \"12\"\nsignature_policy = \"bogus\"\n",
+        )
+        .unwrap();
+        expect_stderr_ok(
+            config,
+            &["rustup", "default", "nightly"],
+            "warning: unknown signature policy: 'bogus'",
+        );
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-2");
+    });
+}
+
+#[test]
+fn install_falls_back_to_mirror() {
+    setup(&|config| {
+        let missing = config.distdir.with_file_name("missing-dist-server");
+        fs::write(
+            config.rustupdir.join("settings.toml"),
+            format!(
+                "version = \"12\"\ndist_servers = [{:?}, {:?}]\n",
+                format!("file://{}", missing.to_string_lossy()),
+                format!("file://{}", config.distdir.to_string_lossy()),
+            ),
+        )
+        .unwrap();
+        let out = run(
+            config,
+            "rustup",
+            &["toolchain", "install", "nightly"],
+            &[("RUSTUP_DIST_SERVER", "")],
+        );
+        assert!(out.ok, "{}", out.stderr);
+        assert!(out.stderr.contains("falling back to"), "{}", out.stderr);
+        expect_stdout_ok(
+            config,
+            &["rustup", "run", "nightly", "rustc", "--version"],
+            "hash-nightly-2",
+        );
+    });
+}
+
+#[test]
+fn damaged_settings_are_restored_from_backup() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        let settings = config.rustupdir.join("settings.toml");
+        let contents = fs::read_to_string(&settings).unwrap();
+        // As left by an interrupted write in place
+        fs::write(&settings, "").unwrap();
+
+        let out = run(config, "rustup", &["default"], &[]);
+        assert!(out.ok, "{}", out.stderr);
+        assert!(out.stdout.contains("nightly"), "{}", out.stdout);
+        assert!(
+            out.stderr.contains("restoring the settings from"),
+            "{}",
+            out.stderr
+        );
+        assert_eq!(fs::read_to_string(&settings).unwrap(), contents);
+    });
+}
+
+#[test]
+fn cache_prune_without_cache() {
+    setup(&|config| {
+        let downloads = config.rustupdir.join("downloads");
+        fs::create_dir_all(&downloads).unwrap();
+        let hash = "0".repeat(64);
+        fs::write(downloads.join(&hash), "archive").unwrap();
+        expect_stdout_ok(config, &["rustup", "cache", "list"], &hash);
+        // Without a cache, archives are only left behind by failed installs
+        expect_stderr_ok(
+            config,
+            &["rustup", "cache", "prune"],
+            "removed 7 B from the download cache",
+        );
+        expect_not_stdout_ok(config, &["rustup", "cache", "list"], &hash);
+    });
+}
+
+#[test]
+fn gc_removes_unused_toolchains() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        expect_ok(config, &["rustup", "toolchain", "install", "beta"]);
+        expect_ok(config, &["rustup", "toolchain", "install", "stable"]);
+
+        // stable is only used by a project
+        let project = config.rustupdir.with_file_name("projects").join("foo");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(project.join("rust-toolchain"), "stable").unwrap();
+        let settings = config.rustupdir.join("settings.toml");
+        let contents = fs::read_to_string(&settings).unwrap();
+        let roots = format!("project_roots = [{:?}]\n", project.parent().unwrap());
+        fs::write(&settings, roots + &contents).unwrap();
+
+        let hash = config.rustupdir.join("update-hashes").join("bogus");
+        fs::write(&hash, "").unwrap();
+        let partial = config.rustupdir.join("downloads").join("foo.partial");
+        fs::create_dir_all(partial.parent().unwrap()).unwrap();
+        fs::write(&partial, "").unwrap();
+        // Could belong to a rustup running at the same time
+        let temp = config.rustupdir.join("tmp").join("fresh");
+        fs::create_dir_all(temp.parent().unwrap()).unwrap();
+        fs::write(&temp, "").unwrap();
+
+        expect_ok(config, &["rustup", "gc", "--yes"]);
+        let out = run(config, "rustup", &["toolchain", "list"], &[]);
+        assert!(out.ok);
+        assert!(out.stdout.contains("nightly"), "{}", out.stdout);
+        assert!(out.stdout.contains("stable"), "{}", out.stdout);
+        assert!(!out.stdout.contains("beta"), "{}", out.stdout);
+        assert!(!hash.exists());
+        assert!(!partial.exists());
+        assert!(temp.exists());
+
+        expect_stderr_ok(config, &["rustup", "gc", "--yes"], "nothing to clean up");
+        // Nothing has been updated for a week
+        expect_ok(config, &["rustup", "default", "stable"]);
+        expect_stderr_ok(
+            config,
+            &["rustup", "gc", "--yes", "--older-than", "1w"],
+            "nothing to clean up",
+        );
+    });
+}
+
+#[test]
+fn du_reports_components() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        let toolchain = format!("nightly-{}", this_host_triple());
+        let rustc = format!("rustc-{}", this_host_triple());
+        expect_stdout_ok(config, &["rustup", "du"], &toolchain);
+        expect_stdout_ok(config, &["rustup", "du"], &rustc);
+        expect_stdout_ok(config, &["rustup", "du"], "total: ");
+        expect_stdout_ok(
+            config,
+            &["rustup", "du", "--format", "json"],
+            &format!(r#"{{"name":"{}","size":"#, rustc),
+        );
+        expect_stdout_ok(config, &["rustup", "du", "--json"], r#""total":"#);
+        expect_stdout_ok(
+            config,
+            &["rustup", "du", "--format", "tsv"],
+            &format!("component\t{}\t{}\t", toolchain, rustc),
+        );
+    });
+}
+
+#[test]
+fn toolchain_verify_and_repair() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        let rustc = format!("rustc-{}: ok", this_host_triple());
+        expect_stdout_ok(config, &["rustup", "toolchain", "verify"], &rustc);
+
+        let bin = config
+            .rustupdir
+            .join("toolchains")
+            .join(format!("nightly-{}", this_host_triple()))
+            .join("bin");
+        fs::remove_file(bin.join(format!("rustc{}", EXE_SUFFIX))).unwrap();
+        fs::write(bin.join(format!("cargo{}", EXE_SUFFIX)), "modified").unwrap();
+        let out = run(config, "rustup", &["toolchain", "verify", "nightly"], &[]);
+        assert!(!out.ok);
+        assert!(out.stdout.contains("missing: bin/rustc"), "{}", out.stdout);
+        assert!(out.stdout.contains("modified: bin/cargo"), "{}", out.stdout);
+
+        expect_ok(config, &["rustup", "toolchain", "verify", "--repair"]);
+        expect_stdout_ok(config, &["rustup", "toolchain", "verify"], &rustc);
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-2");
+    });
+}
+
+#[test]
+fn install_while_downloading() {
+    setup(&|config| {
+        let out = run(
+            config,
+            "rustup",
+            &[
+                "toolchain",
+                "install",
+                "nightly",
+                "-t",
+                clitools::CROSS_ARCH1,
+            ],
+            &[("RUSTUP_STREAM_INSTALL", "1")],
+        );
+        assert!(out.ok, "{}", out.stderr);
+        expect_stdout_ok(
+            config,
+            &["rustup", "run", "nightly", "rustc", "--version"],
+            "hash-nightly-2",
+        );
+        expect_stdout_ok(
+            config,
+            &["rustup", "target", "list", "--toolchain", "nightly"],
+            &format!("{} (installed)", clitools::CROSS_ARCH1),
+        );
+    });
+}
+
 #[test]
 fn install_override_toolchain_from_channel() {
     setup(&|config| {
@@ -666,6 +1065,57 @@ fn add_target1() {
     });
 }
 
+#[test]
+fn add_target_dry_run() {
+    setup(&|config| {
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        expect_stdout_ok(
+            config,
+            &[
+                "rustup",
+                "target",
+                "add",
+                "--dry-run",
+                clitools::CROSS_ARCH1,
+            ],
+            &format!("  install   rust-std-{}\n", clitools::CROSS_ARCH1),
+        );
+        expect_stdout_ok(
+            config,
+            &[
+                "rustup",
+                "target",
+                "add",
+                "--dry-run",
+                clitools::CROSS_ARCH1,
+            ],
+            for_host!("  keep      rustc-{}\n"),
+        );
+        let path = format!(
+            "toolchains/nightly-{}/lib/rustlib/{}/lib/libstd.rlib",
+            this_host_triple(),
+            clitools::CROSS_ARCH1
+        );
+        assert!(!config.rustupdir.has(&path));
+    });
+}
+
+#[test]
+fn update_dry_run_changes_nothing() {
+    setup(&|config| {
+        set_current_dist_date(config, "2015-01-01");
+        expect_ok(config, &["rustup", "default", "nightly"]);
+        set_current_dist_date(config, "2015-01-02");
+        expect_stdout_ok(
+            config,
+            &["rustup", "update", "--dry-run"],
+            for_host!("nightly-{} - release 2015-01-02"),
+        );
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-1");
+        expect_not_stdout_ok(config, &["rustup", "history"], "update");
+    });
+}
+
 #[test]
 fn add_target2() {
     setup(&|config| {
@@ -1454,6 +1904,41 @@ fn warn_on_invalid_signature() {
     });
 }
 
+#[test]
+fn require_valid_signature() {
+    setup(&|config| {
+        make_signature_invalid(config);
+
+        let out = run(
+            config,
+            "rustup",
+            &["update", "nightly"],
+            &[("RUSTUP_SIGNATURE_POLICY", "require")],
+        );
+        assert!(!out.ok);
+        assert!(out.stderr.contains(&format!(
+            "signature verification failed for 'file://{}/dist/channel-rust-nightly.toml'",
+            config.distdir.display(),
+        )));
+    });
+}
+
+#[test]
+fn skip_signature_check_when_policy_is_off() {
+    setup(&|config| {
+        make_signature_invalid(config);
+
+        let out = run(
+            config,
+            "rustup",
+            &["update", "nightly"],
+            &[("RUSTUP_SIGNATURE_POLICY", "off")],
+        );
+        assert!(out.ok);
+        assert!(!out.stderr.contains("Signature verification failed"));
+    });
+}
+
 #[test]
 fn check_pgp_keys() {
     setup(&|config| {
@@ -1470,6 +1955,107 @@ fn check_pgp_keys() {
     })
 }
 
+#[test]
+fn keyring_add_list_remove() {
+    setup(&|config| {
+        let key = std::env::current_dir()
+            .unwrap()
+            .join("tests/mock/signing-key.pub.asc");
+        expect_stderr_ok(
+            config,
+            &["rustup", "keys", "add", &key.to_string_lossy()],
+            "added key B695EF92BE5CD24ED39124DD1F6239942A482D51 to the keyring",
+        );
+        expect_stdout_ok(
+            config,
+            &["rustup", "keys", "list"],
+            "B695EF92BE5CD24ED39124DD1F6239942A482D51 key from the keyring",
+        );
+        expect_stderr_ok(
+            config,
+            &["rustup", "show", "keys"],
+            "from key from the keyring",
+        );
+        expect_ok(
+            config,
+            &[
+                "rustup",
+                "keys",
+                "remove",
+                "b695 ef92 be5c d24e d391 24dd 1f62 3994 2a48 2d51",
+            ],
+        );
+        expect_not_stdout_ok(config, &["rustup", "keys", "list"], "key from the keyring");
+        expect_err(
+            config,
+            &[
+                "rustup",
+                "keys",
+                "remove",
+                "B695EF92BE5CD24ED39124DD1F6239942A482D51",
+            ],
+            "no key with fingerprint 'B695EF92BE5CD24ED39124DD1F6239942A482D51' in the keyring",
+        );
+    })
+}
+
+#[test]
+fn keyring_key_scoped_to_other_dist_server() {
+    setup(&|config| {
+        let key = std::env::current_dir()
+            .unwrap()
+            .join("tests/mock/signing-key.pub.asc");
+        expect_ok(
+            config,
+            &[
+                "rustup",
+                "keys",
+                "add",
+                "--dist-server",
+                "https://example.com/",
+                &key.to_string_lossy(),
+            ],
+        );
+        expect_stdout_ok(
+            config,
+            &["rustup", "keys", "list"],
+            "for dist server https://example.com\n",
+        );
+        expect_not_stderr_ok(config, &["rustup", "show", "keys"], "key from the keyring");
+    })
+}
+
+#[test]
+fn keyring_key_which_cannot_be_read_is_ignored() {
+    setup(&|config| {
+        let keyring = config.rustupdir.join("keyring");
+        fs::create_dir_all(&keyring).unwrap();
+        fs::write(keyring.join("BROKEN.asc"), "not a key").unwrap();
+        expect_stderr_ok(
+            config,
+            &["rustup", "keys", "list"],
+            "BROKEN.asc'; ignoring it",
+        );
+        expect_ok(config, &["rustup", "toolchain", "install", "nightly"]);
+    })
+}
+
+#[test]
+fn keyring_cannot_remove_builtin_key() {
+    setup(&|config| {
+        expect_err(
+            config,
+            &[
+                "rustup",
+                "keys",
+                "remove",
+                "108F66205EAEB0AAA8DD5E1C85AB96E6FA1BE5FE",
+            ],
+            "the builtin Rust release key cannot be removed",
+        );
+    })
+}
+
 #[test]
 fn install_allow_downgrade() {
     clitools::setup(Scenario::MissingComponent, &|config| {
@@ -1509,6 +2095,57 @@ fn install_allow_downgrade() {
     });
 }
 
+#[test]
+fn install_dry_run_shows_backtracked_nightly() {
+    clitools::setup(Scenario::MissingComponent, &|config| {
+        set_current_dist_date(config, "2019-09-14");
+        expect_ok(config, &["rustup", "toolchain", "install", "nightly"]);
+
+        expect_stdout_ok(
+            config,
+            &[
+                "rustup",
+                "toolchain",
+                "install",
+                "nightly",
+                "-c",
+                "rls",
+                "--allow-downgrade",
+                "--dry-run",
+            ],
+            "release 2019-09-13",
+        );
+        expect_stdout_ok(
+            config,
+            &[
+                "rustup",
+                "toolchain",
+                "install",
+                "nightly",
+                "-c",
+                "rls",
+                "--allow-downgrade",
+                "--dry-run",
+                "--format",
+                "json",
+            ],
+            r#""install":["rls"#,
+        );
+        expect_stdout_ok(config, &["rustc", "--version"], "hash-nightly-3");
+        expect_component_not_executable(config, "rls");
+    });
+}
+
+#[test]
+fn install_reports_estimated_size() {
+    setup(&|config| {
+        // Everything is read in place from the local dist server
+        let out = run_with_estimates(config, "rustup", &["toolchain", "install", "nightly"], &[]);
+        assert!(out.ok);
+        assert!(out.stderr.contains("info: nothing to download, about "));
+    });
+}
+
 #[test]
 fn regression_2601() {
     // We're checking that we don't regress per #2601
@@ -21,9 +21,10 @@ use rustup::dist::manifest::{Component, Manifest};
 use rustup::dist::manifestation::{Changes, Manifestation, UpdateStatus};
 use rustup::dist::prefix::InstallPrefix;
 use rustup::dist::temp;
-use rustup::dist::Notification;
+use rustup::dist::{Notification, SignaturePolicy};
 use rustup::errors::RustupError;
 use rustup::utils::raw as utils_raw;
+use rustup::utils::retry_policy::RetryPolicy;
 use rustup::utils::utils;
 use rustup::PgpPublicKey;
 
@@ -443,7 +444,13 @@ fn update_from_dist(
     // Download the dist manifest and place it into the installation prefix
     let manifest_url = make_manifest_url(dist_server, toolchain)?;
     let manifest_file = temp_cfg.new_file()?;
-    utils::download_file(&manifest_url, &manifest_file, None, &|_| {})?;
+    utils::download_file(
+        &manifest_url,
+        &manifest_file,
+        None,
+        download_cfg.retry_policy,
+        &|_| {},
+    )?;
     let manifest_str = utils::read_file("manifest", &manifest_file)?;
     let manifest = Manifest::parse(&manifest_str)?;
 
@@ -459,6 +466,7 @@ fn update_from_dist(
     let changes = Changes {
         explicit_add_components: add_components,
         remove_components: remove.to_owned(),
+        repair_components: vec![],
     };
 
     manifestation.update(
@@ -550,6 +558,8 @@ fn setup_from_dist_server(
     let prefix = InstallPrefix::from(prefix_tempdir.path().to_owned());
     let download_cfg = DownloadCfg {
         dist_root: "phony",
+        dist_server: DEFAULT_DIST_SERVER,
+        mirrors: &[],
         temp_cfg: &temp_cfg,
         download_dir: &prefix.path().to_owned().join("downloads"),
         notify_handler: &|event| {
@@ -559,6 +569,9 @@ fn setup_from_dist_server(
             "test-key".into(),
             get_public_key(),
         )],
+        signature_policy: SignaturePolicy::Warn,
+        retry_policy: &RetryPolicy::default(),
+        cache: None,
     };
 
     currentprocess::with(
@@ -2086,6 +2099,8 @@ fn reuse_downloaded_file() {
 
         let download_cfg = DownloadCfg {
             dist_root: download_cfg.dist_root,
+            dist_server: download_cfg.dist_server,
+            mirrors: download_cfg.mirrors,
             temp_cfg: download_cfg.temp_cfg,
             download_dir: download_cfg.download_dir,
             notify_handler: &|n| {
@@ -2097,6 +2112,9 @@ fn reuse_downloaded_file() {
                 "test-key".into(),
                 get_public_key(),
             )],
+            signature_policy: SignaturePolicy::Warn,
+            retry_policy: download_cfg.retry_policy,
+            cache: download_cfg.cache,
         };
 
         update_from_dist(
@@ -2130,6 +2148,91 @@ fn reuse_downloaded_file() {
     })
 }
 
+#[test]
+fn reuse_file_in_download_dir() {
+    setup(None, GZOnly, &|url, toolchain, prefix, download_cfg, _| {
+        prevent_installation(prefix);
+
+        let reuse_notification_fired = Arc::new(Cell::new(false));
+
+        let work_tempdir = tempfile::Builder::new().prefix("rustup").tempdir().unwrap();
+        let mut temp_cfg = temp::Cfg::new(
+            work_tempdir.path().to_owned(),
+            DEFAULT_DIST_SERVER,
+            Box::new(|_| ()),
+        );
+        temp_cfg.read_in_place = true;
+        let temp_cfg = &temp_cfg;
+
+        let download_cfg = DownloadCfg {
+            dist_root: download_cfg.dist_root,
+            dist_server: download_cfg.dist_server,
+            mirrors: download_cfg.mirrors,
+            temp_cfg,
+            download_dir: download_cfg.download_dir,
+            notify_handler: &|n| {
+                if let Notification::FileAlreadyDownloaded = n {
+                    reuse_notification_fired.set(true);
+                }
+            },
+            pgp_keys: &[PgpPublicKey::FromEnvironment(
+                "test-key".into(),
+                get_public_key(),
+            )],
+            signature_policy: SignaturePolicy::Warn,
+            retry_policy: download_cfg.retry_policy,
+            cache: download_cfg.cache,
+        };
+
+        update_from_dist(
+            url,
+            toolchain,
+            prefix,
+            &[],
+            &[],
+            &download_cfg,
+            temp_cfg,
+            false,
+        )
+        .unwrap_err();
+        assert!(!reuse_notification_fired.get());
+
+        // Archives of a local dist server are read in place, so only what
+        // was downloaded before is reused
+        let archive = url
+            .to_file_path()
+            .unwrap()
+            .join("dist/2016-02-02/rustc-nightly-x86_64-apple-darwin.tar.gz");
+        let hash = utils::read_file("target hash", &archive.with_extension("gz.sha256")).unwrap()
+            [..SHA256_HASH_LEN]
+            .to_owned();
+        assert!(!download_cfg.download_dir.join(&hash).exists());
+        utils::ensure_dir_exists(
+            "download dir",
+            download_cfg.download_dir,
+            &|_: Notification<'_>| {},
+        )
+        .unwrap();
+        fs::copy(&archive, download_cfg.download_dir.join(&hash)).unwrap();
+
+        allow_installation(prefix);
+
+        update_from_dist(
+            url,
+            toolchain,
+            prefix,
+            &[],
+            &[],
+            &download_cfg,
+            temp_cfg,
+            false,
+        )
+        .unwrap();
+
+        assert!(reuse_notification_fired.get());
+    })
+}
+
 #[test]
 fn checks_files_hashes_before_reuse() {
     setup(None, GZOnly, &|url,
@@ -2157,6 +2260,8 @@ fn checks_files_hashes_before_reuse() {
         let noticed_bad_checksum = Arc::new(Cell::new(false));
         let download_cfg = DownloadCfg {
             dist_root: download_cfg.dist_root,
+            dist_server: download_cfg.dist_server,
+            mirrors: download_cfg.mirrors,
             temp_cfg: download_cfg.temp_cfg,
             download_dir: download_cfg.download_dir,
             notify_handler: &|n| {
@@ -2168,6 +2273,9 @@ fn checks_files_hashes_before_reuse() {
                 "test-key".into(),
                 get_public_key(),
             )],
+            signature_policy: SignaturePolicy::Warn,
+            retry_policy: download_cfg.retry_policy,
+            cache: download_cfg.cache,
         };
 
         update_from_dist(
@@ -1,4 +1,4 @@
-use rustup::dist::component::Transaction;
+use rustup::dist::component::{recover_transaction, Transaction};
 use rustup::dist::dist::DEFAULT_DIST_SERVER;
 use rustup::dist::prefix::InstallPrefix;
 use rustup::dist::temp;
@@ -6,9 +6,11 @@ use rustup::dist::Notification;
 use rustup::utils::raw as utils_raw;
 use rustup::utils::utils;
 use rustup::RustupError;
+use std::env;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 #[test]
 fn add_file() {
@@ -773,6 +775,190 @@ fn rollback_failure_keeps_going() {
 
 // Test that when a transaction creates intermediate directories that
 // they are deleted during rollback.
+#[test]
+fn intermediate_dir_rollback() {
+    let prefixdir = tempfile::Builder::new().prefix("rustup").tempdir().unwrap();
+    let txdir = tempfile::Builder::new().prefix("rustup").tempdir().unwrap();
+
+    let tmpcfg = temp::Cfg::new(
+        txdir.path().to_owned(),
+        DEFAULT_DIST_SERVER,
+        Box::new(|_| ()),
+    );
+
+    let prefix = InstallPrefix::from(prefixdir.path().to_owned());
+
+    let notify = |_: Notification<'_>| ();
+    let mut tx = Transaction::new(prefix.clone(), &tmpcfg, &notify);
+
+    fs::create_dir(prefix.path().join("foo")).unwrap();
+    tx.add_file("c", PathBuf::from("foo/bar/baz/qux")).unwrap();
+    tx.modify_file(PathBuf::from("quux/corge")).unwrap();
+    drop(tx);
+
+    assert!(utils::path_exists(prefix.path().join("foo")));
+    assert!(!utils::path_exists(prefix.path().join("foo/bar")));
+    assert!(!utils::path_exists(prefix.path().join("quux")));
+}
+
+const INTERRUPTED_DIR: &str = "RUSTUP_TEST_INTERRUPTED_DIR";
+
+// Runs the ignored test `name` in a process of its own, which starts a
+// transaction in `dir` and exits in the middle of it, as though rustup had
+// been killed.
+fn run_interrupted(name: &str, dir: &Path) {
+    let status = Command::new(env::current_exe().unwrap())
+        .args(&[name, "--exact", "--ignored", "--quiet"])
+        .env(INTERRUPTED_DIR, dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
 #[test]
 #[ignore]
-fn intermediate_dir_rollback() {}
+fn interrupted_transaction() {
+    let dir = match env::var_os(INTERRUPTED_DIR) {
+        Some(dir) => PathBuf::from(dir),
+        None => return,
+    };
+    let tmpcfg = temp::Cfg::new(dir.join("tmp"), DEFAULT_DIST_SERVER, Box::new(|_| ()));
+    let prefix = InstallPrefix::from(dir.join("prefix"));
+
+    let notify = |_: Notification<'_>| ();
+    let mut tx = Transaction::new(prefix.clone(), &tmpcfg, &notify);
+
+    tx.remove_file("c", PathBuf::from("foo")).unwrap();
+    tx.modify_file(PathBuf::from("bar")).unwrap();
+    utils::write_file("", &prefix.path().join("bar"), "new").unwrap();
+    tx.remove_dir("c", PathBuf::from("baz")).unwrap();
+    tx.add_file("c", PathBuf::from("qux/quux")).unwrap();
+    std::process::exit(0);
+}
+
+// Test that a transaction which was never committed or rolled back, because
+// rustup was killed, is rolled back from its journal.
+#[test]
+fn interrupted_transaction_recovery() {
+    let dir = tempfile::Builder::new().prefix("rustup").tempdir().unwrap();
+
+    let prefix = InstallPrefix::from(dir.path().join("prefix"));
+
+    let notify = |_: Notification<'_>| ();
+
+    fs::create_dir(prefix.path()).unwrap();
+    utils::write_file("", &prefix.path().join("foo"), "old").unwrap();
+    utils::write_file("", &prefix.path().join("bar"), "old").unwrap();
+    fs::create_dir(prefix.path().join("baz")).unwrap();
+
+    run_interrupted("interrupted_transaction", dir.path());
+    // The backups are not lost when the temp directory is cleaned
+    fs::remove_dir_all(dir.path().join("tmp")).unwrap();
+
+    assert!(recover_transaction(&prefix, &notify).unwrap());
+
+    assert_eq!(
+        fs::read_to_string(prefix.path().join("foo")).unwrap(),
+        "old"
+    );
+    assert_eq!(
+        fs::read_to_string(prefix.path().join("bar")).unwrap(),
+        "old"
+    );
+    assert!(prefix.path().join("baz").is_dir());
+    assert!(!utils::path_exists(prefix.path().join("qux")));
+    assert!(!utils::path_exists(prefix.path().join("lib")));
+    assert!(!recover_transaction(&prefix, &notify).unwrap());
+}
+
+#[test]
+#[ignore]
+fn interrupted_install() {
+    let dir = match env::var_os(INTERRUPTED_DIR) {
+        Some(dir) => PathBuf::from(dir),
+        None => return,
+    };
+    let tmpcfg = temp::Cfg::new(dir.join("tmp"), DEFAULT_DIST_SERVER, Box::new(|_| ()));
+    let prefix = InstallPrefix::from(dir.join("toolchain"));
+
+    let notify = |_: Notification<'_>| ();
+    let mut tx = Transaction::new(prefix, &tmpcfg, &notify);
+
+    tx.add_file("c", PathBuf::from("bin/rustc")).unwrap();
+    tx.write_file(
+        "c",
+        PathBuf::from("lib/rustlib/components"),
+        "c\n".to_owned(),
+    )
+    .unwrap();
+    std::process::exit(0);
+}
+
+// An interrupted install into a new directory leaves nothing behind.
+#[test]
+fn interrupted_install_recovery() {
+    let dir = tempfile::Builder::new().prefix("rustup").tempdir().unwrap();
+
+    let prefix = InstallPrefix::from(dir.path().join("toolchain"));
+
+    let notify = |_: Notification<'_>| ();
+
+    run_interrupted("interrupted_install", dir.path());
+
+    assert!(recover_transaction(&prefix, &notify).unwrap());
+    assert!(!utils::path_exists(prefix.path()));
+}
+
+// The journal of a transaction which is still running is left alone, and
+// its backups are kept out of the temp directory.
+#[test]
+fn running_transaction_recovery() {
+    let prefixdir = tempfile::Builder::new().prefix("rustup").tempdir().unwrap();
+    let txdir = tempfile::Builder::new().prefix("rustup").tempdir().unwrap();
+
+    let tmpcfg = temp::Cfg::new(
+        txdir.path().to_owned(),
+        DEFAULT_DIST_SERVER,
+        Box::new(|_| ()),
+    );
+
+    let prefix = InstallPrefix::from(prefixdir.path().to_owned());
+
+    let notify = |_: Notification<'_>| ();
+    let mut tx = Transaction::new(prefix.clone(), &tmpcfg, &notify);
+
+    utils::write_file("", &prefix.path().join("foo"), "old").unwrap();
+    tx.remove_file("c", PathBuf::from("foo")).unwrap();
+    tx.add_file("c", PathBuf::from("bar")).unwrap();
+
+    assert!(!recover_transaction(&prefix, &notify).unwrap());
+    assert!(utils::is_file(prefix.path().join("bar")));
+
+    fs::remove_dir_all(txdir.path()).unwrap();
+    drop(tx);
+
+    assert_eq!(
+        fs::read_to_string(prefix.path().join("foo")).unwrap(),
+        "old"
+    );
+    assert!(!utils::path_exists(prefix.path().join("bar")));
+}
+
+// A transaction which committed but did not get to delete its journal is
+// left alone.
+#[test]
+fn committed_transaction_recovery() {
+    let prefixdir = tempfile::Builder::new().prefix("rustup").tempdir().unwrap();
+
+    let prefix = InstallPrefix::from(prefixdir.path().to_owned());
+
+    let notify = |_: Notification<'_>| ();
+    let journal = prefix.manifest_file("rustup-journal");
+    fs::create_dir_all(journal.parent().unwrap()).unwrap();
+    utils::write_file("", &prefix.path().join("foo"), "").unwrap();
+    utils::write_file("", &journal, "added-file\tfoo\ncommit\n").unwrap();
+
+    assert!(recover_transaction(&prefix, &notify).unwrap());
+    assert!(utils::is_file(prefix.path().join("foo")));
+    assert!(!utils::path_exists(&journal));
+}
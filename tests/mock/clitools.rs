@@ -598,6 +598,23 @@ where
 }
 
 pub fn run<I, A>(config: &Config, name: &str, args: I, env: &[(&str, &str)]) -> SanitizedOutput
+where
+    I: IntoIterator<Item = A> + Clone,
+    A: AsRef<OsStr>,
+{
+    let mut output = run_with_estimates(config, name, args, env);
+    output.stderr = without_estimates(&output.stderr);
+    output
+}
+
+/// `run`, keeping in the output the estimate of how large an install is,
+/// which depends on the size of the mock binaries as they happen to be built
+pub fn run_with_estimates<I, A>(
+    config: &Config,
+    name: &str,
+    args: I,
+    env: &[(&str, &str)],
+) -> SanitizedOutput
 where
     I: IntoIterator<Item = A> + Clone,
     A: AsRef<OsStr>,
@@ -622,6 +639,13 @@ where
     output
 }
 
+fn without_estimates(stderr: &str) -> String {
+    stderr
+        .split_inclusive('\n')
+        .filter(|line| !(line.starts_with("info: ") && line.contains(" once installed")))
+        .collect()
+}
+
 pub(crate) fn run_inprocess<I, A>(
     config: &Config,
     name: &str,
@@ -207,10 +207,11 @@ impl MockDistServer {
         let installer_dir = workdir.join(&installer_name);
         let installer_tarball = archive_dir.join(format!("{}{}", installer_name, format));
         let installer_hash = archive_dir.join(format!("{}{}.sha256", installer_name, format));
+        let installer_sig = archive_dir.join(format!("{}{}.asc", installer_name, format));
 
         fs::create_dir_all(&installer_dir).unwrap();
 
-        type Tarball = HashMap<(String, MockTargetedPackage, String), (Vec<u8>, String)>;
+        type Tarball = HashMap<(String, MockTargetedPackage, String), (Vec<u8>, String, String)>;
         // Tarball creation can be super slow, so cache created tarballs
         // globally to avoid recreating and recompressing tons of tarballs.
         lazy_static! {
@@ -224,7 +225,7 @@ impl MockDistServer {
         );
         let tarballs = TARBALLS.lock().unwrap();
         let hash = if tarballs.contains_key(&key) {
-            let (ref contents, ref hash) = tarballs[&key];
+            let (ref contents, ref hash, ref signature) = tarballs[&key];
             File::create(&installer_tarball)
                 .unwrap()
                 .write_all(contents)
@@ -233,6 +234,7 @@ impl MockDistServer {
                 .unwrap()
                 .write_all(hash.as_bytes())
                 .unwrap();
+            write_file(&installer_sig, signature);
             hash.clone()
         } else {
             drop(tarballs);
@@ -249,10 +251,12 @@ impl MockDistServer {
                 .read_to_end(&mut contents)
                 .unwrap();
             let hash = create_hash(&installer_tarball, &installer_hash);
+            let signature = create_signature(&contents).unwrap();
+            write_file(&installer_sig, &signature);
             TARBALLS
                 .lock()
                 .unwrap()
-                .insert(key, (contents, hash.clone()));
+                .insert(key, (contents, hash.clone(), signature));
             hash
         };
 
@@ -261,7 +265,9 @@ impl MockDistServer {
             let main_installer_tarball = dist_dir.join(format!("{}{}", installer_name, format));
             let main_installer_hash = dist_dir.join(format!("{}{}.sha256", installer_name, format));
             hard_link(installer_tarball, main_installer_tarball).unwrap();
+            let main_installer_sig = dist_dir.join(format!("{}{}.asc", installer_name, format));
             hard_link(installer_hash, main_installer_hash).unwrap();
+            hard_link(installer_sig, main_installer_sig).unwrap();
         }
 
         hash